@@ -1,3 +1,6 @@
 fn main() {
-    println!("cargo:rustc-link-arg=-Tlinkall.x");
+    // The ESP32 linker script, not for the host builds of the tests
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("xtensa") {
+        println!("cargo:rustc-link-arg=-Tlinkall.x");
+    }
 }
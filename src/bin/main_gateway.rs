@@ -5,15 +5,20 @@ use core::sync::atomic::Ordering;
 use embedded_graphics::{mono_font::MonoTextStyle, pixelcolor::BinaryColor};
 
 use embassy_executor::Spawner;
-use embassy_net::{tcp::TcpSocket, Config, DhcpConfig, StackResources};
-use embassy_time::{Duration, Ticker};
+use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, Config, DhcpConfig, StackResources};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::gpio::{Input, Level, Output, Pull};
 use esp_hal::i2c::master::I2c;
 use esp_hal::Async;
 
 use esp_backtrace as _;
+use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
 use esp_hal::clock::CpuClock;
 use esp_hal::i2c;
 use esp_hal::peripherals::Peripherals;
+use esp_hal::uart::{self, Uart};
+use esp_storage::FlashStorage;
+use esp_wifi::esp_now::{enable_esp_now_with_wifi, EspNow};
 use esp_wifi::{wifi::WifiStaDevice, EspWifiController};
 use log::{debug, error, info};
 
@@ -26,25 +31,43 @@ use rust_mqtt::{
     utils::rng_generator::CountingRng,
 };
 
+use espnow_mesh_temp_monitoring_rs::common::connectivity::{
+    connectivity, report, LinkError, LinkEvent,
+};
 use espnow_mesh_temp_monitoring_rs::common::wifi::{
-    approx_rssi_to_percent, connection_task, get_ssid_password, net_task, wait_for_connection,
-    CURRENT_RSSI,
+    approx_rssi_to_percent, connection_task, get_ssid_password, net_task, store_ip,
+    wait_for_connection, CURRENT_RSSI,
 };
 
-use espnow_mesh_temp_monitoring_rs::common::temperature::read_temperature_hack;
+use espnow_mesh_temp_monitoring_rs::common::analog::AnalogChannel;
 
+use espnow_mesh_temp_monitoring_rs::gateway_lib::ads1115::analog_input_task;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::buttons::button_task;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::commands::{
+    handle_command_message, COMMAND_TOPIC_PREFIX,
+};
+use espnow_mesh_temp_monitoring_rs::gateway_lib::display::framebuffer::FrameBuffer;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::display::pages::{
+    AlarmsPage, AnalogPage, DiagnosticsPage, MeshPage, NetworkPage, OverviewPage, Page, TrendsPage,
+};
 use espnow_mesh_temp_monitoring_rs::gateway_lib::display::{
-    configure_text_style, display_update_task, DisplayData, MqttLevelUnit, TemperatureLevelUnit,
-    WifiLevelUnit, CURRENT_MQTT,
+    configure_text_style, display_update_task, GatewayDisplay, PANEL_SIZE,
 };
-// TEST: Test the http requests call with this module
-// use espnow_mesh_temp_monitoring_rs::gateway_lib::requests::make_get_request;
-
-use ssd1306::{
-    mode::BufferedGraphicsModeAsync, prelude::*, size::DisplaySize128x64, I2CDisplayInterface,
-    Ssd1306Async,
+use espnow_mesh_temp_monitoring_rs::gateway_lib::espnow::espnow_receive_task;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::i2c_bus::{I2cBus, SharedI2c};
+use espnow_mesh_temp_monitoring_rs::gateway_lib::io::digital_io_task;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::modbus_rtu::{
+    modbus_rtu_task, DataType, FunctionCode, PollEntry,
+};
+use espnow_mesh_temp_monitoring_rs::gateway_lib::modbus_tcp::modbus_tcp_task;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::ota::ota_download_task;
+use espnow_mesh_temp_monitoring_rs::gateway_lib::pid::{pid_control_task, PidConfig, PidTunings};
+use espnow_mesh_temp_monitoring_rs::gateway_lib::publish::{
+    next_status, queue_reading, restore_status, Reading, READINGS,
 };
 
+use ssd1306::{command::AddrMode, prelude::*, I2CDisplayInterface, Ssd1306Async};
+
 // ****** Arena type heap ****** //
 extern crate alloc;
 // NOTE: HOW MUCH HEAP REQ?
@@ -52,6 +75,67 @@ const HEAP_SIZE: usize = 72 * 1024;
 const OLED_ADDRESS: u8 = 0x3C;
 const ADS1115_ADDR: u8 = 0x48;
 
+// PID heating loop: process value from A0, output on transistor T0.0 (GPIO26)
+const PID_CHANNEL: AnalogChannel = AnalogChannel::new("A0", 0, 0.0, 100.0, "C");
+const PID_SETPOINT: f32 = 50.0;
+const PID_TUNINGS: PidTunings = PidTunings {
+    kp: 4.0,
+    ki: 0.05,
+    kd: 0.0,
+};
+// Modbus RTU field devices on the RS-485 port
+const MODBUS_BAUDRATE: u32 = 9600;
+static MODBUS_POLL_TABLE: [PollEntry; 2] = [
+    PollEntry {
+        name: "meter1_voltage",
+        slave_id: 1,
+        function: FunctionCode::ReadInputRegisters,
+        address: 0x0000,
+        count: 2,
+        data_type: DataType::F32,
+        scale: 1.0,
+        offset: 0.0,
+        unit: "V",
+        topic: "/readings/modbus/meter1/voltage",
+        interval: Duration::from_secs(10),
+    },
+    PollEntry {
+        name: "meter1_power",
+        slave_id: 1,
+        function: FunctionCode::ReadInputRegisters,
+        address: 0x000C,
+        count: 2,
+        data_type: DataType::F32,
+        scale: 1.0,
+        offset: 0.0,
+        unit: "W",
+        topic: "/readings/modbus/meter1/power",
+        interval: Duration::from_secs(10),
+    },
+];
+// Longest wait for an MQTT command between two queued readings
+const SESSION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// MQTT packet buffers, an `ota/chunk` command is about 450 bytes
+const MQTT_BUFFER_SIZE: usize = 512;
+// Host name or address of the broker
+const MQTT_BROKER_HOST: &str = match option_env!("MQTT_BROKER_HOST") {
+    Some(host) => host,
+    None => "192.168.68.108",
+};
+const MQTT_BROKER_PORT: u16 = 1883;
+
+/// Failure of the broker connection, told apart for the display
+fn connect_error(code: ReasonCode) -> LinkError {
+    match code {
+        ReasonCode::NetworkError => LinkError::MqttNetwork,
+        ReasonCode::NotAuthorized
+        | ReasonCode::BadUserNameOrPassword
+        | ReasonCode::BadAuthMethod
+        | ReasonCode::Banned => LinkError::NotAuthorized,
+        _ => LinkError::MqttRefused,
+    }
+}
+
 fn allocate_heap() {
     esp_alloc::heap_allocator!(HEAP_SIZE);
 }
@@ -146,54 +230,119 @@ async fn main(spawner: Spawner) {
     esp_hal_embassy::init(timer0.timer0);
     info!("Embassy initialized!");
 
-    // ********** I2C For Display ********** //
+    // ********** I2C For Display and ADS1115 ********** //
     scan_i2c_bus().await;
     let i2c_module = i2c::master::I2c::new(peripherals.I2C0, i2c::master::Config::default())
         .unwrap()
         .with_sda(peripherals.GPIO16)
         .with_scl(peripherals.GPIO17)
         .into_async();
+    let i2c_bus: &'static I2cBus<_> =
+        mk_static!(I2cBus<I2c<'static, Async>>, I2cBus::new(i2c_module));
 
-    let interface = I2CDisplayInterface::new_custom_address(i2c_module, OLED_ADDRESS);
+    let interface = I2CDisplayInterface::new_custom_address(SharedI2c::new(i2c_bus), OLED_ADDRESS);
 
     let display = mk_static!(
-        Ssd1306Async<
-            I2CInterface<I2c<'static, Async>>,
-            DisplaySize128x64,
-            BufferedGraphicsModeAsync<DisplaySize128x64>,
-        >,
-        Ssd1306Async::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode()
+        GatewayDisplay,
+        Ssd1306Async::new(interface, PANEL_SIZE, DisplayRotation::Rotate0)
     );
-    display.init().await.unwrap();
+    // Frames are sent in the layout of `FrameBuffer`
+    display
+        .init_with_addr_mode(AddrMode::Horizontal)
+        .await
+        .unwrap();
     static TEXT_STYLE: MonoTextStyle<'static, BinaryColor> = configure_text_style();
 
-    // ********** DisplayData init ********** //
-    let temp_status_display = TemperatureLevelUnit {
-        msg: "Temp",
-        level: 0.0,
-        unit: "C",
-    };
-    let wifi_status_display = WifiLevelUnit {
-        msg: "Wifi",
-        level: 0,
-        unit: "%",
+    // ********** Display pages ********** //
+    let pages = mk_static!(
+        [&'static dyn Page<FrameBuffer>; 7],
+        [
+            &OverviewPage,
+            &AnalogPage {
+                channels: &[PID_CHANNEL],
+            },
+            &TrendsPage {
+                channels: &[PID_CHANNEL],
+            },
+            &MeshPage,
+            &NetworkPage { ssid: SSID },
+            &AlarmsPage,
+            &DiagnosticsPage,
+        ]
+    );
+    info!("Initialized display device, spawning task with ~1s refresh.");
+    spawner
+        .spawn(display_update_task(display, &TEXT_STYLE, pages))
+        .unwrap();
+
+    // ********** Analog inputs ********** //
+    // Process value of the PID loop, NaN until the first valid sample
+    spawner
+        .spawn(analog_input_task(SharedI2c::new(i2c_bus), &[PID_CHANNEL]))
+        .unwrap();
+
+    // ********** PID control loop ********** //
+    let pid_output = Output::new(peripherals.GPIO26, Level::Low);
+    let pid_config = PidConfig {
+        channel: PID_CHANNEL,
+        output_index: 0,
+        tunings: PID_TUNINGS,
+        setpoint: PID_SETPOINT,
+        sample_time: Duration::from_secs(1),
+        tpo_window: Duration::from_secs(10),
+        tpo_min_switch: Duration::from_millis(500),
     };
-    let mqtt_status_display =
-        MqttLevelUnit::new("MQTT client", CURRENT_MQTT.load(Ordering::Relaxed));
-    let device_data = mk_static!(
-        DisplayData,
-        DisplayData::new(
-            temp_status_display,
-            wifi_status_display,
-            mqtt_status_display
-        )
+    info!(
+        "Spawning PID control task on '{}' -> T0.0",
+        PID_CHANNEL.name
+    );
+    spawner
+        .spawn(pid_control_task(pid_output, pid_config))
+        .unwrap();
+
+    // ********** Digital IO ********** //
+    let digital_inputs = [
+        Input::new(peripherals.GPIO39, Pull::None),
+        Input::new(peripherals.GPIO34, Pull::None),
+        Input::new(peripherals.GPIO35, Pull::None),
+        Input::new(peripherals.GPIO21, Pull::None),
+        Input::new(peripherals.GPIO22, Pull::None),
+        Input::new(peripherals.GPIO15, Pull::None),
+    ];
+    // T0.0 is owned by the PID loop, T0.1 is free for remote control
+    let transistor_outputs = [None, Some(Output::new(peripherals.GPIO27, Level::Low))];
+    spawner
+        .spawn(digital_io_task(digital_inputs, transistor_outputs))
+        .unwrap();
+
+    // ********** Modbus RTU master ********** //
+    let modbus_uart = Uart::new(
+        peripherals.UART1,
+        uart::Config::default().with_baudrate(MODBUS_BAUDRATE),
+    )
+    .unwrap()
+    .with_rx(peripherals.GPIO13)
+    .with_tx(peripherals.GPIO2)
+    .into_async();
+    let rs485_direction = Output::new(peripherals.GPIO4, Level::Low);
+    info!(
+        "Spawning Modbus RTU task on RS-485 at {} baud",
+        MODBUS_BAUDRATE
     );
-    info!("Initialized display device, spawning task with ~5s refresh.");
     spawner
-        .spawn(display_update_task(display, &TEXT_STYLE, device_data))
+        .spawn(modbus_rtu_task(
+            modbus_uart,
+            rs485_direction,
+            &MODBUS_POLL_TABLE,
+        ))
         .unwrap();
 
+    // ********** Front panel buttons ********** //
+    let mut adc_config = AdcConfig::new();
+    let button_pin = adc_config.enable_pin(peripherals.GPIO32, Attenuation::_11dB);
+    let button_adc = Adc::new(peripherals.ADC1, adc_config);
+    spawner.spawn(button_task(button_adc, button_pin)).unwrap();
+
     // ********** Wifi init ********** //
     // Wifi creds from both config and compile args
     pub const SSID: &str = env!("SSID");
@@ -204,18 +353,34 @@ async fn main(spawner: Spawner) {
     let timg0 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG0);
     let mut rng = esp_hal::rng::Rng::new(peripherals.RNG);
 
-    let esp_wifi_ctrl = mk_static!(
+    let esp_wifi_ctrl: &'static EspWifiController<'static> = mk_static!(
         EspWifiController<'static>,
         esp_wifi::init(timg0.timer0, rng, peripherals.RADIO_CLK,).unwrap()
     );
 
+    // ESP-NOW shares the radio with STA mode, on the channel of the AP
+    let (wifi, esp_now_token) = enable_esp_now_with_wifi(peripherals.WIFI);
     let (sta_device, sta_controller) =
-        esp_wifi::wifi::new_with_mode(esp_wifi_ctrl, peripherals.WIFI, WifiStaDevice).unwrap();
+        esp_wifi::wifi::new_with_mode(esp_wifi_ctrl, wifi, WifiStaDevice).unwrap();
     info!("STA device and controller init OK.");
 
+    let esp_now = EspNow::new_with_wifi(esp_wifi_ctrl, esp_now_token).unwrap();
+    info!("ESP-NOW version {}", esp_now.version().unwrap());
+    let (esp_now_manager, esp_now_sender, esp_now_receiver) = esp_now.split();
+    // Node registry and staged node image are persisted in the flash by the
+    // receive task, which also sends the acks and firmware chunks to the nodes
+    spawner
+        .spawn(espnow_receive_task(
+            esp_now_manager,
+            esp_now_sender,
+            esp_now_receiver,
+            FlashStorage::new(),
+        ))
+        .unwrap();
+
     // Network stack init
     let net_seed = rng.random() as u64 | (rng.random() as u64) << 32;
-    // let tls_seed = rng.random() as u64 | (rng.random() as u64) << 32;
+    let tls_seed = rng.random() as u64 | (rng.random() as u64) << 32;
 
     let dhcp_config = DhcpConfig::default();
     let config = Config::dhcpv4(dhcp_config);
@@ -242,13 +407,16 @@ async fn main(spawner: Spawner) {
     let (stack, runner) = embassy_net::new(
         sta_device,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<6>, StackResources::<6>::new()),
         net_seed,
     );
     spawner
         .spawn(connection_task(sta_controller, SSID, SSID_PASSWORD))
         .unwrap();
     spawner.spawn(net_task(runner)).unwrap();
+    spawner.spawn(modbus_tcp_task(stack)).unwrap();
+    // Node images requested with `ota/fetch`
+    spawner.spawn(ota_download_task(stack, tls_seed)).unwrap();
 
     wait_for_connection(stack).await;
     info!("Connection to Wifi '{}' successfull!", SSID);
@@ -257,44 +425,55 @@ async fn main(spawner: Spawner) {
 
     // ********** init end ********** //
 
-    // Test some request to internet
-    // let url = "https://jsonplaceholder.typicode.com/posts/1";
-    // make_get_request(stack, tls_seed, url).await;
-
     // Connecting to Mqtt broker
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
     let mqtt_poll_tick = Duration::from_secs(30);
-    let mut mqtt_ticker = Ticker::every(mqtt_poll_tick);
-
-    loop {
-        mqtt_ticker.next().await;
 
-        // FIX: SHARE i2c mutex with the temperature read function and the dispaly task
-
-        // HACK: Get a rand reading from the time clock
-        let temp = read_temperature_hack();
+    'mqtt_loop: loop {
+        // Next attempt after a broker error
+        if let Some(delay) = connectivity().retry_in_ms(Instant::now().as_millis()) {
+            Timer::after(Duration::from_millis(delay)).await;
+        }
+        if !stack.is_config_up() {
+            wait_for_connection(stack).await;
+        }
+        // The lease may have changed after a reconnection
+        store_ip(stack);
+        report(LinkEvent::LeaseAcquired);
+
+        report(LinkEvent::BrokerAttempt);
+        // An address is returned as it is
+        let address = match stack.dns_query(MQTT_BROKER_HOST, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => addresses[0],
+            result => {
+                error!(
+                    "Could not resolve broker '{}': {:?}",
+                    MQTT_BROKER_HOST, result
+                );
+                report(LinkEvent::Failed(LinkError::Dns));
+                continue;
+            }
+        };
+        report(LinkEvent::HostResolved);
 
         let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
 
-        // FIX:REFACTOR TO NOT hardcoded IP of host machine where Docker is running
-        let host_ip = embassy_net::Ipv4Address::new(192, 168, 68, 108);
-        let address = embassy_net::IpAddress::Ipv4(host_ip);
-
-        info!("Connecting to MQTT broker at {}:1883...", address);
+        info!(
+            "Connecting to MQTT broker at {}:{}...",
+            address, MQTT_BROKER_PORT
+        );
 
-        let remote_endpoint = (address, 1883);
+        let remote_endpoint = (address, MQTT_BROKER_PORT);
         let connection = socket.connect(remote_endpoint).await;
         if let Err(e) = connection {
-            error!(
-                "connect error: {:?}. Retrying in {}s",
-                e,
-                mqtt_poll_tick.as_secs()
-            );
+            error!("connect error: {:?}", e);
+            report(LinkEvent::Failed(LinkError::TcpConnect));
             continue;
         }
         info!("connected!");
+        report(LinkEvent::TcpConnected);
 
         let mut config = ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
@@ -302,148 +481,155 @@ async fn main(spawner: Spawner) {
         );
         config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
         config.add_client_id(&mac_addr_hex);
-        config.max_packet_size = 200;
-        let mut recv_buffer = [0; 160];
-        let mut write_buffer = [0; 160];
+        config.max_packet_size = MQTT_BUFFER_SIZE as u32;
+        let mut recv_buffer = [0; MQTT_BUFFER_SIZE];
+        let mut write_buffer = [0; MQTT_BUFFER_SIZE];
 
         let mut client = MqttClient::<_, 5, _>::new(
             socket,
             &mut write_buffer,
-            160,
+            MQTT_BUFFER_SIZE,
             &mut recv_buffer,
-            160,
+            MQTT_BUFFER_SIZE,
             config,
         );
 
         match client.connect_to_broker().await {
             Ok(()) => {
                 info!("Connected to broker!");
-                CURRENT_MQTT.store(1, Ordering::Relaxed);
+                report(LinkEvent::MqttConnected);
+            }
+            Err(mqtt_error) => {
+                error!("MQTT connect error: {:?}", mqtt_error);
+                report(LinkEvent::Failed(connect_error(mqtt_error)));
+                continue;
             }
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!(
-                        "MQTT Network Error. Retrying in {}s",
-                        mqtt_poll_tick.as_secs()
-                    );
-                    CURRENT_MQTT.store(90, Ordering::Relaxed);
-                    continue;
-                }
-                _ => {
-                    error!(
-                        "Other MQTT Error: {:?}. Retrying in {}s",
-                        mqtt_error,
-                        mqtt_poll_tick.as_secs()
-                    );
-                    CURRENT_MQTT.store(91, Ordering::Relaxed);
-                    continue;
-                }
-            },
         }
+        // Operator commands (PID setpoint/tuning...) for this gateway. The
+        // client always starts a clean session, so the connection is kept
+        // open and the subscription lives as long as it does.
+        let command_topic = alloc::format!("{}{}/#", COMMAND_TOPIC_PREFIX, mac_addr_hex);
+        if let Err(mqtt_error) = client.subscribe_to_topic(&command_topic).await {
+            error!(
+                "Could not subscribe to topic={}: {:?}",
+                &command_topic, mqtt_error
+            );
+            report(LinkEvent::Failed(LinkError::MqttRefused));
+            continue;
+        }
+
         // Get the MAC and make the topic from it
         let gateway_topic = alloc::format!("/readings/gateway/{}", mac_addr_hex);
 
-        // Get the rssi data from the gateway
-        let raw_rssi = CURRENT_RSSI.load(Ordering::Relaxed);
-        info!("Raw RSSI value: {} dBm", raw_rssi);
-        let rssi = approx_rssi_to_percent(&CURRENT_RSSI);
-        info!("Current rssi%: {}", rssi);
-
-        // HACK: Create a simple timestamp using uptime, we format it in flask app for now
-        let mut uptime_ms = embassy_time::Instant::now().as_millis();
-        let mut gateway_data_str: String<128> = String::new();
-
-        write!(
-            gateway_data_str,
-            "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"rssi\":{:.2}}}",
-            mac_addr_hex, uptime_ms, rssi
-        )
-        .expect("write! failed!");
-        info!("Publishing data: {}", gateway_data_str);
-
-        match client
-            .send_message(
-                &gateway_topic,
-                gateway_data_str.as_bytes(),
-                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                true,
-            )
-            .await
-        {
-            Ok(()) => {
-                info!(
-                    "Successfully sent payload to broker on topic={}",
-                    &gateway_topic
-                )
-            }
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!(
-                        "MQTT Network Error. Retrying in {}s",
-                        mqtt_poll_tick.as_secs()
-                    );
-                    CURRENT_MQTT.store(90, Ordering::Relaxed);
-                    continue;
-                }
-                _ => {
-                    error!(
-                        "Other MQTT Error: {:?}. Retrying in {}s",
-                        mqtt_error,
-                        mqtt_poll_tick.as_secs()
-                    );
-                    CURRENT_MQTT.store(90, Ordering::Relaxed);
-                    continue;
+        let mut next_publish = Instant::now();
+        loop {
+            if Instant::now() >= next_publish {
+                next_publish = Instant::now() + mqtt_poll_tick;
+                if let Some(reading) = Reading::from_analog(&PID_CHANNEL, &mac_addr_hex) {
+                    queue_reading(reading);
                 }
-            },
-        }
 
-        // HACK: FOR TEMP DATA FROM MESH
-        let mesh_sens1_mac = "40:91:51:CB:A4:64";
-        let mesh_sens1_topic = alloc::format!("/readings/temperature/{}", mesh_sens1_mac);
-        uptime_ms = embassy_time::Instant::now().as_millis();
-        let mut mesh_sens1_data_str: String<128> = String::new();
-        write!(
-            mesh_sens1_data_str,
-            "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"temperature\":{:.2}}}",
-            mesh_sens1_mac, uptime_ms, temp
-        )
-        .expect("write! failed!");
-        info!("Publishing data: {}", mesh_sens1_data_str);
-
-        match client
-            .send_message(
-                &mesh_sens1_topic,
-                mesh_sens1_data_str.as_bytes(),
-                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
-                true,
-            )
-            .await
-        {
-            Ok(()) => {
-                info!(
-                    "Successfully sent payload to broker on topic={}",
-                    &gateway_topic
+                // Get the rssi data from the gateway
+                let raw_rssi = CURRENT_RSSI.load(Ordering::Relaxed);
+                info!("Raw RSSI value: {} dBm", raw_rssi);
+                let rssi = approx_rssi_to_percent(&CURRENT_RSSI);
+                info!("Current rssi%: {}", rssi);
+
+                // HACK: Create a simple timestamp using uptime, we format it in flask app for now
+                let uptime_ms = embassy_time::Instant::now().as_millis();
+                let mut gateway_data_str: String<128> = String::new();
+
+                write!(
+                    gateway_data_str,
+                    "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"rssi\":{:.2}}}",
+                    mac_addr_hex, uptime_ms, rssi
                 )
+                .expect("write! failed!");
+                info!("Publishing data: {}", gateway_data_str);
+
+                match client
+                    .send_message(
+                        &gateway_topic,
+                        gateway_data_str.as_bytes(),
+                        rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                        true,
+                    )
+                    .await
+                {
+                    Ok(()) => {
+                        info!(
+                            "Successfully sent payload to broker on topic={}",
+                            &gateway_topic
+                        )
+                    }
+                    Err(mqtt_error) => {
+                        error!("MQTT publish error: {:?}", mqtt_error);
+                        report(LinkEvent::Failed(LinkError::Publish));
+                        continue 'mqtt_loop;
+                    }
+                }
             }
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!(
-                        "MQTT Network Error. Retrying in {}s",
-                        mqtt_poll_tick.as_secs()
-                    );
-                    CURRENT_MQTT.store(90, Ordering::Relaxed);
-                    continue;
+
+            // Latest node states and alarms, kept while the broker was away
+            while let Some(status) = next_status() {
+                let payload = status.to_payload();
+                info!("Publishing status: {}", payload);
+                if let Err(mqtt_error) = client
+                    .send_message(
+                        &status.topic,
+                        payload.as_bytes(),
+                        rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                        status.retain,
+                    )
+                    .await
+                {
+                    error!("MQTT Error: {:?} on topic={}", mqtt_error, &status.topic);
+                    restore_status(status);
+                    report(LinkEvent::Failed(LinkError::Publish));
+                    continue 'mqtt_loop;
                 }
-                _ => {
-                    error!(
-                        "Other MQTT Error: {:?}. Retrying in {}s",
-                        mqtt_error,
-                        mqtt_poll_tick.as_secs()
-                    );
-                    CURRENT_MQTT.store(90, Ordering::Relaxed);
-                    continue;
+            }
+
+            // Publish readings queued by the analog channels, field bus and mesh tasks
+            while let Ok(reading) = READINGS.try_receive() {
+                let payload = reading.to_payload();
+                info!("Publishing data: {}", payload);
+                if let Err(mqtt_error) = client
+                    .send_message(
+                        &reading.topic,
+                        payload.as_bytes(),
+                        rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                        reading.retain,
+                    )
+                    .await
+                {
+                    error!("MQTT Error: {:?} on topic={}", mqtt_error, &reading.topic);
+                    report(LinkEvent::Failed(LinkError::Publish));
+                    continue 'mqtt_loop;
                 }
-            },
+            }
+
+            // Commands received since the last turn
+            loop {
+                match client.receive_message_if_ready().await {
+                    Ok(Some((topic, payload))) => {
+                        handle_command_message(topic, payload, &mac_addr_hex)
+                    }
+                    Ok(None) => break,
+                    Err(mqtt_error) => {
+                        error!("Error while listening for commands: {:?}", mqtt_error);
+                        report(LinkEvent::Failed(LinkError::MqttNetwork));
+                        continue 'mqtt_loop;
+                    }
+                }
+            }
+            // Lost Wi-Fi is reported by the connection task
+            if !stack.is_config_up() {
+                continue 'mqtt_loop;
+            }
+            // Readings are published as they are queued, commands are checked
+            // at least every SESSION_POLL_INTERVAL
+            let _ = with_timeout(SESSION_POLL_INTERVAL, READINGS.ready_to_receive()).await;
         }
     }
 }
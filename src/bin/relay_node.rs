@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+use embassy_executor::Spawner;
+use embassy_time::{with_timeout, Duration, Instant};
+
+use esp_backtrace as _;
+use esp_hal::clock::CpuClock;
+use esp_hal::efuse::Efuse;
+use esp_hal::peripherals::Peripherals;
+use esp_wifi::esp_now::{EspNow, PeerInfo, BROADCAST_ADDRESS};
+use esp_wifi::EspWifiController;
+use log::{debug, error, info, warn};
+
+use espnow_mesh_temp_monitoring_rs::common::config::env_u32_or;
+use espnow_mesh_temp_monitoring_rs::common::mesh::format_mac;
+use espnow_mesh_temp_monitoring_rs::common::mesh_proto::{
+    encode_join_request, Header, JoinRequest, LinkSecurity, MessageType,
+};
+use espnow_mesh_temp_monitoring_rs::common::routing::{
+    Outbound, RouteAction, Router, BEACON_INTERVAL_MS,
+};
+use espnow_mesh_temp_monitoring_rs::node_lib::channel_scan::RelayScan;
+
+// ****** Arena type heap ****** //
+extern crate alloc;
+// esp-wifi needs some heap, no network stack on the relay
+const HEAP_SIZE: usize = 48 * 1024;
+
+// ****** Relay configuration from .cargo/config.toml ****** //
+// First channel searched, see `node_lib::channel_scan`
+const MESH_CHANNEL: u32 = env_u32_or(option_env!("MESH_CHANNEL"), 1);
+// Friendly name sent with the join request on boot
+const RELAY_NAME: &str = match option_env!("RELAY_NAME") {
+    Some(name) => name,
+    None => "relay-node",
+};
+const SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn allocate_heap() {
+    esp_alloc::heap_allocator!(HEAP_SIZE);
+}
+
+// ****** RUNTIME static vars ****** //
+macro_rules! mk_static {
+    ($t:ty,$val:expr) => {{
+        static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
+        #[deny(unused_attributes)]
+        let x = STATIC_CELL.uninit().write(($val));
+        x
+    }};
+}
+
+/// Unicast a frame, the peer is only registered for the time of the send so
+/// the relay is not limited by the size of the ESP-NOW peer table
+async fn send(esp_now: &mut EspNow<'_>, outbound: &Outbound) {
+    let temporary = !esp_now.peer_exists(&outbound.to);
+    if temporary {
+        let peer = PeerInfo {
+            peer_address: outbound.to,
+            lmk: None,
+            channel: None,
+            encrypt: false,
+        };
+        if let Err(e) = esp_now.add_peer(peer) {
+            warn!("Could not add peer {}: {:?}", format_mac(&outbound.to), e);
+            return;
+        }
+    }
+    match with_timeout(
+        SEND_TIMEOUT,
+        esp_now.send_async(&outbound.to, &outbound.frame),
+    )
+    .await
+    {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Relay to {} failed: {:?}", format_mac(&outbound.to), e),
+        Err(_) => debug!("Relay to {} timed out", format_mac(&outbound.to)),
+    }
+    if temporary {
+        let _ = esp_now.remove_peer(&outbound.to);
+    }
+}
+
+#[esp_hal_embassy::main]
+async fn main(_spawner: Spawner) {
+    // ********** Hardware init and heap ********** //
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals: Peripherals = esp_hal::init(config);
+    allocate_heap();
+
+    esp_println::logger::init_logger_from_env();
+
+    let timer1 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+    esp_hal_embassy::init(timer1.timer0);
+
+    let timg0 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG0);
+    let rng = esp_hal::rng::Rng::new(peripherals.RNG);
+    let esp_wifi_ctrl: &'static EspWifiController<'static> = mk_static!(
+        EspWifiController<'static>,
+        esp_wifi::init(timg0.timer0, rng, peripherals.RADIO_CLK).unwrap()
+    );
+    let mut esp_now = EspNow::new(esp_wifi_ctrl, peripherals.WIFI).unwrap();
+    if let Err(e) = esp_now.set_channel(MESH_CHANNEL as u8) {
+        warn!("Could not set ESP-NOW channel {}: {:?}", MESH_CHANNEL, e);
+    }
+
+    let own = Efuse::read_base_mac_address();
+    let mut router = Router::new(own);
+    info!("Mesh relay {} started", format_mac(&own));
+
+    // Relays are registered nodes, accepted while the gateway is pairing
+    let header = Header::new(MessageType::JoinRequest, own, 0);
+    let request = JoinRequest {
+        name: RELAY_NAME,
+        channel: MESH_CHANNEL as u8,
+        security: LinkSecurity::Aead,
+    };
+    match encode_join_request(&header, &request) {
+        Ok(frame) => {
+            match with_timeout(SEND_TIMEOUT, esp_now.send_async(&BROADCAST_ADDRESS, &frame)).await {
+                Ok(Ok(())) => info!("Sent join request as '{}'", RELAY_NAME),
+                _ => warn!("Join request not delivered"),
+            }
+        }
+        Err(e) => error!("Could not encode join request: {:?}", e),
+    }
+
+    // Sweep of the channels while no parent is heard, e.g. after the gateway
+    // roamed to another channel
+    let mut scan = RelayScan::new(MESH_CHANNEL as u8, Instant::now().as_millis());
+    let mut next_beacon_ms = 0;
+    loop {
+        let now_ms = Instant::now().as_millis();
+        if now_ms >= next_beacon_ms {
+            next_beacon_ms = now_ms + BEACON_INTERVAL_MS;
+            let channel = scan.channel();
+            if let Some(next) = scan.poll(router.parent(now_ms).is_some(), now_ms) {
+                info!("No parent on channel {}, trying channel {}", channel, next);
+                if let Err(e) = esp_now.set_channel(next) {
+                    warn!("Could not set ESP-NOW channel {}: {:?}", next, e);
+                }
+            }
+            // Silent until a parent is known, children would pick a dead end
+            if let Some(beacon) = router.beacon(now_ms) {
+                if let Err(e) = esp_now.send_async(&BROADCAST_ADDRESS, &beacon).await {
+                    warn!("Could not broadcast the mesh beacon: {:?}", e);
+                }
+            }
+        }
+
+        let timeout = Duration::from_millis(next_beacon_ms.saturating_sub(now_ms));
+        let Ok(received) = with_timeout(timeout, esp_now.receive_async()).await else {
+            continue;
+        };
+        let src = received.info.src_address;
+        let broadcast = received.info.dst_address == BROADCAST_ADDRESS;
+        let rssi = received.info.rx_control.rssi as i8;
+        let now_ms = Instant::now().as_millis();
+        match router.route(src, broadcast, received.data(), rssi, now_ms) {
+            RouteAction::Forward(outbound) => send(&mut esp_now, &outbound).await,
+            RouteAction::Deliver(_) => debug!("Frame for the relay from {}", format_mac(&src)),
+            RouteAction::Handled => {}
+            RouteAction::Drop(reason) => {
+                debug!("Dropping frame from {}: {:?}", format_mac(&src), reason)
+            }
+        }
+    }
+}
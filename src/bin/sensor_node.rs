@@ -0,0 +1,714 @@
+#![no_std]
+#![no_main]
+use embassy_executor::Spawner;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use esp_backtrace as _;
+use esp_hal::analog::adc::{Adc, AdcConfig, Attenuation};
+use esp_hal::clock::CpuClock;
+use esp_hal::efuse::Efuse;
+use esp_hal::peripherals::Peripherals;
+use esp_hal::ram;
+use esp_hal::rtc_cntl::{sleep::TimerWakeupSource, Rtc};
+use esp_storage::FlashStorage;
+use esp_wifi::esp_now::{EspNow, PeerInfo, BROADCAST_ADDRESS};
+use esp_wifi::EspWifiController;
+use log::{error, info, warn};
+
+use espnow_mesh_temp_monitoring_rs::common::config::env_u32_or;
+use espnow_mesh_temp_monitoring_rs::common::keys::{
+    config_of, derive_aead_key, derive_lmk, is_ack_of, offer_of, parse_key, seal, AeadKey,
+};
+use espnow_mesh_temp_monitoring_rs::common::mesh::{format_mac, parse_mac, MacAddress};
+use espnow_mesh_temp_monitoring_rs::common::mesh_proto::{
+    encode_join_request, ConfigUpdate, FirmwareVersion, Frame, FrameBuffer, FrameBuilder, Header,
+    JoinRequest, LinkReport, LinkSecurity, MeshReading, MessageType, NodeStatus, OtaChunk,
+    OtaOffer, OtaOutcome, OtaReport, ReadingKind, Unit, FLAG_ACK_REQUESTED, FLAG_OTA_PENDING,
+    OTA_CHUNK_LEN,
+};
+use espnow_mesh_temp_monitoring_rs::common::ota::{ChunkStatus, OtaDownload, OtaError};
+use espnow_mesh_temp_monitoring_rs::common::reliability::RetryPolicy;
+use espnow_mesh_temp_monitoring_rs::common::routing::{Neighbor, Router};
+use espnow_mesh_temp_monitoring_rs::common::temperature::read_temperature_hack;
+use espnow_mesh_temp_monitoring_rs::node_lib::channel_scan::{
+    ChannelSearch, ScanStep, CHANNEL_DWELL_MS,
+};
+use espnow_mesh_temp_monitoring_rs::node_lib::ota::{
+    ImageState, OtaData, OTA_CONFIRM_ATTEMPTS, OTA_SLOTS,
+};
+use espnow_mesh_temp_monitoring_rs::node_lib::power::{
+    reset_reason, supply_voltage, BATTERY_SAMPLES,
+};
+use espnow_mesh_temp_monitoring_rs::node_lib::schedule::{NodeSchedule, NodeState, WakePlan};
+use espnow_mesh_temp_monitoring_rs::node_lib::session::next_session;
+
+// ****** Arena type heap ****** //
+extern crate alloc;
+// esp-wifi needs some heap, no network stack on the node
+const HEAP_SIZE: usize = 48 * 1024;
+
+// ****** Node configuration from .cargo/config.toml ****** //
+// Defaults, the gateway can change them (`gateway_lib::downlink`)
+const SAMPLE_INTERVAL_S: u32 = env_u32_or(option_env!("NODE_SAMPLE_INTERVAL_S"), 60);
+const TRANSMIT_INTERVAL_S: u32 = env_u32_or(option_env!("NODE_TRANSMIT_INTERVAL_S"), 300);
+// First channel searched after a cold boot, see `node_lib::channel_scan`
+const MESH_CHANNEL: u32 = env_u32_or(option_env!("MESH_CHANNEL"), 1);
+// Unicast to the gateway when set, otherwise to the parent found from the
+// beacons (`common::routing`), broadcast when none was heard. The channel is
+// found from the beacons either way.
+const GATEWAY_MAC: Option<&str> = option_env!("GATEWAY_MAC");
+// Friendly name sent with the join request on cold boot
+const NODE_NAME: &str = match option_env!("NODE_NAME") {
+    Some(name) => name,
+    None => "sensor-node",
+};
+// Encryption, see `common::keys`: sealed payloads by default, or an ESP-NOW
+// encrypted peer with `MESH_LINK_SECURITY = "espnow"` (needs GATEWAY_MAC)
+const MESH_MASTER_KEY: Option<&str> = option_env!("MESH_MASTER_KEY");
+const MESH_PMK: Option<&str> = option_env!("MESH_PMK");
+const MESH_KEY_EPOCH: u32 = env_u32_or(option_env!("MESH_KEY_EPOCH"), 0);
+const MESH_LINK_SECURITY: Option<&str> = option_env!("MESH_LINK_SECURITY");
+const SEND_TIMEOUT: Duration = Duration::from_millis(500);
+// Time given to the gateway to ack each attempt, through the relays
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+const CHANNEL_DWELL: Duration = Duration::from_millis(CHANNEL_DWELL_MS);
+// Time spent downloading a firmware image per wake, resumed on the next one
+const OTA_BUDGET_S: u32 = env_u32_or(option_env!("NODE_OTA_BUDGET_S"), 60);
+// Time given to the gateway to answer a chunk request, through the relays
+const OTA_CHUNK_TIMEOUT: Duration = Duration::from_millis(200);
+// Requests of the same chunk before giving up until the next wake
+const OTA_CHUNK_ATTEMPTS: u32 = 8;
+// Battery read on GPIO35 through a divider of this ratio, see `node_lib::power`
+const BATTERY_DIVIDER: u32 = env_u32_or(option_env!("NODE_BATTERY_DIVIDER"), 2);
+
+fn allocate_heap() {
+    esp_alloc::heap_allocator!(HEAP_SIZE);
+}
+
+// ****** RUNTIME static vars ****** //
+macro_rules! mk_static {
+    ($t:ty,$val:expr) => {{
+        static STATIC_CELL: static_cell::StaticCell<$t> = static_cell::StaticCell::new();
+        #[deny(unused_attributes)]
+        let x = STATIC_CELL.uninit().write(($val));
+        x
+    }};
+}
+
+// Survives deep sleep, validated by its magic on cold boot
+#[ram(rtc_fast, persistent)]
+static mut NODE_STATE: NodeState = NodeState::new();
+
+/// Search the mesh channel, listening to the beacons for a full period on each
+/// channel, returns it with the best parent heard there
+async fn find_mesh(
+    esp_now: &mut EspNow<'_>,
+    node_id: MacAddress,
+    cached: Option<u8>,
+) -> Option<(u8, Neighbor)> {
+    let mut search = ChannelSearch::new(cached, MESH_CHANNEL as u8);
+    let mut found = None;
+    let mut step = search.step();
+    while let ScanStep::Listen(channel) = step {
+        if let Err(e) = esp_now.set_channel(channel) {
+            warn!("Could not set ESP-NOW channel {}: {:?}", channel, e);
+        }
+        let mut router = Router::new(node_id);
+        let _ = with_timeout(CHANNEL_DWELL, async {
+            loop {
+                let received = esp_now.receive_async().await;
+                router.route(
+                    received.info.src_address,
+                    true,
+                    received.data(),
+                    received.info.rx_control.rssi as i8,
+                    Instant::now().as_millis(),
+                );
+            }
+        })
+        .await;
+        found = router.parent(Instant::now().as_millis());
+        step = search.on_dwell_end(found.as_ref());
+    }
+    match (step, found) {
+        (ScanStep::Found(channel), Some(parent)) => {
+            info!(
+                "Parent {} on channel {} at {} hops from the gateway, rssi={} dBm",
+                format_mac(&parent.mac),
+                channel,
+                parent.hops,
+                parent.rssi
+            );
+            Some((channel, parent))
+        }
+        _ => {
+            warn!("No mesh beacon heard on any channel, broadcasting");
+            None
+        }
+    }
+}
+
+/// Wait for the gateway ack of `seq`, returns its header flags. Other frames
+/// are ignored.
+async fn wait_for_ack(
+    esp_now: &mut EspNow<'_>,
+    node_id: &MacAddress,
+    seq: u16,
+    sealed: Option<(&AeadKey, u32)>,
+) -> Option<u8> {
+    with_timeout(ACK_TIMEOUT, async {
+        loop {
+            let received = esp_now.receive_async().await;
+            if is_ack_of(received.data(), node_id, seq, sealed) {
+                return Frame::decode(received.data()).map_or(0, |frame| frame.header.flags);
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+/// Wait for the clock and configuration the gateway sends after each ack
+async fn wait_for_config(
+    esp_now: &mut EspNow<'_>,
+    node_id: &MacAddress,
+    seq: u16,
+    sealed: Option<(&AeadKey, u32)>,
+) -> Option<ConfigUpdate> {
+    with_timeout(ACK_TIMEOUT, async {
+        loop {
+            let received = esp_now.receive_async().await;
+            if let Some(update) = config_of(received.data(), node_id, seq, sealed) {
+                return update;
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+/// Wait for the firmware offer sent after an ack with `FLAG_OTA_PENDING`
+async fn wait_for_offer(
+    esp_now: &mut EspNow<'_>,
+    node_id: &MacAddress,
+    seq: u16,
+    sealed: Option<(&AeadKey, u32)>,
+) -> Option<OtaOffer> {
+    with_timeout(ACK_TIMEOUT, async {
+        loop {
+            let received = esp_now.receive_async().await;
+            if let Some(offer) = offer_of(received.data(), node_id, seq, sealed) {
+                return offer;
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+/// Next hop toward the gateway, and the key sealing the frames sent there
+struct Uplink<'a> {
+    next_hop: MacAddress,
+    node_id: MacAddress,
+    // Node key and its epoch, none when the frames go in clear
+    key: Option<(&'a AeadKey, u8)>,
+}
+
+impl Uplink<'_> {
+    /// Frame of the records pushed on `builder`, sealed in `session`
+    fn finish(&self, builder: FrameBuilder, session: u32) -> Option<FrameBuffer> {
+        match self.key {
+            Some((key, epoch)) => seal(builder, key, epoch, session).ok(),
+            None => Some(builder.finish()),
+        }
+    }
+
+    /// Key and session an answer to a frame sent in `session` is bound to
+    fn sealed(&self, session: u32) -> Option<(&AeadKey, u32)> {
+        self.key.map(|(key, _)| (key, session))
+    }
+}
+
+/// Ask for the next chunk of the download until it comes, copied into
+/// `buffer`. None when the gateway stopped answering.
+async fn fetch_chunk(
+    esp_now: &mut EspNow<'_>,
+    uplink: &Uplink<'_>,
+    download: &OtaDownload,
+    state: &mut NodeState,
+    buffer: &mut [u8; OTA_CHUNK_LEN],
+) -> Option<usize> {
+    let request = download.request()?;
+    let node_id = &uplink.node_id;
+    for _ in 0..OTA_CHUNK_ATTEMPTS {
+        // A sealed request is served once, each attempt takes a new seq which
+        // the chunk is sent back with
+        let seq = state.next_seq();
+        let header = Header::new(MessageType::OtaRequest, *node_id, seq);
+        let mut builder = FrameBuilder::new(&header);
+        request.push(&mut builder).ok()?;
+        let frame = uplink.finish(builder, state.session)?;
+        if !matches!(
+            with_timeout(SEND_TIMEOUT, esp_now.send_async(&uplink.next_hop, &frame)).await,
+            Ok(Ok(()))
+        ) {
+            continue;
+        }
+        let received = with_timeout(OTA_CHUNK_TIMEOUT, async {
+            loop {
+                let received = esp_now.receive_async().await;
+                let Ok(frame) = Frame::decode(received.data()) else {
+                    continue;
+                };
+                if frame.header.msg_type != MessageType::OtaChunk
+                    || frame.header.node_id != *node_id
+                    || frame.header.seq != seq
+                {
+                    continue;
+                }
+                let Ok(chunk) = OtaChunk::from_payload(frame.payload) else {
+                    return None;
+                };
+                match download.check(&chunk) {
+                    ChunkStatus::Accepted => {
+                        buffer[..chunk.data.len()].copy_from_slice(chunk.data);
+                        return Some(chunk.data.len());
+                    }
+                    // Asked again right away
+                    ChunkStatus::Corrupt => return None,
+                    ChunkStatus::Stale => {}
+                }
+            }
+        })
+        .await;
+        if let Ok(Some(len)) = received {
+            return Some(len);
+        }
+    }
+    None
+}
+
+/// Download the offered image into the slot not running and select it, from
+/// the saved progress. None when out of time or when the gateway stopped
+/// answering, the download resumes on the next wake.
+async fn update_firmware(
+    esp_now: &mut EspNow<'_>,
+    uplink: &Uplink<'_>,
+    offer: &OtaOffer,
+    state: &mut NodeState,
+    flash: &mut FlashStorage,
+    otadata: &mut OtaData,
+) -> Option<OtaOutcome> {
+    let slot = otadata.update_slot();
+    let region = OTA_SLOTS[slot];
+    if offer.size > region.size {
+        return Some(OtaOutcome::TooLarge);
+    }
+    let mut download = OtaDownload::new(offer, state.ota);
+    if download.is_complete() {
+        // Already written and booted once, the new image rolled back
+        state.ota = None;
+        return Some(OtaOutcome::RolledBack);
+    }
+    info!(
+        "Downloading firmware {:08x} into slot {} from offset {} of {}",
+        download.image,
+        slot,
+        download.progress().offset,
+        download.size
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(OTA_BUDGET_S as u64);
+    let mut buffer = [0u8; OTA_CHUNK_LEN];
+    while !download.is_complete() {
+        if Instant::now() >= deadline {
+            info!(
+                "Firmware download paused at offset {}",
+                download.progress().offset
+            );
+            return None;
+        }
+        // A long download uses up the sequence numbers of the session
+        if uplink.key.is_some() && state.needs_session() {
+            match next_session(flash) {
+                Ok(session) => state.start_session(session),
+                Err(e) => {
+                    error!("Could not store the session counter: {:?}", e);
+                    return None;
+                }
+            }
+        }
+        let offset = download.progress().offset;
+        let Some(len) = fetch_chunk(esp_now, uplink, &download, state, &mut buffer).await else {
+            warn!("No chunk at offset {}, resuming on the next wake", offset);
+            return None;
+        };
+        // Written bytes cannot be written again, start over on a bad write
+        if let Err(e) = region.write(flash, offset, &buffer[..len]) {
+            error!("Could not write the chunk at offset {}: {:?}", offset, e);
+            state.ota = None;
+            return Some(OtaOutcome::FlashError);
+        }
+        download.advance(len);
+        state.ota = Some(download.progress());
+    }
+
+    let outcome = match download.verify(flash, &region) {
+        Ok(()) => match otadata.select(flash, slot, ImageState::New) {
+            Ok(()) => return Some(OtaOutcome::Verified),
+            Err(e) => {
+                error!("Could not select the new firmware: {:?}", e);
+                OtaOutcome::FlashError
+            }
+        },
+        Err(OtaError::DigestMismatch) => OtaOutcome::DigestMismatch,
+        Err(_) => OtaOutcome::FlashError,
+    };
+    state.ota = None;
+    Some(outcome)
+}
+
+/// Report the outcome of an update, acked like the readings
+async fn send_report(
+    esp_now: &mut EspNow<'_>,
+    uplink: &Uplink<'_>,
+    state: &mut NodeState,
+    report: OtaReport,
+) -> bool {
+    let header = Header::new(MessageType::OtaReport, uplink.node_id, state.next_seq())
+        .with_flags(FLAG_ACK_REQUESTED);
+    let mut builder = FrameBuilder::new(&header);
+    if report.push(&mut builder).is_err() {
+        return false;
+    }
+    let Some(frame) = uplink.finish(builder, state.session) else {
+        return false;
+    };
+    let sealed = uplink.sealed(state.session);
+    for _ in 0..RetryPolicy::default().max_attempts {
+        if matches!(
+            with_timeout(SEND_TIMEOUT, esp_now.send_async(&uplink.next_hop, &frame)).await,
+            Ok(Ok(()))
+        ) && wait_for_ack(esp_now, &uplink.node_id, header.seq, sealed)
+            .await
+            .is_some()
+        {
+            return true;
+        }
+    }
+    false
+}
+
+#[esp_hal_embassy::main]
+async fn main(_spawner: Spawner) {
+    // ********** Hardware init and heap ********** //
+    let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
+    let peripherals: Peripherals = esp_hal::init(config);
+    allocate_heap();
+
+    esp_println::logger::init_logger_from_env();
+
+    let timer0 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG1);
+    esp_hal_embassy::init(timer0.timer0);
+
+    let defaults = NodeSchedule::new(
+        SAMPLE_INTERVAL_S as u64 * 1000,
+        TRANSMIT_INTERVAL_S as u64 * 1000,
+    );
+    // SAFETY: single core access before any task is spawned
+    let state = unsafe { &mut *core::ptr::addr_of_mut!(NODE_STATE) };
+    // The configuration from the gateway is lost with the RTC memory
+    let mut schedule = if state.is_valid() {
+        defaults.with_config(&state.config)
+    } else {
+        defaults
+    };
+
+    // ********** Sample ********** //
+    let temp = read_temperature_hack();
+    let plan = schedule.on_wake(state, temp);
+    if let Some(reason) = esp_hal::reset::reset_reason() {
+        state.on_reset(reset_reason(reason as u8));
+    }
+    info!(
+        "Node wake #{} sampled {} C, {} samples pending, plan={:?}",
+        state.wake_count, temp, state.samples, plan
+    );
+
+    // ********** Transmit to gateway ********** //
+    if let WakePlan::Transmit { average, samples } = plan {
+        // Before the radio draws its current
+        let mut adc_config = AdcConfig::new();
+        let mut battery_pin = adc_config.enable_pin(peripherals.GPIO35, Attenuation::_11dB);
+        let mut adc = Adc::new(peripherals.ADC1, adc_config);
+        let (raw_sum, conversions) = (0..BATTERY_SAMPLES)
+            .filter_map(|_| nb::block!(adc.read_oneshot(&mut battery_pin)).ok())
+            .fold((0, 0), |(sum, count), raw| (sum + raw as u32, count + 1));
+        let battery_v = supply_voltage(raw_sum, conversions, BATTERY_DIVIDER);
+        info!(
+            "Battery at {:?} V, last reset: {:?}",
+            battery_v, state.reset_reason
+        );
+
+        let timg0 = esp_hal::timer::timg::TimerGroup::new(peripherals.TIMG0);
+        let mut rng = esp_hal::rng::Rng::new(peripherals.RNG);
+        let esp_wifi_ctrl: &'static EspWifiController<'static> = mk_static!(
+            EspWifiController<'static>,
+            esp_wifi::init(timg0.timer0, rng, peripherals.RADIO_CLK).unwrap()
+        );
+        let mut esp_now = EspNow::new(esp_wifi_ctrl, peripherals.WIFI).unwrap();
+
+        let node_id = Efuse::read_base_mac_address();
+        let master_key = MESH_MASTER_KEY.and_then(parse_key);
+        let epoch = MESH_KEY_EPOCH as u8;
+        let gateway_mac = GATEWAY_MAC.and_then(parse_mac);
+        if state.parent.is_none() {
+            if let Some((channel, parent)) = find_mesh(&mut esp_now, node_id, state.channel).await {
+                state.channel = Some(channel);
+                state.parent = Some(parent.mac);
+            }
+        }
+        let channel = state.channel.unwrap_or(MESH_CHANNEL as u8);
+        if let Err(e) = esp_now.set_channel(channel) {
+            warn!("Could not set ESP-NOW channel {}: {:?}", channel, e);
+        }
+        // Gateway, or the relay toward it
+        let next_hop = gateway_mac.or(state.parent).unwrap_or(BROADCAST_ADDRESS);
+        let security = match (MESH_LINK_SECURITY, MESH_PMK.and_then(parse_key)) {
+            (Some("espnow"), Some(pmk)) if gateway_mac.is_some() => {
+                if let Err(e) = esp_now.set_pmk(&pmk) {
+                    warn!("Could not set the ESP-NOW PMK: {:?}", e);
+                }
+                LinkSecurity::EspNow
+            }
+            _ => LinkSecurity::Aead,
+        };
+        if next_hop != BROADCAST_ADDRESS && !esp_now.peer_exists(&next_hop) {
+            // The LMK is derived from the node MAC, the gateway does the same
+            let lmk = master_key
+                .filter(|_| security == LinkSecurity::EspNow)
+                .map(|master| derive_lmk(&master, &node_id, epoch));
+            esp_now
+                .add_peer(PeerInfo {
+                    peer_address: next_hop,
+                    lmk,
+                    channel: None,
+                    encrypt: lmk.is_some(),
+                })
+                .unwrap();
+        }
+
+        if master_key.is_some() && state.needs_session() {
+            let mut flash = FlashStorage::new();
+            match next_session(&mut flash) {
+                Ok(session) => state.start_session(session),
+                Err(e) => error!("Could not store the session counter: {:?}", e),
+            }
+        }
+
+        // Broadcast in clear, the gateway has no encrypted peer for us yet.
+        // Accepted only while its pairing window is open.
+        if state.wake_count == 1 {
+            let header = Header::new(MessageType::JoinRequest, node_id, state.next_seq());
+            let request = JoinRequest {
+                name: NODE_NAME,
+                channel,
+                security,
+            };
+            match encode_join_request(&header, &request) {
+                Ok(frame) => {
+                    match with_timeout(SEND_TIMEOUT, esp_now.send_async(&BROADCAST_ADDRESS, &frame))
+                        .await
+                    {
+                        Ok(Ok(())) => info!("Sent join request as '{}'", NODE_NAME),
+                        _ => warn!("Join request not delivered"),
+                    }
+                }
+                Err(e) => error!("Could not encode join request: {:?}", e),
+            }
+        }
+
+        let header = Header::new(MessageType::Readings, node_id, state.next_seq())
+            .with_flags(FLAG_ACK_REQUESTED);
+        let mut builder = FrameBuilder::new(&header);
+        // Both readings, the link report and the status always fit in a frame
+        builder
+            .push_reading(&MeshReading::new(
+                ReadingKind::Temperature,
+                Unit::Celsius,
+                average,
+            ))
+            .unwrap();
+        if let Some(battery_v) = battery_v {
+            builder
+                .push_reading(&MeshReading::new(
+                    ReadingKind::SupplyVoltage,
+                    Unit::Volt,
+                    battery_v,
+                ))
+                .unwrap();
+        }
+        LinkReport {
+            retries: state.retries,
+            given_up: state.given_up,
+        }
+        .push(&mut builder)
+        .unwrap();
+        NodeStatus {
+            firmware: FirmwareVersion::parse(env!("CARGO_PKG_VERSION")),
+            transmit_interval_s: Some((schedule.transmit_interval_ms / 1000) as u32),
+            config_applied: (state.config_id != 0).then_some(state.config_id),
+            wake_count: Some(state.wake_count),
+            reset_reason: state.reset_reason,
+        }
+        .push(&mut builder)
+        .unwrap();
+        // Without a session the nonce could repeat, stay in clear
+        let node_key = match (master_key, security) {
+            (Some(master), LinkSecurity::Aead) if state.session != 0 => {
+                Some(derive_aead_key(&master, &node_id, epoch))
+            }
+            _ => None,
+        };
+        let uplink = Uplink {
+            next_hop,
+            node_id,
+            key: node_key.as_ref().map(|key| (key, epoch)),
+        };
+        let frame = uplink.finish(builder, state.session).unwrap();
+
+        // Retransmits resend the exact same frame, the gateway drops duplicates
+        let policy = RetryPolicy::default();
+        let sealed = uplink.sealed(state.session);
+        // Flags of the gateway ack, none until acked
+        let mut ack_flags = None;
+        for attempt in 0..policy.max_attempts {
+            if attempt > 0 {
+                state.retries = state.retries.saturating_add(1);
+                Timer::after_millis(policy.backoff_ms(attempt, rng.random())).await;
+            }
+            match with_timeout(SEND_TIMEOUT, esp_now.send_async(&next_hop, &frame)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!("ESP-NOW send attempt {} failed: {:?}", attempt + 1, e);
+                    continue;
+                }
+                Err(_) => {
+                    warn!("ESP-NOW send attempt {} timed out", attempt + 1);
+                    continue;
+                }
+            }
+            ack_flags = wait_for_ack(&mut esp_now, &node_id, header.seq, sealed).await;
+            if ack_flags.is_some() {
+                break;
+            }
+        }
+        let sent = ack_flags.is_some();
+        if sent {
+            info!("Sent average of {} samples: {} C", samples, average);
+        } else {
+            state.given_up = state.given_up.saturating_add(1);
+            // The parent may be gone or the gateway moved to another channel,
+            // search again on the next transmit
+            state.parent = None;
+            error!(
+                "No ack from the gateway after {} attempts",
+                policy.max_attempts
+            );
+        }
+        schedule.on_transmit_done(state, sent);
+
+        // ********** Gateway configuration ********** //
+        let update = if sent {
+            wait_for_config(&mut esp_now, &node_id, header.seq, sealed).await
+        } else {
+            None
+        };
+        if let Some(update) = update {
+            match state.sync_clock(update.time_ms, Instant::now().as_millis()) {
+                Some(drift_ms) => info!("Clock synced to the gateway, drifted {} ms", drift_ms),
+                None => info!("Clock synced to the gateway"),
+            }
+            if state.apply_config(&update) {
+                info!(
+                    "Applied config change {}: {:?}",
+                    state.config_id, state.config
+                );
+                schedule = defaults.with_config(&state.config);
+            }
+        }
+
+        // ********** Firmware update ********** //
+        let offer = match ack_flags {
+            Some(flags) if flags & FLAG_OTA_PENDING != 0 => {
+                wait_for_offer(&mut esp_now, &node_id, header.seq, sealed).await
+            }
+            _ => None,
+        };
+        let mut flash = FlashStorage::new();
+        match OtaData::read(&mut flash) {
+            Ok(mut otadata) if otadata.is_unconfirmed() && sent => {
+                match otadata.confirm(&mut flash) {
+                    Ok(()) => {
+                        info!("New firmware delivered its readings, confirmed");
+                        state.unconfirmed_transmits = 0;
+                        if let Some(progress) = state.ota.take() {
+                            let report = OtaReport {
+                                image: progress.image,
+                                outcome: OtaOutcome::Confirmed,
+                            };
+                            send_report(&mut esp_now, &uplink, state, report).await;
+                        }
+                    }
+                    Err(e) => error!("Could not confirm the new firmware: {:?}", e),
+                }
+            }
+            Ok(mut otadata) if otadata.is_unconfirmed() => {
+                state.unconfirmed_transmits += 1;
+                if state.unconfirmed_transmits >= OTA_CONFIRM_ATTEMPTS {
+                    warn!("New firmware cannot reach the gateway, rolling back");
+                    state.unconfirmed_transmits = 0;
+                    let previous = otadata.update_slot();
+                    match otadata.select(&mut flash, previous, ImageState::Valid) {
+                        Ok(()) => esp_hal::reset::software_reset(),
+                        Err(e) => error!("Could not select the previous firmware: {:?}", e),
+                    }
+                }
+            }
+            Ok(mut otadata) => {
+                if let Some(offer) = offer {
+                    let outcome = update_firmware(
+                        &mut esp_now,
+                        &uplink,
+                        &offer,
+                        state,
+                        &mut flash,
+                        &mut otadata,
+                    )
+                    .await;
+                    if let Some(outcome) = outcome {
+                        info!("Firmware update: {}", outcome.name());
+                        let report = OtaReport {
+                            image: offer.image(),
+                            outcome,
+                        };
+                        send_report(&mut esp_now, &uplink, state, report).await;
+                        if outcome == OtaOutcome::Verified {
+                            esp_hal::reset::software_reset();
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("Could not read the boot partition selection: {:?}", e),
+        }
+    }
+
+    // ********** Deep sleep ********** //
+    let awake_ms = Instant::now().as_millis();
+    let sleep_ms = schedule.sleep_duration_ms(awake_ms);
+    state.advance_clock(awake_ms + sleep_ms);
+    info!("Deep sleeping for {} ms", sleep_ms);
+    let mut rtc = Rtc::new(peripherals.LPWR);
+    let wakeup = TimerWakeupSource::new(core::time::Duration::from_millis(sleep_ms));
+    rtc.sleep_deep(&[&wakeup]);
+}
@@ -0,0 +1,88 @@
+//! Analog channel store for the Norvi iIOT AE04 4-20mA inputs
+//!
+//! The latest engineering value of every analog input is kept in an atomic so
+//! that the display, the control loops and the publishers can read it without
+//! sharing the I2C bus with the ADS1115 driver.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub const ANALOG_CHANNEL_COUNT: usize = 6;
+
+// Loop current limits of the AE04 analog inputs
+pub const LOOP_MIN_MA: f32 = 4.0;
+pub const LOOP_MAX_MA: f32 = 20.0;
+// Currents out of these limits are a broken or shorted loop (NAMUR NE 43)
+pub const LOOP_FAULT_LOW_MA: f32 = 3.6;
+pub const LOOP_FAULT_HIGH_MA: f32 = 21.0;
+
+// f32 values are stored as raw bits, NaN until the first sample of the input
+#[allow(clippy::declare_interior_mutable_const)]
+const NAN_F32: AtomicU32 = AtomicU32::new(0x7FC0_0000);
+static ANALOG_VALUES: [AtomicU32; ANALOG_CHANNEL_COUNT] = [NAN_F32; ANALOG_CHANNEL_COUNT];
+
+/// Scaling of a 4-20mA loop into engineering units
+#[derive(Clone, Copy, Debug)]
+pub struct AnalogChannel {
+    pub name: &'static str,
+    pub index: usize,
+    pub range_low: f32,
+    pub range_high: f32,
+    pub unit: &'static str,
+}
+
+impl AnalogChannel {
+    pub const fn new(
+        name: &'static str,
+        index: usize,
+        range_low: f32,
+        range_high: f32,
+        unit: &'static str,
+    ) -> AnalogChannel {
+        AnalogChannel {
+            name,
+            index,
+            range_low,
+            range_high,
+            unit,
+        }
+    }
+
+    /// Linear mapping of the loop current, clamped to the 4-20mA range
+    pub fn current_to_value(&self, current_ma: f32) -> f32 {
+        let ratio = (current_ma.clamp(LOOP_MIN_MA, LOOP_MAX_MA) - LOOP_MIN_MA)
+            / (LOOP_MAX_MA - LOOP_MIN_MA);
+        self.range_low + ratio * (self.range_high - self.range_low)
+    }
+
+    /// Value of a sampled loop current, NaN for a loop fault
+    pub fn loop_value(&self, current_ma: f32) -> f32 {
+        if (LOOP_FAULT_LOW_MA..=LOOP_FAULT_HIGH_MA).contains(&current_ma) {
+            self.current_to_value(current_ma)
+        } else {
+            f32::NAN
+        }
+    }
+
+    pub fn store(&self, value: f32) {
+        store_reading(self.index, value);
+    }
+
+    pub fn value(&self) -> f32 {
+        reading(self.index)
+    }
+}
+
+/// Store the latest value of a channel, out of range indexes are ignored
+pub fn store_reading(index: usize, value: f32) {
+    if let Some(slot) = ANALOG_VALUES.get(index) {
+        slot.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Latest value of a channel, NaN for an out of range index
+pub fn reading(index: usize) -> f32 {
+    match ANALOG_VALUES.get(index) {
+        Some(slot) => f32::from_bits(slot.load(Ordering::Relaxed)),
+        None => f32::NAN,
+    }
+}
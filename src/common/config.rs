@@ -0,0 +1,30 @@
+//! Build time configuration helpers for the values set in `.cargo/config.toml`
+
+/// Parse an optional decimal env value at compile time, `default` if unset or invalid
+pub const fn env_u32_or(value: Option<&'static str>, default: u32) -> u32 {
+    let bytes = match value {
+        Some(s) => s.as_bytes(),
+        None => return default,
+    };
+    if bytes.is_empty() {
+        return default;
+    }
+
+    let mut result: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = bytes[i];
+        if !digit.is_ascii_digit() {
+            return default;
+        }
+        result = match result.checked_mul(10) {
+            Some(r) => match r.checked_add((digit - b'0') as u32) {
+                Some(r) => r,
+                None => return default,
+            },
+            None => return default,
+        };
+        i += 1;
+    }
+    result
+}
@@ -0,0 +1,412 @@
+//! Connectivity of the gateway, from the Wi-Fi association to the MQTT broker
+//!
+//! The Wi-Fi connection task and the MQTT loop report what happens to them as
+//! `LinkEvent`s, `Connectivity` turns them into the `LinkState` shown on the
+//! OLED and in the Modbus diagnostics, along with the last error:
+//!
+//! `WifiAssociating` -> `Dhcp` -> `Dns` -> `TcpConnecting` -> `MqttConnecting`
+//! -> `Online`
+//!
+//! A broker error moves to `Backoff` until the next attempt, each failure in a
+//! row doubling the delay from `BACKOFF_MIN` up to `BACKOFF_MAX`. Credentials
+//! refused by the broker move to `AuthFailed`, retried after `BACKOFF_MAX`.
+//! Losing the Wi-Fi link goes back to `WifiAssociating` from any state.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use log::info;
+
+pub const BACKOFF_MIN: Duration = Duration::from_secs(5);
+pub const BACKOFF_MAX: Duration = Duration::from_secs(120);
+
+static CONNECTIVITY: Mutex<CriticalSectionRawMutex, Cell<Connectivity>> =
+    Mutex::new(Cell::new(Connectivity::new()));
+
+/// Stage of the connection to the broker, its code in the Modbus diagnostics
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum LinkState {
+    WifiAssociating = 0,
+    // Associated, waiting for a lease
+    Dhcp = 1,
+    // Resolving the broker host
+    Dns = 2,
+    TcpConnecting = 3,
+    MqttConnecting = 4,
+    // Last broker session went through
+    Online = 5,
+    // Waiting for the next attempt after an error
+    Backoff = 6,
+    AuthFailed = 7,
+}
+
+impl LinkState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LinkState::WifiAssociating => "Wi-Fi assoc",
+            LinkState::Dhcp => "DHCP",
+            LinkState::Dns => "DNS",
+            LinkState::TcpConnecting => "TCP connect",
+            LinkState::MqttConnecting => "MQTT connect",
+            LinkState::Online => "Online",
+            LinkState::Backoff => "Backoff",
+            LinkState::AuthFailed => "Auth failed",
+        }
+    }
+
+    /// Stages of an attempt to reach the broker, and its outcome
+    fn is_broker(&self) -> bool {
+        matches!(
+            self,
+            LinkState::Dns
+                | LinkState::TcpConnecting
+                | LinkState::MqttConnecting
+                | LinkState::Online
+        )
+    }
+}
+
+/// Reason of the last failure, its code in the Modbus diagnostics (0 for none)
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum LinkError {
+    WifiConnect = 1,
+    LinkLost = 2,
+    Dns = 3,
+    TcpConnect = 4,
+    MqttNetwork = 5,
+    // Any other refusal of the broker
+    MqttRefused = 6,
+    NotAuthorized = 7,
+    Publish = 8,
+}
+
+impl LinkError {
+    pub fn label(&self) -> &'static str {
+        match self {
+            LinkError::WifiConnect => "Wi-Fi connect",
+            LinkError::LinkLost => "Wi-Fi lost",
+            LinkError::Dns => "DNS lookup",
+            LinkError::TcpConnect => "TCP connect",
+            LinkError::MqttNetwork => "MQTT network",
+            LinkError::MqttRefused => "MQTT refused",
+            LinkError::NotAuthorized => "Not authorized",
+            LinkError::Publish => "Publish",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkEvent {
+    // Wi-Fi connection task
+    WifiConnecting,
+    WifiConnected,
+    LeaseAcquired,
+    // MQTT loop, an attempt starts by resolving the broker host
+    BrokerAttempt,
+    HostResolved,
+    TcpConnected,
+    MqttConnected,
+    Failed(LinkError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Connectivity {
+    pub state: LinkState,
+    // Uptime when the state was entered
+    pub since_ms: u64,
+    pub last_error: Option<LinkError>,
+    // Broker errors in a row
+    pub failures: u32,
+    retry_at_ms: u64,
+}
+
+impl Default for Connectivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Connectivity {
+    pub const fn new() -> Self {
+        Connectivity {
+            state: LinkState::WifiAssociating,
+            since_ms: 0,
+            last_error: None,
+            failures: 0,
+            retry_at_ms: 0,
+        }
+    }
+
+    /// Apply `event` happened at `now_ms`, returns whether the state changed.
+    /// Events out of sequence are ignored, e.g. a broker error reported after
+    /// the Wi-Fi link was lost.
+    pub fn handle(&mut self, event: LinkEvent, now_ms: u64) -> bool {
+        let state = self.state;
+        let next = match event {
+            LinkEvent::WifiConnecting => Some(LinkState::WifiAssociating),
+            LinkEvent::WifiConnected => {
+                (state == LinkState::WifiAssociating).then_some(LinkState::Dhcp)
+            }
+            LinkEvent::LeaseAcquired => {
+                matches!(state, LinkState::WifiAssociating | LinkState::Dhcp)
+                    .then_some(LinkState::Dns)
+            }
+            LinkEvent::BrokerAttempt => (state.is_broker()
+                || matches!(state, LinkState::Backoff | LinkState::AuthFailed))
+            .then_some(LinkState::Dns),
+            LinkEvent::HostResolved => {
+                (state == LinkState::Dns).then_some(LinkState::TcpConnecting)
+            }
+            LinkEvent::TcpConnected => {
+                (state == LinkState::TcpConnecting).then_some(LinkState::MqttConnecting)
+            }
+            LinkEvent::MqttConnected => {
+                let online = state == LinkState::MqttConnecting;
+                if online {
+                    self.failures = 0;
+                }
+                online.then_some(LinkState::Online)
+            }
+            LinkEvent::Failed(error) => self.fail(error, now_ms),
+        };
+        match next {
+            Some(next) if next != state => {
+                self.state = next;
+                self.since_ms = now_ms;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn fail(&mut self, error: LinkError, now_ms: u64) -> Option<LinkState> {
+        match error {
+            LinkError::WifiConnect | LinkError::LinkLost => {
+                self.last_error = Some(error);
+                Some(LinkState::WifiAssociating)
+            }
+            _ if self.state.is_broker() => {
+                self.last_error = Some(error);
+                self.failures = self.failures.saturating_add(1);
+                let (next, delay) = match error {
+                    LinkError::NotAuthorized => (LinkState::AuthFailed, BACKOFF_MAX),
+                    _ => (LinkState::Backoff, backoff(self.failures)),
+                };
+                self.retry_at_ms = now_ms + delay.as_millis();
+                Some(next)
+            }
+            _ => None,
+        }
+    }
+
+    /// Time left at `now_ms` before the next broker attempt, when waiting
+    pub fn retry_in_ms(&self, now_ms: u64) -> Option<u64> {
+        matches!(self.state, LinkState::Backoff | LinkState::AuthFailed)
+            .then(|| self.retry_at_ms.saturating_sub(now_ms))
+    }
+}
+
+/// Delay after `failures` broker errors in a row
+pub fn backoff(failures: u32) -> Duration {
+    let shift = failures.saturating_sub(1).min(16);
+    let delay_ms = BACKOFF_MIN.as_millis() << shift;
+    Duration::from_millis(delay_ms.min(BACKOFF_MAX.as_millis()))
+}
+
+/// Report `event` to the shared state
+pub fn report(event: LinkEvent) {
+    let now_ms = Instant::now().as_millis();
+    let (previous, connectivity, changed) = CONNECTIVITY.lock(|cell| {
+        let mut connectivity = cell.get();
+        let previous = connectivity.state;
+        let changed = connectivity.handle(event, now_ms);
+        cell.set(connectivity);
+        (previous, connectivity, changed)
+    });
+    if changed {
+        info!(
+            "Link {:?} -> {:?} on {:?}",
+            previous, connectivity.state, event
+        );
+    }
+}
+
+/// Copy of the shared state
+pub fn connectivity() -> Connectivity {
+    CONNECTIVITY.lock(|cell| cell.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    const ALL_STATES: [LinkState; 8] = [
+        LinkState::WifiAssociating,
+        LinkState::Dhcp,
+        LinkState::Dns,
+        LinkState::TcpConnecting,
+        LinkState::MqttConnecting,
+        LinkState::Online,
+        LinkState::Backoff,
+        LinkState::AuthFailed,
+    ];
+
+    fn in_state(state: LinkState) -> Connectivity {
+        Connectivity {
+            state,
+            ..Connectivity::new()
+        }
+    }
+
+    /// Fail a broker attempt once connected over TCP
+    fn fail_attempt(connectivity: &mut Connectivity, error: LinkError, now_ms: u64) {
+        for event in [
+            LinkEvent::BrokerAttempt,
+            LinkEvent::HostResolved,
+            LinkEvent::TcpConnected,
+            LinkEvent::Failed(error),
+        ] {
+            connectivity.handle(event, now_ms);
+        }
+    }
+
+    #[test]
+    fn connection_goes_through_each_stage() {
+        let mut connectivity = Connectivity::new();
+        let mut steps = StdVec::new();
+        for (event, now_ms) in [
+            (LinkEvent::WifiConnected, 100),
+            (LinkEvent::LeaseAcquired, 200),
+            (LinkEvent::BrokerAttempt, 300),
+            (LinkEvent::HostResolved, 400),
+            (LinkEvent::TcpConnected, 500),
+            (LinkEvent::MqttConnected, 600),
+        ] {
+            let changed = connectivity.handle(event, now_ms);
+            steps.push((changed, connectivity.state, connectivity.since_ms));
+        }
+        assert_eq!(
+            steps,
+            [
+                (true, LinkState::Dhcp, 100),
+                (true, LinkState::Dns, 200),
+                // Already resolving, the attempt changes nothing
+                (false, LinkState::Dns, 200),
+                (true, LinkState::TcpConnecting, 400),
+                (true, LinkState::MqttConnecting, 500),
+                (true, LinkState::Online, 600),
+            ]
+        );
+        assert_eq!(connectivity.last_error, None);
+        assert_eq!(connectivity.retry_in_ms(600), None);
+    }
+
+    #[test]
+    fn events_out_of_sequence_are_ignored() {
+        for (state, event) in [
+            (LinkState::Online, LinkEvent::WifiConnected),
+            (LinkState::Dhcp, LinkEvent::HostResolved),
+            (LinkState::WifiAssociating, LinkEvent::BrokerAttempt),
+            (LinkState::Dns, LinkEvent::TcpConnected),
+            (LinkState::TcpConnecting, LinkEvent::MqttConnected),
+            (LinkState::Online, LinkEvent::LeaseAcquired),
+            (LinkState::Backoff, LinkEvent::MqttConnected),
+            // Broker error reported after the link was lost
+            (
+                LinkState::WifiAssociating,
+                LinkEvent::Failed(LinkError::MqttNetwork),
+            ),
+            (LinkState::Backoff, LinkEvent::Failed(LinkError::Publish)),
+        ] {
+            let mut connectivity = in_state(state);
+            assert!(
+                !connectivity.handle(event, 1_000),
+                "{:?} in {:?}",
+                event,
+                state
+            );
+            assert_eq!(connectivity, in_state(state));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut connectivity = in_state(LinkState::Online);
+        let mut delays = StdVec::new();
+        for attempt in 0..8 {
+            let now_ms = attempt * 1_000_000;
+            fail_attempt(&mut connectivity, LinkError::TcpConnect, now_ms);
+            assert_eq!(connectivity.state, LinkState::Backoff);
+            delays.push(connectivity.retry_in_ms(now_ms).unwrap() / 1000);
+        }
+        assert_eq!(delays, [5, 10, 20, 40, 80, 120, 120, 120]);
+        assert_eq!(connectivity.failures, 8);
+        assert_eq!(connectivity.last_error, Some(LinkError::TcpConnect));
+
+        // A broker session starts over from the minimum
+        for event in [
+            LinkEvent::BrokerAttempt,
+            LinkEvent::HostResolved,
+            LinkEvent::TcpConnected,
+            LinkEvent::MqttConnected,
+        ] {
+            connectivity.handle(event, 9_000_000);
+        }
+        assert_eq!(connectivity.failures, 0);
+        fail_attempt(&mut connectivity, LinkError::MqttNetwork, 10_000_000);
+        assert_eq!(connectivity.retry_in_ms(10_000_000), Some(5_000));
+    }
+
+    #[test]
+    fn backoff_delays() {
+        assert_eq!(backoff(0), BACKOFF_MIN);
+        assert_eq!(backoff(1), BACKOFF_MIN);
+        assert_eq!(backoff(2), BACKOFF_MIN * 2);
+        assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn refused_credentials_wait_the_longest() {
+        let mut connectivity = in_state(LinkState::Online);
+        fail_attempt(&mut connectivity, LinkError::NotAuthorized, 1_000);
+        assert_eq!(connectivity.state, LinkState::AuthFailed);
+        assert_eq!(connectivity.last_error, Some(LinkError::NotAuthorized));
+        assert_eq!(
+            connectivity.retry_in_ms(1_000),
+            Some(BACKOFF_MAX.as_millis())
+        );
+        // Retried like any other failure
+        assert!(connectivity.handle(LinkEvent::BrokerAttempt, 200_000));
+        assert_eq!(connectivity.state, LinkState::Dns);
+    }
+
+    #[test]
+    fn retry_countdown_ends_at_zero() {
+        let mut connectivity = in_state(LinkState::Online);
+        fail_attempt(&mut connectivity, LinkError::Dns, 10_000);
+        assert_eq!(connectivity.retry_in_ms(10_000), Some(5_000));
+        assert_eq!(connectivity.retry_in_ms(12_000), Some(3_000));
+        assert_eq!(connectivity.retry_in_ms(15_000), Some(0));
+        assert_eq!(connectivity.retry_in_ms(60_000), Some(0));
+        // Only while waiting
+        connectivity.handle(LinkEvent::BrokerAttempt, 60_000);
+        assert_eq!(connectivity.retry_in_ms(60_000), None);
+    }
+
+    #[test]
+    fn losing_the_link_restarts_from_any_state() {
+        for state in ALL_STATES {
+            let mut connectivity = in_state(state);
+            let changed = connectivity.handle(LinkEvent::Failed(LinkError::LinkLost), 5_000);
+            assert_eq!(changed, state != LinkState::WifiAssociating);
+            assert_eq!(connectivity.state, LinkState::WifiAssociating);
+            assert_eq!(connectivity.last_error, Some(LinkError::LinkLost));
+            assert_eq!(connectivity.retry_in_ms(5_000), None);
+        }
+    }
+}
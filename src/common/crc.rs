@@ -0,0 +1,34 @@
+//! CRC helpers shared by the field bus, mesh codecs and OTA updates
+
+/// CRC-16/MODBUS (poly 0xA001 reflected, init 0xFFFF), sent low byte first
+pub fn crc16_modbus(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= u16::from(*byte);
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC-32 as `esp_rom_crc32_le` of the ESP32 ROM (poly 0xEDB88320 reflected,
+/// `crc` inverted on entry and exit), used by the bootloader for `otadata`
+pub fn crc32_le(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            if crc & 0x0000_0001 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
@@ -0,0 +1,377 @@
+//! Key management and payload sealing for the ESP-NOW mesh
+//!
+//! Keys come from `.cargo/config.toml` as 32 hex chars:
+//! - `MESH_PMK`: primary master key of the ESP-NOW link layer (gateway)
+//! - `MESH_MASTER_KEY`: shared secret the per-node keys are derived from
+//!
+//! Per-node keys are derived on both sides from the node MAC and a key epoch,
+//! rotating a node key means bumping its epoch. Derivation is
+//! HMAC-SHA256(master, label || MAC || epoch):
+//! - label `lmk`: ESP-NOW local master key (first 16 bytes)
+//! - label `aead`: ChaCha20-Poly1305 key of the application layer fallback
+//!
+//! Sealed payload layout (frames with `FLAG_ENCRYPTED`):
+//! - 1 byte: key epoch
+//! - 4 bytes: session, bumped by the node on each cold boot and seq wrap
+//! - ciphertext of the TLV records
+//! - 16 bytes: Poly1305 tag, the frame header is authenticated data
+//!
+//! The nonce is session || node MAC || seq, unique as long as the session
+//! only moves forward, which the receiver enforces with its replay window.
+//! Acks of sealed frames are not sealed, the gateway has no nonce space of its
+//! own, they carry a truncated HMAC bound to the acked node, seq and session.
+//! Firmware offers (`common::ota`) and configuration updates carry one the
+//! same way, bound to their content; the firmware chunks themselves are
+//! checked against the digest of the offer.
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use heapless::Vec;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{
+    Ack, ConfigUpdate, Frame, FrameBuffer, FrameBuilder, Header, MessageType, OtaOffer, ProtoError,
+    ACK_AUTH_LEN, CONFIG_AUTH_LEN, FLAG_ENCRYPTED, HEADER_LEN, MAX_PAYLOAD_LEN, OTA_AUTH_LEN,
+};
+
+pub const KEY_LEN: usize = 16;
+pub const AEAD_KEY_LEN: usize = 32;
+const SEAL_PREFIX_LEN: usize = 5;
+const TAG_LEN: usize = 16;
+pub const SEAL_OVERHEAD: usize = SEAL_PREFIX_LEN + TAG_LEN;
+// Frames older than this many sequence numbers are rejected
+pub const REPLAY_WINDOW: u16 = 32;
+
+pub type MeshKey = [u8; KEY_LEN];
+pub type AeadKey = [u8; AEAD_KEY_LEN];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CryptoError {
+    Frame(ProtoError),
+    NotEncrypted,
+    UnknownEpoch(u8),
+    AuthFailed,
+    Replayed,
+}
+
+/// Parse a key written as 32 hex chars
+pub fn parse_key(hex: &str) -> Option<MeshKey> {
+    let hex = hex.trim();
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; KEY_LEN];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(key)
+}
+
+fn derive(master: &MeshKey, label: &[u8], mac: &MacAddress, epoch: u8) -> [u8; 32] {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(master).expect("HMAC takes any key size");
+    hmac.update(label);
+    hmac.update(mac);
+    hmac.update(&[epoch]);
+    hmac.finalize().into_bytes().into()
+}
+
+/// ESP-NOW local master key of a node
+pub fn derive_lmk(master: &MeshKey, mac: &MacAddress, epoch: u8) -> MeshKey {
+    let mut lmk = [0u8; KEY_LEN];
+    lmk.copy_from_slice(&derive(master, b"lmk", mac, epoch)[..KEY_LEN]);
+    lmk
+}
+
+/// Application layer AEAD key of a node
+pub fn derive_aead_key(master: &MeshKey, mac: &MacAddress, epoch: u8) -> AeadKey {
+    derive(master, b"aead", mac, epoch)
+}
+
+/// Tag proving an ack comes from the gateway, bound to the acked frame
+pub fn ack_auth(key: &AeadKey, node_id: &MacAddress, seq: u16, session: u32) -> [u8; ACK_AUTH_LEN] {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key size");
+    hmac.update(b"ack");
+    hmac.update(node_id);
+    hmac.update(&seq.to_le_bytes());
+    hmac.update(&session.to_le_bytes());
+    let mut auth = [0u8; ACK_AUTH_LEN];
+    auth.copy_from_slice(&hmac.finalize().into_bytes()[..ACK_AUTH_LEN]);
+    auth
+}
+
+/// Tag proving a firmware offer comes from the gateway, bound to the session
+/// of the node and to the offered image
+pub fn ota_auth(
+    key: &AeadKey,
+    node_id: &MacAddress,
+    session: u32,
+    offer: &OtaOffer,
+) -> [u8; OTA_AUTH_LEN] {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key size");
+    hmac.update(b"ota");
+    hmac.update(node_id);
+    hmac.update(&session.to_le_bytes());
+    hmac.update(&offer.size.to_le_bytes());
+    hmac.update(&offer.digest);
+    if let Some(version) = offer.version {
+        hmac.update(&[version.major, version.minor, version.patch]);
+    }
+    let mut auth = [0u8; OTA_AUTH_LEN];
+    auth.copy_from_slice(&hmac.finalize().into_bytes()[..OTA_AUTH_LEN]);
+    auth
+}
+
+/// Tag proving a configuration update comes from the gateway, bound to the
+/// session of the node and to the acked seq
+pub fn config_auth(
+    key: &AeadKey,
+    node_id: &MacAddress,
+    seq: u16,
+    session: u32,
+    update: &ConfigUpdate,
+) -> [u8; CONFIG_AUTH_LEN] {
+    // The encoding without the tag covers every field
+    let unsigned = ConfigUpdate {
+        auth: None,
+        ..*update
+    };
+    let header = Header::new(MessageType::Config, *node_id, seq);
+    let encoded = unsigned.encode(&header).unwrap_or_default();
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key size");
+    hmac.update(b"config");
+    hmac.update(&session.to_le_bytes());
+    hmac.update(&encoded);
+    let mut auth = [0u8; CONFIG_AUTH_LEN];
+    auth.copy_from_slice(&hmac.finalize().into_bytes()[..CONFIG_AUTH_LEN]);
+    auth
+}
+
+/// Whether `data` is the gateway ack of the frame `seq` of `node_id`, the
+/// ack of a sealed frame must carry a valid tag for its session
+pub fn is_ack_of(
+    data: &[u8],
+    node_id: &MacAddress,
+    seq: u16,
+    sealed: Option<(&AeadKey, u32)>,
+) -> bool {
+    let Ok(frame) = Frame::decode(data) else {
+        return false;
+    };
+    if frame.header.msg_type != MessageType::Ack
+        || frame.header.node_id != *node_id
+        || frame.header.seq != seq
+    {
+        return false;
+    }
+    let Ok(ack) = Ack::from_payload(frame.payload) else {
+        return false;
+    };
+    match sealed {
+        Some((key, session)) => {
+            ack.session == Some(session) && ack.auth == Some(ack_auth(key, node_id, seq, session))
+        }
+        None => true,
+    }
+}
+
+/// Firmware offer following the ack of the frame `seq` of `node_id`, the
+/// offer answering a sealed frame must carry a valid tag for its session
+pub fn offer_of(
+    data: &[u8],
+    node_id: &MacAddress,
+    seq: u16,
+    sealed: Option<(&AeadKey, u32)>,
+) -> Option<OtaOffer> {
+    let frame = Frame::decode(data).ok()?;
+    if frame.header.msg_type != MessageType::OtaOffer
+        || frame.header.node_id != *node_id
+        || frame.header.seq != seq
+    {
+        return None;
+    }
+    let offer = OtaOffer::from_payload(frame.payload).ok()?;
+    match sealed {
+        Some((key, session)) => {
+            (offer.auth == Some(ota_auth(key, node_id, session, &offer))).then_some(offer)
+        }
+        None => Some(offer),
+    }
+}
+
+/// Configuration update following the ack of the frame `seq` of `node_id`,
+/// checked like the firmware offers
+pub fn config_of(
+    data: &[u8],
+    node_id: &MacAddress,
+    seq: u16,
+    sealed: Option<(&AeadKey, u32)>,
+) -> Option<ConfigUpdate> {
+    let frame = Frame::decode(data).ok()?;
+    if frame.header.msg_type != MessageType::Config
+        || frame.header.node_id != *node_id
+        || frame.header.seq != seq
+    {
+        return None;
+    }
+    let update = ConfigUpdate::from_payload(frame.payload).ok()?;
+    match sealed {
+        Some((key, session)) => (update.auth
+            == Some(config_auth(key, node_id, seq, session, &update)))
+        .then_some(update),
+        None => Some(update),
+    }
+}
+
+fn nonce(session: u32, node_id: &MacAddress, seq: u16) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&session.to_le_bytes());
+    nonce[4..10].copy_from_slice(node_id);
+    nonce[10..].copy_from_slice(&seq.to_le_bytes());
+    nonce
+}
+
+/// Encrypt the TLV records of a builder and finish the frame
+pub fn seal(
+    builder: FrameBuilder,
+    key: &AeadKey,
+    epoch: u8,
+    session: u32,
+) -> Result<FrameBuffer, ProtoError> {
+    let mut buffer = builder.buffer;
+    let mut payload: Vec<u8, MAX_PAYLOAD_LEN> =
+        Vec::from_slice(&buffer[HEADER_LEN..]).map_err(|_| ProtoError::Overflow)?;
+    if payload.len() + SEAL_OVERHEAD > MAX_PAYLOAD_LEN {
+        return Err(ProtoError::Overflow);
+    }
+
+    buffer.truncate(HEADER_LEN);
+    buffer[2] |= FLAG_ENCRYPTED;
+    // Final length is part of the authenticated header
+    buffer[11] = (payload.len() + SEAL_OVERHEAD) as u8;
+    let mut node_id = [0u8; 6];
+    node_id.copy_from_slice(&buffer[3..9]);
+    let seq = u16::from_le_bytes([buffer[9], buffer[10]]);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let tag = cipher
+        .encrypt_in_place_detached(
+            Nonce::from_slice(&nonce(session, &node_id, seq)),
+            &buffer,
+            &mut payload,
+        )
+        .map_err(|_| ProtoError::Overflow)?;
+
+    // Sizes checked above, the pushes cannot fail
+    let _ = buffer.push(epoch);
+    let _ = buffer.extend_from_slice(&session.to_le_bytes());
+    let _ = buffer.extend_from_slice(&payload);
+    let _ = buffer.extend_from_slice(&tag);
+    Ok(FrameBuilder { buffer }.finish())
+}
+
+/// Cleartext part of a sealed payload, used to pick the key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SealedPayload<'a> {
+    pub epoch: u8,
+    pub session: u32,
+    ciphertext: &'a [u8],
+    tag: &'a [u8],
+}
+
+impl<'a> SealedPayload<'a> {
+    pub fn parse(frame: &Frame<'a>) -> Result<Self, CryptoError> {
+        if !frame.header.has_flag(FLAG_ENCRYPTED) {
+            return Err(CryptoError::NotEncrypted);
+        }
+        let payload = frame.payload;
+        if payload.len() < SEAL_OVERHEAD {
+            return Err(CryptoError::Frame(ProtoError::TooShort));
+        }
+        let (ciphertext, tag) = payload[SEAL_PREFIX_LEN..].split_at(payload.len() - SEAL_OVERHEAD);
+        Ok(SealedPayload {
+            epoch: payload[0],
+            session: u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]),
+            ciphertext,
+            tag,
+        })
+    }
+
+    /// Decrypt into `out`, returns the plaintext TLV records
+    pub fn open<'b>(
+        &self,
+        frame: &Frame<'_>,
+        key: &AeadKey,
+        out: &'b mut [u8; MAX_PAYLOAD_LEN],
+    ) -> Result<&'b [u8], CryptoError> {
+        let plaintext = &mut out[..self.ciphertext.len()];
+        plaintext.copy_from_slice(self.ciphertext);
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&nonce(
+                    self.session,
+                    &frame.header.node_id,
+                    frame.header.seq,
+                )),
+                frame.header_bytes,
+                plaintext,
+                Tag::from_slice(self.tag),
+            )
+            .map_err(|_| CryptoError::AuthFailed)?;
+        Ok(plaintext)
+    }
+}
+
+/// Sliding window over (session, seq) of one node
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayWindow {
+    // None until the first authenticated frame
+    session: Option<u32>,
+    highest: u16,
+    // Bit n set when `highest - n` was received
+    seen: u32,
+}
+
+impl ReplayWindow {
+    pub fn is_fresh(&self, session: u32, seq: u16) -> bool {
+        match self.session {
+            None => true,
+            Some(current) if session != current => session > current,
+            Some(_) if seq > self.highest => true,
+            Some(_) => {
+                let age = self.highest - seq;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    /// Record an authenticated frame, fails when it was already seen or too old
+    pub fn accept(&mut self, session: u32, seq: u16) -> Result<(), CryptoError> {
+        if !self.is_fresh(session, seq) {
+            return Err(CryptoError::Replayed);
+        }
+        match self.session {
+            Some(current) if current == session && seq <= self.highest => {
+                self.seen |= 1 << (self.highest - seq);
+            }
+            Some(current) if current == session => {
+                let shift = seq - self.highest;
+                self.seen = if shift >= REPLAY_WINDOW {
+                    1
+                } else {
+                    (self.seen << shift) | 1
+                };
+                self.highest = seq;
+            }
+            _ => {
+                self.session = Some(session);
+                self.highest = seq;
+                self.seen = 1;
+            }
+        }
+        Ok(())
+    }
+}
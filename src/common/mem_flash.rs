@@ -0,0 +1,95 @@
+//! NOR flash in memory for the tests of the modules storing to flash
+//!
+//! Behaves as the ESP32 flash seen through `esp-storage`: 4 bytes reads and
+//! writes, 4 KiB sectors, writes only clear bits and erasing sets them again.
+//! Misaligned accesses panic, they would fail on the target.
+
+use std::vec;
+use std::vec::Vec;
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+pub const SECTOR_LEN: usize = 0x1000;
+
+#[derive(Debug)]
+pub struct MemFlashError;
+
+impl NorFlashError for MemFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+pub struct MemFlash {
+    pub bytes: Vec<u8>,
+    // Sectors erased so far, to tell which writes reached the flash
+    pub erases: usize,
+}
+
+impl MemFlash {
+    /// `len` bytes of erased flash
+    pub fn new(len: usize) -> Self {
+        MemFlash {
+            bytes: vec![0xFF; len],
+            erases: 0,
+        }
+    }
+}
+
+impl ErrorType for MemFlash {
+    type Error = MemFlashError;
+}
+
+impl ReadNorFlash for MemFlash {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        assert!(
+            start.is_multiple_of(Self::READ_SIZE) && bytes.len().is_multiple_of(Self::READ_SIZE)
+        );
+        let source = self
+            .bytes
+            .get(start..start + bytes.len())
+            .ok_or(MemFlashError)?;
+        bytes.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl NorFlash for MemFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR_LEN;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let (from, to) = (from as usize, to as usize);
+        assert!(from.is_multiple_of(SECTOR_LEN) && to.is_multiple_of(SECTOR_LEN));
+        self.bytes
+            .get_mut(from..to)
+            .ok_or(MemFlashError)?
+            .fill(0xFF);
+        self.erases += (to - from) / SECTOR_LEN;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let start = offset as usize;
+        assert!(
+            start.is_multiple_of(Self::WRITE_SIZE) && bytes.len().is_multiple_of(Self::WRITE_SIZE)
+        );
+        let target = self
+            .bytes
+            .get_mut(start..start + bytes.len())
+            .ok_or(MemFlashError)?;
+        for (stored, byte) in target.iter_mut().zip(bytes) {
+            *stored &= byte;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+//! MAC address helpers shared by the mesh nodes and the gateway
+//!
+//! The frame format itself lives in `mesh_proto`.
+
+use core::fmt::Write;
+use heapless::String;
+
+pub type MacAddress = [u8; 6];
+
+/// Format a MAC as `AA:BB:CC:DD:EE:FF`, the same form used in the MQTT topics
+pub fn format_mac(mac: &MacAddress) -> String<17> {
+    let mut s = String::<17>::new();
+    write!(
+        s,
+        "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+    .expect("write! failed!");
+    s
+}
+
+/// Parse a MAC written as `AA:BB:CC:DD:EE:FF`
+pub fn parse_mac(s: &str) -> Option<MacAddress> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for byte in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
@@ -0,0 +1,1579 @@
+//! Versioned wire format for the ESP-NOW mesh payloads (250 bytes max)
+//!
+//! Frame layout (multi-byte fields little endian):
+//! - Header (12 bytes):
+//!   - 1 byte: protocol version
+//!   - 1 byte: message type
+//!   - 1 byte: flags
+//!   - 6 bytes: node id (MAC of the originating node, of the addressed node
+//!     for gateway to node messages)
+//!   - 2 bytes: sequence number
+//!   - 1 byte: payload length
+//! - Payload: TLV records (1 byte tag, 1 byte length, value)
+//! - Trailer: CRC-16/MODBUS of header + payload
+//!
+//! With `FLAG_ENCRYPTED` the TLV payload is sealed by `common::keys`. Relays
+//! carry the frames of other nodes unchanged in a `Relay` frame of their own,
+//! see `common::routing`. Firmware images are streamed to the nodes with the
+//! `Ota*` messages, see `common::ota`. After each acked uplink the gateway
+//! sends a `Config` message with its clock and the configuration changes
+//! queued for the node, see `gateway_lib::downlink`.
+//!
+//! Decoding borrows the received buffer (no copy) and never panics on
+//! malformed input, encoding writes into a `heapless::Vec`.
+
+use heapless::Vec;
+
+use crate::common::crc::crc16_modbus;
+use crate::common::mesh::MacAddress;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+// ESP-NOW payload limit
+pub const MAX_FRAME_LEN: usize = 250;
+pub const HEADER_LEN: usize = 12;
+pub const CRC_LEN: usize = 2;
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - HEADER_LEN - CRC_LEN;
+const TLV_HEADER_LEN: usize = 2;
+// unit + quality + f32 value
+const READING_VALUE_LEN: usize = 6;
+
+pub type FrameBuffer = Vec<u8, MAX_FRAME_LEN>;
+
+// Header flags
+pub const FLAG_ACK_REQUESTED: u8 = 0x01;
+pub const FLAG_ENCRYPTED: u8 = 0x02;
+// On gateway acks: an image is offered to the node, stay awake for it
+pub const FLAG_OTA_PENDING: u8 = 0x04;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtoError {
+    TooShort,
+    UnsupportedVersion(u8),
+    UnknownMessageType(u8),
+    BadLength,
+    BadCrc,
+    InvalidTlv,
+    Overflow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    Readings = 0x01,
+    JoinRequest = 0x02,
+    Ack = 0x03,
+    Beacon = 0x04,
+    Relay = 0x05,
+    OtaOffer = 0x06,
+    OtaRequest = 0x07,
+    OtaChunk = 0x08,
+    OtaReport = 0x09,
+    Config = 0x0A,
+}
+
+impl MessageType {
+    pub fn from_u8(value: u8) -> Result<Self, ProtoError> {
+        match value {
+            0x01 => Ok(MessageType::Readings),
+            0x02 => Ok(MessageType::JoinRequest),
+            0x03 => Ok(MessageType::Ack),
+            0x04 => Ok(MessageType::Beacon),
+            0x05 => Ok(MessageType::Relay),
+            0x06 => Ok(MessageType::OtaOffer),
+            0x07 => Ok(MessageType::OtaRequest),
+            0x08 => Ok(MessageType::OtaChunk),
+            0x09 => Ok(MessageType::OtaReport),
+            0x0A => Ok(MessageType::Config),
+            other => Err(ProtoError::UnknownMessageType(other)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub msg_type: MessageType,
+    pub flags: u8,
+    pub node_id: MacAddress,
+    pub seq: u16,
+}
+
+impl Header {
+    pub fn new(msg_type: MessageType, node_id: MacAddress, seq: u16) -> Self {
+        Header {
+            version: PROTOCOL_VERSION,
+            msg_type,
+            flags: 0,
+            node_id,
+            seq,
+        }
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+// *** Readings TLV *** //
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadingKind {
+    Temperature = 0x01,
+    Humidity = 0x02,
+    Pressure = 0x03,
+    SupplyVoltage = 0x04,
+    Current = 0x05,
+    Flow = 0x06,
+}
+
+impl ReadingKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(ReadingKind::Temperature),
+            0x02 => Some(ReadingKind::Humidity),
+            0x03 => Some(ReadingKind::Pressure),
+            0x04 => Some(ReadingKind::SupplyVoltage),
+            0x05 => Some(ReadingKind::Current),
+            0x06 => Some(ReadingKind::Flow),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReadingKind::Temperature => "temperature",
+            ReadingKind::Humidity => "humidity",
+            ReadingKind::Pressure => "pressure",
+            ReadingKind::SupplyVoltage => "supply_voltage",
+            ReadingKind::Current => "current",
+            ReadingKind::Flow => "flow",
+        }
+    }
+}
+
+/// Units of the canonical value sent on the wire
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    None = 0x00,
+    Celsius = 0x01,
+    Percent = 0x02,
+    Pascal = 0x03,
+    Volt = 0x04,
+    MilliAmp = 0x05,
+    LitrePerMinute = 0x06,
+}
+
+impl Unit {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(Unit::None),
+            0x01 => Some(Unit::Celsius),
+            0x02 => Some(Unit::Percent),
+            0x03 => Some(Unit::Pascal),
+            0x04 => Some(Unit::Volt),
+            0x05 => Some(Unit::MilliAmp),
+            0x06 => Some(Unit::LitrePerMinute),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::None => "",
+            Unit::Celsius => "C",
+            Unit::Percent => "%",
+            Unit::Pascal => "Pa",
+            Unit::Volt => "V",
+            Unit::MilliAmp => "mA",
+            Unit::LitrePerMinute => "L/min",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+    Good = 0,
+    Uncertain = 1,
+    Bad = 2,
+}
+
+impl Quality {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Quality::Good),
+            1 => Some(Quality::Uncertain),
+            2 => Some(Quality::Bad),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshReading {
+    pub kind: ReadingKind,
+    pub unit: Unit,
+    pub quality: Quality,
+    pub value: f32,
+}
+
+impl MeshReading {
+    pub fn new(kind: ReadingKind, unit: Unit, value: f32) -> Self {
+        MeshReading {
+            kind,
+            unit,
+            quality: Quality::Good,
+            value,
+        }
+    }
+
+    /// `None` for an unknown tag, which receivers skip for forward compatibility.
+    /// A value which is not finite has a `Bad` quality.
+    pub fn from_tlv(tlv: &Tlv<'_>) -> Result<Option<Self>, ProtoError> {
+        let Some(kind) = ReadingKind::from_u8(tlv.tag) else {
+            return Ok(None);
+        };
+        if tlv.value.len() != READING_VALUE_LEN {
+            return Err(ProtoError::InvalidTlv);
+        }
+        let unit = Unit::from_u8(tlv.value[0]).ok_or(ProtoError::InvalidTlv)?;
+        let quality = Quality::from_u8(tlv.value[1]).ok_or(ProtoError::InvalidTlv)?;
+        let value = f32::from_le_bytes([tlv.value[2], tlv.value[3], tlv.value[4], tlv.value[5]]);
+        // NaN or infinite values are flagged, not taken for a measurement
+        let quality = if value.is_finite() {
+            quality
+        } else {
+            Quality::Bad
+        };
+        Ok(Some(MeshReading {
+            kind,
+            unit,
+            quality,
+            value,
+        }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+    pub value: &'a [u8],
+}
+
+/// Iterator over the TLV records of a payload, stops at the first invalid one
+pub struct TlvIter<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> TlvIter<'a> {
+    pub fn new(payload: &'a [u8]) -> Self {
+        TlvIter { remaining: payload }
+    }
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Result<Tlv<'a>, ProtoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining.is_empty() {
+            return None;
+        }
+        let (tag, len) = match self.remaining {
+            [tag, len, ..] => (*tag, *len as usize),
+            _ => {
+                self.remaining = &[];
+                return Some(Err(ProtoError::InvalidTlv));
+            }
+        };
+        let Some(value) = self.remaining.get(TLV_HEADER_LEN..TLV_HEADER_LEN + len) else {
+            self.remaining = &[];
+            return Some(Err(ProtoError::InvalidTlv));
+        };
+        self.remaining = &self.remaining[TLV_HEADER_LEN + len..];
+        Some(Ok(Tlv { tag, value }))
+    }
+}
+
+// *** Join request TLV *** //
+
+pub const TAG_NODE_NAME: u8 = 0x40;
+pub const TAG_NODE_CHANNEL: u8 = 0x41;
+pub const TAG_NODE_SECURITY: u8 = 0x42;
+
+/// How the readings of a node are protected once keys are configured
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LinkSecurity {
+    // Sealed payloads (`FLAG_ENCRYPTED`), works with any number of nodes
+    #[default]
+    Aead = 0,
+    // ESP-NOW encrypted peer, limited number of peers
+    EspNow = 1,
+}
+
+impl LinkSecurity {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(LinkSecurity::Aead),
+            1 => Some(LinkSecurity::EspNow),
+            _ => None,
+        }
+    }
+}
+
+/// Node asking the gateway to be added to its registry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JoinRequest<'a> {
+    pub name: &'a str,
+    pub channel: u8,
+    pub security: LinkSecurity,
+}
+
+impl<'a> JoinRequest<'a> {
+    pub fn from_payload(payload: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut name = None;
+        let mut channel = None;
+        // Optional, nodes predating encryption do not send it
+        let mut security = LinkSecurity::default();
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_NODE_NAME, value) => {
+                    name = Some(core::str::from_utf8(value).map_err(|_| ProtoError::InvalidTlv)?)
+                }
+                (TAG_NODE_CHANNEL, [value]) => channel = Some(*value),
+                (TAG_NODE_SECURITY, [value]) => {
+                    security = LinkSecurity::from_u8(*value).ok_or(ProtoError::InvalidTlv)?
+                }
+                (TAG_NODE_CHANNEL | TAG_NODE_SECURITY, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        match (name, channel) {
+            (Some(name), Some(channel)) => Ok(JoinRequest {
+                name,
+                channel,
+                security,
+            }),
+            _ => Err(ProtoError::InvalidTlv),
+        }
+    }
+}
+
+// *** Node status TLV *** //
+
+pub const TAG_FIRMWARE_VERSION: u8 = 0x43;
+pub const TAG_TRANSMIT_INTERVAL: u8 = 0x44;
+pub const TAG_CONFIG_APPLIED: u8 = 0x45;
+pub const TAG_WAKE_COUNT: u8 = 0x46;
+pub const TAG_RESET_REASON: u8 = 0x47;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl FirmwareVersion {
+    /// Parse a `major.minor.patch` version, as in `CARGO_PKG_VERSION`
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().splitn(3, '.').map(|part| part.parse::<u8>());
+        let version = FirmwareVersion {
+            major: parts.next()?.ok()?,
+            minor: parts.next()?.ok()?,
+            patch: parts.next()?.ok()?,
+        };
+        Some(version)
+    }
+}
+
+impl core::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Cause of the last reset of a node other than a deep sleep wake-up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetReason {
+    PowerOn = 0x01,
+    Software = 0x02,
+    DeepSleep = 0x03,
+    Watchdog = 0x04,
+    Brownout = 0x05,
+    Other = 0xFF,
+}
+
+impl ResetReason {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x01 => ResetReason::PowerOn,
+            0x02 => ResetReason::Software,
+            0x03 => ResetReason::DeepSleep,
+            0x04 => ResetReason::Watchdog,
+            0x05 => ResetReason::Brownout,
+            _ => ResetReason::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power_on",
+            ResetReason::Software => "software",
+            ResetReason::DeepSleep => "deep_sleep",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Brownout => "brownout",
+            ResetReason::Other => "other",
+        }
+    }
+}
+
+/// Sent by the nodes with their readings, for the gateway liveness table
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NodeStatus {
+    pub firmware: Option<FirmwareVersion>,
+    pub transmit_interval_s: Option<u32>,
+    // Id of the last `Config` change applied by the node
+    pub config_applied: Option<u16>,
+    // Wake-ups since the RTC memory was cleared, and why it last restarted
+    pub wake_count: Option<u32>,
+    pub reset_reason: Option<ResetReason>,
+}
+
+impl NodeStatus {
+    /// Malformed records are ignored, the status is informative only
+    pub fn from_payload(payload: &[u8]) -> Self {
+        let mut status = NodeStatus::default();
+        for tlv in TlvIter::new(payload).map_while(Result::ok) {
+            match (tlv.tag, tlv.value) {
+                (TAG_FIRMWARE_VERSION, [major, minor, patch]) => {
+                    status.firmware = Some(FirmwareVersion {
+                        major: *major,
+                        minor: *minor,
+                        patch: *patch,
+                    })
+                }
+                (TAG_TRANSMIT_INTERVAL, [b0, b1, b2, b3]) => {
+                    status.transmit_interval_s = Some(u32::from_le_bytes([*b0, *b1, *b2, *b3]))
+                }
+                (TAG_CONFIG_APPLIED, [b0, b1]) => {
+                    status.config_applied = Some(u16::from_le_bytes([*b0, *b1]))
+                }
+                (TAG_WAKE_COUNT, [b0, b1, b2, b3]) => {
+                    status.wake_count = Some(u32::from_le_bytes([*b0, *b1, *b2, *b3]))
+                }
+                (TAG_RESET_REASON, [reason]) => {
+                    status.reset_reason = Some(ResetReason::from_u8(*reason))
+                }
+                _ => {}
+            }
+        }
+        status
+    }
+
+    pub fn push(&self, builder: &mut FrameBuilder) -> Result<(), ProtoError> {
+        if let Some(firmware) = self.firmware {
+            builder.push_tlv(
+                TAG_FIRMWARE_VERSION,
+                &[firmware.major, firmware.minor, firmware.patch],
+            )?;
+        }
+        if let Some(interval_s) = self.transmit_interval_s {
+            builder.push_tlv(TAG_TRANSMIT_INTERVAL, &interval_s.to_le_bytes())?;
+        }
+        if let Some(id) = self.config_applied {
+            builder.push_tlv(TAG_CONFIG_APPLIED, &id.to_le_bytes())?;
+        }
+        if let Some(wake_count) = self.wake_count {
+            builder.push_tlv(TAG_WAKE_COUNT, &wake_count.to_le_bytes())?;
+        }
+        if let Some(reason) = self.reset_reason {
+            builder.push_tlv(TAG_RESET_REASON, &[reason as u8])?;
+        }
+        Ok(())
+    }
+}
+
+// *** Delivery TLV *** //
+
+pub const TAG_LINK_REPORT: u8 = 0x50;
+pub const TAG_ACK_SESSION: u8 = 0x51;
+pub const TAG_ACK_AUTH: u8 = 0x52;
+pub const ACK_AUTH_LEN: usize = 8;
+
+/// Delivery counters of a node since its cold boot, sent with its readings
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkReport {
+    pub retries: u16,
+    pub given_up: u16,
+}
+
+impl LinkReport {
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        TlvIter::new(payload)
+            .map_while(Result::ok)
+            .find_map(|tlv| match (tlv.tag, tlv.value) {
+                (TAG_LINK_REPORT, [r0, r1, g0, g1]) => Some(LinkReport {
+                    retries: u16::from_le_bytes([*r0, *r1]),
+                    given_up: u16::from_le_bytes([*g0, *g1]),
+                }),
+                _ => None,
+            })
+    }
+
+    pub fn push(&self, builder: &mut FrameBuilder) -> Result<(), ProtoError> {
+        let mut value = [0u8; 4];
+        value[..2].copy_from_slice(&self.retries.to_le_bytes());
+        value[2..].copy_from_slice(&self.given_up.to_le_bytes());
+        builder.push_tlv(TAG_LINK_REPORT, &value)
+    }
+}
+
+/// Gateway ack of the frame `seq` of the node in the header, with the session
+/// and authentication tag when the frame was sealed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ack {
+    pub session: Option<u32>,
+    pub auth: Option<[u8; ACK_AUTH_LEN]>,
+}
+
+impl Ack {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtoError> {
+        let mut ack = Ack::default();
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_ACK_SESSION, [b0, b1, b2, b3]) => {
+                    ack.session = Some(u32::from_le_bytes([*b0, *b1, *b2, *b3]))
+                }
+                (TAG_ACK_AUTH, value) if value.len() == ACK_AUTH_LEN => {
+                    let mut auth = [0u8; ACK_AUTH_LEN];
+                    auth.copy_from_slice(value);
+                    ack.auth = Some(auth);
+                }
+                (TAG_ACK_SESSION | TAG_ACK_AUTH, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        Ok(ack)
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        if let Some(session) = self.session {
+            builder.push_tlv(TAG_ACK_SESSION, &session.to_le_bytes())?;
+        }
+        if let Some(auth) = self.auth {
+            builder.push_tlv(TAG_ACK_AUTH, &auth)?;
+        }
+        Ok(builder.finish())
+    }
+}
+
+// *** Routing TLV *** //
+
+pub const TAG_BEACON_HOPS: u8 = 0x60;
+pub const TAG_RELAY_TTL: u8 = 0x61;
+pub const TAG_RELAY_FRAME: u8 = 0x62;
+pub const TAG_BEACON_CHANNEL: u8 = 0x63;
+pub const TAG_BEACON_GATEWAY: u8 = 0x64;
+// Largest frame a relay can carry: payload minus the TTL and frame TLVs
+pub const MAX_RELAYED_FRAME_LEN: usize = MAX_PAYLOAD_LEN - 2 * TLV_HEADER_LEN - 1;
+
+/// Broadcast by the gateway and the relays with their distance to the gateway.
+/// The channel and MAC of the gateway are left out while it has no AP channel,
+/// relays repeat the ones of their parent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Beacon {
+    pub hops: u8,
+    pub channel: Option<u8>,
+    pub gateway: Option<MacAddress>,
+}
+
+impl Beacon {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtoError> {
+        let mut hops = None;
+        let mut channel = None;
+        let mut gateway = None;
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_BEACON_HOPS, [value]) => hops = Some(*value),
+                (TAG_BEACON_CHANNEL, [value]) => channel = Some(*value),
+                (TAG_BEACON_GATEWAY, value) => {
+                    gateway = Some(value.try_into().map_err(|_| ProtoError::InvalidTlv)?)
+                }
+                (TAG_BEACON_HOPS | TAG_BEACON_CHANNEL, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        let hops = hops.ok_or(ProtoError::InvalidTlv)?;
+        Ok(Beacon {
+            hops,
+            channel,
+            gateway,
+        })
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        builder.push_tlv(TAG_BEACON_HOPS, &[self.hops])?;
+        if let Some(channel) = self.channel {
+            builder.push_tlv(TAG_BEACON_CHANNEL, &[channel])?;
+        }
+        if let Some(gateway) = &self.gateway {
+            builder.push_tlv(TAG_BEACON_GATEWAY, gateway)?;
+        }
+        Ok(builder.finish())
+    }
+}
+
+/// Frame of another node carried by a relay, `ttl` is the number of relays it
+/// may still go through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Relayed<'a> {
+    pub ttl: u8,
+    pub frame: &'a [u8],
+}
+
+impl<'a> Relayed<'a> {
+    pub fn from_payload(payload: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut ttl = None;
+        let mut frame = None;
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_RELAY_TTL, [value]) => ttl = Some(*value),
+                (TAG_RELAY_FRAME, value) => frame = Some(value),
+                (TAG_RELAY_TTL, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        match (ttl, frame) {
+            (Some(ttl), Some(frame)) => Ok(Relayed { ttl, frame }),
+            _ => Err(ProtoError::InvalidTlv),
+        }
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        builder.push_tlv(TAG_RELAY_TTL, &[self.ttl])?;
+        builder.push_tlv(TAG_RELAY_FRAME, self.frame)?;
+        Ok(builder.finish())
+    }
+}
+
+// *** OTA TLV *** //
+
+pub const TAG_OTA_IMAGE: u8 = 0x70;
+pub const TAG_OTA_SIZE: u8 = 0x71;
+pub const TAG_OTA_DIGEST: u8 = 0x72;
+pub const TAG_OTA_OFFSET: u8 = 0x73;
+pub const TAG_OTA_DATA: u8 = 0x74;
+pub const TAG_OTA_CRC: u8 = 0x75;
+pub const TAG_OTA_AUTH: u8 = 0x76;
+pub const TAG_OTA_OUTCOME: u8 = 0x77;
+pub const OTA_DIGEST_LEN: usize = 32;
+pub const OTA_AUTH_LEN: usize = 16;
+// Chunk frames stay small enough to be carried by a relay, and a multiple of
+// the flash write size
+pub const OTA_CHUNK_LEN: usize = 192;
+
+pub type OtaDigest = [u8; OTA_DIGEST_LEN];
+
+fn u32_tlv(value: &[u8]) -> Result<u32, ProtoError> {
+    let bytes: [u8; 4] = value.try_into().map_err(|_| ProtoError::InvalidTlv)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Image offered by the gateway to the node of the header, sent after the ack
+/// of its readings. `auth` binds it to the node session when keys are set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaOffer {
+    pub size: u32,
+    // SHA-256 of the whole image
+    pub digest: OtaDigest,
+    pub version: Option<FirmwareVersion>,
+    pub auth: Option<[u8; OTA_AUTH_LEN]>,
+}
+
+impl OtaOffer {
+    /// Short id of the image carried by the requests and chunks
+    pub fn image(&self) -> u32 {
+        image_id(&self.digest)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtoError> {
+        let mut size = None;
+        let mut digest = None;
+        let mut version = None;
+        let mut auth = None;
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_OTA_SIZE, value) => size = Some(u32_tlv(value)?),
+                (TAG_OTA_DIGEST, value) => {
+                    digest = Some(value.try_into().map_err(|_| ProtoError::InvalidTlv)?)
+                }
+                (TAG_FIRMWARE_VERSION, [major, minor, patch]) => {
+                    version = Some(FirmwareVersion {
+                        major: *major,
+                        minor: *minor,
+                        patch: *patch,
+                    })
+                }
+                (TAG_OTA_AUTH, value) => {
+                    auth = Some(value.try_into().map_err(|_| ProtoError::InvalidTlv)?)
+                }
+                _ => {}
+            }
+        }
+        match (size, digest) {
+            (Some(size), Some(digest)) => Ok(OtaOffer {
+                size,
+                digest,
+                version,
+                auth,
+            }),
+            _ => Err(ProtoError::InvalidTlv),
+        }
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        builder.push_tlv(TAG_OTA_SIZE, &self.size.to_le_bytes())?;
+        builder.push_tlv(TAG_OTA_DIGEST, &self.digest)?;
+        if let Some(version) = self.version {
+            builder.push_tlv(
+                TAG_FIRMWARE_VERSION,
+                &[version.major, version.minor, version.patch],
+            )?;
+        }
+        if let Some(auth) = &self.auth {
+            builder.push_tlv(TAG_OTA_AUTH, auth)?;
+        }
+        Ok(builder.finish())
+    }
+}
+
+/// First 4 bytes of the image digest, little endian
+pub fn image_id(digest: &OtaDigest) -> u32 {
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Node asking for the chunk of `image` starting at `offset`, the header seq
+/// is the chunk index, the chunk sent back carries it too
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaRequest {
+    pub image: u32,
+    pub offset: u32,
+}
+
+impl OtaRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtoError> {
+        let mut image = None;
+        let mut offset = None;
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match tlv.tag {
+                TAG_OTA_IMAGE => image = Some(u32_tlv(tlv.value)?),
+                TAG_OTA_OFFSET => offset = Some(u32_tlv(tlv.value)?),
+                _ => {}
+            }
+        }
+        match (image, offset) {
+            (Some(image), Some(offset)) => Ok(OtaRequest { image, offset }),
+            _ => Err(ProtoError::InvalidTlv),
+        }
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        self.push(&mut builder)?;
+        Ok(builder.finish())
+    }
+
+    pub fn push(&self, builder: &mut FrameBuilder) -> Result<(), ProtoError> {
+        builder.push_tlv(TAG_OTA_IMAGE, &self.image.to_le_bytes())?;
+        builder.push_tlv(TAG_OTA_OFFSET, &self.offset.to_le_bytes())
+    }
+}
+
+/// Part of an image, `crc` is the CRC-16/MODBUS of `data`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaChunk<'a> {
+    pub image: u32,
+    pub offset: u32,
+    pub crc: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> OtaChunk<'a> {
+    pub fn new(image: u32, offset: u32, data: &'a [u8]) -> Self {
+        OtaChunk {
+            image,
+            offset,
+            crc: crc16_modbus(data),
+            data,
+        }
+    }
+
+    pub fn is_intact(&self) -> bool {
+        crc16_modbus(self.data) == self.crc
+    }
+
+    pub fn from_payload(payload: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut image = None;
+        let mut offset = None;
+        let mut crc = None;
+        let mut data = None;
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_OTA_IMAGE, value) => image = Some(u32_tlv(value)?),
+                (TAG_OTA_OFFSET, value) => offset = Some(u32_tlv(value)?),
+                (TAG_OTA_CRC, [b0, b1]) => crc = Some(u16::from_le_bytes([*b0, *b1])),
+                (TAG_OTA_DATA, value) => data = Some(value),
+                (TAG_OTA_CRC, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        match (image, offset, crc, data) {
+            (Some(image), Some(offset), Some(crc), Some(data)) => Ok(OtaChunk {
+                image,
+                offset,
+                crc,
+                data,
+            }),
+            _ => Err(ProtoError::InvalidTlv),
+        }
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        builder.push_tlv(TAG_OTA_IMAGE, &self.image.to_le_bytes())?;
+        builder.push_tlv(TAG_OTA_OFFSET, &self.offset.to_le_bytes())?;
+        builder.push_tlv(TAG_OTA_CRC, &self.crc.to_le_bytes())?;
+        builder.push_tlv(TAG_OTA_DATA, self.data)?;
+        Ok(builder.finish())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OtaOutcome {
+    // Image written and verified, the node reboots into it
+    Verified = 0x01,
+    // New image booted and delivered its readings, kept
+    Confirmed = 0x02,
+    DigestMismatch = 0x03,
+    FlashError = 0x04,
+    TooLarge = 0x05,
+    // New image could not reach the gateway, previous one booted again
+    RolledBack = 0x06,
+}
+
+impl OtaOutcome {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(OtaOutcome::Verified),
+            0x02 => Some(OtaOutcome::Confirmed),
+            0x03 => Some(OtaOutcome::DigestMismatch),
+            0x04 => Some(OtaOutcome::FlashError),
+            0x05 => Some(OtaOutcome::TooLarge),
+            0x06 => Some(OtaOutcome::RolledBack),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OtaOutcome::Verified => "verified",
+            OtaOutcome::Confirmed => "confirmed",
+            OtaOutcome::DigestMismatch => "digest_mismatch",
+            OtaOutcome::FlashError => "flash_error",
+            OtaOutcome::TooLarge => "too_large",
+            OtaOutcome::RolledBack => "rolled_back",
+        }
+    }
+}
+
+/// Result of an update reported by the node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaReport {
+    pub image: u32,
+    pub outcome: OtaOutcome,
+}
+
+impl OtaReport {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtoError> {
+        let mut image = None;
+        let mut outcome = None;
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_OTA_IMAGE, value) => image = Some(u32_tlv(value)?),
+                (TAG_OTA_OUTCOME, [value]) => {
+                    outcome = Some(OtaOutcome::from_u8(*value).ok_or(ProtoError::InvalidTlv)?)
+                }
+                (TAG_OTA_OUTCOME, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        match (image, outcome) {
+            (Some(image), Some(outcome)) => Ok(OtaReport { image, outcome }),
+            _ => Err(ProtoError::InvalidTlv),
+        }
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        self.push(&mut builder)?;
+        Ok(builder.finish())
+    }
+
+    pub fn push(&self, builder: &mut FrameBuilder) -> Result<(), ProtoError> {
+        builder.push_tlv(TAG_OTA_IMAGE, &self.image.to_le_bytes())?;
+        builder.push_tlv(TAG_OTA_OUTCOME, &[self.outcome as u8])
+    }
+}
+
+// *** Config TLV *** //
+
+pub const TAG_CONFIG_ID: u8 = 0x80;
+pub const TAG_CONFIG_TIME: u8 = 0x81;
+pub const TAG_CONFIG_SAMPLE_INTERVAL: u8 = 0x82;
+pub const TAG_CONFIG_TRANSMIT_INTERVAL: u8 = 0x83;
+pub const TAG_CONFIG_ALARM_LOW: u8 = 0x84;
+pub const TAG_CONFIG_ALARM_HIGH: u8 = 0x85;
+pub const TAG_CONFIG_CHANNEL: u8 = 0x86;
+pub const TAG_CONFIG_AUTH: u8 = 0x87;
+pub const CONFIG_AUTH_LEN: usize = 16;
+
+/// Node settings changed from the gateway, fields left to none are unchanged
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NodeConfig {
+    pub sample_interval_s: Option<u32>,
+    pub transmit_interval_s: Option<u32>,
+    // Readings outside the thresholds are sent right away
+    pub alarm_low: Option<f32>,
+    pub alarm_high: Option<f32>,
+    // Mesh channel searched first, see `node_lib::channel_scan`
+    pub channel: Option<u8>,
+}
+
+impl NodeConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == NodeConfig::default()
+    }
+
+    /// Apply the fields set in `change`
+    pub fn merge(&mut self, change: &NodeConfig) {
+        self.sample_interval_s = change.sample_interval_s.or(self.sample_interval_s);
+        self.transmit_interval_s = change.transmit_interval_s.or(self.transmit_interval_s);
+        self.alarm_low = change.alarm_low.or(self.alarm_low);
+        self.alarm_high = change.alarm_high.or(self.alarm_high);
+        self.channel = change.channel.or(self.channel);
+    }
+
+    /// Whether `value` is outside the alarm thresholds
+    pub fn is_alarm(&self, value: f32) -> bool {
+        self.alarm_low.is_some_and(|low| value < low)
+            || self.alarm_high.is_some_and(|high| value > high)
+    }
+}
+
+/// Sent by the gateway after the ack of each uplink: its clock and the change
+/// queued for the node, if any. `auth` binds it to the node session when keys
+/// are set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConfigUpdate {
+    // None when only the clock is sent
+    pub id: Option<u16>,
+    // Gateway uptime, the time base of the published readings
+    pub time_ms: u64,
+    pub config: NodeConfig,
+    pub auth: Option<[u8; CONFIG_AUTH_LEN]>,
+}
+
+impl ConfigUpdate {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, ProtoError> {
+        let mut id = None;
+        let mut time_ms = None;
+        let mut config = NodeConfig::default();
+        let mut auth = None;
+        let f32_tlv = |value: &[u8]| u32_tlv(value).map(f32::from_bits);
+        for tlv in TlvIter::new(payload) {
+            let tlv = tlv?;
+            match (tlv.tag, tlv.value) {
+                (TAG_CONFIG_ID, [b0, b1]) => id = Some(u16::from_le_bytes([*b0, *b1])),
+                (TAG_CONFIG_TIME, value) => {
+                    let bytes: [u8; 8] = value.try_into().map_err(|_| ProtoError::InvalidTlv)?;
+                    time_ms = Some(u64::from_le_bytes(bytes))
+                }
+                (TAG_CONFIG_SAMPLE_INTERVAL, value) => {
+                    config.sample_interval_s = Some(u32_tlv(value)?)
+                }
+                (TAG_CONFIG_TRANSMIT_INTERVAL, value) => {
+                    config.transmit_interval_s = Some(u32_tlv(value)?)
+                }
+                (TAG_CONFIG_ALARM_LOW, value) => config.alarm_low = Some(f32_tlv(value)?),
+                (TAG_CONFIG_ALARM_HIGH, value) => config.alarm_high = Some(f32_tlv(value)?),
+                (TAG_CONFIG_CHANNEL, [channel]) => config.channel = Some(*channel),
+                (TAG_CONFIG_AUTH, value) => {
+                    auth = Some(value.try_into().map_err(|_| ProtoError::InvalidTlv)?)
+                }
+                (TAG_CONFIG_ID | TAG_CONFIG_CHANNEL, _) => return Err(ProtoError::InvalidTlv),
+                _ => {}
+            }
+        }
+        let time_ms = time_ms.ok_or(ProtoError::InvalidTlv)?;
+        Ok(ConfigUpdate {
+            id,
+            time_ms,
+            config,
+            auth,
+        })
+    }
+
+    pub fn encode(&self, header: &Header) -> Result<FrameBuffer, ProtoError> {
+        let mut builder = FrameBuilder::new(header);
+        if let Some(id) = self.id {
+            builder.push_tlv(TAG_CONFIG_ID, &id.to_le_bytes())?;
+        }
+        builder.push_tlv(TAG_CONFIG_TIME, &self.time_ms.to_le_bytes())?;
+        let config = &self.config;
+        if let Some(interval_s) = config.sample_interval_s {
+            builder.push_tlv(TAG_CONFIG_SAMPLE_INTERVAL, &interval_s.to_le_bytes())?;
+        }
+        if let Some(interval_s) = config.transmit_interval_s {
+            builder.push_tlv(TAG_CONFIG_TRANSMIT_INTERVAL, &interval_s.to_le_bytes())?;
+        }
+        if let Some(low) = config.alarm_low {
+            builder.push_tlv(TAG_CONFIG_ALARM_LOW, &low.to_le_bytes())?;
+        }
+        if let Some(high) = config.alarm_high {
+            builder.push_tlv(TAG_CONFIG_ALARM_HIGH, &high.to_le_bytes())?;
+        }
+        if let Some(channel) = config.channel {
+            builder.push_tlv(TAG_CONFIG_CHANNEL, &[channel])?;
+        }
+        if let Some(auth) = &self.auth {
+            builder.push_tlv(TAG_CONFIG_AUTH, auth)?;
+        }
+        Ok(builder.finish())
+    }
+}
+
+// *** Frame decode *** //
+
+/// Decoded frame borrowing the received bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame<'a> {
+    pub header: Header,
+    // Raw header, authenticated data of sealed payloads
+    pub header_bytes: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn decode(data: &'a [u8]) -> Result<Frame<'a>, ProtoError> {
+        if data.len() < HEADER_LEN + CRC_LEN {
+            return Err(ProtoError::TooShort);
+        }
+        if data.len() > MAX_FRAME_LEN {
+            return Err(ProtoError::BadLength);
+        }
+        if data[0] != PROTOCOL_VERSION {
+            return Err(ProtoError::UnsupportedVersion(data[0]));
+        }
+        let payload_len = data[11] as usize;
+        if data.len() != HEADER_LEN + payload_len + CRC_LEN {
+            return Err(ProtoError::BadLength);
+        }
+        let (body, crc) = data.split_at(HEADER_LEN + payload_len);
+        if crc16_modbus(body).to_le_bytes() != [crc[0], crc[1]] {
+            return Err(ProtoError::BadCrc);
+        }
+
+        let mut node_id = [0u8; 6];
+        node_id.copy_from_slice(&data[3..9]);
+        let header = Header {
+            version: data[0],
+            msg_type: MessageType::from_u8(data[1])?,
+            flags: data[2],
+            node_id,
+            seq: u16::from_le_bytes([data[9], data[10]]),
+        };
+        Ok(Frame {
+            header,
+            header_bytes: &body[..HEADER_LEN],
+            payload: &body[HEADER_LEN..],
+        })
+    }
+
+    pub fn tlvs(&self) -> TlvIter<'a> {
+        TlvIter::new(self.payload)
+    }
+
+    /// Known readings of the payload, unknown tags are skipped
+    pub fn readings(&self) -> impl Iterator<Item = Result<MeshReading, ProtoError>> + 'a {
+        self.tlvs()
+            .filter_map(|tlv| tlv.and_then(|tlv| MeshReading::from_tlv(&tlv)).transpose())
+    }
+}
+
+// *** Frame encode *** //
+
+/// Builds a frame in place: header, then TLVs, then `finish` adds the CRC
+pub struct FrameBuilder {
+    pub(crate) buffer: FrameBuffer,
+}
+
+impl FrameBuilder {
+    pub fn new(header: &Header) -> Self {
+        let mut buffer = FrameBuffer::new();
+        // Cannot fail, the header is smaller than the buffer
+        let _ = buffer.push(header.version);
+        let _ = buffer.push(header.msg_type as u8);
+        let _ = buffer.push(header.flags);
+        let _ = buffer.extend_from_slice(&header.node_id);
+        let _ = buffer.extend_from_slice(&header.seq.to_le_bytes());
+        let _ = buffer.push(0); // payload length patched by finish
+        FrameBuilder { buffer }
+    }
+
+    pub fn push_tlv(&mut self, tag: u8, value: &[u8]) -> Result<(), ProtoError> {
+        let payload_len = self.buffer.len() - HEADER_LEN;
+        if value.len() > u8::MAX as usize
+            || payload_len + TLV_HEADER_LEN + value.len() > MAX_PAYLOAD_LEN
+        {
+            return Err(ProtoError::Overflow);
+        }
+        let _ = self.buffer.push(tag);
+        let _ = self.buffer.push(value.len() as u8);
+        let _ = self.buffer.extend_from_slice(value);
+        Ok(())
+    }
+
+    pub fn push_reading(&mut self, reading: &MeshReading) -> Result<(), ProtoError> {
+        let mut value = [0u8; READING_VALUE_LEN];
+        value[0] = reading.unit as u8;
+        value[1] = reading.quality as u8;
+        value[2..].copy_from_slice(&reading.value.to_le_bytes());
+        self.push_tlv(reading.kind as u8, &value)
+    }
+
+    pub fn finish(mut self) -> FrameBuffer {
+        self.buffer[11] = (self.buffer.len() - HEADER_LEN) as u8;
+        let crc = crc16_modbus(&self.buffer);
+        // Room for the CRC is reserved by MAX_PAYLOAD_LEN
+        let _ = self.buffer.extend_from_slice(&crc.to_le_bytes());
+        self.buffer
+    }
+}
+
+pub fn encode_join_request(
+    header: &Header,
+    request: &JoinRequest<'_>,
+) -> Result<FrameBuffer, ProtoError> {
+    let mut builder = FrameBuilder::new(header);
+    builder.push_tlv(TAG_NODE_NAME, request.name.as_bytes())?;
+    builder.push_tlv(TAG_NODE_CHANNEL, &[request.channel])?;
+    builder.push_tlv(TAG_NODE_SECURITY, &[request.security as u8])?;
+    Ok(builder.finish())
+}
+
+/// Convenience encoder for a readings frame
+pub fn encode_readings(
+    header: &Header,
+    readings: &[MeshReading],
+) -> Result<FrameBuffer, ProtoError> {
+    let mut builder = FrameBuilder::new(header);
+    for reading in readings {
+        builder.push_reading(reading)?;
+    }
+    Ok(builder.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE: MacAddress = [0x24, 0x6F, 0x28, 0x01, 0x02, 0x03];
+
+    fn header(msg_type: MessageType) -> Header {
+        Header::new(msg_type, NODE, 0x1234).with_flags(FLAG_ACK_REQUESTED)
+    }
+
+    /// Decode `frame` and check its header was carried unchanged
+    fn decode(frame: &[u8], msg_type: MessageType) -> Frame<'_> {
+        let decoded = Frame::decode(frame).unwrap();
+        assert_eq!(decoded.header, header(msg_type));
+        decoded
+    }
+
+    #[test]
+    fn readings_round_trip() {
+        let readings = [
+            MeshReading::new(ReadingKind::Temperature, Unit::Celsius, 21.5),
+            MeshReading {
+                quality: Quality::Uncertain,
+                ..MeshReading::new(ReadingKind::Flow, Unit::LitrePerMinute, 3.25)
+            },
+        ];
+        let frame = encode_readings(&header(MessageType::Readings), &readings).unwrap();
+        let decoded = decode(&frame, MessageType::Readings);
+        let mut status = NodeStatus::default();
+        assert!(decoded.readings().map(Result::unwrap).eq(readings));
+        assert_eq!(NodeStatus::from_payload(decoded.payload), status);
+
+        // Status and delivery counters travel next to the readings
+        status = NodeStatus {
+            firmware: Some(FirmwareVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            }),
+            transmit_interval_s: Some(60),
+            config_applied: Some(7),
+            wake_count: Some(1_000),
+            reset_reason: Some(ResetReason::Brownout),
+        };
+        let report = LinkReport {
+            retries: 12,
+            given_up: 1,
+        };
+        let mut builder = FrameBuilder::new(&header(MessageType::Readings));
+        builder.push_reading(&readings[0]).unwrap();
+        status.push(&mut builder).unwrap();
+        report.push(&mut builder).unwrap();
+        let frame = builder.finish();
+        let decoded = decode(&frame, MessageType::Readings);
+        assert!(decoded.readings().map(Result::unwrap).eq([readings[0]]));
+        assert_eq!(NodeStatus::from_payload(decoded.payload), status);
+        assert_eq!(LinkReport::from_payload(decoded.payload), Some(report));
+    }
+
+    #[test]
+    fn join_request_round_trip() {
+        let request = JoinRequest {
+            name: "boiler-room",
+            channel: 6,
+            security: LinkSecurity::EspNow,
+        };
+        let frame = encode_join_request(&header(MessageType::JoinRequest), &request).unwrap();
+        let decoded = decode(&frame, MessageType::JoinRequest);
+        assert_eq!(JoinRequest::from_payload(decoded.payload), Ok(request));
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        for ack in [
+            Ack::default(),
+            Ack {
+                session: Some(0xDEAD_BEEF),
+                auth: Some([0xA5; ACK_AUTH_LEN]),
+            },
+        ] {
+            let frame = ack.encode(&header(MessageType::Ack)).unwrap();
+            let decoded = decode(&frame, MessageType::Ack);
+            assert_eq!(Ack::from_payload(decoded.payload), Ok(ack));
+        }
+    }
+
+    #[test]
+    fn routing_round_trip() {
+        for beacon in [
+            Beacon {
+                hops: 0,
+                channel: None,
+                gateway: None,
+            },
+            Beacon {
+                hops: 2,
+                channel: Some(11),
+                gateway: Some([0xAA; 6]),
+            },
+        ] {
+            let frame = beacon.encode(&header(MessageType::Beacon)).unwrap();
+            let decoded = decode(&frame, MessageType::Beacon);
+            assert_eq!(Beacon::from_payload(decoded.payload), Ok(beacon));
+        }
+
+        let inner = encode_readings(
+            &header(MessageType::Readings),
+            &[MeshReading::new(ReadingKind::Humidity, Unit::Percent, 40.0)],
+        )
+        .unwrap();
+        let relayed = Relayed {
+            ttl: 3,
+            frame: &inner,
+        };
+        let frame = relayed.encode(&header(MessageType::Relay)).unwrap();
+        let decoded = decode(&frame, MessageType::Relay);
+        assert_eq!(Relayed::from_payload(decoded.payload), Ok(relayed));
+        decode(relayed.frame, MessageType::Readings);
+
+        // The largest frame a relay accepts still fits in its own frame
+        let carried = [0x55; MAX_RELAYED_FRAME_LEN];
+        let relayed = Relayed {
+            ttl: 1,
+            frame: &carried,
+        };
+        let frame = relayed.encode(&header(MessageType::Relay)).unwrap();
+        assert_eq!(frame.len(), MAX_FRAME_LEN);
+        let too_large = [0x55; MAX_RELAYED_FRAME_LEN + 1];
+        let relayed = Relayed {
+            ttl: 1,
+            frame: &too_large,
+        };
+        assert_eq!(
+            relayed.encode(&header(MessageType::Relay)),
+            Err(ProtoError::Overflow)
+        );
+    }
+
+    #[test]
+    fn ota_round_trip() {
+        let offer = OtaOffer {
+            size: 917_504,
+            digest: core::array::from_fn(|i| i as u8),
+            version: Some(FirmwareVersion {
+                major: 0,
+                minor: 3,
+                patch: 1,
+            }),
+            auth: Some([0x3C; OTA_AUTH_LEN]),
+        };
+        let frame = offer.encode(&header(MessageType::OtaOffer)).unwrap();
+        let decoded = decode(&frame, MessageType::OtaOffer);
+        assert_eq!(OtaOffer::from_payload(decoded.payload), Ok(offer));
+        assert_eq!(offer.image(), 0x0302_0100);
+
+        let request = OtaRequest {
+            image: offer.image(),
+            offset: 4 * OTA_CHUNK_LEN as u32,
+        };
+        let frame = request.encode(&header(MessageType::OtaRequest)).unwrap();
+        let decoded = decode(&frame, MessageType::OtaRequest);
+        assert_eq!(OtaRequest::from_payload(decoded.payload), Ok(request));
+
+        let data: [u8; OTA_CHUNK_LEN] = core::array::from_fn(|i| (i * 7) as u8);
+        let chunk = OtaChunk::new(request.image, request.offset, &data);
+        let frame = chunk.encode(&header(MessageType::OtaChunk)).unwrap();
+        assert!(frame.len() <= MAX_RELAYED_FRAME_LEN);
+        let decoded = decode(&frame, MessageType::OtaChunk);
+        let received = OtaChunk::from_payload(decoded.payload).unwrap();
+        assert_eq!(received, chunk);
+        assert!(received.is_intact());
+        let mut corrupted = data;
+        corrupted[100] ^= 0x01;
+        assert!(!OtaChunk {
+            data: &corrupted,
+            ..chunk
+        }
+        .is_intact());
+
+        let report = OtaReport {
+            image: offer.image(),
+            outcome: OtaOutcome::RolledBack,
+        };
+        let frame = report.encode(&header(MessageType::OtaReport)).unwrap();
+        let decoded = decode(&frame, MessageType::OtaReport);
+        assert_eq!(OtaReport::from_payload(decoded.payload), Ok(report));
+    }
+
+    #[test]
+    fn config_round_trip() {
+        for update in [
+            ConfigUpdate {
+                id: None,
+                time_ms: 86_400_000,
+                config: NodeConfig::default(),
+                auth: None,
+            },
+            ConfigUpdate {
+                id: Some(42),
+                time_ms: u64::MAX,
+                config: NodeConfig {
+                    sample_interval_s: Some(10),
+                    transmit_interval_s: Some(300),
+                    alarm_low: Some(-12.5),
+                    alarm_high: Some(85.0),
+                    channel: Some(1),
+                },
+                auth: Some([0x0F; CONFIG_AUTH_LEN]),
+            },
+        ] {
+            let frame = update.encode(&header(MessageType::Config)).unwrap();
+            let decoded = decode(&frame, MessageType::Config);
+            assert_eq!(ConfigUpdate::from_payload(decoded.payload), Ok(update));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let frame = encode_readings(
+            &header(MessageType::Readings),
+            &[MeshReading::new(
+                ReadingKind::Pressure,
+                Unit::Pascal,
+                101_325.0,
+            )],
+        )
+        .unwrap();
+
+        // Truncated anywhere, header included
+        for len in 0..frame.len() {
+            assert!(Frame::decode(&frame[..len]).is_err(), "{len} bytes");
+        }
+        assert_eq!(
+            Frame::decode(&frame[..HEADER_LEN]),
+            Err(ProtoError::TooShort)
+        );
+
+        // Longer than ESP-NOW allows, or than the payload length says
+        assert_eq!(
+            Frame::decode(&[PROTOCOL_VERSION; MAX_FRAME_LEN + 1]),
+            Err(ProtoError::BadLength)
+        );
+        let mut padded = frame.clone();
+        padded.push(0).unwrap();
+        assert_eq!(Frame::decode(&padded), Err(ProtoError::BadLength));
+
+        let mut corrupted = frame.clone();
+        corrupted[HEADER_LEN + 3] ^= 0x10;
+        assert_eq!(Frame::decode(&corrupted), Err(ProtoError::BadCrc));
+
+        let mut newer = frame.clone();
+        newer[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Frame::decode(&newer),
+            Err(ProtoError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+
+        // Unknown message type with a valid CRC
+        let mut unknown = frame.clone();
+        unknown[1] = 0x7F;
+        let len = unknown.len() - CRC_LEN;
+        let crc = crc16_modbus(&unknown[..len]).to_le_bytes();
+        unknown[len..].copy_from_slice(&crc);
+        assert_eq!(
+            Frame::decode(&unknown),
+            Err(ProtoError::UnknownMessageType(0x7F))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        // TLV running past the payload
+        assert_eq!(
+            TlvIter::new(&[TAG_NODE_NAME, 4, b'a']).next(),
+            Some(Err(ProtoError::InvalidTlv))
+        );
+        assert_eq!(
+            TlvIter::new(&[TAG_NODE_NAME]).next(),
+            Some(Err(ProtoError::InvalidTlv))
+        );
+        // Reading value of the wrong size, unknown unit or quality
+        for value in [
+            &[0x01, 0x00, 0, 0][..],
+            &[0x7F, 0x00, 0, 0, 0, 0],
+            &[0x01, 0x09, 0, 0, 0, 0],
+        ] {
+            let tlv = Tlv {
+                tag: ReadingKind::Temperature as u8,
+                value,
+            };
+            assert_eq!(MeshReading::from_tlv(&tlv), Err(ProtoError::InvalidTlv));
+        }
+        // Unknown reading kinds are skipped, not errors
+        let tlv = Tlv {
+            tag: 0x3F,
+            value: &[0; READING_VALUE_LEN],
+        };
+        assert_eq!(MeshReading::from_tlv(&tlv), Ok(None));
+
+        // Missing mandatory fields or fields of the wrong size
+        assert!(JoinRequest::from_payload(&[TAG_NODE_NAME, 1, b'n']).is_err());
+        assert!(
+            JoinRequest::from_payload(&[TAG_NODE_NAME, 2, 0xFF, 0xFE, TAG_NODE_CHANNEL, 1, 1])
+                .is_err()
+        );
+        assert!(Ack::from_payload(&[TAG_ACK_SESSION, 2, 0, 0]).is_err());
+        assert!(Beacon::from_payload(&[TAG_BEACON_CHANNEL, 1, 6]).is_err());
+        assert!(
+            Beacon::from_payload(&[TAG_BEACON_HOPS, 1, 1, TAG_BEACON_GATEWAY, 2, 1, 2]).is_err()
+        );
+        assert!(Relayed::from_payload(&[TAG_RELAY_TTL, 1, 2]).is_err());
+        assert!(OtaOffer::from_payload(&[TAG_OTA_SIZE, 4, 0, 1, 0, 0]).is_err());
+        assert!(OtaRequest::from_payload(&[
+            TAG_OTA_IMAGE,
+            3,
+            0,
+            0,
+            0,
+            TAG_OTA_OFFSET,
+            4,
+            0,
+            0,
+            0,
+            0
+        ])
+        .is_err());
+        assert!(OtaChunk::from_payload(&[TAG_OTA_IMAGE, 4, 0, 0, 0, 0]).is_err());
+        assert!(
+            OtaReport::from_payload(&[TAG_OTA_IMAGE, 4, 0, 0, 0, 0, TAG_OTA_OUTCOME, 1, 0x42])
+                .is_err()
+        );
+        assert!(ConfigUpdate::from_payload(&[TAG_CONFIG_ID, 2, 1, 0]).is_err());
+        assert!(ConfigUpdate::from_payload(&[TAG_CONFIG_TIME, 4, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        // Deterministic xorshift bytes of every length around the frame limit
+        let mut state = 0x2545_F491u32;
+        let mut garbage = [0u8; MAX_FRAME_LEN + 8];
+        for len in 0..garbage.len() {
+            for byte in garbage.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                *byte = state as u8;
+            }
+            let data = &garbage[..len];
+            let _ = Frame::decode(data);
+            let _ = TlvIter::new(data).count();
+            let _ = JoinRequest::from_payload(data);
+            let _ = NodeStatus::from_payload(data);
+            let _ = LinkReport::from_payload(data);
+            let _ = Ack::from_payload(data);
+            let _ = Beacon::from_payload(data);
+            let _ = Relayed::from_payload(data);
+            let _ = OtaOffer::from_payload(data);
+            let _ = OtaRequest::from_payload(data);
+            let _ = OtaChunk::from_payload(data);
+            let _ = OtaReport::from_payload(data);
+            let _ = ConfigUpdate::from_payload(data);
+
+            // Random bytes behind a valid header and CRC reach the TLV decoders
+            if len <= MAX_PAYLOAD_LEN {
+                let mut frame = FrameBuffer::new();
+                frame
+                    .extend_from_slice(&FrameBuilder::new(&header(MessageType::Readings)).buffer)
+                    .unwrap();
+                frame.extend_from_slice(data).unwrap();
+                frame[11] = len as u8;
+                let crc = crc16_modbus(&frame).to_le_bytes();
+                frame.extend_from_slice(&crc).unwrap();
+                let decoded = Frame::decode(&frame).unwrap();
+                assert_eq!(decoded.payload, data);
+                let _ = decoded.readings().count();
+            }
+        }
+    }
+}
@@ -1,3 +1,16 @@
+pub mod analog;
+pub mod config;
+pub mod connectivity;
+pub mod crc;
+pub mod keys;
+#[cfg(test)]
+pub mod mem_flash;
+pub mod mesh;
+pub mod mesh_proto;
+pub mod ota;
+pub mod reliability;
 pub mod rng;
+pub mod routing;
 pub mod temperature;
+pub mod units;
 pub mod wifi;
@@ -0,0 +1,388 @@
+//! Firmware image transfer over the mesh, shared by the gateway and the nodes
+//!
+//! The gateway stages the image in its flash, then offers it to the nodes
+//! queued for the update in the ack of their readings (`FLAG_OTA_PENDING` then
+//! an `OtaOffer`). The node pulls the image one chunk at a time:
+//! - it sends an `OtaRequest` for the offset it needs and waits for the chunk,
+//!   asking again after a timeout, so a lost frame only costs a retry. With
+//!   keys, requests and reports are sealed like the readings, each attempt
+//!   under a new sequence number.
+//! - each chunk carries the CRC of its data, checked on receipt and again on
+//!   the flash read back
+//! - the offset reached is kept in RTC memory (`OtaProgress`), the next wake
+//!   resumes where the last one stopped when the same image is offered
+//! - once complete, the SHA-256 of the written flash must match the offer
+//!
+//! Images are written sequentially to a `FlashRegion`, each sector is erased
+//! when the first chunk reaches it.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use sha2::{Digest, Sha256};
+
+use crate::common::crc::crc16_modbus;
+use crate::common::mesh_proto::{OtaChunk, OtaDigest, OtaOffer, OtaRequest, OTA_CHUNK_LEN};
+
+// Read back granularity of the digest, multiple of the flash read size
+const READ_BLOCK_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OtaError {
+    Flash,
+    TooLarge,
+    // Write not following the previous one, or not aligned
+    OutOfOrder,
+    // Chunk CRC wrong on receipt or after the write
+    BadChunk,
+    DigestMismatch,
+}
+
+/// Part of the flash holding an image, offsets are relative to its start
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlashRegion {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl FlashRegion {
+    /// Write the chunk at `pos` of an image written in order, the sectors it
+    /// starts are erased first. The last chunk is padded to the write size.
+    pub fn write<F: NorFlash>(&self, flash: &mut F, pos: u32, data: &[u8]) -> Result<(), OtaError> {
+        if data.len() > OTA_CHUNK_LEN || !pos.is_multiple_of(F::WRITE_SIZE as u32) {
+            return Err(OtaError::OutOfOrder);
+        }
+        let end = pos
+            .checked_add(data.len() as u32)
+            .filter(|&end| end <= self.size)
+            .ok_or(OtaError::TooLarge)?;
+        let sector = F::ERASE_SIZE as u32;
+        let mut erase_at = pos.div_ceil(sector) * sector;
+        while erase_at < end {
+            let start = self.offset + erase_at;
+            flash
+                .erase(start, start + sector)
+                .map_err(|_| OtaError::Flash)?;
+            erase_at += sector;
+        }
+
+        let mut padded = [0xFFu8; OTA_CHUNK_LEN];
+        padded[..data.len()].copy_from_slice(data);
+        let len = data.len().next_multiple_of(F::WRITE_SIZE);
+        flash
+            .write(self.offset + pos, &padded[..len])
+            .map_err(|_| OtaError::Flash)?;
+
+        let mut written = [0u8; OTA_CHUNK_LEN];
+        flash
+            .read(self.offset + pos, &mut written[..len])
+            .map_err(|_| OtaError::Flash)?;
+        if crc16_modbus(&written[..data.len()]) != crc16_modbus(data) {
+            return Err(OtaError::BadChunk);
+        }
+        Ok(())
+    }
+
+    /// Read `out.len()` bytes at `pos`, at most one chunk
+    pub fn read<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        pos: u32,
+        out: &mut [u8],
+    ) -> Result<(), OtaError> {
+        let end = pos.checked_add(out.len() as u32);
+        if out.len() > OTA_CHUNK_LEN || end.is_none_or(|end| end > self.size) {
+            return Err(OtaError::TooLarge);
+        }
+        // Reads are aligned on the flash read size, as are the chunk offsets
+        let mut aligned = [0u8; OTA_CHUNK_LEN];
+        let len = out.len().next_multiple_of(F::READ_SIZE);
+        flash
+            .read(self.offset + pos, &mut aligned[..len])
+            .map_err(|_| OtaError::Flash)?;
+        out.copy_from_slice(&aligned[..out.len()]);
+        Ok(())
+    }
+
+    /// SHA-256 of the first `len` bytes
+    pub fn digest<F: ReadNorFlash>(&self, flash: &mut F, len: u32) -> Result<OtaDigest, OtaError> {
+        if len > self.size {
+            return Err(OtaError::TooLarge);
+        }
+        let mut hasher = Sha256::new();
+        let mut block = [0u8; READ_BLOCK_LEN];
+        let mut pos = 0;
+        while pos < len {
+            let take = (len - pos).min(READ_BLOCK_LEN as u32) as usize;
+            let aligned = take.next_multiple_of(F::READ_SIZE);
+            flash
+                .read(self.offset + pos, &mut block[..aligned])
+                .map_err(|_| OtaError::Flash)?;
+            hasher.update(&block[..take]);
+            pos += take as u32;
+        }
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Length of the chunk at `offset` of an image of `size` bytes
+pub fn chunk_len(size: u32, offset: u32) -> usize {
+    size.saturating_sub(offset).min(OTA_CHUNK_LEN as u32) as usize
+}
+
+/// Download state kept in RTC memory across deep sleeps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaProgress {
+    pub image: u32,
+    // Bytes written so far
+    pub offset: u32,
+}
+
+/// What a received chunk turned into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkStatus {
+    // Chunk expected next, to write at its offset then `advance`
+    Accepted,
+    // Late duplicate or other image, ignored
+    Stale,
+    Corrupt,
+}
+
+/// Node side of a transfer: the next offset to request and the chunks to keep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaDownload {
+    pub image: u32,
+    pub size: u32,
+    pub digest: OtaDigest,
+    offset: u32,
+}
+
+impl OtaDownload {
+    /// Start the offered image, from the saved progress when it is the same
+    pub fn new(offer: &OtaOffer, saved: Option<OtaProgress>) -> Self {
+        let offset = saved
+            .filter(|saved| saved.image == offer.image() && saved.offset <= offer.size)
+            .map_or(0, |saved| saved.offset);
+        OtaDownload {
+            image: offer.image(),
+            size: offer.size,
+            digest: offer.digest,
+            offset,
+        }
+    }
+
+    pub fn progress(&self) -> OtaProgress {
+        OtaProgress {
+            image: self.image,
+            offset: self.offset,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset >= self.size
+    }
+
+    /// Request for the next chunk, none once complete
+    pub fn request(&self) -> Option<OtaRequest> {
+        (!self.is_complete()).then_some(OtaRequest {
+            image: self.image,
+            offset: self.offset,
+        })
+    }
+
+    /// Check a received chunk against the one requested
+    pub fn check(&self, chunk: &OtaChunk<'_>) -> ChunkStatus {
+        if chunk.image != self.image || chunk.offset != self.offset {
+            ChunkStatus::Stale
+        } else if !chunk.is_intact() || chunk.data.len() != chunk_len(self.size, self.offset) {
+            ChunkStatus::Corrupt
+        } else {
+            ChunkStatus::Accepted
+        }
+    }
+
+    /// Move past an accepted chunk once written
+    pub fn advance(&mut self, len: usize) {
+        self.offset = (self.offset + len as u32).min(self.size);
+    }
+
+    /// Compare the written image with the offered digest
+    pub fn verify<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        region: &FlashRegion,
+    ) -> Result<(), OtaError> {
+        if region.digest(flash, self.size)? == self.digest {
+            Ok(())
+        } else {
+            Err(OtaError::DigestMismatch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::mem_flash::MemFlash;
+
+    const REGION: FlashRegion = FlashRegion {
+        offset: 0x2000,
+        size: 0x10000,
+    };
+
+    fn image(size: u32) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 + i / 300) as u8).collect()
+    }
+
+    fn offer(image: &[u8]) -> OtaOffer {
+        OtaOffer {
+            size: image.len() as u32,
+            digest: Sha256::digest(image).into(),
+            version: None,
+            auth: None,
+        }
+    }
+
+    /// Drops and corrupts frames with fixed odds, the same on every run
+    struct LossyLink(u64);
+
+    impl LossyLink {
+        fn happens(&mut self, percent: u64) -> bool {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 33) % 100 < percent
+        }
+    }
+
+    #[test]
+    fn transfer_resumes_over_a_lossy_link() {
+        let image = image(20_003);
+        let offer = offer(&image);
+        let mut flash = MemFlash::new(0x20000);
+        let mut link = LossyLink(1);
+        let mut saved = None;
+        let mut wakes = 0;
+        let (mut corrupted, mut rejected) = (0, 0);
+        while wakes < 100 {
+            wakes += 1;
+            let mut download = OtaDownload::new(&offer, saved);
+            // Requests a node has time for in a wake
+            for _ in 0..40 {
+                let Some(request) = download.request() else {
+                    break;
+                };
+                // Request or chunk lost, asked again after the timeout
+                if link.happens(30) || link.happens(30) {
+                    continue;
+                }
+                let start = request.offset as usize;
+                let data = &image[start..start + chunk_len(offer.size, request.offset)];
+                let mut received = data.to_vec();
+                if link.happens(5) {
+                    received[0] ^= 0x40;
+                    corrupted += 1;
+                }
+                let chunk = OtaChunk {
+                    data: &received,
+                    ..OtaChunk::new(request.image, request.offset, data)
+                };
+                match download.check(&chunk) {
+                    ChunkStatus::Accepted => {
+                        REGION.write(&mut flash, chunk.offset, chunk.data).unwrap();
+                        download.advance(chunk.data.len());
+                    }
+                    ChunkStatus::Corrupt => rejected += 1,
+                    ChunkStatus::Stale => panic!("chunk of the request is stale"),
+                }
+            }
+            saved = Some(download.progress());
+            if download.is_complete() {
+                assert_eq!(download.verify(&mut flash, &REGION), Ok(()));
+                break;
+            }
+        }
+        assert!(wakes > 1 && saved.unwrap().offset == offer.size);
+        assert!(corrupted > 0 && rejected == corrupted);
+        let start = REGION.offset as usize;
+        assert_eq!(&flash.bytes[start..start + image.len()], &image[..]);
+    }
+
+    #[test]
+    fn resumes_only_the_same_image() {
+        let offer = offer(&image(1000));
+        let saved = OtaProgress {
+            image: offer.image(),
+            offset: 384,
+        };
+        assert_eq!(OtaDownload::new(&offer, Some(saved)).progress(), saved);
+        let other = OtaProgress {
+            image: offer.image() ^ 1,
+            ..saved
+        };
+        assert_eq!(OtaDownload::new(&offer, Some(other)).progress().offset, 0);
+        let beyond = OtaProgress {
+            offset: 2000,
+            ..saved
+        };
+        assert_eq!(OtaDownload::new(&offer, Some(beyond)).progress().offset, 0);
+    }
+
+    #[test]
+    fn late_and_foreign_chunks_are_stale() {
+        let image = image(1000);
+        let offer = offer(&image);
+        let mut download = OtaDownload::new(&offer, None);
+        let first = OtaChunk::new(offer.image(), 0, &image[..OTA_CHUNK_LEN]);
+        assert_eq!(download.check(&first), ChunkStatus::Accepted);
+        download.advance(first.data.len());
+        // Duplicate of the chunk already written
+        assert_eq!(download.check(&first), ChunkStatus::Stale);
+        let next = &image[OTA_CHUNK_LEN..2 * OTA_CHUNK_LEN];
+        let foreign = OtaChunk::new(offer.image() ^ 1, OTA_CHUNK_LEN as u32, next);
+        assert_eq!(download.check(&foreign), ChunkStatus::Stale);
+        let short = OtaChunk::new(offer.image(), OTA_CHUNK_LEN as u32, &next[..4]);
+        assert_eq!(download.check(&short), ChunkStatus::Corrupt);
+    }
+
+    #[test]
+    fn verify_detects_a_corrupted_image() {
+        let image = image(1000);
+        let offer = offer(&image);
+        let mut flash = MemFlash::new(0x20000);
+        for (index, chunk) in image.chunks(OTA_CHUNK_LEN).enumerate() {
+            let pos = (index * OTA_CHUNK_LEN) as u32;
+            REGION.write(&mut flash, pos, chunk).unwrap();
+        }
+        let download = OtaDownload::new(&offer, None);
+        assert_eq!(download.verify(&mut flash, &REGION), Ok(()));
+        flash.bytes[REGION.offset as usize + 100] ^= 1;
+        assert_eq!(
+            download.verify(&mut flash, &REGION),
+            Err(OtaError::DigestMismatch)
+        );
+    }
+
+    #[test]
+    fn region_rejects_writes_out_of_it() {
+        let mut flash = MemFlash::new(0x20000);
+        let data = [0u8; 8];
+        assert_eq!(
+            REGION.write(&mut flash, 2, &data),
+            Err(OtaError::OutOfOrder)
+        );
+        assert_eq!(
+            REGION.write(&mut flash, REGION.size - 4, &data),
+            Err(OtaError::TooLarge)
+        );
+        assert_eq!(
+            REGION.write(&mut flash, u32::MAX - 3, &data),
+            Err(OtaError::TooLarge)
+        );
+        let mut out = [0u8; 8];
+        assert_eq!(
+            REGION.read(&mut flash, u32::MAX - 3, &mut out),
+            Err(OtaError::TooLarge)
+        );
+        assert_eq!(flash.erases, 0);
+    }
+}
@@ -0,0 +1,217 @@
+//! Application level delivery between the mesh nodes and the gateway
+//!
+//! Nodes set `FLAG_ACK_REQUESTED` and resend the exact same frame until the
+//! gateway acks it or the retry policy gives up. The gateway acks every frame
+//! it processed, duplicates included, and keeps a sequence window per node to
+//! drop duplicates and count lost frames.
+
+// Sequence numbers tracked behind the highest one received
+pub const DEDUP_WINDOW: u16 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeqStatus {
+    New,
+    Duplicate,
+    // Far behind the window, the node restarted its sequence
+    Restart,
+}
+
+/// Sliding window over the sequence numbers of one node
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SequenceWindow {
+    highest: Option<u16>,
+    // Bit n set when `highest - n` was received
+    seen: u32,
+    // Bit n set when `highest - n` is expected, so frames sent before the
+    // first one received are not counted as lost
+    expected: u32,
+}
+
+impl SequenceWindow {
+    pub fn classify(&self, seq: u16) -> SeqStatus {
+        let Some(highest) = self.highest else {
+            return SeqStatus::New;
+        };
+        let diff = seq.wrapping_sub(highest) as i16;
+        if diff > 0 {
+            SeqStatus::New
+        } else if diff > -(DEDUP_WINDOW as i16) {
+            if self.seen & (1 << diff.unsigned_abs()) != 0 {
+                SeqStatus::Duplicate
+            } else {
+                SeqStatus::New
+            }
+        } else {
+            SeqStatus::Restart
+        }
+    }
+
+    /// Record a processed frame, returns the frames found lost, the ones that
+    /// left the window without being received
+    pub fn record(&mut self, seq: u16) -> u32 {
+        let Some(highest) = self
+            .highest
+            .filter(|_| self.classify(seq) != SeqStatus::Restart)
+        else {
+            self.highest = Some(seq);
+            self.seen = 1;
+            self.expected = 1;
+            return 0;
+        };
+        let diff = seq.wrapping_sub(highest) as i16;
+        if diff <= 0 {
+            self.seen |= 1 << diff.unsigned_abs();
+            return 0;
+        }
+
+        let shift = diff as u32;
+        let missing = self.expected & !self.seen;
+        let lost = if shift >= DEDUP_WINDOW as u32 {
+            self.seen = 1;
+            self.expected = u32::MAX;
+            missing.count_ones() + (shift - DEDUP_WINDOW as u32)
+        } else {
+            let dropped_out = missing >> (DEDUP_WINDOW as u32 - shift);
+            self.seen = (self.seen << shift) | 1;
+            self.expected = (self.expected << shift) | ((1 << shift) - 1);
+            dropped_out.count_ones()
+        };
+        self.highest = Some(seq);
+        lost
+    }
+}
+
+/// Bounded retransmission with exponential backoff
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u8,
+    pub base_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_backoff_ms: 30,
+            max_backoff_ms: 500,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before `attempt` (1 for the first retry), the jitter spreads the
+    /// retries of nodes that collided
+    pub fn backoff_ms(&self, attempt: u8, jitter: u32) -> u64 {
+        let exponent = attempt.saturating_sub(1).min(16) as u32;
+        let backoff = self.base_backoff_ms.saturating_mul(1 << exponent);
+        let jitter = jitter as u64 % self.base_backoff_ms.max(1);
+        backoff.min(self.max_backoff_ms) + jitter
+    }
+}
+
+/// Delivery counters of one node, as seen by the gateway
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkCounters {
+    pub received: u32,
+    pub duplicates: u32,
+    pub lost: u32,
+    // Reported by the node itself
+    pub retried: u32,
+    pub given_up: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn after(received: &[u16]) -> (SequenceWindow, u32) {
+        let mut window = SequenceWindow::default();
+        let lost = received.iter().map(|seq| window.record(*seq)).sum();
+        (window, lost)
+    }
+
+    #[test]
+    fn first_frame_starts_the_window() {
+        let window = SequenceWindow::default();
+        assert_eq!(window.classify(0), SeqStatus::New);
+        assert_eq!(window.classify(40_000), SeqStatus::New);
+        // Frames sent before the first one received are not lost
+        let (mut window, lost) = after(&[500]);
+        assert_eq!(lost, 0);
+        assert_eq!(window.classify(499), SeqStatus::New);
+        assert_eq!(window.record(500 + DEDUP_WINDOW), 0);
+    }
+
+    #[test]
+    fn duplicates_are_detected_within_the_window() {
+        let (window, lost) = after(&[1, 2, 3]);
+        assert_eq!(lost, 0);
+        for seq in [1, 2, 3] {
+            assert_eq!(window.classify(seq), SeqStatus::Duplicate);
+        }
+        assert_eq!(window.classify(4), SeqStatus::New);
+    }
+
+    #[test]
+    fn reordered_frames_are_not_lost() {
+        let (mut window, lost) = after(&[1, 3, 4]);
+        assert_eq!(lost, 0);
+        assert_eq!(window.classify(2), SeqStatus::New);
+        assert_eq!(window.record(2), 0);
+        assert_eq!(window.classify(2), SeqStatus::Duplicate);
+        // Nothing missing when it leaves the window
+        assert_eq!(window.record(4 + DEDUP_WINDOW), 0);
+    }
+
+    #[test]
+    fn frames_leaving_the_window_unseen_are_lost() {
+        let (mut window, lost) = after(&[1, 3, 6]);
+        assert_eq!(lost, 0);
+        // 2, 4 and 5 drop out of the window
+        assert_eq!(window.record(6 + DEDUP_WINDOW), 3);
+        // A gap larger than the window counts the frames never seen
+        assert_eq!(
+            window.record(6 + 3 * DEDUP_WINDOW),
+            2 * DEDUP_WINDOW as u32 - 1
+        );
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        let (mut window, lost) = after(&[u16::MAX - 1, u16::MAX]);
+        assert_eq!(lost, 0);
+        assert_eq!(window.classify(0), SeqStatus::New);
+        assert_eq!(window.record(0), 0);
+        assert_eq!(window.record(2), 0);
+        assert_eq!(window.classify(u16::MAX), SeqStatus::Duplicate);
+        assert_eq!(window.classify(0), SeqStatus::Duplicate);
+        assert_eq!(window.classify(1), SeqStatus::New);
+        // 1 is the only one missing across the wrap
+        assert_eq!(window.record(2 + DEDUP_WINDOW), 1);
+    }
+
+    #[test]
+    fn far_behind_is_a_restart() {
+        let (mut window, _) = after(&[1_000, 1_001]);
+        assert_eq!(window.classify(1_000 - DEDUP_WINDOW), SeqStatus::Restart);
+        assert_eq!(window.classify(0), SeqStatus::Restart);
+        // The node starts over, nothing counted as lost
+        assert_eq!(window.record(0), 0);
+        assert_eq!(window.classify(0), SeqStatus::Duplicate);
+        assert_eq!(window.classify(1_001), SeqStatus::New);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff_ms(1, 0), 30);
+        assert_eq!(policy.backoff_ms(2, 0), 60);
+        assert_eq!(policy.backoff_ms(4, 0), 240);
+        assert_eq!(policy.backoff_ms(10, 0), 500);
+        assert_eq!(policy.backoff_ms(u8::MAX, 0), 500);
+        // Jitter below the base backoff
+        assert_eq!(policy.backoff_ms(1, 29), 59);
+        assert_eq!(policy.backoff_ms(1, 31), 31);
+    }
+}
@@ -3,6 +3,12 @@ pub struct SimpleRngU64 {
     seed: u64,
 }
 
+impl Default for SimpleRngU64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SimpleRngU64 {
     pub fn new() -> Self {
         // Use the current time as initial seed
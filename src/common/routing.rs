@@ -0,0 +1,700 @@
+//! Multi-hop routing of the mesh frames toward the gateway
+//!
+//! Distance-vector over beacons: the gateway broadcasts a `Beacon` with 0 hops
+//! every `BEACON_INTERVAL_MS` and relays with a route rebroadcast it with their
+//! own distance. Each node picks as parent the neighbor with the fewest hops to
+//! the gateway, the best RSSI breaking ties, and keeps it until a clearly
+//! better one shows up or it stops beaconing.
+//!
+//! Beacons also carry the Wi-Fi channel and MAC of the gateway, ESP-NOW only
+//! reaches the nodes listening on the channel of the AP the gateway joined.
+//!
+//! Sensor nodes send their frames unchanged to their parent. A relay wraps the
+//! frames of its children in a `Relay` frame toward its own parent and learns
+//! the reverse route, used to bring the acks back down. A frame crosses at most
+//! `MAX_HOPS` radio hops: relays decrement its TTL, drop the frames they just
+//! relayed and never pick one of their children as parent.
+
+use heapless::{Deque, Vec};
+
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{
+    Beacon, Frame, FrameBuffer, Header, MessageType, ProtoError, Relayed,
+};
+
+pub const MAX_HOPS: u8 = 4;
+// TTL of a frame wrapped by its first relay, the hop from the node is spent
+pub const INITIAL_TTL: u8 = MAX_HOPS - 2;
+pub const BEACON_INTERVAL_MS: u64 = 10_000;
+// A neighbor missing 3 beacons is dropped
+pub const NEIGHBOR_TIMEOUT_MS: u64 = 3 * BEACON_INTERVAL_MS;
+// Reverse routes outlive a few transmit intervals of the nodes
+pub const ROUTE_TIMEOUT_MS: u64 = 15 * 60 * 1000;
+// Only switch to a parent as far as the current one when this much louder
+pub const PARENT_SWITCH_RSSI_DB: i8 = 6;
+pub const MAX_NEIGHBORS: usize = 8;
+pub const MAX_ROUTES: usize = 16;
+const SEEN_CACHE_LEN: usize = 16;
+// Shorter than the ack timeout plus backoff of the nodes, so a retransmit is
+// relayed again
+pub const SEEN_CACHE_MS: u64 = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteDrop {
+    Frame(ProtoError),
+    // Only beacons and join requests are accepted broadcast
+    Broadcast,
+    // Frame of another node not wrapped by a relay
+    NotForUs,
+    // Relay frame inside a relay frame
+    Nested,
+    TtlExpired,
+    Seen,
+    NoParent,
+    // The next hop is where the frame came from
+    Loop,
+}
+
+/// Frame to send and its next hop
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outbound {
+    pub to: MacAddress,
+    pub frame: FrameBuffer,
+}
+
+/// What a received frame turned into
+// Only moved out of `route` once per frame, never stored
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteAction<'a> {
+    // Frame addressed to this node
+    Deliver(&'a [u8]),
+    Forward(Outbound),
+    // Beacon, only updates the neighbors
+    Handled,
+    Drop(RouteDrop),
+}
+
+/// Frame of another node taken out of a relay frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unwrapped<'a> {
+    pub frame: Frame<'a>,
+    // Raw bytes of the frame, relayed unchanged
+    pub data: &'a [u8],
+    pub ttl: u8,
+}
+
+impl Unwrapped<'_> {
+    /// Relays the frame went through
+    pub fn relays(&self) -> u8 {
+        INITIAL_TTL.saturating_sub(self.ttl) + 1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Neighbor {
+    pub mac: MacAddress,
+    // Distance of the neighbor to the gateway
+    pub hops: u8,
+    pub rssi: i8,
+    pub last_heard_ms: u64,
+    // Channel and MAC of the gateway, as advertised by the neighbor
+    pub channel: Option<u8>,
+    pub gateway: Option<MacAddress>,
+}
+
+impl Neighbor {
+    // Lower is better
+    fn rank(&self) -> (u8, i16) {
+        (self.hops, -(self.rssi as i16))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Route {
+    node: MacAddress,
+    via: MacAddress,
+    last_seen_ms: u64,
+}
+
+/// Recently relayed (origin, seq), a frame coming back through a loop or
+/// heard by two relays is only relayed once
+#[derive(Clone, Debug, Default)]
+pub struct SeenCache {
+    entries: Deque<(MacAddress, u16, u64), SEEN_CACHE_LEN>,
+}
+
+impl SeenCache {
+    /// Record a frame, returns false when it was seen within `SEEN_CACHE_MS`
+    pub fn insert(&mut self, origin: MacAddress, seq: u16, now_ms: u64) -> bool {
+        while let Some((_, _, seen_ms)) = self.entries.front() {
+            if now_ms.saturating_sub(*seen_ms) < SEEN_CACHE_MS {
+                break;
+            }
+            self.entries.pop_front();
+        }
+        if self
+            .entries
+            .iter()
+            .any(|(mac, seen_seq, _)| *mac == origin && *seen_seq == seq)
+        {
+            return false;
+        }
+        if self.entries.is_full() {
+            self.entries.pop_front();
+        }
+        let _ = self.entries.push_back((origin, seq, now_ms));
+        true
+    }
+}
+
+/// Routing state of the gateway, a relay or a sensor node
+#[derive(Clone, Debug)]
+pub struct Router {
+    own: MacAddress,
+    is_gateway: bool,
+    // AP channel of the gateway, advertised in its beacons
+    channel: Option<u8>,
+    seq: u16,
+    neighbors: Vec<Neighbor, MAX_NEIGHBORS>,
+    parent: Option<MacAddress>,
+    // Oldest first
+    routes: Vec<Route, MAX_ROUTES>,
+    seen: SeenCache,
+}
+
+impl Router {
+    pub fn new(own: MacAddress) -> Self {
+        Router {
+            own,
+            is_gateway: false,
+            channel: None,
+            seq: 0,
+            neighbors: Vec::new(),
+            parent: None,
+            routes: Vec::new(),
+            seen: SeenCache::default(),
+        }
+    }
+
+    /// Root of the routing tree, never has a parent
+    pub fn gateway(own: MacAddress) -> Self {
+        Router {
+            is_gateway: true,
+            ..Router::new(own)
+        }
+    }
+
+    pub fn own(&self) -> &MacAddress {
+        &self.own
+    }
+
+    pub fn neighbors(&self) -> &[Neighbor] {
+        &self.neighbors
+    }
+
+    /// Set the channel advertised by the gateway, returns whether it changed
+    pub fn set_channel(&mut self, channel: Option<u8>) -> bool {
+        let changed = self.channel != channel;
+        self.channel = channel;
+        changed
+    }
+
+    fn next_seq(&mut self) -> u16 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    pub fn on_beacon(&mut self, src: MacAddress, beacon: &Beacon, rssi: i8, now_ms: u64) {
+        // Nothing to learn for the root, and a parent that far is useless
+        if self.is_gateway || beacon.hops >= MAX_HOPS {
+            return;
+        }
+        let neighbor = Neighbor {
+            mac: src,
+            hops: beacon.hops,
+            rssi,
+            last_heard_ms: now_ms,
+            channel: beacon.channel,
+            gateway: beacon.gateway,
+        };
+        if let Some(known) = self.neighbors.iter_mut().find(|n| n.mac == src) {
+            *known = neighbor;
+            return;
+        }
+        self.expire_neighbors(now_ms);
+        if let Err(neighbor) = self.neighbors.push(neighbor) {
+            // Full, keep the best ones
+            if let Some(worst) = self.neighbors.iter_mut().max_by_key(|n| n.rank()) {
+                if neighbor.rank() < worst.rank() {
+                    *worst = neighbor;
+                }
+            }
+        }
+    }
+
+    fn expire_neighbors(&mut self, now_ms: u64) {
+        self.neighbors
+            .retain(|n| now_ms.saturating_sub(n.last_heard_ms) < NEIGHBOR_TIMEOUT_MS);
+    }
+
+    fn is_child(&self, mac: &MacAddress, now_ms: u64) -> bool {
+        self.routes.iter().any(|route| {
+            route.via == *mac && now_ms.saturating_sub(route.last_seen_ms) < ROUTE_TIMEOUT_MS
+        })
+    }
+
+    /// Current parent, re-elected among the live neighbors
+    pub fn parent(&mut self, now_ms: u64) -> Option<Neighbor> {
+        if self.is_gateway {
+            return None;
+        }
+        self.expire_neighbors(now_ms);
+        let candidates = || {
+            self.neighbors
+                .iter()
+                .filter(|n| !self.is_child(&n.mac, now_ms))
+        };
+        let Some(best) = candidates().min_by_key(|n| n.rank()).copied() else {
+            self.parent = None;
+            return None;
+        };
+        let current = candidates().find(|n| Some(n.mac) == self.parent).copied();
+        let parent = match current {
+            Some(current)
+                if current.hops == best.hops
+                    && best.rssi < current.rssi.saturating_add(PARENT_SWITCH_RSSI_DB) =>
+            {
+                current
+            }
+            _ => best,
+        };
+        self.parent = Some(parent.mac);
+        Some(parent)
+    }
+
+    /// Distance to the gateway, none without a parent
+    pub fn hops(&mut self, now_ms: u64) -> Option<u8> {
+        if self.is_gateway {
+            Some(0)
+        } else {
+            self.parent(now_ms).map(|parent| parent.hops + 1)
+        }
+    }
+
+    /// Beacon to broadcast, none while this node has no usable route
+    pub fn beacon(&mut self, now_ms: u64) -> Option<FrameBuffer> {
+        let beacon = if self.is_gateway {
+            Beacon {
+                hops: 0,
+                channel: self.channel,
+                gateway: Some(self.own),
+            }
+        } else {
+            let parent = self.parent(now_ms).filter(|p| p.hops + 1 < MAX_HOPS)?;
+            Beacon {
+                hops: parent.hops + 1,
+                channel: parent.channel,
+                gateway: parent.gateway,
+            }
+        };
+        let header = Header::new(MessageType::Beacon, self.own, self.next_seq());
+        beacon.encode(&header).ok()
+    }
+
+    /// Remember that frames of `node` arrive through the neighbor `via`
+    pub fn learn(&mut self, node: MacAddress, via: MacAddress, now_ms: u64) {
+        self.routes.retain(|route| {
+            route.node != node && now_ms.saturating_sub(route.last_seen_ms) < ROUTE_TIMEOUT_MS
+        });
+        if self.routes.is_full() {
+            self.routes.remove(0);
+        }
+        let _ = self.routes.push(Route {
+            node,
+            via,
+            last_seen_ms: now_ms,
+        });
+    }
+
+    pub fn next_hop(&self, node: &MacAddress, now_ms: u64) -> Option<MacAddress> {
+        self.routes
+            .iter()
+            .find(|route| {
+                route.node == *node && now_ms.saturating_sub(route.last_seen_ms) < ROUTE_TIMEOUT_MS
+            })
+            .map(|route| route.via)
+    }
+
+    /// Route a frame received by a relay or a sensor node, `broadcast` when it
+    /// was not sent to this node only
+    pub fn route<'a>(
+        &mut self,
+        src: MacAddress,
+        broadcast: bool,
+        data: &'a [u8],
+        rssi: i8,
+        now_ms: u64,
+    ) -> RouteAction<'a> {
+        let frame = match Frame::decode(data) {
+            Ok(frame) => frame,
+            Err(e) => return RouteAction::Drop(RouteDrop::Frame(e)),
+        };
+        match frame.header.msg_type {
+            MessageType::Beacon => match Beacon::from_payload(frame.payload) {
+                Ok(beacon) => {
+                    self.on_beacon(src, &beacon, rssi, now_ms);
+                    RouteAction::Handled
+                }
+                Err(e) => RouteAction::Drop(RouteDrop::Frame(e)),
+            },
+            // Join requests are broadcast, the node has no parent yet
+            msg_type if broadcast && msg_type != MessageType::JoinRequest => {
+                RouteAction::Drop(RouteDrop::Broadcast)
+            }
+            MessageType::Relay => self.route_relayed(src, &frame, now_ms),
+            _ if frame.header.node_id == self.own => RouteAction::Deliver(data),
+            // Frames are sent unwrapped by their origin only
+            _ if frame.header.node_id != src => RouteAction::Drop(RouteDrop::NotForUs),
+            _ => self.forward_up(src, data, &frame, INITIAL_TTL, now_ms),
+        }
+    }
+
+    fn route_relayed<'a>(
+        &mut self,
+        src: MacAddress,
+        frame: &Frame<'a>,
+        now_ms: u64,
+    ) -> RouteAction<'a> {
+        let inner = match Router::unwrap(frame) {
+            Ok(inner) => inner,
+            Err(e) => return RouteAction::Drop(e),
+        };
+        let dest = inner.frame.header.node_id;
+        if dest == self.own {
+            return RouteAction::Deliver(inner.data);
+        }
+        let ttl = inner.ttl.checked_sub(1);
+        if self.parent == Some(src) {
+            // Coming down from the gateway side
+            return match self.down(inner.data, dest, ttl, now_ms) {
+                Ok(outbound) => RouteAction::Forward(outbound),
+                Err(e) => RouteAction::Drop(e),
+            };
+        }
+        match ttl {
+            Some(ttl) => self.forward_up(src, inner.data, &inner.frame, ttl, now_ms),
+            None => RouteAction::Drop(RouteDrop::TtlExpired),
+        }
+    }
+
+    fn forward_up<'a>(
+        &mut self,
+        src: MacAddress,
+        data: &[u8],
+        frame: &Frame<'_>,
+        ttl: u8,
+        now_ms: u64,
+    ) -> RouteAction<'a> {
+        if !self
+            .seen
+            .insert(frame.header.node_id, frame.header.seq, now_ms)
+        {
+            return RouteAction::Drop(RouteDrop::Seen);
+        }
+        self.learn(frame.header.node_id, src, now_ms);
+        let Some(parent) = self.parent(now_ms) else {
+            return RouteAction::Drop(RouteDrop::NoParent);
+        };
+        if parent.mac == src {
+            return RouteAction::Drop(RouteDrop::Loop);
+        }
+        match self.wrap(parent.mac, data, ttl) {
+            Ok(outbound) => RouteAction::Forward(outbound),
+            Err(e) => RouteAction::Drop(e),
+        }
+    }
+
+    fn wrap(&mut self, to: MacAddress, data: &[u8], ttl: u8) -> Result<Outbound, RouteDrop> {
+        let header = Header::new(MessageType::Relay, self.own, self.next_seq());
+        let frame = Relayed { ttl, frame: data }
+            .encode(&header)
+            .map_err(RouteDrop::Frame)?;
+        Ok(Outbound { to, frame })
+    }
+
+    // Nodes without a known route are assumed in range
+    fn down(
+        &mut self,
+        data: &[u8],
+        dest: MacAddress,
+        ttl: Option<u8>,
+        now_ms: u64,
+    ) -> Result<Outbound, RouteDrop> {
+        let next = self.next_hop(&dest, now_ms).unwrap_or(dest);
+        if next == dest {
+            let frame = FrameBuffer::from_slice(data)
+                .map_err(|_| RouteDrop::Frame(ProtoError::Overflow))?;
+            return Ok(Outbound { to: dest, frame });
+        }
+        self.wrap(next, data, ttl.ok_or(RouteDrop::TtlExpired)?)
+    }
+
+    /// Next hop of a frame sent by this node to the node in its header, the
+    /// frame is wrapped when going through relays
+    pub fn route_down(&mut self, data: &[u8], now_ms: u64) -> Result<Outbound, RouteDrop> {
+        let dest = Frame::decode(data)
+            .map_err(RouteDrop::Frame)?
+            .header
+            .node_id;
+        self.down(data, dest, Some(INITIAL_TTL), now_ms)
+    }
+
+    /// Frame carried by a relay frame
+    pub fn unwrap<'a>(frame: &Frame<'a>) -> Result<Unwrapped<'a>, RouteDrop> {
+        let relayed = Relayed::from_payload(frame.payload).map_err(RouteDrop::Frame)?;
+        let inner = Frame::decode(relayed.frame).map_err(RouteDrop::Frame)?;
+        if inner.header.msg_type == MessageType::Relay {
+            return Err(RouteDrop::Nested);
+        }
+        Ok(Unwrapped {
+            frame: inner,
+            data: relayed.frame,
+            ttl: relayed.ttl,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::mesh_proto::{
+        encode_readings, Ack, MeshReading, ReadingKind, Unit, FLAG_ACK_REQUESTED,
+    };
+
+    const GATEWAY: MacAddress = [0xAA; 6];
+    const R1: MacAddress = [1; 6];
+    const R2: MacAddress = [2; 6];
+    const NODE: MacAddress = [3; 6];
+
+    fn readings(seq: u16) -> FrameBuffer {
+        let header = Header::new(MessageType::Readings, NODE, seq).with_flags(FLAG_ACK_REQUESTED);
+        let reading = MeshReading::new(ReadingKind::Temperature, Unit::Celsius, 21.5);
+        encode_readings(&header, &[reading]).unwrap()
+    }
+
+    fn ack(seq: u16) -> FrameBuffer {
+        Ack::default()
+            .encode(&Header::new(MessageType::Ack, NODE, seq))
+            .unwrap()
+    }
+
+    fn beacon(hops: u8) -> Beacon {
+        Beacon {
+            hops,
+            channel: Some(6),
+            gateway: Some(GATEWAY),
+        }
+    }
+
+    fn forwarded(action: RouteAction<'_>) -> Outbound {
+        match action {
+            RouteAction::Forward(outbound) => outbound,
+            other => panic!("not forwarded: {:?}", other),
+        }
+    }
+
+    /// Chain gateway - R1 - R2 - NODE, each hearing only its neighbors
+    fn chain(now_ms: u64) -> (Router, Router, Router, Router) {
+        let mut gateway = Router::gateway(GATEWAY);
+        let mut r1 = Router::new(R1);
+        let mut r2 = Router::new(R2);
+        let mut node = Router::new(NODE);
+        let b = gateway.beacon(now_ms).unwrap();
+        assert_eq!(
+            r1.route(GATEWAY, true, &b, -50, now_ms),
+            RouteAction::Handled
+        );
+        let b1 = r1.beacon(now_ms).unwrap();
+        r2.route(R1, true, &b1, -60, now_ms);
+        let b2 = r2.beacon(now_ms).unwrap();
+        node.route(R2, true, &b2, -60, now_ms);
+        (gateway, r1, r2, node)
+    }
+
+    #[test]
+    fn frames_cross_two_relays_and_acks_come_back() {
+        let now = 1_000;
+        let (mut gateway, mut r1, mut r2, mut node) = chain(now);
+        assert_eq!(node.parent(now).unwrap().mac, R2);
+        assert_eq!(node.hops(now), Some(3));
+        let b2 = r2.beacon(now).unwrap();
+        // A louder relay farther from the gateway is not a better parent
+        r1.route(R2, true, &b2, -40, now);
+        assert_eq!(r1.parent(now).unwrap().mac, GATEWAY);
+
+        let frame = readings(7);
+        let up2 = forwarded(r2.route(NODE, false, &frame, -60, now));
+        assert_eq!(up2.to, R1);
+        let up1 = forwarded(r1.route(R2, false, &up2.frame, -60, now));
+        assert_eq!(up1.to, GATEWAY);
+        let at_gateway = Frame::decode(&up1.frame).unwrap();
+        let inner = Router::unwrap(&at_gateway).unwrap();
+        assert_eq!(inner.data, &frame[..]);
+        assert_eq!(inner.relays(), 2);
+        gateway.learn(NODE, at_gateway.header.node_id, now);
+
+        let ack = ack(7);
+        let down1 = gateway.route_down(&ack, now).unwrap();
+        assert_eq!(down1.to, R1);
+        let down2 = forwarded(r1.route(GATEWAY, false, &down1.frame, -50, now));
+        assert_eq!(down2.to, R2);
+        // The last relay hands the ack over unwrapped
+        let last = forwarded(r2.route(R1, false, &down2.frame, -60, now));
+        assert_eq!((last.to, &last.frame[..]), (NODE, &ack[..]));
+        assert_eq!(
+            node.route(R2, false, &last.frame, -60, now),
+            RouteAction::Deliver(&ack[..])
+        );
+    }
+
+    #[test]
+    fn retransmits_are_relayed_once_the_seen_cache_expired() {
+        let now = 1_000;
+        let (_, _, mut r2, _) = chain(now);
+        let frame = readings(7);
+        forwarded(r2.route(NODE, false, &frame, -60, now));
+        assert_eq!(
+            r2.route(NODE, false, &frame, -60, now + SEEN_CACHE_MS - 1),
+            RouteAction::Drop(RouteDrop::Seen)
+        );
+        forwarded(r2.route(NODE, false, &frame, -60, now + SEEN_CACHE_MS));
+    }
+
+    #[test]
+    fn ttl_bounds_the_relayed_hops() {
+        let now = 1_000;
+        let (_, mut r1, _, _) = chain(now);
+        let frame = readings(7);
+        let relayed = |ttl| {
+            Relayed { ttl, frame: &frame }
+                .encode(&Header::new(MessageType::Relay, R2, 1))
+                .unwrap()
+        };
+        let up = forwarded(r1.route(R2, false, &relayed(1), -60, now));
+        let inner = Router::unwrap(&Frame::decode(&up.frame).unwrap()).unwrap();
+        assert_eq!(inner.ttl, 0);
+        assert_eq!(
+            r1.route(R2, false, &relayed(0), -60, now + SEEN_CACHE_MS),
+            RouteAction::Drop(RouteDrop::TtlExpired)
+        );
+
+        // Beacons as far as `MAX_HOPS` are ignored, a relay that far does not
+        // advertise a route
+        let mut far = Router::new(NODE);
+        far.on_beacon(R2, &beacon(MAX_HOPS), -40, now);
+        assert!(far.parent(now).is_none());
+        far.on_beacon(R2, &beacon(MAX_HOPS - 1), -40, now);
+        assert_eq!(far.hops(now), Some(MAX_HOPS));
+        assert!(far.beacon(now).is_none());
+    }
+
+    #[test]
+    fn reverse_routes_expire() {
+        let now = 1_000;
+        let mut gateway = Router::gateway(GATEWAY);
+        gateway.learn(NODE, R1, now);
+        assert_eq!(
+            gateway.next_hop(&NODE, now + ROUTE_TIMEOUT_MS - 1),
+            Some(R1)
+        );
+        assert_eq!(gateway.route_down(&ack(1), now).unwrap().to, R1);
+
+        // A node heard through another relay moves over
+        gateway.learn(NODE, R2, now + 10);
+        assert_eq!(gateway.next_hop(&NODE, now + 10), Some(R2));
+
+        // Expired nodes are assumed in range and get the frame unwrapped
+        let later = now + 10 + ROUTE_TIMEOUT_MS;
+        assert_eq!(gateway.next_hop(&NODE, later), None);
+        let direct = gateway.route_down(&ack(1), later).unwrap();
+        assert_eq!((direct.to, &direct.frame[..]), (NODE, &ack(1)[..]));
+    }
+
+    #[test]
+    fn children_are_never_picked_as_parent() {
+        let now = 1_000;
+        let (_, mut r1, mut r2, _) = chain(now);
+        let frame = readings(7);
+        let up = forwarded(r2.route(NODE, false, &frame, -60, now));
+        forwarded(r1.route(R2, false, &up.frame, -60, now));
+
+        // R1 loses the gateway, R2 still beacons its stale route
+        let later = now + NEIGHBOR_TIMEOUT_MS;
+        r1.on_beacon(R2, &beacon(2), -40, later);
+        assert!(r1.parent(later).is_none());
+        assert!(r2.parent(later).is_none());
+        assert_eq!(
+            r1.route(R2, false, &up.frame, -60, later),
+            RouteAction::Drop(RouteDrop::NoParent)
+        );
+
+        // Once the route through R2 expired, R2 is a neighbor like any other
+        let expired = later + ROUTE_TIMEOUT_MS;
+        r1.on_beacon(R2, &beacon(1), -40, expired);
+        assert_eq!(r1.parent(expired).unwrap().mac, R2);
+    }
+
+    #[test]
+    fn parent_only_changes_for_a_clearly_better_neighbor() {
+        let now = 1_000;
+        let mut node = Router::new(NODE);
+        node.on_beacon(R1, &beacon(1), -70, now);
+        assert_eq!(node.parent(now).unwrap().mac, R1);
+        node.on_beacon(R2, &beacon(1), -70 + PARENT_SWITCH_RSSI_DB - 1, now);
+        assert_eq!(node.parent(now).unwrap().mac, R1);
+        node.on_beacon(R2, &beacon(1), -70 + PARENT_SWITCH_RSSI_DB, now);
+        assert_eq!(node.parent(now).unwrap().mac, R2);
+
+        // Fewer hops win whatever the signal
+        node.on_beacon(GATEWAY, &beacon(0), -90, now);
+        assert_eq!(node.parent(now).unwrap().mac, GATEWAY);
+
+        // A parent missing its beacons is replaced
+        let later = now + NEIGHBOR_TIMEOUT_MS;
+        node.on_beacon(R1, &beacon(1), -70, later - 1);
+        assert_eq!(node.parent(later).unwrap().mac, R1);
+        assert_eq!(node.neighbors().len(), 1);
+    }
+
+    #[test]
+    fn misdirected_frames_are_dropped() {
+        let now = 1_000;
+        let (_, mut r1, _, _) = chain(now);
+        assert_eq!(
+            r1.route(NODE, true, &readings(1), -60, now),
+            RouteAction::Drop(RouteDrop::Broadcast)
+        );
+        assert_eq!(
+            r1.route(R2, false, &readings(1), -60, now),
+            RouteAction::Drop(RouteDrop::NotForUs)
+        );
+        let inner = Relayed {
+            ttl: 1,
+            frame: &readings(1),
+        }
+        .encode(&Header::new(MessageType::Relay, R2, 1))
+        .unwrap();
+        let nested = Relayed {
+            ttl: 1,
+            frame: &inner,
+        }
+        .encode(&Header::new(MessageType::Relay, R2, 2))
+        .unwrap();
+        assert_eq!(
+            r1.route(R2, false, &nested, -60, now),
+            RouteAction::Drop(RouteDrop::Nested)
+        );
+    }
+}
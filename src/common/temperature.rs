@@ -18,7 +18,7 @@ use core::sync::atomic::{AtomicI16, Ordering};
 // use esp_hal::i2c::master::I2c;
 // use esp_hal::Async;
 use crate::common::rng::SimpleRngU64;
-use log::info;
+use log::{debug, info};
 
 // ****** I2C address for ADS1115 ****** //
 // const ADS1115_ADDR: u8 = 0x48;
@@ -88,7 +88,7 @@ pub fn read_temperature_hack() -> f32 {
 pub fn raw_voltage_to_temp(voltage: &AtomicI16) -> f32 {
     let raw_voltage = voltage.load(Ordering::Relaxed) as f32;
     let temp = (raw_voltage - 0.5) / 100.0; // HACK: PLACEHOLDER LINEAR REG
-    info!(
+    debug!(
         "Converted Raw voltage: {} to Temperature: {} C",
         raw_voltage, temp
     );
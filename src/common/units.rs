@@ -0,0 +1,195 @@
+//! Units of the values shown on the display and published
+//!
+//! Readings keep the canonical unit they are measured in: `C` for the
+//! temperatures, `Pa`, `kPa` or `bar` for the pressures and `L/min` or `m3/h`
+//! for the flows. Each deployment picks the units they are presented in with
+//! `TEMPERATURE_UNIT` (`C`, `F` or `K`) and `UNIT_SYSTEM` (`metric`, or
+//! `imperial` for psi and US gallons per minute). The display always shows the
+//! converted values; the payloads keep the canonical `value` and `unit` and
+//! add the converted ones when `PAYLOAD_DISPLAY_UNITS` is 1.
+
+use crate::common::config::env_u32_or;
+
+pub const UNITS: UnitPreferences = UnitPreferences {
+    temperature: TemperatureUnit::parse(option_env!("TEMPERATURE_UNIT")),
+    system: UnitSystem::parse(option_env!("UNIT_SYSTEM")),
+};
+pub const PAYLOAD_DISPLAY_UNITS: bool = env_u32_or(option_env!("PAYLOAD_DISPLAY_UNITS"), 0) != 0;
+
+const PA_PER_PSI: f32 = 6_894.757;
+const LITRES_PER_GALLON: f32 = 3.785_411_8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    /// Unit named by its symbol, Celsius if unset or unknown
+    pub const fn parse(value: Option<&str>) -> Self {
+        let Some(value) = value else {
+            return TemperatureUnit::Celsius;
+        };
+        match value.as_bytes() {
+            b"F" | b"f" => TemperatureUnit::Fahrenheit,
+            b"K" | b"k" => TemperatureUnit::Kelvin,
+            _ => TemperatureUnit::Celsius,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "C",
+            TemperatureUnit::Fahrenheit => "F",
+            TemperatureUnit::Kelvin => "K",
+        }
+    }
+
+    /// `celsius` in this unit
+    pub fn convert_celsius(&self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => celsius + 273.15,
+        }
+    }
+}
+
+/// Units of the pressures and flows
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnitSystem {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+impl UnitSystem {
+    /// System named `metric` or `imperial`, metric if unset or unknown
+    pub const fn parse(value: Option<&str>) -> Self {
+        let Some(value) = value else {
+            return UnitSystem::Metric;
+        };
+        match value.as_bytes() {
+            b"imperial" | b"IMPERIAL" => UnitSystem::Imperial,
+            _ => UnitSystem::Metric,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UnitPreferences {
+    pub temperature: TemperatureUnit,
+    pub system: UnitSystem,
+}
+
+impl UnitPreferences {
+    /// `value` in its canonical `unit` converted to the preferred unit, as is
+    /// for the units without a preference
+    pub fn convert(&self, value: f32, unit: &'static str) -> (f32, &'static str) {
+        match unit {
+            "C" => (
+                self.temperature.convert_celsius(value),
+                self.temperature.symbol(),
+            ),
+            _ if self.system == UnitSystem::Metric => (value, unit),
+            "Pa" => (value / PA_PER_PSI, "psi"),
+            "kPa" => (value * 1_000.0 / PA_PER_PSI, "psi"),
+            "bar" => (value * 100_000.0 / PA_PER_PSI, "psi"),
+            "L/min" => (value / LITRES_PER_GALLON, "gpm"),
+            "m3/h" => (value * 1_000.0 / 60.0 / LITRES_PER_GALLON, "gpm"),
+            _ => (value, unit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRIC: UnitPreferences = UnitPreferences {
+        temperature: TemperatureUnit::Celsius,
+        system: UnitSystem::Metric,
+    };
+    const IMPERIAL: UnitPreferences = UnitPreferences {
+        temperature: TemperatureUnit::Fahrenheit,
+        system: UnitSystem::Imperial,
+    };
+    const KELVIN: UnitPreferences = UnitPreferences {
+        temperature: TemperatureUnit::Kelvin,
+        system: UnitSystem::Metric,
+    };
+
+    fn assert_converts(
+        units: UnitPreferences,
+        value: f32,
+        unit: &'static str,
+        expected: (f32, &str),
+    ) {
+        let (converted, symbol) = units.convert(value, unit);
+        assert_eq!(symbol, expected.1, "{} {}", value, unit);
+        assert!(
+            (converted - expected.0).abs() <= 1e-3 * expected.0.abs().max(1.0),
+            "{} {} gave {} {}, expected {}",
+            value,
+            unit,
+            converted,
+            symbol,
+            expected.0
+        );
+    }
+
+    #[test]
+    fn temperatures() {
+        assert_eq!(METRIC.convert(21.5, "C"), (21.5, "C"));
+        assert_converts(IMPERIAL, 100.0, "C", (212.0, "F"));
+        assert_converts(IMPERIAL, -40.0, "C", (-40.0, "F"));
+        assert_converts(IMPERIAL, 0.0, "C", (32.0, "F"));
+        assert_converts(KELVIN, 0.0, "C", (273.15, "K"));
+        assert_converts(KELVIN, -273.15, "C", (0.0, "K"));
+    }
+
+    #[test]
+    fn pressures_to_psi() {
+        assert_converts(IMPERIAL, 101_325.0, "Pa", (14.6959, "psi"));
+        assert_converts(IMPERIAL, 6.894_757, "kPa", (1.0, "psi"));
+        assert_converts(IMPERIAL, 1.0, "bar", (14.5038, "psi"));
+        // Metric keeps the canonical unit
+        assert_eq!(METRIC.convert(2.0, "bar"), (2.0, "bar"));
+        assert_eq!(KELVIN.convert(350.0, "kPa"), (350.0, "kPa"));
+    }
+
+    #[test]
+    fn flows_to_gpm() {
+        assert_converts(IMPERIAL, 3.785_411_8, "L/min", (1.0, "gpm"));
+        assert_converts(IMPERIAL, 1.0, "m3/h", (4.4029, "gpm"));
+        assert_eq!(METRIC.convert(12.0, "m3/h"), (12.0, "m3/h"));
+    }
+
+    #[test]
+    fn other_units_are_kept() {
+        assert_eq!(IMPERIAL.convert(12.0, "mA"), (12.0, "mA"));
+        assert_eq!(IMPERIAL.convert(45.0, "%"), (45.0, "%"));
+        assert!(IMPERIAL.convert(f32::NAN, "bar").0.is_nan());
+    }
+
+    #[test]
+    fn parse_falls_back_to_metric_celsius() {
+        assert_eq!(
+            TemperatureUnit::parse(Some("F")),
+            TemperatureUnit::Fahrenheit
+        );
+        assert_eq!(TemperatureUnit::parse(Some("k")), TemperatureUnit::Kelvin);
+        assert_eq!(TemperatureUnit::parse(Some("C")), TemperatureUnit::Celsius);
+        assert_eq!(TemperatureUnit::parse(Some("x")), TemperatureUnit::Celsius);
+        assert_eq!(TemperatureUnit::parse(Some("")), TemperatureUnit::Celsius);
+        assert_eq!(TemperatureUnit::parse(None), TemperatureUnit::Celsius);
+        assert_eq!(UnitSystem::parse(Some("imperial")), UnitSystem::Imperial);
+        assert_eq!(UnitSystem::parse(Some("IMPERIAL")), UnitSystem::Imperial);
+        assert_eq!(UnitSystem::parse(Some("metric")), UnitSystem::Metric);
+        assert_eq!(UnitSystem::parse(Some("us")), UnitSystem::Metric);
+        assert_eq!(UnitSystem::parse(None), UnitSystem::Metric);
+    }
+}
@@ -1,16 +1,47 @@
-use embassy_net::{Runner, Stack};
+#[cfg(feature = "esp32")]
+use embassy_net::Runner;
+use embassy_net::Stack;
 use embassy_time::{Duration, Timer};
-use log::{debug, info, warn};
+#[cfg(feature = "esp32")]
+use log::warn;
+use log::{debug, info};
 
+#[cfg(feature = "esp32")]
 use esp_wifi::wifi::{
     ClientConfiguration, Configuration, WifiController, WifiDevice, WifiStaDevice, WifiState,
 };
 
-use core::sync::atomic::{AtomicI8, Ordering};
+use core::sync::atomic::{AtomicI8, AtomicU32, AtomicU8, Ordering};
+
+#[cfg(feature = "esp32")]
+use crate::common::connectivity::LinkError;
+use crate::common::connectivity::{report, LinkEvent};
 
 // Global atomic variable for the current WiFi signal strength
 pub static CURRENT_RSSI: AtomicI8 = AtomicI8::new(-100); // Default value when not connected
+                                                         // Channel of the AP, 0 when not connected. ESP-NOW shares it, the gateway
+                                                         // advertises it in its mesh beacons.
+pub static CURRENT_CHANNEL: AtomicU8 = AtomicU8::new(0);
+// IPv4 address of the STA as a big-endian u32, 0 without a DHCP lease
+pub static CURRENT_IP: AtomicU32 = AtomicU32::new(0);
+
+/// Keep `CURRENT_IP` up to date with the DHCP lease of the stack
+pub fn store_ip(stack: Stack<'_>) {
+    let ip = stack.config_v4().map_or(0, |config| {
+        u32::from_be_bytes(config.address.address().octets())
+    });
+    CURRENT_IP.store(ip, Ordering::Relaxed);
+}
 
+/// Address stored in `CURRENT_IP`, if any
+pub fn current_ip() -> Option<[u8; 4]> {
+    match CURRENT_IP.load(Ordering::Relaxed) {
+        0 => None,
+        ip => Some(ip.to_be_bytes()),
+    }
+}
+
+#[cfg(feature = "esp32")]
 #[embassy_executor::task]
 pub async fn connection_task(
     mut controller: WifiController<'static>,
@@ -33,10 +64,15 @@ pub async fn connection_task(
         }
 
         info!("Attempting connection...");
+        report(LinkEvent::WifiConnecting);
         match controller.connect_async().await {
-            Ok(_) => info!("Connected!"),
+            Ok(_) => {
+                info!("Connected!");
+                report(LinkEvent::WifiConnected);
+            }
             Err(e) => {
                 warn!("Connection failed: {e:?}");
+                report(LinkEvent::Failed(LinkError::WifiConnect));
                 info!("Retrying in 5 secs...");
                 Timer::after(Duration::from_secs(5)).await;
                 continue 'start_conn_loop;
@@ -46,6 +82,18 @@ pub async fn connection_task(
         // Main polling loop while connected
         'poll_rssi_when_conn: while esp_wifi::wifi::wifi_state() == WifiState::StaConnected {
             if let Ok(scan_res) = controller.scan_n::<10>() {
+                // The STA joins the strongest AP of the SSID, its channel is ours
+                if let Some(ap) = scan_res
+                    .0
+                    .iter()
+                    .filter(|ap| ap.ssid == ssid)
+                    .max_by_key(|ap| ap.signal_strength)
+                {
+                    let previous = CURRENT_CHANNEL.swap(ap.channel, Ordering::Relaxed);
+                    if previous != ap.channel {
+                        info!("AP channel changed from {} to {}", previous, ap.channel);
+                    }
+                }
                 for ap in scan_res.0 {
                     // HACK: N=10 is arbitrary, in my case I scan current conn SSID twice
                     if ap.ssid == ssid {
@@ -63,6 +111,9 @@ pub async fn connection_task(
 
             if esp_wifi::wifi::wifi_state() == WifiState::StaDisconnected {
                 warn!("WiFi Disconnected! Restarting connection...");
+                CURRENT_CHANNEL.store(0, Ordering::Relaxed);
+                CURRENT_IP.store(0, Ordering::Relaxed);
+                report(LinkEvent::Failed(LinkError::LinkLost));
                 Timer::after(Duration::from_secs(5)).await;
                 break 'poll_rssi_when_conn; // Exit loop to retry connection
             }
@@ -70,6 +121,7 @@ pub async fn connection_task(
     }
 }
 
+#[cfg(feature = "esp32")]
 #[embassy_executor::task]
 pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static, WifiStaDevice>>) {
     runner.run().await
@@ -88,6 +140,8 @@ pub async fn wait_for_connection(stack: Stack<'_>) {
     loop {
         if let Some(config) = stack.config_v4() {
             info!("Got IP: {}", config.address);
+            store_ip(stack);
+            report(LinkEvent::LeaseAcquired);
             break;
         }
         Timer::after(Duration::from_millis(500)).await;
@@ -0,0 +1,200 @@
+//! ADS1115 converters of the Norvi iIOT AE04 4-20mA inputs
+//!
+//! Hardware configuration:
+//! - A0-A3: ADS1115 0x48 AIN0-AIN3
+//! - A4-A5: ADS1115 0x49 AIN0-AIN1
+//! - Loop current measured across a shunt, single-ended, +-4.096V range
+//!
+//! Every input is converted in single-shot mode and its engineering value
+//! stored in the `common::analog` store, NaN on a bus error or a loop fault.
+
+#[cfg(feature = "esp32")]
+use embassy_time::Ticker;
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::i2c::I2c;
+#[cfg(feature = "esp32")]
+use esp_hal::{i2c::master::I2c as EspI2c, Async};
+#[cfg(feature = "esp32")]
+use log::{info, warn};
+
+#[cfg(feature = "esp32")]
+use crate::common::analog::AnalogChannel;
+use crate::common::analog::ANALOG_CHANNEL_COUNT;
+use crate::common::config::env_u32_or;
+#[cfg(feature = "esp32")]
+use crate::gateway_lib::i2c_bus::SharedI2c;
+
+const ADS1115_ADDRESSES: [u8; 2] = [0x48, 0x49];
+const CONVERSION_REGISTER: u8 = 0x00;
+const CONFIG_REGISTER: u8 = 0x01;
+
+// Single shot of AINx against GND, +-4.096V, 128 SPS, comparator disabled
+const CONFIG_START: u16 = 0x8000;
+const CONFIG_MUX_AIN0: u16 = 0b100 << 12;
+const CONFIG_PGA_4096MV: u16 = 0b001 << 9;
+const CONFIG_SINGLE_SHOT: u16 = 1 << 8;
+const CONFIG_DR_128SPS: u16 = 0b100 << 5;
+const CONFIG_COMPARATOR_OFF: u16 = 0b11;
+// 7.8ms at 128 SPS, with the wake-up of the converter
+const CONVERSION_TIME: Duration = Duration::from_millis(9);
+const MILLIVOLTS_PER_LSB: f32 = 4096.0 / 32768.0;
+
+// Shunt of the 4-20mA inputs, 20mA gives 3V across 150 ohms
+pub const SHUNT_OHMS: u32 = env_u32_or(option_env!("ANALOG_SHUNT_OHMS"), 150);
+#[cfg(feature = "esp32")]
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Converter address and single-ended input of an analog input (A0-A5)
+pub fn input(index: usize) -> Option<(u8, u8)> {
+    if index >= ANALOG_CHANNEL_COUNT {
+        return None;
+    }
+    Some((ADS1115_ADDRESSES[index / 4], (index % 4) as u8))
+}
+
+/// Config register starting a conversion of `ain`
+pub fn config_word(ain: u8) -> u16 {
+    CONFIG_START
+        | (CONFIG_MUX_AIN0 + ((ain as u16 & 0b11) << 12))
+        | CONFIG_PGA_4096MV
+        | CONFIG_SINGLE_SHOT
+        | CONFIG_DR_128SPS
+        | CONFIG_COMPARATOR_OFF
+}
+
+pub fn raw_to_millivolts(raw: i16) -> f32 {
+    raw as f32 * MILLIVOLTS_PER_LSB
+}
+
+/// Loop current in mA for the voltage across the shunt
+pub fn loop_current_ma(millivolts: f32) -> f32 {
+    millivolts / SHUNT_OHMS as f32
+}
+
+/// Convert the single-ended input `ain` of the converter at `address`
+pub async fn read_millivolts<I: I2c>(i2c: &mut I, address: u8, ain: u8) -> Result<f32, I::Error> {
+    let [high, low] = config_word(ain).to_be_bytes();
+    i2c.write(address, &[CONFIG_REGISTER, high, low]).await?;
+    Timer::after(CONVERSION_TIME).await;
+    let mut raw = [0; 2];
+    i2c.write_read(address, &[CONVERSION_REGISTER], &mut raw)
+        .await?;
+    Ok(raw_to_millivolts(i16::from_be_bytes(raw)))
+}
+
+#[cfg(feature = "esp32")]
+#[embassy_executor::task]
+pub async fn analog_input_task(
+    mut i2c: SharedI2c<EspI2c<'static, Async>>,
+    channels: &'static [AnalogChannel],
+) {
+    info!(
+        "Sampling {} analog inputs every {} ms",
+        channels.len(),
+        SAMPLE_INTERVAL.as_millis()
+    );
+    // Inputs which failed at their last sample, warned once per fault
+    let mut faulted = 0u8;
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    loop {
+        for channel in channels {
+            let Some((address, ain)) = input(channel.index) else {
+                continue;
+            };
+            let value = match read_millivolts(&mut i2c, address, ain).await {
+                Ok(millivolts) => channel.loop_value(loop_current_ma(millivolts)),
+                Err(e) => {
+                    if faulted & (1 << channel.index) == 0 {
+                        warn!("ADS1115 0x{:02X} read failed: {:?}", address, e);
+                    }
+                    f32::NAN
+                }
+            };
+            let bit = 1 << channel.index;
+            if !value.is_finite() && faulted & bit == 0 {
+                warn!("Analog input '{}' has no valid sample", channel.name);
+                faulted |= bit;
+            } else if value.is_finite() && faulted & bit != 0 {
+                info!("Analog input '{}' is back: {}", channel.name, value);
+                faulted &= !bit;
+            }
+            channel.store(value);
+        }
+        ticker.next().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embedded_hal_async::i2c::{ErrorKind, ErrorType, Operation};
+
+    /// Converter answering `raw` and recording the config words written to it
+    struct FakeAds {
+        raw: i16,
+        nack: bool,
+        configs: Vec<(u8, u16)>,
+    }
+
+    impl ErrorType for FakeAds {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for FakeAds {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if self.nack {
+                return Err(ErrorKind::Other);
+            }
+            for operation in operations {
+                match operation {
+                    Operation::Write([CONFIG_REGISTER, high, low]) => self
+                        .configs
+                        .push((address, u16::from_be_bytes([*high, *low]))),
+                    Operation::Write(_) => {}
+                    Operation::Read(buffer) => buffer.copy_from_slice(&self.raw.to_be_bytes()),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn inputs_map_to_both_converters() {
+        assert_eq!(input(0), Some((0x48, 0)));
+        assert_eq!(input(3), Some((0x48, 3)));
+        assert_eq!(input(4), Some((0x49, 0)));
+        assert_eq!(input(5), Some((0x49, 1)));
+        assert_eq!(input(6), None);
+    }
+
+    #[test]
+    fn config_starts_a_single_shot_of_the_input() {
+        assert_eq!(config_word(0), 0xC383);
+        assert_eq!(config_word(3), 0xF383);
+    }
+
+    #[test]
+    fn reads_the_conversion_in_millivolts() {
+        let mut ads = FakeAds {
+            raw: 24_000,
+            nack: false,
+            configs: Vec::new(),
+        };
+        let millivolts = block_on(read_millivolts(&mut ads, 0x49, 1)).unwrap();
+        assert_eq!(millivolts, 3000.0);
+        assert_eq!(ads.configs, [(0x49, config_word(1))]);
+        // 20mA across the shunt
+        assert_eq!(loop_current_ma(millivolts), 3000.0 / SHUNT_OHMS as f32);
+
+        ads.nack = true;
+        assert!(block_on(read_millivolts(&mut ads, 0x48, 0)).is_err());
+    }
+}
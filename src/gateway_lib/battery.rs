@@ -0,0 +1,377 @@
+//! Battery of the mesh nodes, tracked by the gateway
+//!
+//! The supply voltage of each reading frame is averaged over
+//! `TREND_SPACING_MS` and kept in a short history per node. The remaining
+//! battery life is the time the least-squares line of that history takes to
+//! reach the cutoff voltage. A node is in low-battery alarm once below
+//! `LOW_BATTERY_MV`, or when expected to get there within `LOW_BATTERY_HOURS`;
+//! it recovers 100 mV above the threshold. A voltage jump up means the battery
+//! was replaced or charged and restarts the history.
+//!
+//! The history lives in RAM only, the estimate needs a few hours of readings
+//! after a gateway restart.
+
+use heapless::{FnvIndexMap, Vec};
+
+use crate::common::config::env_u32_or;
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{NodeStatus, ResetReason};
+use crate::gateway_lib::registry::MAX_NODES;
+
+pub const LOW_BATTERY_MV: u32 = env_u32_or(option_env!("NODE_LOW_BATTERY_MV"), 3300);
+pub const LOW_BATTERY_HOURS: u32 = env_u32_or(option_env!("NODE_LOW_BATTERY_HOURS"), 72);
+// Voltage the estimate counts down to, where the node browns out
+pub const CUTOFF_MV: u32 = env_u32_or(option_env!("NODE_CUTOFF_MV"), 3000);
+pub const TREND_SPACING_MS: u64 = 30 * 60 * 1000;
+pub const TREND_LEN: usize = 16;
+// Fewer points or a shorter span give a meaningless slope
+const MIN_TREND_SAMPLES: usize = 4;
+const MIN_TREND_SPAN_MS: u64 = 2 * 60 * 60 * 1000;
+const RECOVERY_MARGIN_V: f32 = 0.1;
+const REPLACED_JUMP_V: f32 = 0.2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatterySample {
+    pub at_ms: u64,
+    pub voltage: f32,
+}
+
+/// Hours until the line fitted through `samples` reaches `cutoff_v`, counted
+/// from the last sample. None while the samples do not show a discharge.
+pub fn estimate_remaining_hours(samples: &[BatterySample], cutoff_v: f32) -> Option<f32> {
+    let (first, last) = (samples.first()?, samples.last()?);
+    if samples.len() < MIN_TREND_SAMPLES
+        || last.at_ms.saturating_sub(first.at_ms) < MIN_TREND_SPAN_MS
+    {
+        return None;
+    }
+    // Hours from the first sample, small enough for f32
+    let hours = |sample: &BatterySample| (sample.at_ms - first.at_ms) as f32 / 3_600_000.0;
+    let count = samples.len() as f32;
+    let mean_t = samples.iter().map(hours).sum::<f32>() / count;
+    let mean_v = samples.iter().map(|sample| sample.voltage).sum::<f32>() / count;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for sample in samples {
+        let dt = hours(sample) - mean_t;
+        covariance += dt * (sample.voltage - mean_v);
+        variance += dt * dt;
+    }
+    // Volts per hour, must be going down
+    let slope = covariance / variance;
+    if !slope.is_finite() || slope >= 0.0 {
+        return None;
+    }
+    let now_v = mean_v + slope * (hours(last) - mean_t);
+    Some(((now_v - cutoff_v) / -slope).max(0.0))
+}
+
+/// Power telemetry of a node, published with each of its readings
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NodePower {
+    pub battery_v: Option<f32>,
+    pub remaining_hours: Option<f32>,
+    pub wake_count: Option<u32>,
+    pub reset_reason: Option<ResetReason>,
+}
+
+/// Low-battery alarm raised or cleared
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatteryAlarm {
+    pub low: bool,
+    pub battery_v: f32,
+    pub remaining_hours: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct NodeBattery {
+    trend: Vec<BatterySample, TREND_LEN>,
+    // Voltages averaged into the next trend sample, since `bucket_start_ms`
+    bucket_sum: f32,
+    bucket_count: u32,
+    bucket_start_ms: u64,
+    last_v: Option<f32>,
+    low: bool,
+}
+
+impl NodeBattery {
+    fn record(&mut self, voltage: f32, now_ms: u64) {
+        if self
+            .last_v
+            .is_some_and(|last_v| voltage > last_v + REPLACED_JUMP_V)
+        {
+            *self = NodeBattery {
+                low: self.low,
+                ..NodeBattery::default()
+            };
+        }
+        self.last_v = Some(voltage);
+        if self.bucket_count == 0 {
+            self.bucket_start_ms = now_ms;
+        }
+        self.bucket_sum += voltage;
+        self.bucket_count += 1;
+        if now_ms.saturating_sub(self.bucket_start_ms) < TREND_SPACING_MS && !self.trend.is_empty()
+        {
+            return;
+        }
+        if self.trend.is_full() {
+            self.trend.remove(0);
+        }
+        // Room was made above
+        let _ = self.trend.push(BatterySample {
+            at_ms: now_ms,
+            voltage: self.bucket_sum / self.bucket_count as f32,
+        });
+        self.bucket_sum = 0.0;
+        self.bucket_count = 0;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BatteryMonitor {
+    nodes: FnvIndexMap<MacAddress, NodeBattery, MAX_NODES>,
+}
+
+impl BatteryMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the power fields of a reading frame, returns the telemetry to
+    /// publish and the alarm when it changed
+    pub fn on_readings(
+        &mut self,
+        mac: MacAddress,
+        battery_v: Option<f32>,
+        status: &NodeStatus,
+        now_ms: u64,
+    ) -> (NodePower, Option<BatteryAlarm>) {
+        let mut power = NodePower {
+            battery_v,
+            remaining_hours: None,
+            wake_count: status.wake_count,
+            reset_reason: status.reset_reason,
+        };
+        let Some(voltage) = battery_v.filter(|v| v.is_finite() && *v > 0.0) else {
+            return (power, None);
+        };
+        if !self.nodes.contains_key(&mac) {
+            // As many entries as the liveness table, extra nodes are not tracked
+            let _ = self.nodes.insert(mac, NodeBattery::default());
+        }
+        let Some(node) = self.nodes.get_mut(&mac) else {
+            return (power, None);
+        };
+        node.record(voltage, now_ms);
+        power.remaining_hours = estimate_remaining_hours(&node.trend, CUTOFF_MV as f32 / 1000.0);
+
+        let low_v = LOW_BATTERY_MV as f32 / 1000.0;
+        let running_out = power
+            .remaining_hours
+            .is_some_and(|hours| hours < LOW_BATTERY_HOURS as f32);
+        let low = if node.low {
+            voltage < low_v + RECOVERY_MARGIN_V || running_out
+        } else {
+            voltage < low_v || running_out
+        };
+        let alarm = (low != node.low).then_some(BatteryAlarm {
+            low,
+            battery_v: voltage,
+            remaining_hours: power.remaining_hours,
+        });
+        node.low = low;
+        (power, alarm)
+    }
+
+    /// Whether `mac` is in low-battery alarm
+    pub fn is_low(&self, mac: &MacAddress) -> bool {
+        self.nodes.get(mac).is_some_and(|node| node.low)
+    }
+
+    pub fn remove(&mut self, mac: &MacAddress) {
+        self.nodes.remove(mac);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    const NODE: MacAddress = [1, 2, 3, 4, 5, 6];
+    const HOUR_MS: u64 = 3_600_000;
+
+    /// One sample per hour from `start_v`, changing by `slope_v` per hour
+    fn line(count: u64, start_v: f32, slope_v: f32) -> StdVec<BatterySample> {
+        (0..count)
+            .map(|hour| BatterySample {
+                at_ms: hour * HOUR_MS,
+                voltage: start_v + slope_v * hour as f32,
+            })
+            .collect()
+    }
+
+    fn assert_near(value: Option<f32>, expected: f32) {
+        let value = value.unwrap();
+        assert!((value - expected).abs() < 0.05, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn discharge_is_extrapolated_to_the_cutoff() {
+        // 10 mV per hour from 3.6 V, 3.56 V after four hours
+        let samples = line(5, 3.6, -0.01);
+        assert_near(estimate_remaining_hours(&samples, 3.0), 56.0);
+        assert_near(estimate_remaining_hours(&samples, 3.5), 6.0);
+        // Already below the cutoff
+        assert_eq!(estimate_remaining_hours(&samples, 3.58), Some(0.0));
+
+        // Noise around the same line leaves the slope unchanged
+        let mut noisy = line(6, 3.6, -0.01);
+        for (sample, noise) in noisy.iter_mut().zip([1.0, -1.0, 0.0, 0.0, -1.0, 1.0]) {
+            sample.voltage += 0.005 * noise;
+        }
+        assert_near(estimate_remaining_hours(&noisy, 3.0), 55.0);
+    }
+
+    #[test]
+    fn short_histories_give_no_estimate() {
+        assert_eq!(estimate_remaining_hours(&[], 3.0), None);
+        let samples = line(5, 3.6, -0.01);
+        assert_eq!(
+            estimate_remaining_hours(&samples[..MIN_TREND_SAMPLES - 1], 3.0),
+            None
+        );
+        // Enough samples, within too short a time
+        let close: StdVec<_> = samples
+            .iter()
+            .map(|sample| BatterySample {
+                at_ms: sample.at_ms / 4,
+                ..*sample
+            })
+            .collect();
+        assert!(close.last().unwrap().at_ms < MIN_TREND_SPAN_MS);
+        assert_eq!(estimate_remaining_hours(&close, 3.0), None);
+    }
+
+    #[test]
+    fn rising_or_flat_voltage_gives_no_estimate() {
+        assert_eq!(estimate_remaining_hours(&line(5, 3.6, 0.01), 3.0), None);
+        assert_eq!(estimate_remaining_hours(&line(5, 3.6, 0.0), 3.0), None);
+    }
+
+    fn readings(monitor: &mut BatteryMonitor, voltage: f32, now_ms: u64) -> NodePower {
+        monitor
+            .on_readings(NODE, Some(voltage), &NodeStatus::default(), now_ms)
+            .0
+    }
+
+    #[test]
+    fn readings_are_averaged_into_the_trend() {
+        let mut monitor = BatteryMonitor::new();
+        for (minute, voltage) in [(0, 3.7), (10, 3.6), (20, 3.62)] {
+            readings(&mut monitor, voltage, minute * 60_000);
+        }
+        // The bucket started with the second reading
+        readings(&mut monitor, 3.58, TREND_SPACING_MS + 10 * 60_000);
+        let trend = &monitor.nodes.get(&NODE).unwrap().trend;
+        assert_eq!(trend.len(), 2);
+        assert_eq!(trend[0].voltage, 3.7);
+        assert!((trend[1].voltage - 3.6).abs() < 1e-4);
+
+        // Discharging for a few hours gives an estimate
+        let mut power = NodePower::default();
+        for step in 2..8 {
+            let voltage = 3.6 - 0.005 * step as f32;
+            power = readings(&mut monitor, voltage, step * TREND_SPACING_MS + 10 * 60_000);
+        }
+        assert!(power.remaining_hours.is_some());
+    }
+
+    #[test]
+    fn replaced_battery_restarts_the_trend() {
+        let mut monitor = BatteryMonitor::new();
+        let mut power = NodePower::default();
+        for step in 0..8 {
+            let voltage = 3.6 - 0.01 * step as f32;
+            power = readings(&mut monitor, voltage, step * TREND_SPACING_MS);
+        }
+        assert!(power.remaining_hours.is_some());
+
+        let power = readings(&mut monitor, 4.1, 8 * TREND_SPACING_MS);
+        assert_eq!(power.remaining_hours, None);
+        let trend = &monitor.nodes.get(&NODE).unwrap().trend;
+        assert_eq!(
+            trend.as_slice(),
+            [BatterySample {
+                at_ms: 8 * TREND_SPACING_MS,
+                voltage: 4.1,
+            }]
+        );
+    }
+
+    #[test]
+    fn low_battery_alarm_does_not_flap() {
+        let mut monitor = BatteryMonitor::new();
+        let low_v = LOW_BATTERY_MV as f32 / 1000.0;
+        // Readings a minute apart, too close for an estimate
+        let mut alarms = StdVec::new();
+        let mut states = StdVec::new();
+        for (minute, voltage) in [
+            (0, low_v + 0.05),
+            (1, low_v - 0.01),
+            (2, low_v + 0.01),
+            (3, low_v - 0.01),
+            (4, low_v + RECOVERY_MARGIN_V / 2.0),
+            (5, low_v + RECOVERY_MARGIN_V + 0.01),
+            (6, low_v + RECOVERY_MARGIN_V / 2.0),
+        ] {
+            let (_, alarm) =
+                monitor.on_readings(NODE, Some(voltage), &NodeStatus::default(), minute * 60_000);
+            alarms.push(alarm.map(|alarm| alarm.low));
+            states.push(monitor.is_low(&NODE));
+        }
+        assert_eq!(
+            alarms,
+            [None, Some(true), None, None, None, Some(false), None]
+        );
+        assert_eq!(states, [false, true, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn discharge_raises_the_alarm_before_the_threshold() {
+        let mut monitor = BatteryMonitor::new();
+        // 20 mV per hour, well above the threshold but a day from the cutoff
+        let mut raised = None;
+        for step in 0..12 {
+            let voltage = 3.8 - 0.01 * step as f32;
+            let (power, alarm) = monitor.on_readings(
+                NODE,
+                Some(voltage),
+                &NodeStatus::default(),
+                step * TREND_SPACING_MS,
+            );
+            if let Some(alarm) = alarm {
+                raised = Some((alarm, power.remaining_hours));
+                break;
+            }
+        }
+        let (alarm, remaining_hours) = raised.unwrap();
+        assert!(alarm.low);
+        assert!(alarm.battery_v > LOW_BATTERY_MV as f32 / 1000.0);
+        assert!(remaining_hours.unwrap() < LOW_BATTERY_HOURS as f32);
+    }
+
+    #[test]
+    fn missing_or_invalid_voltages_are_ignored() {
+        let mut monitor = BatteryMonitor::new();
+        for battery_v in [None, Some(f32::NAN), Some(0.0)] {
+            let (power, alarm) =
+                monitor.on_readings(NODE, battery_v, &NodeStatus::default(), 1_000);
+            assert_eq!(power.remaining_hours, None);
+            assert_eq!(alarm, None);
+        }
+        assert!(!monitor.is_low(&NODE));
+        assert!(monitor.nodes.is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+//! Front panel buttons of the NORVI AE04
+//!
+//! Hardware configuration:
+//! - 3 push buttons on a resistor ladder read by ADC1 on GPIO32
+//! - Line idles near full scale, any press pulls it down
+//!
+//! The line is polled every `POLL_INTERVAL` and `GestureRecognizer` turns it
+//! into gestures, queued on `BUTTON_GESTURES` for the display task:
+//! - short press: next page
+//! - double press: previous page
+//! - long press: action of the page shown, e.g. open the mesh pairing window
+//!
+//! A short press is only reported once no second press followed within
+//! `DOUBLE_PRESS_GAP`.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+#[cfg(feature = "esp32")]
+use embassy_time::Timer;
+#[cfg(feature = "esp32")]
+use esp_hal::analog::adc::{Adc, AdcPin};
+#[cfg(feature = "esp32")]
+use esp_hal::gpio::GpioPin;
+#[cfg(feature = "esp32")]
+use esp_hal::peripherals::ADC1;
+#[cfg(feature = "esp32")]
+use log::{debug, info, warn};
+
+// Raw 12-bit reading (11 dB attenuation) under which a button is pressed
+pub const BUTTON_PRESSED_BELOW_RAW: u16 = 3000;
+// Time the line must hold a new state before it is taken
+pub const DEBOUNCE: Duration = Duration::from_millis(30);
+pub const LONG_PRESS: Duration = Duration::from_millis(1500);
+// Release to second press, for a double press
+pub const DOUBLE_PRESS_GAP: Duration = Duration::from_millis(300);
+#[cfg(feature = "esp32")]
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// Between the checks of a conversion in progress
+#[cfg(feature = "esp32")]
+const CONVERSION_POLL: Duration = Duration::from_micros(50);
+
+/// Gestures of the button, handled by the display task
+pub static BUTTON_GESTURES: Channel<CriticalSectionRawMutex, Gesture, 4> = Channel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Gesture {
+    Short,
+    Double,
+    Long,
+}
+
+/// Debounced button state to gestures, from timed samples of the line
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GestureRecognizer {
+    // Debounced state
+    pressed: bool,
+    // Raw state and since when, not yet debounced
+    raw: bool,
+    raw_since_ms: u64,
+    pressed_since_ms: u64,
+    long_fired: bool,
+    // Release of a short press, waiting for a second one
+    released_at_ms: Option<u64>,
+}
+
+impl GestureRecognizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the line state sampled at `now_ms`, returns the gesture completed
+    pub fn update(&mut self, pressed: bool, now_ms: u64) -> Option<Gesture> {
+        if pressed != self.raw {
+            self.raw = pressed;
+            self.raw_since_ms = now_ms;
+        }
+        if self.raw != self.pressed
+            && now_ms.saturating_sub(self.raw_since_ms) >= DEBOUNCE.as_millis()
+        {
+            self.pressed = self.raw;
+            if let Some(gesture) = self.on_edge(now_ms) {
+                return Some(gesture);
+            }
+        }
+
+        if self.pressed {
+            if !self.long_fired
+                && now_ms.saturating_sub(self.pressed_since_ms) >= LONG_PRESS.as_millis()
+            {
+                self.long_fired = true;
+                // Second press of a double held too long, only the long press
+                self.released_at_ms = None;
+                return Some(Gesture::Long);
+            }
+        } else if let Some(released_at_ms) = self.released_at_ms {
+            if now_ms.saturating_sub(released_at_ms) >= DOUBLE_PRESS_GAP.as_millis() {
+                self.released_at_ms = None;
+                return Some(Gesture::Short);
+            }
+        }
+        None
+    }
+
+    fn on_edge(&mut self, now_ms: u64) -> Option<Gesture> {
+        if self.pressed {
+            self.pressed_since_ms = now_ms;
+            self.long_fired = false;
+            return None;
+        }
+        if self.long_fired {
+            return None;
+        }
+        match self.released_at_ms.take() {
+            Some(_) => Some(Gesture::Double),
+            None => {
+                self.released_at_ms = Some(now_ms);
+                None
+            }
+        }
+    }
+}
+
+/// Raw reading of the line, the other tasks run until the conversion is done
+#[cfg(feature = "esp32")]
+async fn read_line(
+    adc: &mut Adc<'static, ADC1>,
+    pin: &mut AdcPin<GpioPin<32>, ADC1>,
+) -> Result<u16, ()> {
+    loop {
+        match adc.read_oneshot(pin) {
+            Ok(raw) => return Ok(raw),
+            Err(nb::Error::WouldBlock) => Timer::after(CONVERSION_POLL).await,
+            Err(nb::Error::Other(e)) => return Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "esp32")]
+#[embassy_executor::task]
+pub async fn button_task(mut adc: Adc<'static, ADC1>, mut pin: AdcPin<GpioPin<32>, ADC1>) {
+    info!("Starting front panel button task");
+    let mut recognizer = GestureRecognizer::new();
+    loop {
+        match read_line(&mut adc, &mut pin).await {
+            Ok(raw) => {
+                let now_ms = embassy_time::Instant::now().as_millis();
+                if let Some(gesture) = recognizer.update(raw < BUTTON_PRESSED_BELOW_RAW, now_ms) {
+                    debug!("Button gesture {:?}", gesture);
+                    if BUTTON_GESTURES.try_send(gesture).is_err() {
+                        warn!("Button gesture queue full, dropping {:?}", gesture);
+                    }
+                }
+            }
+            Err(e) => warn!("Button ADC read failed: {:?}", e),
+        }
+        Timer::after(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    // Poll interval of the button task
+    const STEP_MS: u64 = 10;
+
+    /// Gestures recognized from the line sampled every `STEP_MS` until
+    /// `until_ms`, pressed during the `presses` intervals
+    fn gestures(presses: &[(u64, u64)], until_ms: u64) -> StdVec<(u64, Gesture)> {
+        let mut recognizer = GestureRecognizer::new();
+        (0..=until_ms)
+            .step_by(STEP_MS as usize)
+            .filter_map(|now_ms| {
+                let pressed = presses
+                    .iter()
+                    .any(|(from_ms, to_ms)| (*from_ms..*to_ms).contains(&now_ms));
+                recognizer
+                    .update(pressed, now_ms)
+                    .map(|gesture| (now_ms, gesture))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_press_is_reported_once_no_second_press_follows() {
+        let release_ms = 250 + DEBOUNCE.as_millis();
+        assert_eq!(
+            gestures(&[(100, 250)], 2_000),
+            [(release_ms + DOUBLE_PRESS_GAP.as_millis(), Gesture::Short)]
+        );
+        // Two presses further apart than the gap
+        let presses = gestures(&[(100, 200), (600, 700)], 2_000);
+        let kinds: StdVec<Gesture> = presses.iter().map(|(_, gesture)| *gesture).collect();
+        assert_eq!(kinds, [Gesture::Short, Gesture::Short]);
+    }
+
+    #[test]
+    fn double_press_replaces_the_short_ones() {
+        assert_eq!(
+            gestures(&[(100, 200), (350, 450)], 2_000),
+            [(450 + DEBOUNCE.as_millis(), Gesture::Double)]
+        );
+    }
+
+    #[test]
+    fn long_press_fires_while_held_and_not_on_release() {
+        let long_ms = 100 + DEBOUNCE.as_millis() + LONG_PRESS.as_millis();
+        assert_eq!(gestures(&[(100, 4_000)], 5_000), [(long_ms, Gesture::Long)]);
+        // Second press of a double held too long
+        assert_eq!(
+            gestures(&[(100, 200), (350, 4_000)], 5_000),
+            [(
+                350 + DEBOUNCE.as_millis() + LONG_PRESS.as_millis(),
+                Gesture::Long
+            )]
+        );
+    }
+
+    #[test]
+    fn contact_bounce_and_glitches_are_ignored() {
+        // Chatter on press and release, shorter than the debounce time each
+        let bouncy = [(100, 110), (120, 130), (140, 300), (310, 320)];
+        let found = gestures(&bouncy, 2_000);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1, Gesture::Short);
+
+        assert!(gestures(&[(100, 100 + DEBOUNCE.as_millis() - STEP_MS)], 2_000).is_empty());
+    }
+}
@@ -0,0 +1,394 @@
+//! MQTT command channel for the gateway
+//!
+//! Commands are published by the operator on `/commands/gateway/{mac}/{path}`
+//! with a plain text payload, e.g:
+//! - `pid/setpoint` -> `65.0`
+//! - `pid/tunings` -> `2.0,0.1,0.0` (kp,ki,kd)
+//! - `pid/mode` -> `auto` or `manual`
+//! - `pid/output` -> `40.0` (manual output in %)
+//! - `mesh/pairing` -> `start` or `stop`
+//! - `mesh/remove` -> `AA:BB:CC:DD:EE:FF` (node MAC)
+//! - `mesh/rotate_key` -> `AA:BB:CC:DD:EE:FF` (node MAC)
+//! - `ota/begin` -> `size,sha256,version` (hex SHA-256 of the node image)
+//! - `ota/chunk` -> `offset,data` (hex, 192 bytes at most, in order)
+//! - `ota/fetch` -> `size,sha256,version,url` (image downloaded by the gateway)
+//! - `ota/push` -> `AA:BB:CC:DD:EE:FF` (node MAC, offered the staged image)
+//! - `ota/cancel` -> `AA:BB:CC:DD:EE:FF` (node MAC)
+//! - `node/interval` -> `AA:BB:CC:DD:EE:FF,60,300` (node MAC, sample,transmit in s)
+//! - `node/alarm` -> `AA:BB:CC:DD:EE:FF,5.0,35.0` (node MAC, low,high in C)
+//! - `node/channel` -> `AA:BB:CC:DD:EE:FF,6` (node MAC, mesh channel)
+//!
+//! Empty `node/*` values are left unchanged, e.g. `AA:BB:CC:DD:EE:FF,,600`.
+//!
+//! Parsing is kept separate from the dispatch so it can run off-target.
+
+use log::{info, warn};
+
+use crate::common::mesh::parse_mac;
+use crate::common::mesh_proto::{FirmwareVersion, NodeConfig, OtaDigest};
+use crate::gateway_lib::downlink::{DownlinkCommand, DOWNLINK_COMMANDS};
+use crate::gateway_lib::ota::{
+    ChunkData, OtaCommand, OtaFetch, StagedImage, OTA_COMMANDS, OTA_DOWNLOADS,
+};
+use crate::gateway_lib::pid::{PidCommand, PidMode, PID_COMMANDS};
+use crate::gateway_lib::registry::{RegistryCommand, REGISTRY_COMMANDS};
+
+pub const COMMAND_TOPIC_PREFIX: &str = "/commands/gateway/";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Pid(PidCommand),
+    Registry(RegistryCommand),
+    Ota(OtaCommand),
+    OtaFetch(OtaFetch),
+    Downlink(DownlinkCommand),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandError {
+    UnknownTopic,
+    InvalidPayload,
+}
+
+/// Strip the `/commands/gateway/{mac}/` prefix, returns the command path
+pub fn command_path<'a>(topic: &'a str, mac: &str) -> Option<&'a str> {
+    topic
+        .strip_prefix(COMMAND_TOPIC_PREFIX)?
+        .strip_prefix(mac)?
+        .strip_prefix('/')
+}
+
+pub fn parse_command(path: &str, payload: &[u8]) -> Result<Command, CommandError> {
+    let payload = core::str::from_utf8(payload)
+        .map_err(|_| CommandError::InvalidPayload)?
+        .trim();
+
+    match path {
+        "pid/setpoint" => Ok(Command::Pid(PidCommand::Setpoint(parse_f32(payload)?))),
+        "pid/tunings" => {
+            let mut values = payload.split(',');
+            let mut next = || parse_f32(values.next().ok_or(CommandError::InvalidPayload)?);
+            let (kp, ki, kd) = (next()?, next()?, next()?);
+            if values.next().is_some() {
+                return Err(CommandError::InvalidPayload);
+            }
+            Ok(Command::Pid(PidCommand::Tunings { kp, ki, kd }))
+        }
+        "pid/mode" => match payload {
+            "auto" => Ok(Command::Pid(PidCommand::Mode(PidMode::Auto))),
+            "manual" => Ok(Command::Pid(PidCommand::Mode(PidMode::Manual))),
+            _ => Err(CommandError::InvalidPayload),
+        },
+        "pid/output" => Ok(Command::Pid(PidCommand::ManualOutput(parse_f32(payload)?))),
+        "mesh/pairing" => match payload {
+            "start" => Ok(Command::Registry(RegistryCommand::StartPairing)),
+            "stop" => Ok(Command::Registry(RegistryCommand::StopPairing)),
+            _ => Err(CommandError::InvalidPayload),
+        },
+        "mesh/remove" => parse_mac(payload)
+            .map(|mac| Command::Registry(RegistryCommand::Remove(mac)))
+            .ok_or(CommandError::InvalidPayload),
+        "mesh/rotate_key" => parse_mac(payload)
+            .map(|mac| Command::Registry(RegistryCommand::RotateKey(mac)))
+            .ok_or(CommandError::InvalidPayload),
+        "ota/begin" => Ok(Command::Ota(OtaCommand::Begin(parse_image(
+            &mut payload.split(','),
+        )?))),
+        "ota/chunk" => {
+            let (offset, data) = payload
+                .split_once(',')
+                .ok_or(CommandError::InvalidPayload)?;
+            let offset = offset
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| CommandError::InvalidPayload)?;
+            let mut chunk = ChunkData::new();
+            chunk
+                .resize_default(data.trim().len() / 2)
+                .map_err(|_| CommandError::InvalidPayload)?;
+            parse_hex(data, &mut chunk)?;
+            Ok(Command::Ota(OtaCommand::Chunk {
+                offset,
+                data: chunk,
+            }))
+        }
+        "ota/fetch" => {
+            let mut values = payload.splitn(4, ',');
+            let image = parse_image(&mut values)?;
+            let url = values
+                .next()
+                .and_then(|url| url.trim().try_into().ok())
+                .ok_or(CommandError::InvalidPayload)?;
+            Ok(Command::OtaFetch(OtaFetch { image, url }))
+        }
+        "ota/push" => parse_mac(payload)
+            .map(|mac| Command::Ota(OtaCommand::Push(mac)))
+            .ok_or(CommandError::InvalidPayload),
+        "ota/cancel" => parse_mac(payload)
+            .map(|mac| Command::Ota(OtaCommand::Cancel(mac)))
+            .ok_or(CommandError::InvalidPayload),
+        "node/interval" => parse_downlink(payload, |values, change| {
+            change.sample_interval_s = parse_optional(values.next())?;
+            change.transmit_interval_s = parse_optional(values.next())?;
+            Ok(())
+        }),
+        "node/alarm" => parse_downlink(payload, |values, change| {
+            change.alarm_low = parse_optional(values.next())?;
+            change.alarm_high = parse_optional(values.next())?;
+            Ok(())
+        }),
+        "node/channel" => parse_downlink(payload, |values, change| {
+            change.channel = parse_optional(values.next())?;
+            match change.channel {
+                Some(1..=13) | None => Ok(()),
+                Some(_) => Err(CommandError::InvalidPayload),
+            }
+        }),
+        _ => Err(CommandError::UnknownTopic),
+    }
+}
+
+/// `mac,values...` of a node configuration change, at least one value set
+fn parse_downlink(
+    payload: &str,
+    parse_values: impl FnOnce(
+        &mut core::str::Split<'_, char>,
+        &mut NodeConfig,
+    ) -> Result<(), CommandError>,
+) -> Result<Command, CommandError> {
+    let mut values = payload.split(',');
+    let node = values
+        .next()
+        .and_then(|mac| parse_mac(mac.trim()))
+        .ok_or(CommandError::InvalidPayload)?;
+    let mut change = NodeConfig::default();
+    parse_values(&mut values, &mut change)?;
+    if values.next().is_some() || change.is_empty() {
+        return Err(CommandError::InvalidPayload);
+    }
+    Ok(Command::Downlink(DownlinkCommand { node, change }))
+}
+
+/// Missing or empty values are none
+fn parse_optional<T: core::str::FromStr>(value: Option<&str>) -> Result<Option<T>, CommandError> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| CommandError::InvalidPayload),
+    }
+}
+
+/// `size,sha256,version` of a node image, the version may be empty
+fn parse_image<'a>(
+    values: &mut impl Iterator<Item = &'a str>,
+) -> Result<StagedImage, CommandError> {
+    let mut next = || values.next().ok_or(CommandError::InvalidPayload);
+    let size = next()?
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| CommandError::InvalidPayload)?;
+    let mut digest: OtaDigest = [0; 32];
+    parse_hex(next()?, &mut digest)?;
+    let version = match next()?.trim() {
+        "" => None,
+        version => Some(FirmwareVersion::parse(version).ok_or(CommandError::InvalidPayload)?),
+    };
+    Ok(StagedImage {
+        size,
+        digest,
+        version,
+    })
+}
+
+/// Decode exactly `out.len()` bytes written as hex
+fn parse_hex(hex: &str, out: &mut [u8]) -> Result<(), CommandError> {
+    let hex = hex.trim();
+    if hex.len() != out.len() * 2 || !hex.is_ascii() {
+        return Err(CommandError::InvalidPayload);
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let pair = core::str::from_utf8(pair).map_err(|_| CommandError::InvalidPayload)?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| CommandError::InvalidPayload)?;
+    }
+    Ok(())
+}
+
+fn parse_f32(s: &str) -> Result<f32, CommandError> {
+    match s.trim().parse::<f32>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(CommandError::InvalidPayload),
+    }
+}
+
+/// Route a parsed command to the task owning it
+pub fn dispatch_command(command: Command) {
+    match command {
+        Command::Pid(pid_command) => {
+            if PID_COMMANDS.try_send(pid_command).is_err() {
+                warn!("PID command queue full, dropping {:?}", pid_command);
+            }
+        }
+        Command::Registry(registry_command) => {
+            if REGISTRY_COMMANDS.try_send(registry_command).is_err() {
+                warn!(
+                    "Registry command queue full, dropping {:?}",
+                    registry_command
+                );
+            }
+        }
+        Command::Ota(ota_command) => {
+            if let Err(e) = OTA_COMMANDS.try_send(ota_command) {
+                warn!("OTA command queue full, dropping {:?}", e);
+            }
+        }
+        Command::Downlink(downlink_command) => {
+            if DOWNLINK_COMMANDS.try_send(downlink_command).is_err() {
+                warn!(
+                    "Downlink command queue full, dropping {:?}",
+                    downlink_command
+                );
+            }
+        }
+        Command::OtaFetch(fetch) => {
+            if OTA_DOWNLOADS.try_send(fetch).is_err() {
+                warn!("OTA download already queued, dropping request");
+            }
+        }
+    }
+}
+
+/// Parse and dispatch a message received on the command topic
+pub fn handle_command_message(topic: &str, payload: &[u8], mac: &str) {
+    let Some(path) = command_path(topic, mac) else {
+        warn!("Ignoring message on unexpected topic={}", topic);
+        return;
+    };
+    match parse_command(path, payload) {
+        Ok(command) => {
+            info!("Received command {:?} on topic={}", command, topic);
+            dispatch_command(command);
+        }
+        Err(e) => warn!("Invalid command on topic={}: {:?}", topic, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(path: &str, payload: &str) -> Result<PidCommand, CommandError> {
+        match parse_command(path, payload.as_bytes())? {
+            Command::Pid(command) => Ok(command),
+            other => panic!("not a PID command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn command_path_follows_the_gateway_prefix() {
+        let mac = "AA:BB:CC:DD:EE:FF";
+        assert_eq!(
+            command_path("/commands/gateway/AA:BB:CC:DD:EE:FF/pid/mode", mac),
+            Some("pid/mode")
+        );
+        assert_eq!(
+            command_path("/commands/gateway/11:22:33:44:55:66/pid/mode", mac),
+            None
+        );
+        assert_eq!(
+            command_path("/commands/gateway/AA:BB:CC:DD:EE:FFpid/mode", mac),
+            None
+        );
+        assert_eq!(
+            command_path("/status/gateway/AA:BB:CC:DD:EE:FF/pid", mac),
+            None
+        );
+    }
+
+    #[test]
+    fn pid_commands_are_parsed() {
+        assert_eq!(pid("pid/setpoint", "65.0"), Ok(PidCommand::Setpoint(65.0)));
+        assert_eq!(pid("pid/setpoint", " -5\n"), Ok(PidCommand::Setpoint(-5.0)));
+        assert_eq!(
+            pid("pid/tunings", "2.0, 0.1 ,0"),
+            Ok(PidCommand::Tunings {
+                kp: 2.0,
+                ki: 0.1,
+                kd: 0.0
+            })
+        );
+        assert_eq!(pid("pid/mode", "auto"), Ok(PidCommand::Mode(PidMode::Auto)));
+        assert_eq!(
+            pid("pid/mode", "manual"),
+            Ok(PidCommand::Mode(PidMode::Manual))
+        );
+        assert_eq!(pid("pid/output", "40"), Ok(PidCommand::ManualOutput(40.0)));
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        for (path, payload) in [
+            ("pid/setpoint", ""),
+            ("pid/setpoint", "hot"),
+            ("pid/setpoint", "65.0,70.0"),
+            ("pid/tunings", "2.0,0.1"),
+            ("pid/tunings", "2.0,0.1,0.0,1.0"),
+            ("pid/tunings", "2.0;0.1;0.0"),
+            ("pid/mode", "Auto"),
+            ("pid/mode", ""),
+            ("pid/output", "40%"),
+        ] {
+            assert_eq!(
+                parse_command(path, payload.as_bytes()),
+                Err(CommandError::InvalidPayload),
+                "{} {}",
+                path,
+                payload
+            );
+        }
+        assert_eq!(
+            parse_command("pid/setpoint", &[0xFF, 0xFE]),
+            Err(CommandError::InvalidPayload)
+        );
+    }
+
+    #[test]
+    fn values_out_of_range_are_rejected() {
+        for payload in ["NaN", "inf", "-inf", "1e39"] {
+            assert_eq!(
+                pid("pid/setpoint", payload),
+                Err(CommandError::InvalidPayload),
+                "{}",
+                payload
+            );
+            assert_eq!(
+                pid("pid/output", payload),
+                Err(CommandError::InvalidPayload)
+            );
+        }
+        assert_eq!(
+            pid("pid/tunings", "1.0,NaN,0.0"),
+            Err(CommandError::InvalidPayload)
+        );
+    }
+
+    #[test]
+    fn unknown_topics_are_rejected() {
+        for path in [
+            "",
+            "pid",
+            "pid/",
+            "pid/setpoint/extra",
+            "PID/setpoint",
+            "unknown",
+        ] {
+            assert_eq!(
+                parse_command(path, b"1.0"),
+                Err(CommandError::UnknownTopic),
+                "{}",
+                path
+            );
+        }
+    }
+}
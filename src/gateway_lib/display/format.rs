@@ -0,0 +1,99 @@
+//! Text of the display values
+//!
+//! A value and its unit, placed next to its label by the `layout` of the page.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+// *** Temperature for display *** //
+
+pub trait FloatLevelUnit {
+    fn msg(&self) -> &'static str;
+    fn level(&self) -> f32;
+    fn unit(&self) -> &'static str;
+
+    /// Level with one decimal and its unit cut to 4 chars, `--` for a level
+    /// which is not a number
+    fn value(&self) -> String<24> {
+        let mut s = String::<24>::new();
+
+        // Manual formatting for floating point
+        let value = self.level();
+        let integer = value as i32;
+        let decimal = ((value - integer as f32).abs() * 10.0) as u32; // One decimal place
+
+        // Format unit (4 chars max)
+        let unit = if self.unit().len() <= 4 {
+            self.unit()
+        } else {
+            &self.unit()[..4]
+        };
+
+        // Avoid using floating point format specifiers
+        if !value.is_finite() {
+            // NaN and infinities would be cast to 0 or i32 bounds
+            let _ = write!(&mut s, "-- {}", unit);
+        } else if value < 0.0 {
+            // Handle negative values
+            let _ = write!(&mut s, "-{}.{} {}", integer.abs(), decimal, unit);
+        } else {
+            let _ = write!(&mut s, "{}.{} {}", integer, decimal, unit);
+        }
+
+        s
+    }
+}
+
+pub struct TemperatureLevelUnit {
+    pub msg: &'static str,
+    pub level: f32,
+    pub unit: &'static str,
+}
+
+impl TemperatureLevelUnit {
+    pub fn new(msg: &'static str, level: f32, unit: &'static str) -> TemperatureLevelUnit {
+        TemperatureLevelUnit { msg, level, unit }
+    }
+    pub fn set_level(&mut self, level: f32) {
+        self.level = level;
+    }
+}
+
+impl FloatLevelUnit for TemperatureLevelUnit {
+    fn msg(&self) -> &'static str {
+        self.msg
+    }
+    fn level(&self) -> f32 {
+        self.level
+    }
+    fn unit(&self) -> &'static str {
+        self.unit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(level: f32, unit: &'static str) -> String<24> {
+        TemperatureLevelUnit::new("Temp", level, unit).value()
+    }
+
+    #[test]
+    fn value_has_one_decimal_and_a_short_unit() {
+        assert_eq!(value(21.56, "C"), "21.5 C");
+        assert_eq!(value(0.0, "C"), "0.0 C");
+        assert_eq!(value(-0.5, "F"), "-0.5 F");
+        assert_eq!(value(-12.25, "C"), "-12.2 C");
+        assert_eq!(value(294.7, "K"), "294.7 K");
+        assert_eq!(value(14.5, "L/min"), "14.5 L/mi");
+    }
+
+    #[test]
+    fn value_not_a_number_is_dashed() {
+        assert_eq!(value(f32::NAN, "C"), "-- C");
+        assert_eq!(value(f32::INFINITY, "psi"), "-- psi");
+        assert_eq!(value(f32::NEG_INFINITY, "C"), "-- C");
+    }
+}
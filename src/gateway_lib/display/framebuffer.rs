@@ -0,0 +1,259 @@
+//! In-memory frame of the 128x64 OLED
+//!
+//! Laid out as the SSD1306 RAM in horizontal addressing mode: one byte per
+//! column of each 8 rows page, the top row in the LSB. Pages render into it
+//! off-target and the display task sends its bytes to the panel as they are,
+//! only the pages that changed since the last frame sent. The frame is 128x32
+//! with the `oled-128x32` feature.
+//!
+//! A frame with a margin is drawn short of its right and bottom edges, so
+//! `shift` can move it by up to the margin without losing a pixel.
+
+use core::convert::Infallible;
+use core::ops::Range;
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+pub const FRAME_WIDTH: u32 = 128;
+#[cfg(not(feature = "oled-128x32"))]
+pub const FRAME_HEIGHT: u32 = 64;
+#[cfg(feature = "oled-128x32")]
+pub const FRAME_HEIGHT: u32 = 32;
+pub const FRAME_LEN: usize = (FRAME_WIDTH * FRAME_HEIGHT / 8) as usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameBuffer {
+    bytes: [u8; FRAME_LEN],
+    // Columns and rows left blank on the right and at the bottom
+    margin: Size,
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameBuffer {
+    pub const fn new() -> Self {
+        Self::with_margin(Size::zero())
+    }
+
+    /// Frame drawn `margin` pixels short of the right and bottom edges
+    pub const fn with_margin(margin: Size) -> Self {
+        FrameBuffer {
+            bytes: [0; FRAME_LEN],
+            margin,
+        }
+    }
+
+    /// Bytes in the order of the display RAM
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Rows of the pages from the first to the last one differing in `other`,
+    /// none when the frames are the same
+    pub fn changed_rows(&self, other: &FrameBuffer) -> Option<Range<u32>> {
+        let page_len = FRAME_WIDTH as usize;
+        let mut changed = self
+            .bytes
+            .chunks(page_len)
+            .zip(other.bytes.chunks(page_len))
+            .enumerate()
+            .filter(|(_, (page, other))| page != other)
+            .map(|(page, _)| page as u32);
+        let first = changed.next()?;
+        let last = changed.next_back().unwrap_or(first);
+        Some(first * 8..(last + 1) * 8)
+    }
+
+    /// Bytes of the pages of `rows`, multiples of 8 as from `changed_rows`
+    pub fn rows_bytes(&self, rows: Range<u32>) -> &[u8] {
+        let page_len = FRAME_WIDTH as usize;
+        &self.bytes[rows.start as usize / 8 * page_len..rows.end as usize / 8 * page_len]
+    }
+
+    /// Whether the pixel at `point` is on, off outside of the frame
+    pub fn pixel(&self, point: Point) -> bool {
+        Self::locate(point).is_some_and(|(index, mask)| self.bytes[index] & mask != 0)
+    }
+
+    /// Move every pixel by `offset`, those leaving the frame are lost: none
+    /// when moved right and down by up to the margin
+    pub fn shift(&mut self, offset: Point) {
+        if offset == Point::zero() {
+            return;
+        }
+        let source = self.clone();
+        self.bytes = [0; FRAME_LEN];
+        for y in 0..FRAME_HEIGHT as i32 {
+            for x in 0..FRAME_WIDTH as i32 {
+                let point = Point::new(x, y);
+                if source.pixel(point) {
+                    if let Some((index, mask)) = Self::locate(point + offset) {
+                        self.bytes[index] |= mask;
+                    }
+                }
+            }
+        }
+    }
+
+    fn locate(point: Point) -> Option<(usize, u8)> {
+        let (x, y) = (u32::try_from(point.x).ok()?, u32::try_from(point.y).ok()?);
+        if x >= FRAME_WIDTH || y >= FRAME_HEIGHT {
+            return None;
+        }
+        Some(((y / 8 * FRAME_WIDTH + x) as usize, 1 << (y % 8)))
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    /// Size drawn, inside the margin
+    fn size(&self) -> Size {
+        Size::new(FRAME_WIDTH, FRAME_HEIGHT).saturating_sub(self.margin)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    /// Pixels outside of the frame or in its margin are clipped
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let drawn = self.bounding_box();
+        for Pixel(point, color) in pixels {
+            if !drawn.contains(point) {
+                continue;
+            }
+            if let Some((index, mask)) = Self::locate(point) {
+                match color {
+                    BinaryColor::On => self.bytes[index] |= mask,
+                    BinaryColor::Off => self.bytes[index] &= !mask,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The margin is left blank
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        if self.margin != Size::zero() {
+            self.bytes = [0; FRAME_LEN];
+            return self.fill_solid(&self.bounding_box(), color);
+        }
+        self.bytes = [if color.is_on() { 0xFF } else { 0x00 }; FRAME_LEN];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway_lib::display::snapshot::assert_snapshot;
+
+    #[test]
+    fn pixels_follow_the_display_ram() {
+        let mut frame = FrameBuffer::new();
+        for point in [Point::new(0, 0), Point::new(0, 8), Point::new(127, 31)] {
+            Pixel(point, BinaryColor::On).draw(&mut frame).unwrap();
+        }
+        let bytes = frame.as_bytes();
+        assert_eq!(bytes.len(), FRAME_LEN);
+        assert_eq!(
+            (bytes[0], bytes[128], bytes[3 * 128 + 127]),
+            (0x01, 0x01, 0x80)
+        );
+        assert_eq!(bytes.iter().map(|b| b.count_ones()).sum::<u32>(), 3);
+    }
+
+    #[test]
+    fn pixels_outside_are_clipped() {
+        let mut frame = FrameBuffer::new();
+        for point in [
+            Point::new(-1, 0),
+            Point::new(0, -1),
+            Point::new(FRAME_WIDTH as i32, 0),
+            Point::new(0, FRAME_HEIGHT as i32),
+        ] {
+            Pixel(point, BinaryColor::On).draw(&mut frame).unwrap();
+            assert!(!frame.pixel(point));
+        }
+        assert_eq!(frame, FrameBuffer::new());
+    }
+
+    #[test]
+    fn clear_sets_every_pixel() {
+        let mut frame = FrameBuffer::new();
+        frame.clear(BinaryColor::On).unwrap();
+        assert!(frame.as_bytes().iter().all(|&byte| byte == 0xFF));
+        Pixel(Point::new(3, 3), BinaryColor::Off)
+            .draw(&mut frame)
+            .unwrap();
+        assert!(!frame.pixel(Point::new(3, 3)));
+        frame.clear(BinaryColor::Off).unwrap();
+        assert_eq!(frame, FrameBuffer::new());
+    }
+
+    #[test]
+    fn margin_is_left_blank_for_the_shift() {
+        let margin = Size::new(1, 1);
+        let mut frame = FrameBuffer::with_margin(margin);
+        assert_eq!(frame.size(), Size::new(FRAME_WIDTH - 1, FRAME_HEIGHT - 1));
+        frame.clear(BinaryColor::On).unwrap();
+        Pixel(Point::new(FRAME_WIDTH as i32 - 1, 0), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        let lit = (FRAME_WIDTH - 1) * (FRAME_HEIGHT - 1);
+        let count = |frame: &FrameBuffer| {
+            frame
+                .as_bytes()
+                .iter()
+                .map(|byte| byte.count_ones())
+                .sum::<u32>()
+        };
+        assert_eq!(count(&frame), lit);
+        frame.shift(Point::new(1, 1));
+        assert_eq!(count(&frame), lit);
+        assert!(!frame.pixel(Point::zero()));
+        assert!(frame.pixel(Point::new(FRAME_WIDTH as i32 - 1, FRAME_HEIGHT as i32 - 1)));
+    }
+
+    #[test]
+    fn changed_rows_cover_the_differing_pages() {
+        let sent = FrameBuffer::new();
+        let mut frame = sent.clone();
+        assert_eq!(frame.changed_rows(&sent), None);
+        Pixel(Point::new(5, 3), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        assert_eq!(frame.changed_rows(&sent), Some(0..8));
+        assert_eq!(frame.rows_bytes(0..8).len(), FRAME_WIDTH as usize);
+        assert_eq!(frame.rows_bytes(0..8)[5], 0x08);
+        Pixel(Point::new(0, 16), BinaryColor::On)
+            .draw(&mut frame)
+            .unwrap();
+        assert_eq!(frame.changed_rows(&sent), Some(0..24));
+        assert_eq!(frame.rows_bytes(0..24).len(), 3 * FRAME_WIDTH as usize);
+        assert_eq!(sent.changed_rows(&frame), Some(0..24));
+    }
+
+    #[test]
+    fn draws_primitives() {
+        use embedded_graphics::primitives::{Circle, PrimitiveStyle, Rectangle};
+
+        let mut frame = FrameBuffer::new();
+        Rectangle::new(Point::zero(), frame.size())
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(&mut frame)
+            .unwrap();
+        Circle::new(Point::new(48, 0), 32)
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(&mut frame)
+            .unwrap();
+        assert_snapshot("framebuffer_primitives", &frame);
+    }
+}
@@ -0,0 +1,222 @@
+//! Text grid of the OLED, fitted to the font and the panel
+//!
+//! `Layout` splits an area into rows of the font height and columns of its
+//! character advance, so the pages place their text and widgets by row and
+//! column whatever the font and panel size. Text is fitted into fields of a
+//! number of columns, left or right aligned; text too long for its field is
+//! cut and ends with an ellipsis, drawn by the `Screen` as the ASCII fonts
+//! have no glyph for it.
+
+use embedded_graphics::{mono_font::MonoFont, prelude::*, primitives::Rectangle};
+
+// Columns a label keeps next to a long value
+pub const MIN_LABEL_COLUMNS: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layout {
+    pub area: Rectangle,
+    // Advance of a character and height of a line, in pixels
+    pub cell: Size,
+}
+
+impl Layout {
+    pub fn new(font: &MonoFont, area: Rectangle) -> Self {
+        Layout {
+            area,
+            cell: Size::new(
+                font.character_size.width + font.character_spacing,
+                font.character_size.height,
+            ),
+        }
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.area.size.width / self.cell.width.max(1)
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.area.size.height / self.cell.height.max(1)
+    }
+
+    /// Offset of `column` from the left of a row
+    pub fn column(&self, column: u32) -> i32 {
+        (column * self.cell.width) as i32
+    }
+
+    /// Whole columns in `width` pixels
+    pub fn columns_in(&self, width: u32) -> u32 {
+        width / self.cell.width.max(1)
+    }
+
+    /// Bounds of `row`, none past the last one
+    pub fn row(&self, row: u32) -> Option<Rectangle> {
+        (row < self.rows()).then(|| {
+            Rectangle::new(
+                self.area.top_left + Point::new(0, (row * self.cell.height) as i32),
+                Size::new(self.area.size.width, self.cell.height),
+            )
+        })
+    }
+
+    /// Area left under the first `rows`
+    pub fn below(&self, rows: u32) -> Rectangle {
+        let top = (rows * self.cell.height).min(self.area.size.height);
+        Rectangle::new(
+            self.area.top_left + Point::new(0, top as i32),
+            Size::new(self.area.size.width, self.area.size.height - top),
+        )
+    }
+}
+
+/// Text fitted into a field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fitted<'a> {
+    pub text: &'a str,
+    // Columns between the start of the field and the text
+    pub offset: u32,
+    // Cut short, the column after `text` holds the ellipsis
+    pub truncated: bool,
+}
+
+/// Fit `text` into a field of `width` columns
+pub fn fit(text: &str, width: u32, align: Align) -> Fitted<'_> {
+    let len = text.chars().count() as u32;
+    if len <= width {
+        let offset = match align {
+            Align::Left => 0,
+            Align::Right => width - len,
+        };
+        return Fitted {
+            text,
+            offset,
+            truncated: false,
+        };
+    }
+    // Room for the ellipsis, none in an empty field
+    let kept = width.saturating_sub(1) as usize;
+    let end = text
+        .char_indices()
+        .nth(kept)
+        .map_or(text.len(), |(end, _)| end);
+    Fitted {
+        text: text[..end].trim_end(),
+        offset: 0,
+        truncated: width > 0,
+    }
+}
+
+/// Columns of a label and of a value right-aligned after it in a row of
+/// `columns`, a column apart. The value takes what it needs but leaves the
+/// label `MIN_LABEL_COLUMNS`, the label is cut first.
+pub fn label_value_widths(label: &str, value: &str, columns: u32) -> (u32, u32) {
+    let label_len = label.chars().count() as u32;
+    let value_len = value.chars().count() as u32;
+    let value_width = value_len.min(columns.saturating_sub(label_len.min(MIN_LABEL_COLUMNS) + 1));
+    (columns.saturating_sub(value_width + 1), value_width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway_lib::display::framebuffer::{FRAME_HEIGHT, FRAME_WIDTH};
+    use embedded_graphics::mono_font::ascii;
+
+    #[test]
+    fn grid_fits_each_font_and_panel() {
+        let frame = Rectangle::new(Point::zero(), Size::new(FRAME_WIDTH, FRAME_HEIGHT));
+        // Columns, then rows on the 128x64 and the 128x32 panels
+        for (font, columns, rows_64, rows_32) in [
+            (&ascii::FONT_5X8, 25, 8, 4),
+            (&ascii::FONT_6X10, 21, 6, 3),
+            (&ascii::FONT_7X13, 18, 4, 2),
+            (&ascii::FONT_10X20, 12, 3, 1),
+        ] {
+            let layout = Layout::new(font, frame);
+            let rows = if FRAME_HEIGHT == 64 { rows_64 } else { rows_32 };
+            let height = font.character_size.height;
+            assert_eq!((layout.columns(), layout.rows()), (columns, rows));
+            assert!(layout.column(columns) <= FRAME_WIDTH as i32);
+
+            let last = layout.row(rows - 1).unwrap();
+            assert_eq!(last.top_left, Point::new(0, ((rows - 1) * height) as i32));
+            assert!(frame.contains(last.bottom_right().unwrap()));
+            assert_eq!(layout.row(rows), None);
+
+            let below = layout.below(1);
+            assert_eq!(below.top_left, Point::new(0, height as i32));
+            assert_eq!(Layout::new(font, below).rows(), rows - 1);
+            assert_eq!(layout.below(rows + 1).size.height, 0);
+        }
+    }
+
+    #[test]
+    fn short_text_is_aligned_in_its_field() {
+        let fitted = |text, align| fit(text, 8, align);
+        assert_eq!(
+            fitted("12.5C", Align::Left),
+            Fitted {
+                text: "12.5C",
+                offset: 0,
+                truncated: false
+            }
+        );
+        assert_eq!(fitted("12.5C", Align::Right).offset, 3);
+        assert_eq!(fitted("12345678", Align::Right).offset, 0);
+        // Columns are characters, not bytes
+        assert_eq!(fitted("25.0°C", Align::Right).offset, 2);
+    }
+
+    #[test]
+    fn long_text_is_cut_before_the_ellipsis() {
+        assert_eq!(
+            fit("Temperature", 5, Align::Right),
+            Fitted {
+                text: "Temp",
+                offset: 0,
+                truncated: true
+            }
+        );
+        // Not ending on a space
+        assert_eq!(fit("Boiler in", 8, Align::Left).text, "Boiler");
+        assert_eq!(fit("25.0°C out", 6, Align::Left).text, "25.0°");
+        assert_eq!(fit("ab", 1, Align::Left).text, "");
+        // No room for the ellipsis either
+        assert_eq!(
+            fit("ab", 0, Align::Left),
+            Fitted {
+                text: "",
+                offset: 0,
+                truncated: false
+            }
+        );
+    }
+
+    #[test]
+    fn value_takes_what_the_label_leaves() {
+        // Both fit, a column apart
+        assert_eq!(label_value_widths("Uptime", "3d 4h", 25), (19, 5));
+        // The label is cut first
+        assert_eq!(
+            label_value_widths("Nodes online", "10.0.0.100", 21),
+            (10, 10)
+        );
+        // Down to `MIN_LABEL_COLUMNS`
+        assert_eq!(
+            label_value_widths("IP", "192.168.100.200 (dhcp)", 12),
+            (2, 9)
+        );
+        assert_eq!(
+            label_value_widths("Link", "192.168.100.200 (dhcp)", 12),
+            (MIN_LABEL_COLUMNS, 7)
+        );
+        // A row too narrow for both
+        assert_eq!(label_value_widths("Link", "up", 3), (2, 0));
+        assert_eq!(label_value_widths("Link", "up", 0), (0, 0));
+    }
+}
@@ -0,0 +1,71 @@
+//! SSD1306 OLED of the gateway
+//!
+//! `display_update_task` samples the shared state into a `DisplayData` every
+//! `REFRESH_INTERVAL` and draws the page of the rotation shown (`pages`) into
+//! a `FrameBuffer`, of which only the pages that changed are sent to the
+//! panel: the driver runs in its basic mode and the task only flushes frames
+//! to it. The button gestures
+//! are handled as they come, the page is redrawn right away. Every
+//! `TREND_INTERVAL_S` the analog values are added to the history of their
+//! sparklines.
+//!
+//! The panel is dimmed, turned off and its frame shifted as `power` decides,
+//! a button press on a panel not fully on only wakes it up. While new alarms
+//! are active the first one flashes in a banner over the title bar. The long
+//! press on the overview shows the identity QR code (`qr`) instead of the
+//! pages, until the next press.
+//!
+//! Pages place their text with the `layout` of the font set by
+//! `DISPLAY_FONT_HEIGHT`, on a 128x64 panel or a 128x32 one with the
+//! `oled-128x32` feature. Values are shown in the units of `common::units`.
+//!
+//! Only the task (`task`) drives the panel, it is built with the `esp32`
+//! feature: the pages are drawn in memory and are tested on a host.
+
+pub mod format;
+pub mod framebuffer;
+pub mod layout;
+pub mod pages;
+pub mod power;
+pub mod qr;
+#[cfg(test)]
+mod snapshot;
+pub mod widgets;
+
+use embedded_graphics::{
+    mono_font::{ascii, MonoFont, MonoTextStyle, MonoTextStyleBuilder},
+    pixelcolor::BinaryColor,
+};
+use ssd1306::prelude::*;
+
+use crate::common::config::env_u32_or;
+
+#[cfg(feature = "esp32")]
+mod task;
+
+#[cfg(feature = "esp32")]
+pub use task::{display_update_task, GatewayDisplay};
+
+// Height of the font in pixels: 8 (5x8), 10 (6x10), 13 (7x13) or 20 (10x20)
+const DISPLAY_FONT: MonoFont = match env_u32_or(option_env!("DISPLAY_FONT_HEIGHT"), 8) {
+    10 => ascii::FONT_6X10,
+    13 => ascii::FONT_7X13,
+    20 => ascii::FONT_10X20,
+    _ => ascii::FONT_5X8,
+};
+
+#[cfg(not(feature = "oled-128x32"))]
+pub type PanelSize = DisplaySize128x64;
+#[cfg(not(feature = "oled-128x32"))]
+pub const PANEL_SIZE: PanelSize = DisplaySize128x64;
+#[cfg(feature = "oled-128x32")]
+pub type PanelSize = DisplaySize128x32;
+#[cfg(feature = "oled-128x32")]
+pub const PANEL_SIZE: PanelSize = DisplaySize128x32;
+
+pub const fn configure_text_style() -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(&DISPLAY_FONT)
+        .text_color(BinaryColor::On)
+        .build()
+}
@@ -0,0 +1,1034 @@
+//! Pages of the gateway OLED
+//!
+//! The display task shows one `Page` at a time under a title bar and moves to
+//! the next one every `PAGE_ROTATION_S`. Pages draw from a `DisplayData`
+//! snapshot taken by the task before each refresh, on any `DrawTarget`, so
+//! they render the same off-target. A new screen implements `Page` and is
+//! added to the list given to `display_update_task`.
+//!
+//! The front panel button moves through the pages (`buttons::Gesture`), a
+//! long press runs the `PageAction` of the page shown, if any.
+//!
+//! Pages place their text on the rows and columns of the `Screen` layout,
+//! fitted to the font and the panel rather than to fixed widths. Besides
+//! text, they draw the `widgets` in the rows they take from it.
+
+use core::fmt::Write;
+
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+use heapless::{String, Vec};
+
+use crate::common::analog::{AnalogChannel, ANALOG_CHANNEL_COUNT};
+use crate::common::config::env_u32_or;
+use crate::common::connectivity::{Connectivity, LinkState};
+use crate::common::mesh::{format_mac, MacAddress};
+use crate::common::units::UnitPreferences;
+use crate::gateway_lib::display::format::{FloatLevelUnit, TemperatureLevelUnit};
+use crate::gateway_lib::display::layout::{self, Align, Layout};
+use crate::gateway_lib::display::qr::{draw_qr_code, identity_text};
+use crate::gateway_lib::display::widgets::{
+    signal_bars, BarGauge, History, SignalBars, Sparkline, SPARKLINE_LEN,
+};
+use crate::gateway_lib::liveness::{Liveness, NodeSummaries};
+use crate::gateway_lib::registry::MAX_NODES;
+
+// 0 keeps the page shown until the next one is selected
+pub const PAGE_ROTATION_S: u32 = env_u32_or(option_env!("DISPLAY_PAGE_ROTATION_S"), 5);
+// Between the points of the analog sparklines
+pub const TREND_INTERVAL_S: u32 = env_u32_or(option_env!("DISPLAY_TREND_INTERVAL_S"), 10);
+// Every node offline and low on battery, plus the gateway alarms
+pub const MAX_ALARMS: usize = 2 * MAX_NODES + 2;
+
+// Columns of the analog labels, less on narrow rows
+const ANALOG_LABEL_COLUMNS: u32 = 8;
+// Narrowest bar gauge drawn after the analog values, in pixels
+const GAUGE_MIN_WIDTH: u32 = 8;
+// Columns of the trend labels, at most a quarter of the row
+const TREND_LABEL_COLUMNS: u32 = 5;
+// Mesh node row with all its fields
+const MESH_ROW_COLUMNS: u32 = 24;
+
+// A display line, the 25 columns of the 5x8 font on 128 pixels and then some
+pub type Line = String<32>;
+
+/// State shown on the pages, sampled by the display task
+#[derive(Clone, Debug, Default)]
+pub struct DisplayData {
+    pub uptime_s: u64,
+    pub temperature: f32,
+    // dBm, -100 when not connected
+    pub rssi: i8,
+    pub wifi_percent: u8,
+    // AP and mesh channel, 0 when not connected
+    pub channel: u8,
+    pub ip: Option<[u8; 4]>,
+    pub mac: MacAddress,
+    pub firmware: &'static str,
+    // Units the values are shown in
+    pub units: UnitPreferences,
+    pub link: Connectivity,
+    pub analog: [f32; ANALOG_CHANNEL_COUNT],
+    // Last `SPARKLINE_LEN` analog values, one every `TREND_INTERVAL_S`
+    pub analog_history: [History; ANALOG_CHANNEL_COUNT],
+    pub registered_nodes: u32,
+    pub pairing: bool,
+    pub nodes: NodeSummaries,
+    pub frames_received: u32,
+    pub frames_duplicate: u32,
+    pub frames_lost: u32,
+    pub frames_retried: u32,
+    pub unknown_frames: u32,
+    // Alarms acknowledged with the button, while active
+    pub acknowledged: Vec<Alarm, MAX_ALARMS>,
+    // Identity QR code shown instead of the pages, until the next press
+    pub device_qr: bool,
+}
+
+impl DisplayData {
+    /// Add the current analog values to their history
+    pub fn record_history(&mut self) {
+        for (history, value) in self.analog_history.iter_mut().zip(self.analog) {
+            history.write(value);
+        }
+    }
+
+    pub fn online_nodes(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.state == Liveness::Online)
+            .count()
+    }
+
+    /// Active alarms, the gateway ones first
+    pub fn alarms(&self) -> Vec<Alarm, MAX_ALARMS> {
+        let mut alarms = Vec::new();
+        // Sized for all of them, cannot be full
+        if self.ip.is_none() {
+            let _ = alarms.push(Alarm::WifiDown);
+        }
+        if matches!(self.link.state, LinkState::Backoff | LinkState::AuthFailed) {
+            let _ = alarms.push(Alarm::MqttError);
+        }
+        for node in &self.nodes {
+            if node.state == Liveness::Offline {
+                let _ = alarms.push(Alarm::NodeOffline(node.mac));
+            }
+            if node.battery_low {
+                let _ = alarms.push(Alarm::LowBattery(node.mac, node.battery_v));
+            }
+        }
+        alarms
+    }
+
+    pub fn is_new(&self, alarm: &Alarm) -> bool {
+        !self.acknowledged.iter().any(|acked| acked.is_same(alarm))
+    }
+
+    /// Active alarms not acknowledged yet
+    pub fn new_alarms(&self) -> usize {
+        self.alarms()
+            .iter()
+            .filter(|alarm| self.is_new(alarm))
+            .count()
+    }
+
+    pub fn first_new_alarm(&self) -> Option<Alarm> {
+        self.alarms().into_iter().find(|alarm| self.is_new(alarm))
+    }
+
+    pub fn acknowledge_alarms(&mut self) {
+        self.acknowledged = self.alarms();
+    }
+
+    /// Forget the acknowledged alarms that cleared, they show up as new if
+    /// raised again
+    pub fn retain_acknowledged(&mut self) {
+        let alarms = self.alarms();
+        self.acknowledged
+            .retain(|acked| alarms.iter().any(|alarm| alarm.is_same(acked)));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alarm {
+    // No DHCP lease
+    WifiDown,
+    MqttError,
+    NodeOffline(MacAddress),
+    LowBattery(MacAddress, Option<f32>),
+}
+
+impl Alarm {
+    /// Same condition, whatever the values it carries
+    pub fn is_same(&self, other: &Alarm) -> bool {
+        match (self, other) {
+            (Alarm::LowBattery(mac, _), Alarm::LowBattery(other, _)) => mac == other,
+            _ => self == other,
+        }
+    }
+
+    pub fn text(&self) -> Line {
+        let mut line = Line::new();
+        let _ = match self {
+            Alarm::WifiDown => write!(line, "Wi-Fi down"),
+            Alarm::MqttError => write!(line, "MQTT error"),
+            Alarm::NodeOffline(mac) => write!(line, "Offline  {}", short_mac(mac)),
+            Alarm::LowBattery(mac, Some(battery_v)) => {
+                write!(line, "Low batt {} {:.2}V", short_mac(mac), battery_v)
+            }
+            Alarm::LowBattery(mac, None) => write!(line, "Low batt {}", short_mac(mac)),
+        };
+        line
+    }
+}
+
+/// Content area of a page, filled with lines of text from the top
+pub struct Screen<'a, D> {
+    pub target: &'a mut D,
+    pub style: MonoTextStyle<'static, BinaryColor>,
+    pub layout: Layout,
+    row: u32,
+}
+
+impl<'a, D: DrawTarget<Color = BinaryColor>> Screen<'a, D> {
+    pub fn new(
+        target: &'a mut D,
+        style: MonoTextStyle<'static, BinaryColor>,
+        area: Rectangle,
+    ) -> Self {
+        Screen {
+            target,
+            style,
+            layout: Layout::new(style.font, area),
+            row: 0,
+        }
+    }
+
+    /// Offset of the text column `column` from the left of a row
+    pub fn column(&self, column: u32) -> i32 {
+        self.layout.column(column)
+    }
+
+    /// Text columns of a row
+    pub fn columns(&self) -> u32 {
+        self.layout.columns()
+    }
+
+    /// Lines left under the ones drawn
+    pub fn rows_left(&self) -> u32 {
+        self.layout.rows().saturating_sub(self.row)
+    }
+
+    /// Take the next line, none once the area is full
+    pub fn row(&mut self) -> Option<Rectangle> {
+        let row = self.layout.row(self.row)?;
+        self.row += 1;
+        Some(row)
+    }
+
+    /// Draw `text` with its top left corner at `position`
+    pub fn text(&mut self, text: &str, position: Point) -> Result<(), D::Error> {
+        Text::with_baseline(text, position, self.style, Baseline::Top).draw(self.target)?;
+        Ok(())
+    }
+
+    /// Draw `text` in a field of `width` columns from `position`, cut with an
+    /// ellipsis when too long
+    pub fn field(
+        &mut self,
+        text: &str,
+        position: Point,
+        width: u32,
+        align: Align,
+    ) -> Result<(), D::Error> {
+        let fitted = layout::fit(text, width, align);
+        let start = position + Point::new(self.column(fitted.offset), 0);
+        self.text(fitted.text, start)?;
+        if fitted.truncated {
+            let len = fitted.text.chars().count() as u32;
+            self.ellipsis(start + Point::new(self.column(len), 0))?;
+        }
+        Ok(())
+    }
+
+    /// Draw `text` on the next line, ignored once the area is full
+    pub fn line(&mut self, text: &str) -> Result<(), D::Error> {
+        match self.row() {
+            Some(row) => self.field(text, row.top_left, self.columns(), Align::Left),
+            None => Ok(()),
+        }
+    }
+
+    /// Draw `label` on the left of the next line and `value` on its right
+    pub fn label_value(&mut self, label: &str, value: &str) -> Result<(), D::Error> {
+        let Some(row) = self.row() else {
+            return Ok(());
+        };
+        let columns = self.columns();
+        let (label_width, value_width) = layout::label_value_widths(label, value, columns);
+        self.field(label, row.top_left, label_width, Align::Left)?;
+        let value_at = row.top_left + Point::new(self.column(columns - value_width), 0);
+        self.field(value, value_at, value_width, Align::Right)
+    }
+
+    /// Draw a widget, the bounds given by the page
+    pub fn widget(&mut self, widget: &impl Drawable<Color = BinaryColor>) -> Result<(), D::Error> {
+        widget.draw(self.target)?;
+        Ok(())
+    }
+
+    /// Three dots on the baseline of the character cell at `position`
+    fn ellipsis(&mut self, position: Point) -> Result<(), D::Error> {
+        let font = self.style.font;
+        let step = (font.character_size.width.saturating_sub(1) / 2).max(1) as i32;
+        let color = self.style.text_color.unwrap_or(BinaryColor::On);
+        let dots = (0..3).map(|dot| {
+            Pixel(
+                position + Point::new(dot * step, font.baseline as i32),
+                color,
+            )
+        });
+        self.target.draw_iter(dots)
+    }
+}
+
+/// Context action of a page, run by the display task on a long press
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageAction {
+    AcknowledgeAlarms,
+    StartPairing,
+    ShowDeviceQr,
+}
+
+/// A screen of the display
+pub trait Page<D: DrawTarget<Color = BinaryColor>> {
+    fn title(&self) -> &'static str;
+    /// Draw the content under the title bar
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error>;
+    fn action(&self) -> Option<PageAction> {
+        None
+    }
+}
+
+/// Draw the title bar of page `index` out of `count`, then its content
+pub fn draw_page<D: DrawTarget<Color = BinaryColor>>(
+    page: &dyn Page<D>,
+    index: usize,
+    count: usize,
+    data: &DisplayData,
+    style: MonoTextStyle<'static, BinaryColor>,
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let bounds = target.bounding_box();
+    let mut position = String::<8>::new();
+    let _ = write!(position, "{}/{}", index + 1, count);
+    let mut title_bar = Screen::new(target, style, bounds);
+    title_bar.label_value(page.title(), &position)?;
+    let area = title_bar.layout.below(1);
+    page.draw(data, &mut Screen::new(target, style, area))
+}
+
+/// Bar over the title bar with `alarm`, in inverse video when `inverse` so it
+/// flashes when toggled
+pub fn draw_alarm_banner<D: DrawTarget<Color = BinaryColor>>(
+    alarm: &Alarm,
+    inverse: bool,
+    style: MonoTextStyle<'static, BinaryColor>,
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let (background, text_color) = if inverse {
+        (BinaryColor::On, BinaryColor::Off)
+    } else {
+        (BinaryColor::Off, BinaryColor::On)
+    };
+    let mut banner_style = style;
+    banner_style.text_color = Some(text_color);
+    let bounds = target.bounding_box();
+    let mut banner = Screen::new(target, banner_style, bounds);
+    if let Some(row) = banner.layout.row(0) {
+        row.into_styled(PrimitiveStyle::with_fill(background))
+            .draw(banner.target)?;
+    }
+    let mut line = Line::new();
+    let _ = write!(line, "! {}", alarm.text());
+    banner.line(&line)
+}
+
+/// Identity QR code of the gateway on the left of the panel, its MAC and
+/// firmware version in text on the right
+pub fn draw_device_qr<D: DrawTarget<Color = BinaryColor>>(
+    data: &DisplayData,
+    style: MonoTextStyle<'static, BinaryColor>,
+    target: &mut D,
+) -> Result<(), D::Error> {
+    let bounds = target.bounding_box();
+    let side = bounds.size.width.min(bounds.size.height);
+    let text = identity_text(&data.mac, data.firmware);
+    let square = Rectangle::new(bounds.top_left, Size::new(side, side));
+    let fits = draw_qr_code(&text, square, target)?.is_some();
+
+    // A column apart from the code
+    let left = side + style.font.character_size.width;
+    let area = Rectangle::new(
+        bounds.top_left + Point::new(left as i32, 0),
+        Size::new(bounds.size.width.saturating_sub(left), bounds.size.height),
+    );
+    let mut screen = Screen::new(target, style, area);
+    if !fits {
+        screen.line("No QR code")?;
+    }
+    screen.line("MAC")?;
+    let mut line = Line::new();
+    for byte in data.mac {
+        let _ = write!(line, "{:02X}", byte);
+    }
+    screen.line(&line)?;
+    line.clear();
+    let _ = write!(line, "FW {}", data.firmware);
+    screen.line(&line)
+}
+
+/// Index of the page shown, moving to the next one every `period_ms`
+#[derive(Clone, Copy, Debug)]
+pub struct PageRotation {
+    index: usize,
+    count: usize,
+    // 0 keeps the page until `next`
+    period_ms: u64,
+    shown_at_ms: u64,
+}
+
+impl PageRotation {
+    pub fn new(count: usize, period_ms: u64, now_ms: u64) -> Self {
+        PageRotation {
+            index: 0,
+            count,
+            period_ms,
+            shown_at_ms: now_ms,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.index
+    }
+
+    /// Show the next page from `now_ms`
+    pub fn next(&mut self, now_ms: u64) -> usize {
+        if self.count > 0 {
+            self.index = (self.index + 1) % self.count;
+        }
+        self.shown_at_ms = now_ms;
+        self.index
+    }
+
+    /// Show the previous page from `now_ms`
+    pub fn previous(&mut self, now_ms: u64) -> usize {
+        if self.count > 0 {
+            self.index = (self.index + self.count - 1) % self.count;
+        }
+        self.shown_at_ms = now_ms;
+        self.index
+    }
+
+    /// Page to show at `now_ms`
+    pub fn tick(&mut self, now_ms: u64) -> usize {
+        if self.period_ms > 0 && now_ms.saturating_sub(self.shown_at_ms) >= self.period_ms {
+            self.next(now_ms)
+        } else {
+            self.index
+        }
+    }
+}
+
+// *** Pages *** //
+
+/// Uptime, temperature and link states, the former single screen
+pub struct OverviewPage;
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for OverviewPage {
+    fn title(&self) -> &'static str {
+        "Overview"
+    }
+
+    fn action(&self) -> Option<PageAction> {
+        Some(PageAction::ShowDeviceQr)
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        let mut line = Line::new();
+        write_uptime(&mut line, data.uptime_s);
+        screen.label_value("Uptime", &line)?;
+        let (level, unit) = data.units.convert(data.temperature, "C");
+        let temperature = TemperatureLevelUnit::new("Temp", level, unit);
+        screen.label_value(temperature.msg(), &temperature.value())?;
+        if let Some(row) = screen.row() {
+            screen.text("Wifi", row.top_left)?;
+            // 4 bars of 3 pixels at the end of the text columns, above the
+            // descenders
+            let right = screen.column(screen.columns());
+            let bars = Rectangle::new(
+                row.top_left + Point::new(right - 16, 0),
+                Size::new(15, row.size.height - 1),
+            );
+            screen.widget(&SignalBars {
+                bars: signal_bars(data.rssi),
+                bounds: bars,
+            })?;
+        }
+        screen.label_value("Link", &link_status(data))?;
+        line.clear();
+        let _ = write!(line, "{}/{}", data.online_nodes(), data.nodes.len());
+        screen.label_value("Nodes online", &line)?;
+        line.clear();
+        let _ = write!(line, "{}", data.alarms().len());
+        let new_alarms = data.new_alarms();
+        if new_alarms > 0 {
+            let _ = write!(line, " ({} new)", new_alarms);
+        }
+        screen.label_value("Alarms", &line)
+    }
+}
+
+/// Latest value of each 4-20mA input, named after the configured channels,
+/// with a gauge of its range for those
+pub struct AnalogPage {
+    pub channels: &'static [AnalogChannel],
+}
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for AnalogPage {
+    fn title(&self) -> &'static str {
+        "Analog inputs"
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        // Label, value and unit a column apart, the label narrowed to leave
+        // room for the others
+        let label_width = ANALOG_LABEL_COLUMNS.min(screen.columns().saturating_sub(11));
+        let value_column = label_width + 1;
+        let unit_column = value_column + 7;
+        for (index, value) in data.analog.iter().enumerate() {
+            let Some(row) = screen.row() else {
+                break;
+            };
+            let channel = find_channel(self.channels, index);
+            let label = channel_label(channel, index);
+            screen.field(&label, row.top_left, label_width, Align::Left)?;
+            // The gauge stays on the canonical value and range
+            let (shown, unit) = match channel {
+                Some(channel) => data.units.convert(*value, channel.unit),
+                None => (*value, ""),
+            };
+            let mut line = Line::new();
+            write_value(&mut line, shown, 6);
+            let value_at = row.top_left + Point::new(screen.column(value_column), 0);
+            screen.field(&line, value_at, 6, Align::Right)?;
+            let Some(channel) = channel else {
+                continue;
+            };
+            let unit_at = row.top_left + Point::new(screen.column(unit_column), 0);
+            screen.field(unit, unit_at, 3, Align::Left)?;
+            // Right of the text, above the descenders and clear of the last
+            // column for the pixel shift, if there is room left
+            let left = screen.column(unit_column + 4);
+            let width = row.size.width.saturating_sub(left as u32 + 1);
+            if width >= GAUGE_MIN_WIDTH {
+                let gauge = Rectangle::new(
+                    row.top_left + Point::new(left, 0),
+                    Size::new(width, row.size.height - 1),
+                );
+                screen.widget(&BarGauge::new(
+                    *value,
+                    channel.range_low,
+                    channel.range_high,
+                    gauge,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sparkline of the recent values of each analog input
+pub struct TrendsPage {
+    pub channels: &'static [AnalogChannel],
+}
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for TrendsPage {
+    fn title(&self) -> &'static str {
+        "Analog trends"
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        let columns = screen.columns();
+        let label_width = TREND_LABEL_COLUMNS.min(columns / 4);
+        let value_column = columns.saturating_sub(6);
+        for (index, history) in data.analog_history.iter().enumerate() {
+            let Some(row) = screen.row() else {
+                break;
+            };
+            let channel = find_channel(self.channels, index);
+            let label = channel_label(channel, index);
+            screen.field(&label, row.top_left, label_width, Align::Left)?;
+            // Up to 2 pixels per point between the label and the latest value
+            let left = screen.column(label_width + 1);
+            let right = screen.column(value_column) - 2;
+            let width = (right - left).clamp(0, 2 * SPARKLINE_LEN as i32 - 1);
+            let sparkline = Rectangle::new(
+                row.top_left + Point::new(left, 0),
+                Size::new(width as u32, row.size.height - 1),
+            );
+            screen.widget(&Sparkline {
+                history,
+                bounds: sparkline,
+            })?;
+            let value = match channel {
+                Some(channel) => data.units.convert(data.analog[index], channel.unit).0,
+                None => data.analog[index],
+            };
+            let mut line = Line::new();
+            write_value(&mut line, value, 6);
+            let value_at = row.top_left + Point::new(screen.column(value_column), 0);
+            screen.field(&line, value_at, 6, Align::Right)?;
+        }
+        Ok(())
+    }
+}
+
+/// State, RSSI and battery of each watched node
+pub struct MeshPage;
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for MeshPage {
+    fn title(&self) -> &'static str {
+        "Mesh nodes"
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        if data.nodes.is_empty() {
+            screen.line("No nodes registered")?;
+        }
+        // The RSSI is left out of rows too narrow for all the fields
+        let with_rssi = screen.columns() >= MESH_ROW_COLUMNS;
+        // Keep the last row for the count of the nodes left out
+        let rows = screen.rows_left() as usize;
+        let shown = if data.nodes.len() > rows {
+            rows.saturating_sub(1)
+        } else {
+            data.nodes.len()
+        };
+        for node in data.nodes.iter().take(shown) {
+            let mut line = Line::new();
+            let state = match node.state {
+                Liveness::Online => "on",
+                Liveness::Offline => "off",
+                Liveness::Unknown => "?",
+            };
+            let _ = write!(line, "{} {:3}", short_mac(&node.mac), state);
+            // No RSSI for the nodes behind a relay
+            let _ = match node.rssi {
+                _ if !with_rssi => Ok(()),
+                Some(rssi) => write!(line, " {:>4}", rssi),
+                None => write!(line, " {:>4}", "--"),
+            };
+            let _ = match node.battery_v {
+                Some(battery_v) => write!(line, " {:4.2}V", battery_v),
+                None => write!(line, "   --"),
+            };
+            if node.battery_low {
+                let _ = line.push('!');
+            }
+            screen.line(&line)?;
+        }
+        if shown < data.nodes.len() {
+            let mut line = Line::new();
+            let _ = write!(line, "+{} more", data.nodes.len() - shown);
+            screen.line(&line)?;
+        }
+        if data.pairing {
+            screen.line("Pairing active")?;
+        }
+        Ok(())
+    }
+
+    fn action(&self) -> Option<PageAction> {
+        Some(PageAction::StartPairing)
+    }
+}
+
+/// Addresses and links of the gateway
+pub struct NetworkPage {
+    pub ssid: &'static str,
+}
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for NetworkPage {
+    fn title(&self) -> &'static str {
+        "Network"
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        let mut line = Line::new();
+        let _ = match data.ip {
+            Some([a, b, c, d]) => write!(line, "{}.{}.{}.{}", a, b, c, d),
+            None => write!(line, "--"),
+        };
+        screen.label_value("IP", &line)?;
+        screen.label_value("MAC", &format_mac(&data.mac))?;
+        screen.label_value("SSID", self.ssid)?;
+        line.clear();
+        let _ = match data.channel {
+            0 => write!(line, "--"),
+            channel => write!(line, "{}", channel),
+        };
+        screen.label_value("Chan", &line)?;
+        line.clear();
+        let _ = write!(line, "{} dBm ({}%)", data.rssi, data.wifi_percent);
+        screen.label_value("RSSI", &line)?;
+        screen.label_value("Link", &link_status(data))?;
+        screen.label_value(
+            "Err",
+            data.link.last_error.map_or("--", |error| error.label()),
+        )
+    }
+}
+
+/// Active alarms of the gateway and the nodes
+pub struct AlarmsPage;
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for AlarmsPage {
+    fn title(&self) -> &'static str {
+        "Alarms"
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        let alarms = data.alarms();
+        if alarms.is_empty() {
+            return screen.line("No active alarms");
+        }
+        let rows = screen.rows_left() as usize;
+        let shown = if alarms.len() > rows {
+            rows.saturating_sub(1)
+        } else {
+            alarms.len()
+        };
+        // New alarms first
+        let (new, acked) = (
+            alarms.iter().filter(|alarm| data.is_new(alarm)),
+            alarms.iter().filter(|alarm| !data.is_new(alarm)),
+        );
+        for alarm in new.chain(acked).take(shown) {
+            screen.line(&alarm.text())?;
+        }
+        if shown < alarms.len() {
+            let mut line = Line::new();
+            let _ = write!(line, "+{} more", alarms.len() - shown);
+            screen.line(&line)?;
+        }
+        Ok(())
+    }
+
+    fn action(&self) -> Option<PageAction> {
+        Some(PageAction::AcknowledgeAlarms)
+    }
+}
+
+/// Mesh delivery counters and registry state
+pub struct DiagnosticsPage;
+
+impl<D: DrawTarget<Color = BinaryColor>> Page<D> for DiagnosticsPage {
+    fn title(&self) -> &'static str {
+        "Diagnostics"
+    }
+
+    fn draw(&self, data: &DisplayData, screen: &mut Screen<'_, D>) -> Result<(), D::Error> {
+        let counters = [
+            ("Frames rx", data.frames_received),
+            ("Duplicates", data.frames_duplicate),
+            ("Lost", data.frames_lost),
+            ("Retried", data.frames_retried),
+            ("Unknown src", data.unknown_frames),
+            ("Registered", data.registered_nodes),
+        ];
+        for (label, value) in counters {
+            let mut line = Line::new();
+            let _ = write!(line, "{}", value);
+            screen.label_value(label, &line)?;
+        }
+        screen.label_value("Pairing", if data.pairing { "on" } else { "off" })
+    }
+}
+
+/// `DD:EE:FF`, the part of the MAC that tells the nodes apart
+fn short_mac(mac: &MacAddress) -> String<8> {
+    let mut s = String::<8>::new();
+    let _ = write!(s, "{:02X}:{:02X}:{:02X}", mac[3], mac[4], mac[5]);
+    s
+}
+
+/// State of the link, with the seconds left before the next attempt
+fn link_status(data: &DisplayData) -> Line {
+    let mut status = Line::new();
+    let _ = write!(status, "{}", data.link.state.label());
+    if let Some(retry_in_ms) = data.link.retry_in_ms(data.uptime_s * 1000) {
+        let _ = write!(status, " {}s", retry_in_ms.div_ceil(1000));
+    }
+    status
+}
+
+/// `[1d ]hh:mm:ss`
+fn write_uptime(line: &mut Line, seconds: u64) {
+    let days = seconds / 86_400;
+    if days > 0 {
+        let _ = write!(line, "{}d ", days);
+    }
+    let _ = write!(
+        line,
+        "{:02}:{:02}:{:02}",
+        seconds % 86_400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    );
+}
+
+fn find_channel(channels: &[AnalogChannel], index: usize) -> Option<&AnalogChannel> {
+    channels.iter().find(|channel| channel.index == index)
+}
+
+/// Name of the configured channel, `A<index>` otherwise
+fn channel_label(channel: Option<&AnalogChannel>, index: usize) -> Line {
+    let mut label = Line::new();
+    let _ = match channel {
+        Some(channel) => write!(label, "{}", channel.name),
+        None => write!(label, "A{}", index),
+    };
+    label
+}
+
+/// `value` right-aligned in `width` columns with 1 decimal, `--` if invalid
+fn write_value(line: &mut Line, value: f32, width: usize) {
+    let _ = if value.is_finite() {
+        write!(line, "{:width$.1}", value, width = width)
+    } else {
+        write!(line, "{:>width$}", "--", width = width)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway_lib::display::framebuffer::FrameBuffer;
+    use crate::gateway_lib::display::framebuffer::FRAME_HEIGHT;
+    use crate::gateway_lib::display::power::{shift_margin, PowerManager};
+    use crate::gateway_lib::display::qr::identity_text;
+    use crate::gateway_lib::display::snapshot::{
+        assert_snapshot, decode_qr, sample_data, small_style, style, CHANNELS,
+    };
+    use embedded_graphics::mono_font::ascii;
+    use std::vec::Vec as StdVec;
+
+    fn pages() -> [(&'static str, &'static dyn Page<FrameBuffer>); 7] {
+        [
+            ("overview", &OverviewPage),
+            ("analog", &AnalogPage { channels: CHANNELS }),
+            ("trends", &TrendsPage { channels: CHANNELS }),
+            ("mesh", &MeshPage),
+            (
+                "network",
+                &NetworkPage {
+                    ssid: "bike_maniacs_extended_5g",
+                },
+            ),
+            ("alarms", &AlarmsPage),
+            ("diagnostics", &DiagnosticsPage),
+        ]
+    }
+
+    #[test]
+    fn pages_match_snapshots() {
+        let data = sample_data();
+        let pages = pages();
+        for (index, (name, page)) in pages.iter().enumerate() {
+            let mut frame = FrameBuffer::new();
+            draw_page(*page, index, pages.len(), &data, small_style(), &mut frame).unwrap();
+            assert_snapshot(&format!("page_{}", name), &frame);
+        }
+    }
+
+    #[test]
+    fn pages_draw_without_data() {
+        let data = DisplayData::default();
+        let pages = pages();
+        for (index, (name, page)) in pages.iter().enumerate() {
+            let mut frame = FrameBuffer::new();
+            draw_page(*page, index, pages.len(), &data, small_style(), &mut frame).unwrap();
+            assert_snapshot(&format!("page_{}_empty", name), &frame);
+        }
+    }
+
+    #[test]
+    fn pages_fit_every_font() {
+        // The 5x8 font is the one of the other snapshots
+        let data = sample_data();
+        let pages = pages();
+        for font in [&ascii::FONT_6X10, &ascii::FONT_7X13, &ascii::FONT_10X20] {
+            for (index, (name, page)) in pages.iter().enumerate() {
+                let mut frame = FrameBuffer::new();
+                draw_page(*page, index, pages.len(), &data, style(font), &mut frame).unwrap();
+                let height = font.character_size.height;
+                assert_snapshot(&format!("page_{}_font{}", name, height), &frame);
+            }
+        }
+    }
+
+    #[test]
+    fn device_qr_holds_the_identity() {
+        let data = sample_data();
+        let mut frame = FrameBuffer::new();
+        draw_device_qr(&data, small_style(), &mut frame).unwrap();
+        assert_eq!(
+            decode_qr(&frame, FRAME_HEIGHT),
+            identity_text(&data.mac, data.firmware).as_str()
+        );
+        assert_snapshot("device_qr", &frame);
+    }
+
+    #[test]
+    fn rotation_moves_on_every_period() {
+        let mut rotation = PageRotation::new(3, 5_000, 1_000);
+        assert_eq!(rotation.tick(5_999), 0);
+        assert_eq!(rotation.tick(6_000), 1);
+        assert_eq!(rotation.tick(10_999), 1);
+        assert_eq!(rotation.tick(11_000), 2);
+        // Back to the first page after the last one
+        assert_eq!(rotation.tick(16_000), 0);
+        // A late tick moves one page only, and restarts the period
+        assert_eq!(rotation.tick(60_000), 1);
+        assert_eq!(rotation.tick(64_999), 1);
+    }
+
+    #[test]
+    fn button_moves_wrap_around_and_restart_the_period() {
+        let mut rotation = PageRotation::new(3, 5_000, 0);
+        assert_eq!(rotation.previous(1_000), 2);
+        assert_eq!(rotation.next(2_000), 0);
+        assert_eq!(rotation.next(3_000), 1);
+        assert_eq!(rotation.tick(7_999), 1);
+        assert_eq!(rotation.tick(8_000), 2);
+        assert_eq!(rotation.current(), 2);
+    }
+
+    #[test]
+    fn rotation_can_be_disabled() {
+        let mut rotation = PageRotation::new(3, 0, 0);
+        assert_eq!(rotation.tick(u64::MAX), 0);
+        assert_eq!(rotation.next(1), 1);
+        assert_eq!(rotation.tick(u64::MAX), 1);
+
+        // Without pages the index stays on the first one
+        let mut empty = PageRotation::new(0, 5_000, 0);
+        assert_eq!(empty.next(1), 0);
+        assert_eq!(empty.previous(2), 0);
+        assert_eq!(empty.tick(10_000), 0);
+    }
+
+    #[test]
+    fn rotation_shows_every_page_in_turn() {
+        let data = sample_data();
+        let pages = pages();
+        let mut rotation = PageRotation::new(pages.len(), 5_000, 0);
+        let mut shown = StdVec::new();
+        for now_ms in (0..=pages.len() as u64 * 5_000).step_by(1_000) {
+            let index = rotation.tick(now_ms);
+            if shown.last() == Some(&index) {
+                continue;
+            }
+            shown.push(index);
+            let (name, page) = pages[index];
+            let mut frame = FrameBuffer::new();
+            draw_page(page, index, pages.len(), &data, small_style(), &mut frame).unwrap();
+            assert_snapshot(&format!("page_{}", name), &frame);
+        }
+        assert_eq!(shown, [0, 1, 2, 3, 4, 5, 6, 0]);
+    }
+
+    #[test]
+    fn acknowledged_alarms_come_back_once_cleared() {
+        let mut data = sample_data();
+        let offline = Alarm::NodeOffline([0, 0, 0, 1, 2, 2]);
+        assert_eq!(data.new_alarms(), 2);
+        assert_eq!(
+            data.first_new_alarm(),
+            Some(Alarm::LowBattery([0, 0, 0, 1, 2, 1], Some(3.6)))
+        );
+        data.acknowledge_alarms();
+        assert_eq!((data.new_alarms(), data.first_new_alarm()), (0, None));
+
+        // Another reading of the battery is the same alarm
+        data.nodes[1].battery_v = Some(3.4);
+        data.retain_acknowledged();
+        assert_eq!(data.new_alarms(), 0);
+
+        // A new alarm is shown over the acknowledged ones
+        data.ip = None;
+        assert_eq!(data.first_new_alarm(), Some(Alarm::WifiDown));
+        data.ip = Some([192, 168, 1, 20]);
+
+        // The node comes back then drops again: a new alarm
+        data.nodes[2].state = Liveness::Online;
+        data.retain_acknowledged();
+        assert_eq!(data.acknowledged.len(), 1);
+        data.nodes[2].state = Liveness::Offline;
+        data.retain_acknowledged();
+        assert_eq!(data.first_new_alarm(), Some(offline));
+        assert_eq!(data.new_alarms(), 1);
+    }
+
+    #[test]
+    fn pixel_shift_keeps_every_pixel_of_each_font() {
+        let data = sample_data();
+        let alarm = data.first_new_alarm().unwrap();
+        let pages = pages();
+        let lit = |frame: &FrameBuffer| {
+            frame
+                .as_bytes()
+                .iter()
+                .map(|byte| byte.count_ones())
+                .sum::<u32>()
+        };
+        for font in [
+            &ascii::FONT_5X8,
+            &ascii::FONT_6X10,
+            &ascii::FONT_7X13,
+            &ascii::FONT_10X20,
+        ] {
+            let style = style(font);
+            let power = PowerManager::new(0, 0, 1, shift_margin(font), 0);
+            let mut frames = StdVec::new();
+            for (index, (_, page)) in pages.iter().enumerate() {
+                for banner in [None, Some(false), Some(true)] {
+                    let mut frame = FrameBuffer::with_margin(shift_margin(font));
+                    draw_page(*page, index, pages.len(), &data, style, &mut frame).unwrap();
+                    if let Some(inverse) = banner {
+                        draw_alarm_banner(&alarm, inverse, style, &mut frame).unwrap();
+                    }
+                    frames.push(frame);
+                }
+            }
+            let mut frame = FrameBuffer::with_margin(shift_margin(font));
+            draw_device_qr(&data, style, &mut frame).unwrap();
+            frames.push(frame);
+
+            for frame in frames {
+                for now_ms in 0..4 {
+                    let mut shifted = frame.clone();
+                    shifted.shift(power.pixel_shift(now_ms));
+                    assert_eq!(lit(&shifted), lit(&frame));
+                }
+            }
+        }
+    }
+}
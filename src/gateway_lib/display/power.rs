@@ -0,0 +1,200 @@
+//! Power management of the OLED, against burn-in
+//!
+//! The panel is dimmed after `DIM_AFTER_S` without a button press and turned
+//! off after `OFF_AFTER_S`, 0 to never do it. A press wakes it up, the panel
+//! also stays on while new alarms are active and for the same timeouts after.
+//! Every `PIXEL_SHIFT_S` the whole frame moves by a pixel around a 2x2 square,
+//! so no pixel stays lit all day. It moves within the blank margin the text
+//! grid leaves on the right and at the bottom of the panel (`shift_margin`),
+//! only sideways when the rows of the font fill the height. The display task
+//! applies `PowerManager`.
+
+use embedded_graphics::{mono_font::MonoFont, prelude::*, primitives::Rectangle};
+
+use super::framebuffer::{FRAME_HEIGHT, FRAME_WIDTH};
+use super::layout::Layout;
+use crate::common::config::env_u32_or;
+
+pub const DIM_AFTER_S: u32 = env_u32_or(option_env!("DISPLAY_DIM_AFTER_S"), 60);
+pub const OFF_AFTER_S: u32 = env_u32_or(option_env!("DISPLAY_OFF_AFTER_S"), 600);
+pub const PIXEL_SHIFT_S: u32 = env_u32_or(option_env!("DISPLAY_PIXEL_SHIFT_S"), 60);
+
+// Right then down, by the margin
+const PIXEL_SHIFTS: [Point; 4] = [
+    Point::new(0, 0),
+    Point::new(1, 0),
+    Point::new(1, 1),
+    Point::new(0, 1),
+];
+
+/// Margin for the pixel shift, a pixel on each side the text grid of `font`
+/// leaves one
+pub fn shift_margin(font: &MonoFont) -> Size {
+    let frame = Rectangle::new(Point::zero(), Size::new(FRAME_WIDTH, FRAME_HEIGHT));
+    let cell = Layout::new(font, frame).cell;
+    Size::new(
+        (FRAME_WIDTH % cell.width.max(1)).min(1),
+        (FRAME_HEIGHT % cell.height.max(1)).min(1),
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerLevel {
+    On,
+    Dimmed,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PowerManager {
+    // 0 to never dim or turn off, not to shift
+    dim_after_ms: u64,
+    off_after_ms: u64,
+    shift_period_ms: u64,
+    // Blank margin of the frame, from `shift_margin`
+    margin: Size,
+    // Last button press or alarm
+    active_at_ms: u64,
+}
+
+impl PowerManager {
+    pub fn new(
+        dim_after_ms: u64,
+        off_after_ms: u64,
+        shift_period_ms: u64,
+        margin: Size,
+        now_ms: u64,
+    ) -> Self {
+        PowerManager {
+            dim_after_ms,
+            off_after_ms,
+            shift_period_ms,
+            margin,
+            active_at_ms: now_ms,
+        }
+    }
+
+    /// Restart the timeouts on a button press, returns whether the panel was
+    /// awake to take it
+    pub fn wake(&mut self, now_ms: u64) -> bool {
+        let awake = self.level(now_ms) == PowerLevel::On;
+        self.active_at_ms = now_ms;
+        awake
+    }
+
+    /// Level of the panel at `now_ms`, kept on while `alarm` is raised
+    pub fn update(&mut self, now_ms: u64, alarm: bool) -> PowerLevel {
+        if alarm {
+            self.active_at_ms = now_ms;
+        }
+        self.level(now_ms)
+    }
+
+    fn level(&self, now_ms: u64) -> PowerLevel {
+        let idle_ms = now_ms.saturating_sub(self.active_at_ms);
+        if self.off_after_ms > 0 && idle_ms >= self.off_after_ms {
+            PowerLevel::Off
+        } else if self.dim_after_ms > 0 && idle_ms >= self.dim_after_ms {
+            PowerLevel::Dimmed
+        } else {
+            PowerLevel::On
+        }
+    }
+
+    /// Offset of the frame shown at `now_ms`, within the margin
+    pub fn pixel_shift(&self, now_ms: u64) -> Point {
+        if self.shift_period_ms == 0 {
+            return Point::zero();
+        }
+        let shift =
+            PIXEL_SHIFTS[(now_ms / self.shift_period_ms % PIXEL_SHIFTS.len() as u64) as usize];
+        Point::new(
+            shift.x * self.margin.width as i32,
+            shift.y * self.margin.height as i32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::mono_font::ascii;
+    use std::vec::Vec as StdVec;
+
+    const MARGIN: Size = Size::new(1, 1);
+
+    #[test]
+    fn dims_then_turns_off_when_idle() {
+        let mut power = PowerManager::new(60_000, 600_000, 0, MARGIN, 1_000);
+        assert_eq!(power.update(60_999, false), PowerLevel::On);
+        assert_eq!(power.update(61_000, false), PowerLevel::Dimmed);
+        assert_eq!(power.update(600_999, false), PowerLevel::Dimmed);
+        assert_eq!(power.update(601_000, false), PowerLevel::Off);
+    }
+
+    #[test]
+    fn press_wakes_the_panel_up() {
+        let mut power = PowerManager::new(60_000, 600_000, 0, MARGIN, 0);
+        // Taken by the page when on
+        assert!(power.wake(30_000));
+        assert_eq!(power.update(89_999, false), PowerLevel::On);
+        // Only wakes it up when dimmed or off
+        assert!(!power.wake(90_000));
+        assert_eq!(power.update(90_000, false), PowerLevel::On);
+        assert_eq!(power.update(700_000, false), PowerLevel::Off);
+        assert!(!power.wake(700_000));
+        assert_eq!(power.update(759_999, false), PowerLevel::On);
+        assert_eq!(power.update(760_000, false), PowerLevel::Dimmed);
+    }
+
+    #[test]
+    fn alarm_keeps_the_panel_on() {
+        let mut power = PowerManager::new(60_000, 600_000, 0, MARGIN, 0);
+        assert_eq!(power.update(700_000, false), PowerLevel::Off);
+        assert_eq!(power.update(700_001, true), PowerLevel::On);
+        assert_eq!(power.update(900_000, true), PowerLevel::On);
+        // The same timeouts once cleared
+        assert_eq!(power.update(959_999, false), PowerLevel::On);
+        assert_eq!(power.update(960_000, false), PowerLevel::Dimmed);
+        assert_eq!(power.update(1_500_000, false), PowerLevel::Off);
+    }
+
+    #[test]
+    fn zero_timeouts_keep_the_panel_on() {
+        let mut power = PowerManager::new(0, 0, 0, MARGIN, 0);
+        assert_eq!(power.update(u64::MAX, false), PowerLevel::On);
+        let mut power = PowerManager::new(60_000, 0, 0, MARGIN, 0);
+        assert_eq!(power.update(u64::MAX, false), PowerLevel::Dimmed);
+    }
+
+    #[test]
+    fn shift_cycles_within_the_margin() {
+        let shifts = |margin: Size| {
+            let power = PowerManager::new(0, 0, 60_000, margin, 0);
+            (0..5)
+                .map(|period| power.pixel_shift(period * 60_000 + 59_999))
+                .collect::<StdVec<_>>()
+        };
+        assert_eq!(
+            shifts(MARGIN),
+            [(0, 0), (1, 0), (1, 1), (0, 1), (0, 0)].map(Point::from)
+        );
+        // Sideways only
+        assert_eq!(
+            shifts(Size::new(1, 0)),
+            [(0, 0), (1, 0), (1, 0), (0, 0), (0, 0)].map(Point::from)
+        );
+        assert_eq!(shifts(Size::zero()), [Point::zero(); 5]);
+        let power = PowerManager::new(0, 0, 0, MARGIN, 0);
+        assert_eq!(power.pixel_shift(60_000), Point::zero());
+    }
+
+    #[test]
+    fn margin_is_what_the_text_grid_leaves() {
+        // Rows of 8 pixels fill both panels
+        assert_eq!(shift_margin(&ascii::FONT_5X8), Size::new(1, 0));
+        for font in [ascii::FONT_6X10, ascii::FONT_7X13, ascii::FONT_10X20] {
+            assert_eq!(shift_margin(&font), MARGIN);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! QR code of the gateway identity
+//!
+//! Technicians scan it into the asset system rather than typing the MAC: a
+//! long press on the overview page shows it until the next press, with the
+//! MAC and the firmware version. The code is encoded with `qrcodegen-no-heap` in buffers on the
+//! stack and drawn dark on a lit square, as printed codes are.
+
+use core::fmt::Write;
+
+use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+};
+use heapless::String;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+use crate::common::mesh::MacAddress;
+
+// 57 modules, the largest code a pixel per module fits in the panel height
+pub const QR_MAX_VERSION: u8 = 10;
+const QR_BUFFER_LEN: usize = Version::new(QR_MAX_VERSION).buffer_len();
+// Light modules kept around the code, at least
+const QUIET_ZONE: u32 = 1;
+
+pub type QrText = String<128>;
+
+/// `MAC:AABBCCDDEEFF FW:x.y.z`, alphanumeric for a smaller code
+pub fn identity_text(mac: &MacAddress, firmware: &str) -> QrText {
+    let mut text = QrText::new();
+    let _ = write!(text, "MAC:");
+    for byte in mac {
+        let _ = write!(text, "{:02X}", byte);
+    }
+    let _ = write!(text, " FW:{}", firmware);
+    text
+}
+
+/// Largest version drawn in a square of `side` pixels, none if too small
+fn max_version(side: u32) -> Option<u8> {
+    let modules = side.checked_sub(2 * QUIET_ZONE + 17)?;
+    let version = (modules / 4).min(QR_MAX_VERSION as u32) as u8;
+    (version >= 1).then_some(version)
+}
+
+/// Draw the code of `text` in the middle of the square `bounds`, as large as
+/// it fits. Returns the version of the code, none when `text` does not fit.
+pub fn draw_qr_code<D: DrawTarget<Color = BinaryColor>>(
+    text: &str,
+    bounds: Rectangle,
+    target: &mut D,
+) -> Result<Option<u8>, D::Error> {
+    let side = bounds.size.width.min(bounds.size.height);
+    let Some(max_version) = max_version(side) else {
+        return Ok(None);
+    };
+    let mut tempbuffer = [0; QR_BUFFER_LEN];
+    let mut outbuffer = [0; QR_BUFFER_LEN];
+    let Ok(code) = QrCode::encode_text(
+        text,
+        &mut tempbuffer,
+        &mut outbuffer,
+        QrCodeEcc::Medium,
+        Version::MIN,
+        Version::new(max_version),
+        None,
+        true,
+    ) else {
+        return Ok(None);
+    };
+
+    let size = code.size() as u32;
+    let scale = side / (size + 2 * QUIET_ZONE);
+    let square = Rectangle::new(bounds.top_left, Size::new(side, side));
+    square
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+        .draw(target)?;
+    let margin = ((side - size * scale) / 2) as i32;
+    let origin = bounds.top_left + Point::new(margin, margin);
+    let dark = PrimitiveStyle::with_fill(BinaryColor::Off);
+    for y in 0..size as i32 {
+        for x in 0..size as i32 {
+            if code.get_module(x, y) {
+                let module = Point::new(x, y) * scale as i32;
+                Rectangle::new(origin + module, Size::new(scale, scale))
+                    .into_styled(dark)
+                    .draw(target)?;
+            }
+        }
+    }
+    Ok(Some(code.version().value()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway_lib::display::framebuffer::{FrameBuffer, FRAME_HEIGHT};
+    use crate::gateway_lib::display::snapshot::decode_qr;
+
+    const MAC: MacAddress = [0xAA, 0xBB, 0xCC, 0x01, 0x02, 0x03];
+
+    fn square(side: u32) -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(side, side))
+    }
+
+    #[test]
+    fn identity_text_is_alphanumeric() {
+        let text = identity_text(&MAC, "0.1.0");
+        assert_eq!(text.as_str(), "MAC:AABBCC010203 FW:0.1.0");
+        assert!(qrcodegen_no_heap::QrSegment::is_alphanumeric(&text));
+    }
+
+    #[test]
+    fn code_decodes_back_to_the_identity() {
+        let text = identity_text(&MAC, "0.1.0");
+        let mut frame = FrameBuffer::new();
+        let version = draw_qr_code(&text, square(FRAME_HEIGHT), &mut frame).unwrap();
+        assert!(version.is_some());
+        assert_eq!(decode_qr(&frame, FRAME_HEIGHT), text.as_str());
+    }
+
+    #[test]
+    fn code_scales_to_the_square() {
+        let text = identity_text(&MAC, "0.1.0");
+        // Squares taller than the panel are clipped
+        for side in [32, 64].into_iter().filter(|side| *side <= FRAME_HEIGHT) {
+            let mut frame = FrameBuffer::new();
+            assert_eq!(
+                draw_qr_code(&text, square(side), &mut frame).unwrap(),
+                Some(2)
+            );
+            assert_eq!(decode_qr(&frame, side), text.as_str());
+        }
+    }
+
+    #[test]
+    fn text_or_square_too_large_is_not_drawn() {
+        let mut frame = FrameBuffer::new();
+        let long = "X".repeat(400);
+        assert_eq!(draw_qr_code(&long, square(64), &mut frame).unwrap(), None);
+        // 21 modules of version 1 and the quiet zone
+        assert_eq!(draw_qr_code("A", square(22), &mut frame).unwrap(), None);
+        assert_eq!(frame, FrameBuffer::new());
+        assert_eq!(draw_qr_code("A", square(23), &mut frame).unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,151 @@
+//! Golden images of the frames drawn by the tests
+//!
+//! A frame is compared with its PBM image in `snapshots/`, one directory per
+//! panel size (`--features oled-128x32` for the smaller one), written instead
+//! when missing or when `UPDATE_SNAPSHOTS` is set: new images are reviewed
+//! before they are committed.
+
+use std::fs;
+use std::path::PathBuf;
+
+use embedded_graphics::{
+    mono_font::{ascii, MonoFont, MonoTextStyle, MonoTextStyleBuilder},
+    pixelcolor::BinaryColor,
+    prelude::*,
+};
+
+use crate::common::analog::AnalogChannel;
+use crate::common::connectivity::{Connectivity, LinkEvent};
+use crate::gateway_lib::display::framebuffer::{FrameBuffer, FRAME_HEIGHT, FRAME_WIDTH};
+use crate::gateway_lib::display::pages::DisplayData;
+use crate::gateway_lib::liveness::{Liveness, NodeSummary};
+
+pub const CHANNELS: &[AnalogChannel] = &[
+    AnalogChannel::new("Boiler in", 0, 0.0, 100.0, "C"),
+    AnalogChannel::new("Pressure", 1, 0.0, 10.0, "bar"),
+];
+
+pub fn style(font: &'static MonoFont<'static>) -> MonoTextStyle<'static, BinaryColor> {
+    MonoTextStyleBuilder::new()
+        .font(font)
+        .text_color(BinaryColor::On)
+        .build()
+}
+
+pub fn small_style() -> MonoTextStyle<'static, BinaryColor> {
+    style(&ascii::FONT_5X8)
+}
+
+/// Gateway connected with three nodes, one offline and one low on battery
+pub fn sample_data() -> DisplayData {
+    let mut link = Connectivity::new();
+    for event in [
+        LinkEvent::WifiConnected,
+        LinkEvent::LeaseAcquired,
+        LinkEvent::HostResolved,
+        LinkEvent::TcpConnected,
+        LinkEvent::MqttConnected,
+    ] {
+        link.handle(event, 0);
+    }
+    let mut data = DisplayData {
+        uptime_s: 93_784,
+        temperature: 23.4,
+        rssi: -61,
+        wifi_percent: 78,
+        channel: 6,
+        ip: Some([192, 168, 1, 20]),
+        mac: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+        firmware: "0.1.0",
+        link,
+        registered_nodes: 3,
+        frames_received: 1_234,
+        frames_duplicate: 5,
+        frames_lost: 2,
+        frames_retried: 7,
+        ..DisplayData::default()
+    };
+    for step in 0..40 {
+        data.analog[0] = 40.0 + ((step % 10) as f32 - 5.0).abs();
+        data.analog[1] = if step == 30 {
+            f32::NAN
+        } else {
+            step as f32 / 8.0
+        };
+        data.record_history();
+    }
+    data.analog[0] = 42.5;
+    for index in 0..3u8 {
+        let node = NodeSummary {
+            mac: [0, 0, 0, 1, 2, index],
+            state: if index == 2 {
+                Liveness::Offline
+            } else {
+                Liveness::Online
+            },
+            rssi: Some(-70),
+            battery_v: Some(3.6),
+            battery_low: index == 1,
+        };
+        data.nodes.push(node).unwrap();
+    }
+    data
+}
+
+/// The frame as a plain PBM image
+pub fn to_pbm(frame: &FrameBuffer) -> String {
+    let mut pbm = format!("P1\n{} {}\n", FRAME_WIDTH, FRAME_HEIGHT);
+    for y in 0..FRAME_HEIGHT as i32 {
+        for x in 0..FRAME_WIDTH as i32 {
+            pbm.push(if frame.pixel(Point::new(x, y)) {
+                '1'
+            } else {
+                '0'
+            });
+        }
+        pbm.push('\n');
+    }
+    pbm
+}
+
+/// Text of the QR code drawn in the square of `side` pixels at the origin,
+/// read the way a phone camera does: upscaled and with a light margin
+pub fn decode_qr(frame: &FrameBuffer, side: u32) -> String {
+    const SCALE: usize = 4;
+    const MARGIN: i32 = 4;
+    let width = (side as usize + 2 * MARGIN as usize) * SCALE;
+    let mut image = rqrr::PreparedImage::prepare_from_greyscale(width, width, |x, y| {
+        let point = Point::new((x / SCALE) as i32 - MARGIN, (y / SCALE) as i32 - MARGIN);
+        let inside = point.x >= 0 && point.y >= 0 && point.x < side as i32 && point.y < side as i32;
+        // Lit pixels are the light modules
+        if !inside || frame.pixel(point) {
+            255
+        } else {
+            0
+        }
+    });
+    let grids = image.detect_grids();
+    assert_eq!(grids.len(), 1, "one QR code expected");
+    grids[0].decode().unwrap().1
+}
+
+pub fn assert_snapshot(name: &str, frame: &FrameBuffer) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/gateway_lib/display/snapshots")
+        .join(format!("{}x{}", FRAME_WIDTH, FRAME_HEIGHT))
+        .join(format!("{}.pbm", name));
+    let image = to_pbm(frame);
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() || !path.exists() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, image).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap();
+    assert!(
+        expected == image,
+        "frame {} differs from {}, UPDATE_SNAPSHOTS=1 to accept it:\n{}",
+        name,
+        path.display(),
+        image
+    );
+}
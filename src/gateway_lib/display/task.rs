@@ -0,0 +1,217 @@
+//! Display task of the gateway, drawing the pages on the SSD1306 over I2C
+
+use core::sync::atomic::Ordering;
+
+use embassy_time::{with_timeout, Duration, Instant};
+use embedded_graphics::{mono_font::MonoTextStyle, pixelcolor::BinaryColor, prelude::DrawTarget};
+use esp_hal::efuse::Efuse;
+use esp_hal::i2c::master::I2c;
+use esp_hal::Async;
+use ssd1306::{mode::BasicMode, prelude::*, Ssd1306Async};
+
+use super::framebuffer::{FrameBuffer, FRAME_HEIGHT, FRAME_WIDTH};
+use super::pages::{
+    draw_alarm_banner, draw_device_qr, draw_page, DisplayData, Page, PageAction, PageRotation,
+    PAGE_ROTATION_S, TREND_INTERVAL_S,
+};
+use super::power::{
+    shift_margin, PowerLevel, PowerManager, DIM_AFTER_S, OFF_AFTER_S, PIXEL_SHIFT_S,
+};
+use super::PanelSize;
+use crate::common::analog::reading;
+use crate::common::connectivity::connectivity;
+use crate::common::units::UNITS;
+use crate::common::wifi::{approx_rssi_to_percent, current_ip, CURRENT_CHANNEL, CURRENT_RSSI};
+use crate::gateway_lib::buttons::{Gesture, BUTTON_GESTURES};
+use crate::gateway_lib::espnow::{
+    MESH_FRAMES_DUPLICATE, MESH_FRAMES_LOST, MESH_FRAMES_RECEIVED, MESH_FRAMES_RETRIED,
+};
+use crate::gateway_lib::i2c_bus::SharedI2c;
+use crate::gateway_lib::liveness::NODE_SUMMARY;
+use crate::gateway_lib::registry::{
+    RegistryCommand, PAIRING_ACTIVE, REGISTERED_NODES, REGISTRY_COMMANDS, UNKNOWN_SENDER_FRAMES,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// The display shares I2C0 with the ADS1115 converters
+pub type GatewayDisplay =
+    Ssd1306Async<I2CInterface<SharedI2c<I2c<'static, Async>>>, PanelSize, BasicMode>;
+
+/// Refresh `data` from the state shared by the other tasks
+fn sample(data: &mut DisplayData) {
+    data.uptime_s = Instant::now().as_secs();
+    // A0 is the temperature of the heating loop
+    data.temperature = reading(0);
+    data.rssi = CURRENT_RSSI.load(Ordering::Relaxed);
+    data.wifi_percent = approx_rssi_to_percent(&CURRENT_RSSI);
+    data.channel = CURRENT_CHANNEL.load(Ordering::Relaxed);
+    data.ip = current_ip();
+    data.link = connectivity();
+    for (index, value) in data.analog.iter_mut().enumerate() {
+        *value = reading(index);
+    }
+    data.registered_nodes = REGISTERED_NODES.load(Ordering::Relaxed);
+    data.pairing = PAIRING_ACTIVE.load(Ordering::Relaxed);
+    // Kept until the ESP-NOW task signals a newer one
+    if let Some(nodes) = NODE_SUMMARY.try_take() {
+        data.nodes = nodes;
+    }
+    data.frames_received = MESH_FRAMES_RECEIVED.load(Ordering::Relaxed);
+    data.frames_duplicate = MESH_FRAMES_DUPLICATE.load(Ordering::Relaxed);
+    data.frames_lost = MESH_FRAMES_LOST.load(Ordering::Relaxed);
+    data.frames_retried = MESH_FRAMES_RETRIED.load(Ordering::Relaxed);
+    data.unknown_frames = UNKNOWN_SENDER_FRAMES.load(Ordering::Relaxed);
+    data.retain_acknowledged();
+}
+
+fn run_action(action: PageAction, data: &mut DisplayData) {
+    log::info!("Display page action {:?}", action);
+    match action {
+        PageAction::AcknowledgeAlarms => data.acknowledge_alarms(),
+        PageAction::StartPairing => {
+            if REGISTRY_COMMANDS
+                .try_send(RegistryCommand::StartPairing)
+                .is_err()
+            {
+                log::warn!("Registry command queue full, pairing not started");
+            }
+        }
+        PageAction::ShowDeviceQr => data.device_qr = true,
+    }
+}
+
+#[embassy_executor::task]
+pub async fn display_update_task(
+    display: &'static mut GatewayDisplay,
+    text_style: &'static MonoTextStyle<'static, BinaryColor>,
+    pages: &'static [&'static dyn Page<FrameBuffer>],
+) {
+    let mut data = DisplayData {
+        mac: Efuse::read_base_mac_address(),
+        firmware: env!("CARGO_PKG_VERSION"),
+        units: UNITS,
+        ..DisplayData::default()
+    };
+    let mut rotation = PageRotation::new(
+        pages.len(),
+        PAGE_ROTATION_S as u64 * 1000,
+        Instant::now().as_millis(),
+    );
+
+    let trend_interval = Duration::from_secs(TREND_INTERVAL_S as u64);
+    let mut record_at = Instant::now();
+
+    let margin = shift_margin(text_style.font);
+    let mut power = PowerManager::new(
+        DIM_AFTER_S as u64 * 1000,
+        OFF_AFTER_S as u64 * 1000,
+        PIXEL_SHIFT_S as u64 * 1000,
+        margin,
+        Instant::now().as_millis(),
+    );
+    // Level set on the panel, none to set the next one whatever it is
+    let mut level: Option<PowerLevel> = None;
+    let mut banner_inverse = false;
+
+    let mut frame = FrameBuffer::with_margin(margin);
+    // Last frame sent, none to send the next one whatever it is
+    let mut sent: Option<FrameBuffer> = None;
+
+    loop {
+        sample(&mut data);
+        if Instant::now() >= record_at {
+            data.record_history();
+            record_at = Instant::now() + trend_interval;
+        }
+        let now_ms = Instant::now().as_millis();
+        let index = rotation.tick(now_ms);
+        let new_alarm = data.first_new_alarm();
+        let wanted = power.update(now_ms, new_alarm.is_some());
+
+        if level != Some(wanted) {
+            let brightness = match wanted {
+                PowerLevel::On => Some(Brightness::NORMAL),
+                PowerLevel::Dimmed => Some(Brightness::DIMMEST),
+                PowerLevel::Off => None,
+            };
+            let set_level = match brightness {
+                Some(brightness) => match display.set_brightness(brightness).await {
+                    Ok(()) => display.set_display_on(true).await,
+                    Err(e) => Err(e),
+                },
+                None => display.set_display_on(false).await,
+            };
+            match set_level {
+                Ok(()) => {
+                    log::debug!("Display power {:?}", wanted);
+                    level = Some(wanted);
+                }
+                Err(e) => log::error!("Display power error: {:?}", e),
+            }
+        }
+
+        if wanted != PowerLevel::Off {
+            // Drawing in memory cannot fail
+            let _ = frame.clear(BinaryColor::Off);
+            if data.device_qr {
+                // No banner over the code
+                let _ = draw_device_qr(&data, *text_style, &mut frame);
+            } else if let Some(page) = pages.get(index) {
+                let _ = draw_page(*page, index, pages.len(), &data, *text_style, &mut frame);
+            }
+            if let Some(alarm) = new_alarm.filter(|_| !data.device_qr) {
+                banner_inverse = !banner_inverse;
+                let _ = draw_alarm_banner(&alarm, banner_inverse, *text_style, &mut frame);
+            }
+            frame.shift(power.pixel_shift(now_ms));
+            // Only the pages that changed, the flashing banner a few of them
+            let rows = match &sent {
+                Some(sent) => frame.changed_rows(sent),
+                None => Some(0..FRAME_HEIGHT),
+            };
+            if let Some(rows) = rows {
+                // From the left of the display RAM
+                let sent_frame = match display
+                    .set_draw_area((0, rows.start as u8), (FRAME_WIDTH as u8, rows.end as u8))
+                    .await
+                {
+                    Ok(()) => display.draw(frame.rows_bytes(rows)).await,
+                    Err(e) => Err(e),
+                };
+                match sent_frame {
+                    Ok(()) => sent = Some(frame.clone()),
+                    Err(e) => {
+                        log::error!("Display flush error: {:?}", e);
+                        sent = None;
+                    }
+                }
+            }
+        }
+
+        let Ok(gesture) = with_timeout(REFRESH_INTERVAL, BUTTON_GESTURES.receive()).await else {
+            continue;
+        };
+        let now_ms = Instant::now().as_millis();
+        if !power.wake(now_ms) {
+            log::debug!("Display woken up by {:?}", gesture);
+            continue;
+        }
+        if data.device_qr {
+            data.device_qr = false;
+            continue;
+        }
+        match gesture {
+            Gesture::Short => {
+                rotation.next(now_ms);
+            }
+            Gesture::Double => {
+                rotation.previous(now_ms);
+            }
+            Gesture::Long => match pages.get(rotation.current()).and_then(|page| page.action()) {
+                Some(action) => run_action(action, &mut data),
+                None => log::debug!("No action on display page {}", rotation.current()),
+            },
+        }
+    }
+}
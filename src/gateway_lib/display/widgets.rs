@@ -0,0 +1,271 @@
+//! Graphic widgets of the display pages
+//!
+//! Drawn with embedded-graphics primitives in the bounds given by the page,
+//! usually a text row or part of one:
+//! - `Sparkline`: recent values of a `History`, scaled to their own range
+//! - `BarGauge`: share of a range, e.g. the 4-20mA span of an analog channel
+//! - `SignalBars`: Wi-Fi signal strength in `SIGNAL_BARS` bars
+
+use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle, Rectangle},
+};
+use heapless::HistoryBuffer;
+
+// Points of a sparkline
+pub const SPARKLINE_LEN: usize = 32;
+pub const SIGNAL_BARS: u8 = 4;
+
+/// Latest values of a reading, oldest overwritten first
+pub type History = HistoryBuffer<f32, SPARKLINE_LEN>;
+
+/// Line through the values of `history`, the latest on the right edge. Non
+/// finite values leave a gap.
+pub struct Sparkline<'a> {
+    pub history: &'a History,
+    pub bounds: Rectangle,
+}
+
+impl Sparkline<'_> {
+    /// Lowest and highest finite values
+    fn range(&self) -> Option<(f32, f32)> {
+        self.history.iter().filter(|value| value.is_finite()).fold(
+            None,
+            |range, &value| match range {
+                None => Some((value, value)),
+                Some((low, high)) => Some((value.min(low), value.max(high))),
+            },
+        )
+    }
+}
+
+impl Drawable for Sparkline<'_> {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = BinaryColor>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let Some((low, high)) = self.range() else {
+            return Ok(());
+        };
+        let Some(bottom_right) = self.bounds.bottom_right() else {
+            return Ok(());
+        };
+        let (width, height) = (self.bounds.size.width, self.bounds.size.height);
+        // Full history across the width, a shorter one starts further right
+        let step = width.saturating_sub(1) as f32 / (SPARKLINE_LEN - 1) as f32;
+        let skipped = SPARKLINE_LEN - self.history.len();
+        let point = |index: usize, value: f32| {
+            let x = self.bounds.top_left.x + ((skipped + index) as f32 * step + 0.5) as i32;
+            // A flat line in the middle
+            let level = if high > low {
+                (value - low) / (high - low)
+            } else {
+                0.5
+            };
+            let y = bottom_right.y - (level * height.saturating_sub(1) as f32 + 0.5) as i32;
+            Point::new(x, y)
+        };
+
+        let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+        let mut previous: Option<Point> = None;
+        for (index, &value) in self.history.oldest_ordered().enumerate() {
+            if !value.is_finite() {
+                previous = None;
+                continue;
+            }
+            let current = point(index, value);
+            match previous {
+                Some(previous) => Line::new(previous, current)
+                    .into_styled(stroke)
+                    .draw(target)?,
+                None => Pixel(current, BinaryColor::On).draw(target)?,
+            }
+            previous = Some(current);
+        }
+        Ok(())
+    }
+}
+
+/// Outlined bar filled with the share of `low..high` reached by a value
+pub struct BarGauge {
+    // 0.0 to 1.0
+    ratio: f32,
+    bounds: Rectangle,
+}
+
+impl BarGauge {
+    /// `value` is clamped to the range, an invalid one leaves the bar empty
+    pub fn new(value: f32, low: f32, high: f32, bounds: Rectangle) -> Self {
+        let ratio = (value - low) / (high - low);
+        BarGauge {
+            ratio: if ratio.is_finite() {
+                ratio.clamp(0.0, 1.0)
+            } else {
+                0.0
+            },
+            bounds,
+        }
+    }
+}
+
+impl Drawable for BarGauge {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = BinaryColor>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(target)?;
+        // Fill inside the outline with a 1 pixel gap
+        let inner = self.bounds.offset(-2);
+        let filled = Size::new(
+            (inner.size.width as f32 * self.ratio + 0.5) as u32,
+            inner.size.height,
+        );
+        Rectangle::new(inner.top_left, filled)
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+            .draw(target)
+    }
+}
+
+/// Bars of an RSSI in dBm, 0 when not connected
+pub fn signal_bars(rssi: i8) -> u8 {
+    match rssi {
+        -55.. => 4,
+        -66..=-56 => 3,
+        -77..=-67 => 2,
+        -88..=-78 => 1,
+        _ => 0,
+    }
+}
+
+/// Bars of increasing height, the first `bars` filled, the others outlined
+pub struct SignalBars {
+    pub bars: u8,
+    pub bounds: Rectangle,
+}
+
+impl Drawable for SignalBars {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = BinaryColor>>(&self, target: &mut D) -> Result<(), D::Error> {
+        let count = SIGNAL_BARS as u32;
+        // 1 pixel between the bars
+        let bar_width = self.bounds.size.width.saturating_sub(count - 1) / count;
+        let Some(bottom_right) = self.bounds.bottom_right() else {
+            return Ok(());
+        };
+        if bar_width == 0 {
+            return Ok(());
+        }
+        for bar in 0..count {
+            let height = (self.bounds.size.height * (bar + 1) / count).max(1);
+            let top_left = Point::new(
+                self.bounds.top_left.x + (bar * (bar_width + 1)) as i32,
+                bottom_right.y + 1 - height as i32,
+            );
+            let style = if bar < self.bars as u32 {
+                PrimitiveStyle::with_fill(BinaryColor::On)
+            } else {
+                PrimitiveStyle::with_stroke(BinaryColor::On, 1)
+            };
+            Rectangle::new(top_left, Size::new(bar_width, height))
+                .into_styled(style)
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway_lib::display::framebuffer::FrameBuffer;
+    use crate::gateway_lib::display::snapshot::assert_snapshot;
+    use std::vec::Vec as StdVec;
+
+    fn history(values: &[f32]) -> History {
+        let mut history = History::new();
+        history.extend_from_slice(values);
+        history
+    }
+
+    #[test]
+    fn signal_bars_follow_rssi_thresholds() {
+        for (rssi, bars) in [
+            (0, 4),
+            (-55, 4),
+            (-56, 3),
+            (-66, 3),
+            (-67, 2),
+            (-77, 2),
+            (-78, 1),
+            (-88, 1),
+            (-89, 0),
+            (i8::MIN, 0),
+        ] {
+            assert_eq!(signal_bars(rssi), bars, "{} dBm", rssi);
+        }
+    }
+
+    #[test]
+    fn draws_sparklines() {
+        let mut frame = FrameBuffer::new();
+        let full: StdVec<f32> = (0..SPARKLINE_LEN).map(|i| (i % 8) as f32).collect();
+        let lines = [
+            // Full history across the width
+            history(&full),
+            // Shorter one on the right, with a gap for an invalid value
+            history(&[1.0, 3.0, f32::NAN, 2.0, 4.0, 0.0]),
+            // Flat line in the middle
+            history(&[5.0; 10]),
+            // Nothing to draw
+            history(&[f32::NAN; 4]),
+        ];
+        for (row, line) in lines.iter().enumerate() {
+            Sparkline {
+                history: line,
+                bounds: Rectangle::new(Point::new(0, row as i32 * 8), Size::new(64, 7)),
+            }
+            .draw(&mut frame)
+            .unwrap();
+        }
+        assert_snapshot("widget_sparklines", &frame);
+    }
+
+    #[test]
+    fn draws_bar_gauges_and_signal_bars() {
+        let mut frame = FrameBuffer::new();
+        // Empty, half, clamped to full and invalid
+        for (row, value) in [4.0, 12.0, 25.0, f32::NAN].into_iter().enumerate() {
+            BarGauge::new(
+                value,
+                4.0,
+                20.0,
+                Rectangle::new(Point::new(0, row as i32 * 8), Size::new(60, 7)),
+            )
+            .draw(&mut frame)
+            .unwrap();
+        }
+        // Every level at the size of the network page, 3 pixels per bar
+        for bars in 0..=SIGNAL_BARS {
+            let top_left = Point::new(64 + (bars % 3) as i32 * 20, (bars / 3) as i32 * 13);
+            SignalBars {
+                bars,
+                bounds: Rectangle::new(top_left, Size::new(15, 12)),
+            }
+            .draw(&mut frame)
+            .unwrap();
+        }
+        // Too narrow for a bar each, nothing drawn
+        SignalBars {
+            bars: 4,
+            bounds: Rectangle::new(Point::new(104, 13), Size::new(3, 12)),
+        }
+        .draw(&mut frame)
+        .unwrap();
+        assert_snapshot("widget_gauges", &frame);
+    }
+}
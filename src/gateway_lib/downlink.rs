@@ -0,0 +1,229 @@
+//! Configuration changes queued by the gateway for the mesh nodes
+//!
+//! Nodes only listen right after their uplink, so a change is kept here until
+//! the node reports it applied. After the ack of each uplink the ESP-NOW task
+//! sends a `ConfigUpdate` with the gateway clock and the pending configuration
+//! of the node, if any. The node applies it and reports its id in the
+//! `NodeStatus` of its next readings; until then it is sent again after every
+//! uplink.
+//!
+//! Changes queued for the same node are merged under a new id, the latest
+//! value of each field wins, and the whole configuration is sent. It is kept
+//! once applied and sent again when the node reports another id, e.g. after a
+//! power loss cleared its RTC memory. The queue lives in RAM only.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use heapless::FnvIndexMap;
+
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{ConfigUpdate, NodeConfig};
+use crate::gateway_lib::registry::MAX_NODES;
+
+/// Changes requested by MQTT, applied by the ESP-NOW task
+pub static DOWNLINK_COMMANDS: Channel<CriticalSectionRawMutex, DownlinkCommand, 4> = Channel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DownlinkCommand {
+    pub node: MacAddress,
+    pub change: NodeConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownlinkError {
+    // More configured nodes than registered nodes
+    QueueFull,
+    EmptyChange,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct QueuedConfig {
+    id: u16,
+    config: NodeConfig,
+    // Reported by the node in its last readings
+    applied: bool,
+}
+
+/// Configuration of each node
+#[derive(Clone, Debug, Default)]
+pub struct DownlinkQueue {
+    nodes: FnvIndexMap<MacAddress, QueuedConfig, MAX_NODES>,
+    last_id: u16,
+}
+
+impl DownlinkQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a change for `node`, merged with its configuration. Returns the
+    /// id the node will report once applied.
+    pub fn queue(&mut self, node: MacAddress, change: &NodeConfig) -> Result<u16, DownlinkError> {
+        if change.is_empty() {
+            return Err(DownlinkError::EmptyChange);
+        }
+        let mut config = self
+            .nodes
+            .get(&node)
+            .map_or(NodeConfig::default(), |queued| queued.config);
+        config.merge(change);
+        let id = self.next_id();
+        self.nodes
+            .insert(
+                node,
+                QueuedConfig {
+                    id,
+                    config,
+                    applied: false,
+                },
+            )
+            .map_err(|_| DownlinkError::QueueFull)?;
+        Ok(id)
+    }
+
+    // Never 0, so a node that applied nothing yet never matches
+    fn next_id(&mut self) -> u16 {
+        self.last_id = self.last_id.wrapping_add(1).max(1);
+        self.last_id
+    }
+
+    /// Configuration of `node` with its id, while not applied
+    pub fn pending(&self, node: &MacAddress) -> Option<(u16, NodeConfig)> {
+        self.nodes
+            .get(node)
+            .filter(|queued| !queued.applied)
+            .map(|queued| (queued.id, queued.config))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The node reported the configuration `reported` applied in its
+    /// readings, returns the id it just applied. Any other id than the latest
+    /// one, or none, has the configuration sent again.
+    pub fn on_status(&mut self, node: &MacAddress, reported: Option<u16>) -> Option<u16> {
+        let queued = self.nodes.get_mut(node)?;
+        let applied = reported == Some(queued.id);
+        let newly = applied && !queued.applied;
+        queued.applied = applied;
+        newly.then_some(queued.id)
+    }
+
+    pub fn remove(&mut self, node: &MacAddress) {
+        self.nodes.remove(node);
+    }
+
+    /// Update sent after the ack of an uplink of `node` at `now_ms`, without
+    /// the authentication tag
+    pub fn update_for(&self, node: &MacAddress, now_ms: u64) -> ConfigUpdate {
+        let pending = self.pending(node);
+        ConfigUpdate {
+            id: pending.map(|(id, _)| id),
+            time_ms: now_ms,
+            config: pending.map(|(_, config)| config).unwrap_or_default(),
+            auth: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE: MacAddress = [1, 2, 3, 4, 5, 6];
+    const OTHER: MacAddress = [6, 5, 4, 3, 2, 1];
+
+    fn interval(transmit_interval_s: u32) -> NodeConfig {
+        NodeConfig {
+            transmit_interval_s: Some(transmit_interval_s),
+            ..NodeConfig::default()
+        }
+    }
+
+    #[test]
+    fn pending_changes_are_merged_under_a_new_id() {
+        let mut queue = DownlinkQueue::new();
+        let first = queue.queue(NODE, &interval(60)).unwrap();
+        let alarm = NodeConfig {
+            alarm_high: Some(30.0),
+            ..NodeConfig::default()
+        };
+        let second = queue.queue(NODE, &alarm).unwrap();
+        assert_ne!(first, second);
+        let second = queue.queue(NODE, &interval(120)).unwrap();
+
+        let (id, config) = queue.pending(&NODE).unwrap();
+        assert_eq!(id, second);
+        assert_eq!(config.transmit_interval_s, Some(120));
+        assert_eq!(config.alarm_high, Some(30.0));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn applied_configuration_is_sent_again_on_another_id() {
+        let mut queue = DownlinkQueue::new();
+        let id = queue.queue(NODE, &interval(60)).unwrap();
+        // Not applied yet, or an older change
+        assert_eq!(queue.on_status(&NODE, None), None);
+        assert_eq!(queue.on_status(&NODE, Some(id.wrapping_sub(1))), None);
+        assert!(queue.pending(&NODE).is_some());
+
+        assert_eq!(queue.on_status(&NODE, Some(id)), Some(id));
+        assert_eq!(queue.pending(&NODE), None);
+        // Reported once
+        assert_eq!(queue.on_status(&NODE, Some(id)), None);
+
+        // Lost with the RTC memory of the node
+        assert_eq!(queue.on_status(&NODE, None), None);
+        assert_eq!(queue.pending(&NODE), Some((id, interval(60))));
+        assert_eq!(queue.on_status(&NODE, Some(id)), Some(id));
+
+        // Nothing queued for the other nodes
+        assert_eq!(queue.on_status(&OTHER, Some(id)), None);
+    }
+
+    #[test]
+    fn ids_skip_zero_when_wrapping() {
+        let mut queue = DownlinkQueue::new();
+        queue.last_id = u16::MAX - 1;
+        assert_eq!(queue.queue(NODE, &interval(60)), Ok(u16::MAX));
+        assert_eq!(queue.queue(NODE, &interval(60)), Ok(1));
+        assert_eq!(queue.queue(OTHER, &interval(60)), Ok(2));
+    }
+
+    #[test]
+    fn empty_changes_are_refused() {
+        let mut queue = DownlinkQueue::new();
+        assert_eq!(
+            queue.queue(NODE, &NodeConfig::default()),
+            Err(DownlinkError::EmptyChange)
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn updates_carry_the_change_of_their_node_only() {
+        let mut queue = DownlinkQueue::new();
+        let id = queue.queue(NODE, &interval(60)).unwrap();
+
+        let update = queue.update_for(&NODE, 5_000);
+        assert_eq!(update.id, Some(id));
+        assert_eq!(update.time_ms, 5_000);
+        assert_eq!(update.config, interval(60));
+        assert_eq!(update.auth, None);
+
+        // Clock only for the others, and once applied
+        let update = queue.update_for(&OTHER, 5_000);
+        assert_eq!((update.id, update.config), (None, NodeConfig::default()));
+        queue.on_status(&NODE, Some(id));
+        assert_eq!(queue.update_for(&NODE, 6_000).id, None);
+
+        queue.remove(&NODE);
+        assert!(queue.is_empty());
+    }
+}
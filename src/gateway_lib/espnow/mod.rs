@@ -0,0 +1,1138 @@
+//! ESP-NOW receiver forwarding mesh sensor readings to MQTT
+//!
+//! The receiver runs alongside the STA connection (same radio channel as the
+//! AP). Each `mesh_proto` frame is decoded, its sender MAC tracked and the
+//! readings queued on the shared publish path. Only nodes of the registry are
+//! forwarded, join requests are accepted while pairing mode is active. With
+//! keys configured, payloads are authenticated by `security::KeyManager`.
+//!
+//! Frames asking for it are acked once processed, duplicates of an already
+//! processed frame are acked again but not forwarded (`common::reliability`).
+//! Per-node delivery counters are published on `/diagnostics/mesh/{node_mac}`.
+//!
+//! The gateway is the root of the multi-hop mesh (`common::routing`): it
+//! broadcasts a beacon every `BEACON_INTERVAL_MS`, unwraps the frames carried
+//! by registered relays and sends the acks back through the same relay. The
+//! beacon carries the AP channel (`wifi::CURRENT_CHANNEL`) and is rebroadcast
+//! right away when it changes after a roam, for the nodes to follow.
+//!
+//! Registered nodes are watched by `liveness::LivenessTable`, their state
+//! changes are published retained on `/status/node/{mac}`. The power fields of
+//! their readings go to `battery::BatteryMonitor`, published on
+//! `/diagnostics/power/{mac}` with the low-battery alarms retained on
+//! `/alarms/battery/{mac}`.
+//!
+//! Node firmware updates (`gateway_lib::ota`) are staged and served from here:
+//! the ack of a node queued for the update carries `FLAG_OTA_PENDING` and is
+//! followed by the offer, then each `OtaRequest` is answered with the chunk
+//! read from the staged image. With keys configured, the requests and reports
+//! of the nodes are sealed like their readings.
+//!
+//! Every acked uplink is followed by a `Config` frame with the gateway clock
+//! and the change queued for the node by `gateway_lib::downlink`, if any.
+//!
+//! The decisions are taken by `MeshForwarder`, free of the radio and the flash
+//! which are driven by `espnow_receive_task` (`task`, with the `esp32`
+//! feature).
+
+use core::sync::atomic::AtomicU32;
+use heapless::{FnvIndexMap, Vec};
+use log::{debug, warn};
+
+use crate::common::keys::CryptoError;
+use crate::common::mesh::{format_mac, MacAddress};
+use crate::common::mesh_proto::{
+    Ack, Frame, FrameBuffer, Header, JoinRequest, LinkReport, MeshReading, MessageType, NodeStatus,
+    OtaOffer, OtaReport, OtaRequest, ProtoError, Quality, TlvIter, FLAG_ACK_REQUESTED,
+    FLAG_ENCRYPTED, FLAG_OTA_PENDING, MAX_PAYLOAD_LEN,
+};
+use crate::common::reliability::{LinkCounters, SeqStatus, SequenceWindow};
+use crate::common::routing::{Outbound, RouteDrop, Router, BEACON_INTERVAL_MS};
+use crate::gateway_lib::battery::{BatteryAlarm, BatteryMonitor, NodePower};
+use crate::gateway_lib::downlink::DownlinkQueue;
+use crate::gateway_lib::liveness::{
+    battery_voltage, Heard, LivenessTable, NodeHealth, NodeSummaries, NodeSummary,
+};
+use crate::gateway_lib::ota::{OtaCampaign, StagedImage, TargetState};
+use crate::gateway_lib::publish::Reading;
+use crate::gateway_lib::registry::{JoinOutcome, NodeRegistry, RegistryCommand, RegistryError};
+use crate::gateway_lib::security::KeyManager;
+
+#[cfg(feature = "esp32")]
+mod task;
+
+#[cfg(feature = "esp32")]
+pub use task::espnow_receive_task;
+
+// Must be a power of 2 for the index map
+pub const MAX_TRACKED_SENDERS: usize = 16;
+pub const MAX_READINGS_PER_FRAME: usize = 8;
+
+pub const LINK_REPORT_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+/// Delivery counters summed over all nodes, for the local diagnostics
+pub static MESH_FRAMES_RECEIVED: AtomicU32 = AtomicU32::new(0);
+pub static MESH_FRAMES_DUPLICATE: AtomicU32 = AtomicU32::new(0);
+pub static MESH_FRAMES_LOST: AtomicU32 = AtomicU32::new(0);
+pub static MESH_FRAMES_RETRIED: AtomicU32 = AtomicU32::new(0);
+
+pub type FrameReadings = Vec<Reading, MAX_READINGS_PER_FRAME>;
+
+/// What a received frame turned into
+// Only moved out of `handle_frame` once per frame, never stored
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum Forwarded {
+    Readings(FrameReadings),
+    Joined(JoinOutcome),
+    // Already processed, only acked again
+    Duplicate,
+    // Relay beacon, only refreshes the relay liveness
+    Beacon,
+    // Chunk to send back from the staged image, with the seq of the request
+    // and the offset when a progress step was crossed
+    OtaRequest {
+        request: OtaRequest,
+        seq: u16,
+        progress: Option<u32>,
+    },
+    // Outcome of an update, with the staged image it is about
+    OtaReport {
+        report: OtaReport,
+        image: Option<StagedImage>,
+    },
+}
+
+/// Outcome of a frame and the ack to send back to its node
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    // Node the frame comes from, relayed or not
+    pub node: MacAddress,
+    pub forwarded: Forwarded,
+    pub ack: Option<Outbound>,
+    // Clock and pending configuration sent after the ack
+    pub config: Option<Outbound>,
+    // Id of the queued configuration the node reported applied
+    pub config_applied: Option<u16>,
+    // Firmware offer sent after the ack, to the nodes queued for an update
+    pub offer: Option<Outbound>,
+    // Staged image the node reported running, its update is done
+    pub updated: Option<StagedImage>,
+    // Node health when it just came online, to publish
+    pub came_online: Option<NodeHealth>,
+    // Power telemetry of new readings, and the alarm when it changed
+    pub power: Option<NodePower>,
+    pub battery_alarm: Option<BatteryAlarm>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SenderStats {
+    pub frames: u32,
+    pub errors: u32,
+    pub last_seen_ms: u64,
+    pub last_rssi: i8,
+    pub last_seq: u16,
+    // Relays crossed by the last frame, 0 when heard directly
+    pub relays: u8,
+    pub link: LinkCounters,
+    window: SequenceWindow,
+    last_report_ms: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForwardError {
+    Frame(ProtoError),
+    // Header node id differs from the ESP-NOW source address
+    SenderMismatch,
+    UnexpectedMessage(MessageType),
+    Route(RouteDrop),
+    UnknownSender,
+    Join(RegistryError),
+    Crypto(CryptoError),
+    // Sealed frame while no keys are configured
+    EncryptionDisabled,
+    TooManySenders,
+    TopicOverflow,
+}
+
+/// Decodes frames, filters them on the registry and keeps per-sender statistics
+pub struct MeshForwarder {
+    senders: FnvIndexMap<MacAddress, SenderStats, MAX_TRACKED_SENDERS>,
+    pub registry: NodeRegistry,
+    // None when no keys are configured, frames are then accepted in clear
+    pub keys: Option<KeyManager>,
+    pub router: Router,
+    pub liveness: LivenessTable,
+    pub ota: OtaCampaign,
+    pub downlink: DownlinkQueue,
+    pub battery: BatteryMonitor,
+}
+
+impl MeshForwarder {
+    pub fn new(own: MacAddress, registry: NodeRegistry, keys: Option<KeyManager>) -> Self {
+        MeshForwarder {
+            senders: FnvIndexMap::new(),
+            registry,
+            keys,
+            router: Router::gateway(own),
+            liveness: LivenessTable::new(),
+            ota: OtaCampaign::new(),
+            downlink: DownlinkQueue::new(),
+            battery: BatteryMonitor::new(),
+        }
+    }
+
+    /// Liveness and battery of the watched nodes, for the display
+    pub fn node_summaries(&self) -> NodeSummaries {
+        self.liveness
+            .iter()
+            .map(|(mac, health)| NodeSummary {
+                mac: *mac,
+                state: health.state,
+                rssi: health.rssi,
+                battery_v: health.battery_v,
+                battery_low: self.battery.is_low(mac),
+            })
+            .collect()
+    }
+
+    pub fn sender(&self, mac: &MacAddress) -> Option<&SenderStats> {
+        self.senders.get(mac)
+    }
+
+    pub fn sender_count(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// Stats of `mac`, a full table makes room by dropping the senders no
+    /// longer registered first, then the one heard the longest ago
+    fn track(&mut self, mac: MacAddress, now_ms: u64) -> Result<&mut SenderStats, ForwardError> {
+        if !self.senders.contains_key(&mac) {
+            if self.senders.len() == MAX_TRACKED_SENDERS {
+                let evicted = self
+                    .senders
+                    .iter()
+                    .min_by_key(|(mac, stats)| (self.registry.is_allowed(mac), stats.last_seen_ms))
+                    .map(|(mac, _)| *mac);
+                if let Some(evicted) = evicted {
+                    debug!("No longer tracking sender {}", format_mac(&evicted));
+                    self.senders.remove(&evicted);
+                }
+            }
+            let stats = SenderStats {
+                frames: 0,
+                errors: 0,
+                last_seen_ms: now_ms,
+                last_rssi: 0,
+                last_seq: 0,
+                relays: 0,
+                link: LinkCounters::default(),
+                window: SequenceWindow::default(),
+                last_report_ms: now_ms,
+            };
+            self.senders
+                .insert(mac, stats)
+                .map_err(|_| ForwardError::TooManySenders)?;
+        }
+        self.senders
+            .get_mut(&mac)
+            .ok_or(ForwardError::TooManySenders)
+    }
+
+    /// Decode a received frame, readings of bad quality are dropped and
+    /// frames of unknown senders are counted then dropped
+    pub fn handle_frame(
+        &mut self,
+        src: MacAddress,
+        rssi: i8,
+        data: &[u8],
+        now_ms: u64,
+    ) -> Result<Delivery, ForwardError> {
+        let frame = match Frame::decode(data) {
+            Ok(frame) if frame.header.node_id == src => frame,
+            Ok(_) => return self.reject(src, ForwardError::SenderMismatch),
+            Err(e) => return self.reject(src, ForwardError::Frame(e)),
+        };
+        let msg_type = frame.header.msg_type;
+        if !matches!(msg_type, MessageType::Relay | MessageType::Beacon) {
+            return self.handle_node_frame(&frame, src, 0, rssi, now_ms);
+        }
+
+        // Relays are registered nodes themselves
+        if !self.registry.is_allowed(&src) {
+            self.registry.record_unknown(src);
+            return Err(ForwardError::UnknownSender);
+        }
+        if msg_type == MessageType::Beacon {
+            // Nothing to learn for the root, but the relay is alive
+            let heard = Heard {
+                rssi: Some(rssi),
+                status: NodeStatus {
+                    transmit_interval_s: Some((BEACON_INTERVAL_MS / 1000) as u32),
+                    ..NodeStatus::default()
+                },
+                ..Heard::default()
+            };
+            return Ok(Delivery {
+                node: src,
+                forwarded: Forwarded::Beacon,
+                ack: None,
+                config: None,
+                config_applied: None,
+                offer: None,
+                updated: None,
+                came_online: self.liveness.on_frame(src, &heard, now_ms),
+                power: None,
+                battery_alarm: None,
+            });
+        }
+        match Router::unwrap(&frame) {
+            Ok(inner) => self.handle_node_frame(&inner.frame, src, inner.relays(), rssi, now_ms),
+            Err(e) => self.reject(src, ForwardError::Route(e)),
+        }
+    }
+
+    /// Frame of a node, received from `via` after crossing `relays` relays
+    fn handle_node_frame(
+        &mut self,
+        frame: &Frame<'_>,
+        via: MacAddress,
+        relays: u8,
+        rssi: i8,
+        now_ms: u64,
+    ) -> Result<Delivery, ForwardError> {
+        let src = frame.header.node_id;
+        let (forwarded, mut heard) = match frame.header.msg_type {
+            MessageType::JoinRequest => {
+                let request = match JoinRequest::from_payload(frame.payload) {
+                    Ok(request) => request,
+                    Err(e) => return self.reject(src, ForwardError::Frame(e)),
+                };
+                let outcome = self
+                    .registry
+                    .handle_join(src, request.name, request.channel, request.security, now_ms)
+                    .map_err(ForwardError::Join)?;
+                let heard = Heard {
+                    seq: Some(frame.header.seq),
+                    ..Heard::default()
+                };
+                (Forwarded::Joined(outcome), heard)
+            }
+            // OTA frames are sealed like the readings, the chunks are then
+            // checked against the authenticated offer
+            MessageType::Readings | MessageType::OtaRequest | MessageType::OtaReport => {
+                if !self.registry.is_allowed(&src) {
+                    self.registry.record_unknown(src);
+                    return Err(ForwardError::UnknownSender);
+                }
+                // ESP-NOW link encryption ends at the first relay
+                if relays > 0 && self.keys.is_some() && !frame.header.has_flag(FLAG_ENCRYPTED) {
+                    return self.reject(src, ForwardError::Crypto(CryptoError::NotEncrypted));
+                }
+                // Authenticated before it is classified, a forged copy of an
+                // accepted frame must not be acked as its duplicate
+                let mut plaintext = [0u8; MAX_PAYLOAD_LEN];
+                let payload = match self.open(frame, &mut plaintext) {
+                    Ok(payload) => Some(payload),
+                    // Genuine retransmit of a frame already accepted
+                    Err(ForwardError::Crypto(CryptoError::Replayed)) => None,
+                    Err(e) => return self.reject(src, e),
+                };
+                let stats = self.track(src, now_ms)?;
+                let duplicate = stats.window.classify(frame.header.seq) == SeqStatus::Duplicate;
+                match payload {
+                    Some(payload) if !duplicate => self.accept(frame, payload, now_ms)?,
+                    _ => {
+                        stats.link.duplicates = stats.link.duplicates.wrapping_add(1);
+                        (Forwarded::Duplicate, Heard::default())
+                    }
+                }
+            }
+            // Only sent by the gateway, relay frames are unwrapped once
+            msg_type @ (MessageType::Ack
+            | MessageType::Beacon
+            | MessageType::Relay
+            | MessageType::OtaOffer
+            | MessageType::OtaChunk
+            | MessageType::Config) => {
+                return self.reject(src, ForwardError::UnexpectedMessage(msg_type))
+            }
+        };
+        // The status is only decoded from new readings
+        let (config_applied, power, battery_alarm) = match forwarded {
+            Forwarded::Readings(_) => {
+                let (power, alarm) =
+                    self.battery
+                        .on_readings(src, heard.battery_v, &heard.status, now_ms);
+                (
+                    self.downlink.on_status(&src, heard.status.config_applied),
+                    Some(power),
+                    alarm,
+                )
+            }
+            _ => (None, None, None),
+        };
+
+        self.router.learn(src, via, now_ms);
+        // Only meaningful for the nodes heard directly
+        heard.rssi = (relays == 0).then_some(rssi);
+        let came_online = self.liveness.on_frame(src, &heard, now_ms);
+        if let Ok(stats) = self.track(src, now_ms) {
+            stats.last_seen_ms = now_ms;
+            if relays == 0 {
+                stats.last_rssi = rssi;
+            }
+            stats.relays = relays;
+            stats.last_seq = frame.header.seq;
+            stats.frames = stats.frames.wrapping_add(1);
+        }
+
+        let uplink = frame.header.msg_type == MessageType::Readings;
+        let target = if uplink {
+            let firmware = self.liveness.get(&src).and_then(|health| health.firmware);
+            self.ota.target_state(&src, firmware)
+        } else {
+            TargetState::Idle
+        };
+        let (ack, config, offer) = if frame.header.has_flag(FLAG_ACK_REQUESTED) {
+            let flags = match target {
+                TargetState::Pending(_) => FLAG_OTA_PENDING,
+                _ => 0,
+            };
+            let ack = self
+                .ack_for(frame, flags)
+                .and_then(|ack| self.router.route_down(&ack, now_ms).ok());
+            let config = uplink
+                .then(|| self.config_for(frame, now_ms))
+                .flatten()
+                .and_then(|config| self.router.route_down(&config, now_ms).ok());
+            let offer = match target {
+                TargetState::Pending(image) => self
+                    .offer_for(frame, &image)
+                    .and_then(|offer| self.router.route_down(&offer, now_ms).ok()),
+                _ => None,
+            };
+            (ack, config, offer)
+        } else {
+            (None, None, None)
+        };
+        Ok(Delivery {
+            node: src,
+            forwarded,
+            ack,
+            config,
+            config_applied,
+            offer,
+            updated: match target {
+                TargetState::Updated(image) => Some(image),
+                _ => None,
+            },
+            came_online,
+            power,
+            battery_alarm,
+        })
+    }
+
+    /// Payload of a node frame, authenticated when keys are configured
+    fn open<'a>(
+        &mut self,
+        frame: &Frame<'a>,
+        out: &'a mut [u8; MAX_PAYLOAD_LEN],
+    ) -> Result<&'a [u8], ForwardError> {
+        match (&mut self.keys, self.registry.get(&frame.header.node_id)) {
+            (Some(keys), Some(node)) => keys.open(node, frame, out).map_err(ForwardError::Crypto),
+            _ if frame.header.has_flag(FLAG_ENCRYPTED) => Err(ForwardError::EncryptionDisabled),
+            _ => Ok(frame.payload),
+        }
+    }
+
+    /// Decode a new authenticated frame, only new requests and reports move
+    /// the campaign
+    fn accept(
+        &mut self,
+        frame: &Frame<'_>,
+        payload: &[u8],
+        now_ms: u64,
+    ) -> Result<(Forwarded, Heard), ForwardError> {
+        let src = frame.header.node_id;
+        let seq = frame.header.seq;
+        let forwarded = match frame.header.msg_type {
+            MessageType::OtaRequest => OtaRequest::from_payload(payload)
+                .map(|request| Forwarded::OtaRequest {
+                    progress: self.ota.on_request(&src, &request),
+                    request,
+                    seq,
+                })
+                .map_err(ForwardError::Frame),
+            MessageType::OtaReport => OtaReport::from_payload(payload)
+                .map(|report| Forwarded::OtaReport {
+                    image: self.ota.on_report(&src, &report),
+                    report,
+                })
+                .map_err(ForwardError::Frame),
+            _ => decode_readings(&src, payload, now_ms).map(Forwarded::Readings),
+        };
+        let forwarded = match forwarded {
+            Ok(forwarded) => forwarded,
+            Err(e) => return self.reject(src, e),
+        };
+
+        let stats = self.track(src, now_ms)?;
+        let lost = stats.window.record(seq);
+        stats.link.lost = stats.link.lost.wrapping_add(lost);
+        stats.link.received = stats.link.received.wrapping_add(1);
+        if let Some(report) = LinkReport::from_payload(payload) {
+            stats.link.retried = report.retries as u32;
+            stats.link.given_up = report.given_up as u32;
+        }
+        let heard = match forwarded {
+            Forwarded::Readings(_) => Heard {
+                seq: Some(seq),
+                rssi: None,
+                battery_v: battery_voltage(payload),
+                status: NodeStatus::from_payload(payload),
+            },
+            _ => Heard {
+                seq: Some(seq),
+                ..Heard::default()
+            },
+        };
+        Ok((forwarded, heard))
+    }
+
+    /// Ack frame for a processed frame, authenticated when it was sealed
+    fn ack_for(&self, frame: &Frame<'_>, flags: u8) -> Option<FrameBuffer> {
+        let ack = match &self.keys {
+            Some(keys) => keys.ack_for(frame),
+            None => Ack::default(),
+        };
+        let header =
+            Header::new(MessageType::Ack, frame.header.node_id, frame.header.seq).with_flags(flags);
+        ack.encode(&header).ok()
+    }
+
+    /// Clock and pending change of the node, authenticated like the ack
+    fn config_for(&self, frame: &Frame<'_>, now_ms: u64) -> Option<FrameBuffer> {
+        let mut update = self.downlink.update_for(&frame.header.node_id, now_ms);
+        if let Some(keys) = &self.keys {
+            update.auth = keys.config_auth(frame, &update);
+        }
+        let header = Header::new(MessageType::Config, frame.header.node_id, frame.header.seq);
+        update.encode(&header).ok()
+    }
+
+    /// Offer of the staged image, authenticated like the ack
+    fn offer_for(&self, frame: &Frame<'_>, image: &StagedImage) -> Option<FrameBuffer> {
+        let mut offer = OtaOffer {
+            size: image.size,
+            digest: image.digest,
+            version: image.version,
+            auth: None,
+        };
+        if let Some(keys) = &self.keys {
+            offer.auth = keys.offer_auth(frame, &offer);
+        }
+        let header = Header::new(
+            MessageType::OtaOffer,
+            frame.header.node_id,
+            frame.header.seq,
+        );
+        offer.encode(&header).ok()
+    }
+
+    /// Delivery counters of a node when its periodic report is due
+    pub fn take_link_report(&mut self, mac: &MacAddress, now_ms: u64) -> Option<LinkCounters> {
+        let stats = self.senders.get_mut(mac)?;
+        if now_ms.saturating_sub(stats.last_report_ms) < LINK_REPORT_INTERVAL_MS {
+            return None;
+        }
+        stats.last_report_ms = now_ms;
+        Some(stats.link)
+    }
+
+    /// Counters summed over all the nodes
+    pub fn link_totals(&self) -> LinkCounters {
+        self.senders
+            .values()
+            .fold(LinkCounters::default(), |total, stats| LinkCounters {
+                received: total.received.wrapping_add(stats.link.received),
+                duplicates: total.duplicates.wrapping_add(stats.link.duplicates),
+                lost: total.lost.wrapping_add(stats.link.lost),
+                retried: total.retried.wrapping_add(stats.link.retried),
+                given_up: total.given_up.wrapping_add(stats.link.given_up),
+            })
+    }
+
+    /// Count an invalid frame, only registered senders get their stats tracked
+    fn reject<T>(&mut self, src: MacAddress, error: ForwardError) -> Result<T, ForwardError> {
+        if !self.registry.is_allowed(&src) {
+            self.registry.record_unknown(src);
+        } else if let Some(stats) = self.senders.get_mut(&src) {
+            stats.errors = stats.errors.wrapping_add(1);
+        }
+        Err(error)
+    }
+
+    /// Apply a command from the button or MQTT, returns whether the registry
+    /// changed and must be persisted
+    pub fn apply(&mut self, command: RegistryCommand, now_ms: u64) -> bool {
+        match command {
+            RegistryCommand::StartPairing => {
+                self.registry.start_pairing(now_ms);
+                false
+            }
+            RegistryCommand::StopPairing => {
+                self.registry.stop_pairing();
+                false
+            }
+            RegistryCommand::Remove(mac) => {
+                self.senders.remove(&mac);
+                self.liveness.remove(&mac);
+                self.downlink.remove(&mac);
+                self.battery.remove(&mac);
+                if let Some(keys) = &mut self.keys {
+                    keys.forget(&mac);
+                }
+                self.registry.remove(&mac)
+            }
+            RegistryCommand::RotateKey(mac) => match self.registry.rotate_key(&mac) {
+                Ok(previous_epoch) => {
+                    if let Some(keys) = &mut self.keys {
+                        keys.on_rotate(mac, previous_epoch);
+                    }
+                    true
+                }
+                Err(e) => {
+                    warn!("Cannot rotate key of {}: {:?}", format_mac(&mac), e);
+                    false
+                }
+            },
+        }
+    }
+}
+
+fn decode_readings(
+    node_id: &MacAddress,
+    payload: &[u8],
+    now_ms: u64,
+) -> Result<FrameReadings, ForwardError> {
+    let node_mac = format_mac(node_id);
+    let mut readings = FrameReadings::new();
+    for tlv in TlvIter::new(payload) {
+        let tlv = tlv.map_err(ForwardError::Frame)?;
+        let Some(mesh_reading) = MeshReading::from_tlv(&tlv).map_err(ForwardError::Frame)? else {
+            continue;
+        };
+        if mesh_reading.quality == Quality::Bad {
+            continue;
+        }
+        let mut reading =
+            Reading::from_mesh(&node_mac, &mesh_reading).ok_or(ForwardError::TopicOverflow)?;
+        reading.timestamp_ms = now_ms;
+        readings
+            .push(reading)
+            .map_err(|_| ForwardError::Frame(ProtoError::Overflow))?;
+    }
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::keys::{derive_aead_key, seal, MeshKey, KEY_LEN};
+    use crate::common::mesh_proto::{
+        encode_join_request, encode_readings, FrameBuilder, LinkSecurity, OtaOutcome, ReadingKind,
+        Relayed, Unit,
+    };
+    use crate::common::routing::RouteAction;
+
+    const GATEWAY: MacAddress = [0xAA; 6];
+    const NODE: MacAddress = [1, 2, 3, 4, 5, 6];
+    const STRANGER: MacAddress = [9; 6];
+    const BROADCAST: MacAddress = [0xFF; 6];
+    // Relays and sensor nodes of the simulated mesh
+    const R1: MacAddress = [0x11; 6];
+    const R2: MacAddress = [0x12; 6];
+    const FAR: MacAddress = [0x21; 6];
+    const NEAR: MacAddress = [0x22; 6];
+
+    fn forwarder() -> MeshForwarder {
+        let mut registry = NodeRegistry::new();
+        registry.add(NODE, "boiler", 1, LinkSecurity::Aead).unwrap();
+        MeshForwarder::new(GATEWAY, registry, None)
+    }
+
+    fn readings_frame(node: MacAddress, seq: u16, values: &[f32]) -> FrameBuffer {
+        let readings: std::vec::Vec<_> = values
+            .iter()
+            .map(|value| MeshReading::new(ReadingKind::Temperature, Unit::Celsius, *value))
+            .collect();
+        let header = Header::new(MessageType::Readings, node, seq).with_flags(FLAG_ACK_REQUESTED);
+        encode_readings(&header, &readings).unwrap()
+    }
+
+    fn forwarded_values(delivery: &Delivery) -> std::vec::Vec<f32> {
+        match &delivery.forwarded {
+            Forwarded::Readings(readings) => readings.iter().map(|r| r.value).collect(),
+            other => panic!("not readings: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn forwards_and_acks_the_readings_of_registered_nodes() {
+        let mut forwarder = forwarder();
+        let frame = readings_frame(NODE, 1, &[21.5, 22.0]);
+        let delivery = forwarder.handle_frame(NODE, -60, &frame, 1_000).unwrap();
+        assert_eq!(forwarded_values(&delivery), [21.5, 22.0]);
+        let ack = delivery.ack.unwrap();
+        assert_eq!(ack.to, NODE);
+        assert!(crate::common::keys::is_ack_of(&ack.frame, &NODE, 1, None));
+
+        let stats = forwarder.sender(&NODE).unwrap();
+        assert_eq!((stats.frames, stats.last_rssi, stats.last_seq), (1, -60, 1));
+    }
+
+    #[test]
+    fn values_which_are_not_finite_are_dropped() {
+        let mut forwarder = forwarder();
+        let frame = readings_frame(NODE, 1, &[f32::NAN, 21.5, f32::INFINITY, f32::NEG_INFINITY]);
+        let delivery = forwarder.handle_frame(NODE, -60, &frame, 1_000).unwrap();
+        assert_eq!(forwarded_values(&delivery), [21.5]);
+    }
+
+    #[test]
+    fn duplicates_are_acked_again_but_not_forwarded() {
+        let mut forwarder = forwarder();
+        let frame = readings_frame(NODE, 7, &[21.5]);
+        forwarder.handle_frame(NODE, -60, &frame, 1_000).unwrap();
+        let delivery = forwarder.handle_frame(NODE, -60, &frame, 2_000).unwrap();
+        assert!(matches!(delivery.forwarded, Forwarded::Duplicate));
+        assert!(delivery.ack.is_some());
+        assert_eq!(forwarder.link_totals().duplicates, 1);
+    }
+
+    const MASTER: MeshKey = [0x11; KEY_LEN];
+
+    /// Forwarder with keys, `NODE` seals its frames
+    fn keyed_forwarder() -> MeshForwarder {
+        let mut registry = NodeRegistry::new();
+        registry.add(NODE, "sensor", 1, LinkSecurity::Aead).unwrap();
+        MeshForwarder::new(GATEWAY, registry, Some(KeyManager::new(MASTER, None)))
+    }
+
+    fn sealed(builder: FrameBuilder, session: u32) -> FrameBuffer {
+        seal(builder, &derive_aead_key(&MASTER, &NODE, 0), 0, session).unwrap()
+    }
+
+    fn sealed_readings(seq: u16, value: f32) -> FrameBuffer {
+        let header = Header::new(MessageType::Readings, NODE, seq).with_flags(FLAG_ACK_REQUESTED);
+        let mut builder = FrameBuilder::new(&header);
+        builder
+            .push_reading(&MeshReading::new(
+                ReadingKind::Temperature,
+                Unit::Celsius,
+                value,
+            ))
+            .unwrap();
+        sealed(builder, 1)
+    }
+
+    #[test]
+    fn forged_duplicates_are_not_acked() {
+        let mut forwarder = keyed_forwarder();
+        let frame = sealed_readings(7, 21.5);
+        let delivery = forwarder.handle_frame(NODE, -60, &frame, 1_000).unwrap();
+        assert_eq!(forwarded_values(&delivery), [21.5]);
+
+        // Same sequence number, tag of another payload
+        let mut forged = sealed_readings(7, 30.0).to_vec();
+        let crc_at = forged.len() - 2;
+        let tag_at = crc_at - 16;
+        forged[tag_at..crc_at].copy_from_slice(&frame[tag_at..crc_at]);
+        let crc = crate::common::crc::crc16_modbus(&forged[..crc_at]);
+        forged[crc_at..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            forwarder.handle_frame(NODE, -60, &forged, 2_000).err(),
+            Some(ForwardError::Crypto(CryptoError::AuthFailed))
+        );
+        assert_eq!(forwarder.link_totals().duplicates, 0);
+
+        // The genuine retransmit is acked again
+        let delivery = forwarder.handle_frame(NODE, -60, &frame, 3_000).unwrap();
+        assert!(matches!(delivery.forwarded, Forwarded::Duplicate));
+        assert!(delivery.ack.is_some());
+        assert_eq!(forwarder.link_totals().duplicates, 1);
+    }
+
+    #[test]
+    fn ota_reports_of_keyed_nodes_must_be_sealed() {
+        let mut forwarder = keyed_forwarder();
+        let image = StagedImage {
+            size: 1000,
+            digest: [7; 32],
+            version: None,
+        };
+        forwarder.ota.set_image(Some(image));
+        forwarder.ota.push(NODE);
+        let report = OtaReport {
+            image: image.image(),
+            outcome: OtaOutcome::Confirmed,
+        };
+        let header = Header::new(MessageType::OtaReport, NODE, 3).with_flags(FLAG_ACK_REQUESTED);
+
+        let clear = report.encode(&header).unwrap();
+        assert_eq!(
+            forwarder.handle_frame(NODE, -60, &clear, 1_000).err(),
+            Some(ForwardError::Crypto(CryptoError::NotEncrypted))
+        );
+        assert_eq!(forwarder.ota.targets().len(), 1);
+
+        let mut builder = FrameBuilder::new(&header);
+        report.push(&mut builder).unwrap();
+        let delivery = forwarder
+            .handle_frame(NODE, -60, &sealed(builder, 1), 2_000)
+            .unwrap();
+        assert!(matches!(
+            delivery.forwarded,
+            Forwarded::OtaReport { image: Some(staged), .. } if staged == image
+        ));
+        assert!(delivery.ack.is_some());
+        assert!(forwarder.ota.targets().is_empty());
+    }
+
+    #[test]
+    fn frames_of_unknown_or_spoofed_senders_are_rejected() {
+        let mut forwarder = forwarder();
+        let frame = readings_frame(STRANGER, 1, &[21.5]);
+        assert_eq!(
+            forwarder.handle_frame(STRANGER, -60, &frame, 1_000).err(),
+            Some(ForwardError::UnknownSender)
+        );
+        assert_eq!(forwarder.registry.unknown_frames(), 1);
+        assert_eq!(forwarder.registry.last_unknown(), Some(STRANGER));
+        assert!(forwarder.sender(&STRANGER).is_none());
+
+        // Header of the registered node sent from another address
+        let frame = readings_frame(NODE, 1, &[21.5]);
+        assert_eq!(
+            forwarder.handle_frame(STRANGER, -60, &frame, 1_000).err(),
+            Some(ForwardError::SenderMismatch)
+        );
+    }
+
+    #[test]
+    fn corrupted_frames_count_as_errors_of_the_sender() {
+        let mut forwarder = forwarder();
+        let frame = readings_frame(NODE, 1, &[21.5]);
+        forwarder.handle_frame(NODE, -60, &frame, 1_000).unwrap();
+        let mut corrupted = readings_frame(NODE, 2, &[21.5]).to_vec();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xFF;
+        assert!(matches!(
+            forwarder.handle_frame(NODE, -60, &corrupted, 2_000),
+            Err(ForwardError::Frame(_))
+        ));
+        assert_eq!(forwarder.sender(&NODE).unwrap().errors, 1);
+    }
+
+    #[test]
+    fn reordered_frames_are_forwarded_once() {
+        let mut forwarder = forwarder();
+        for (seq, now_ms) in [(1, 1_000), (3, 2_000), (2, 3_000)] {
+            let frame = readings_frame(NODE, seq, &[seq as f32]);
+            let delivery = forwarder.handle_frame(NODE, -60, &frame, now_ms).unwrap();
+            assert_eq!(forwarded_values(&delivery), [seq as f32]);
+        }
+        for seq in [1, 2, 3] {
+            let frame = readings_frame(NODE, seq, &[seq as f32]);
+            let delivery = forwarder.handle_frame(NODE, -60, &frame, 4_000).unwrap();
+            assert!(matches!(delivery.forwarded, Forwarded::Duplicate));
+        }
+        let totals = forwarder.link_totals();
+        assert_eq!((totals.duplicates, totals.lost), (3, 0));
+    }
+
+    #[test]
+    fn sequence_wraps_around() {
+        let mut forwarder = forwarder();
+        for seq in [u16::MAX - 1, u16::MAX, 0, 1] {
+            let frame = readings_frame(NODE, seq, &[21.5]);
+            let delivery = forwarder.handle_frame(NODE, -60, &frame, 1_000).unwrap();
+            assert_eq!(forwarded_values(&delivery), [21.5]);
+        }
+        let frame = readings_frame(NODE, u16::MAX, &[21.5]);
+        let delivery = forwarder.handle_frame(NODE, -60, &frame, 2_000).unwrap();
+        assert!(matches!(delivery.forwarded, Forwarded::Duplicate));
+        assert_eq!(forwarder.sender(&NODE).unwrap().last_seq, u16::MAX);
+    }
+
+    #[test]
+    fn unknown_node_behind_a_relay_is_recorded() {
+        let mut forwarder = forwarder();
+        let inner = readings_frame(STRANGER, 1, &[21.5]);
+        let relayed = Relayed {
+            ttl: 2,
+            frame: &inner,
+        }
+        .encode(&Header::new(MessageType::Relay, NODE, 1))
+        .unwrap();
+        assert_eq!(
+            forwarder.handle_frame(NODE, -60, &relayed, 1_000).err(),
+            Some(ForwardError::UnknownSender)
+        );
+        assert_eq!(forwarder.registry.last_unknown(), Some(STRANGER));
+    }
+
+    #[test]
+    fn full_sender_table_drops_the_stalest_sender() {
+        let mut registry = NodeRegistry::new();
+        let nodes: std::vec::Vec<MacAddress> = (0..MAX_TRACKED_SENDERS as u8)
+            .map(|index| [2, 0, 0, 0, 0, index])
+            .collect();
+        for mac in &nodes {
+            registry.add(*mac, "node", 1, LinkSecurity::Aead).unwrap();
+        }
+        let mut forwarder = MeshForwarder::new(GATEWAY, registry, None);
+        for (index, mac) in nodes.iter().enumerate() {
+            let frame = readings_frame(*mac, 1, &[21.5]);
+            let now_ms = 1_000 * (index as u64 + 1);
+            forwarder.handle_frame(*mac, -60, &frame, now_ms).unwrap();
+        }
+        assert_eq!(forwarder.sender_count(), MAX_TRACKED_SENDERS);
+
+        // Nodes replaced in the registry behind the forwarder, as after a
+        // reload: the stalest of the ones no longer registered goes first,
+        // before the registered ones heard even longer ago
+        let stale = nodes[3];
+        let recent = nodes[10];
+        forwarder.registry.remove(&stale);
+        forwarder.registry.remove(&recent);
+        let newcomer = [3; 6];
+        forwarder
+            .registry
+            .add(newcomer, "new", 1, LinkSecurity::Aead)
+            .unwrap();
+        let frame = readings_frame(newcomer, 1, &[21.5]);
+        forwarder
+            .handle_frame(newcomer, -60, &frame, 100_000)
+            .unwrap();
+        assert_eq!(forwarder.sender_count(), MAX_TRACKED_SENDERS);
+        assert!(forwarder.sender(&stale).is_none());
+        assert!(forwarder.sender(&recent).is_some());
+        assert!(forwarder.sender(&nodes[0]).is_some());
+        assert!(forwarder.sender(&newcomer).is_some());
+    }
+
+    /// Radio links of a simulated mesh around a `MeshForwarder`, frames only
+    /// reach the stations in range of their sender
+    struct Air {
+        gateway: MeshForwarder,
+        // Relays and sensor nodes, listed from the gateway outwards
+        stations: std::vec::Vec<Router>,
+        // Stations in range of each other and the RSSI between them
+        links: std::vec::Vec<(MacAddress, MacAddress, i8)>,
+        // Frames received by the sensor nodes
+        delivered: std::vec::Vec<(MacAddress, std::vec::Vec<u8>)>,
+    }
+
+    impl Air {
+        fn new(gateway: MeshForwarder, stations: &[MacAddress]) -> Self {
+            Air {
+                gateway,
+                stations: stations.iter().map(|mac| Router::new(*mac)).collect(),
+                links: std::vec::Vec::new(),
+                delivered: std::vec::Vec::new(),
+            }
+        }
+
+        fn link(&mut self, a: MacAddress, b: MacAddress, rssi: i8) {
+            self.links.push((a, b, rssi));
+        }
+
+        fn unlink(&mut self, mac: MacAddress) {
+            self.links.retain(|(a, b, _)| *a != mac && *b != mac);
+        }
+
+        fn rssi(&self, from: MacAddress, to: MacAddress) -> Option<i8> {
+            self.links
+                .iter()
+                .find(|(a, b, _)| (*a, *b) == (from, to) || (*a, *b) == (to, from))
+                .map(|(_, _, rssi)| *rssi)
+        }
+
+        fn station(&mut self, mac: MacAddress) -> &mut Router {
+            self.stations
+                .iter_mut()
+                .find(|router| *router.own() == mac)
+                .unwrap()
+        }
+
+        /// One beacon round, the gateway first
+        fn beacons(&mut self, now_ms: u64) {
+            if let Some(beacon) = self.gateway.router.beacon(now_ms) {
+                self.send(GATEWAY, BROADCAST, &beacon, now_ms);
+            }
+            for index in 0..self.stations.len() {
+                let from = *self.stations[index].own();
+                if let Some(beacon) = self.stations[index].beacon(now_ms) {
+                    self.send(from, BROADCAST, &beacon, now_ms);
+                }
+            }
+        }
+
+        /// Send a frame from a station, to `BROADCAST` or a neighbor, and
+        /// follow it and the frames sent in answer until they are delivered
+        /// or lost. Returns the deliveries of the gateway.
+        fn send(
+            &mut self,
+            from: MacAddress,
+            to: MacAddress,
+            frame: &[u8],
+            now_ms: u64,
+        ) -> std::vec::Vec<Delivery> {
+            let mut queue = std::collections::VecDeque::new();
+            if to == BROADCAST {
+                for mac in self.stations.iter().map(|router| *router.own()) {
+                    queue.push_back((from, mac, true, frame.to_vec()));
+                }
+                queue.push_back((from, GATEWAY, true, frame.to_vec()));
+            } else {
+                queue.push_back((from, to, false, frame.to_vec()));
+            }
+            let mut deliveries = std::vec::Vec::new();
+            while let Some((from, to, broadcast, frame)) = queue.pop_front() {
+                let Some(rssi) = self.rssi(from, to) else {
+                    continue;
+                };
+                if to == GATEWAY {
+                    let Ok(delivery) = self.gateway.handle_frame(from, rssi, &frame, now_ms) else {
+                        continue;
+                    };
+                    for outbound in [&delivery.ack, &delivery.config].into_iter().flatten() {
+                        queue.push_back((GATEWAY, outbound.to, false, outbound.frame.to_vec()));
+                    }
+                    deliveries.push(delivery);
+                    continue;
+                }
+                match self
+                    .station(to)
+                    .route(from, broadcast, &frame, rssi, now_ms)
+                {
+                    RouteAction::Forward(outbound) => {
+                        queue.push_back((to, outbound.to, false, outbound.frame.to_vec()))
+                    }
+                    RouteAction::Deliver(data) => self.delivered.push((to, data.to_vec())),
+                    RouteAction::Handled | RouteAction::Drop(_) => {}
+                }
+            }
+            deliveries
+        }
+
+        fn acked(&self, node: MacAddress, seq: u16) -> bool {
+            self.delivered.iter().any(|(to, data)| {
+                *to == node && crate::common::keys::is_ack_of(data, &node, seq, None)
+            })
+        }
+    }
+
+    fn relays_registry() -> NodeRegistry {
+        let mut registry = NodeRegistry::new();
+        for relay in [R1, R2] {
+            registry.add(relay, "relay", 1, LinkSecurity::Aead).unwrap();
+        }
+        registry
+    }
+
+    fn join_frame(node: MacAddress, seq: u16) -> FrameBuffer {
+        let header =
+            Header::new(MessageType::JoinRequest, node, seq).with_flags(FLAG_ACK_REQUESTED);
+        let request = JoinRequest {
+            name: "sensor",
+            channel: 1,
+            security: LinkSecurity::Aead,
+        };
+        encode_join_request(&header, &request).unwrap()
+    }
+
+    #[test]
+    fn nodes_join_and_report_through_two_relays() {
+        let now = 1_000;
+        let mut air = Air::new(
+            MeshForwarder::new(GATEWAY, relays_registry(), None),
+            &[R1, R2, NEAR, FAR],
+        );
+        air.link(GATEWAY, R1, -50);
+        air.link(R1, R2, -60);
+        air.link(R2, FAR, -70);
+        air.link(GATEWAY, NEAR, -55);
+        air.beacons(now);
+        assert_eq!(air.station(FAR).hops(now), Some(3));
+        assert_eq!(air.station(NEAR).hops(now), Some(1));
+
+        // Join requests are broadcast, the relays carry them up
+        air.gateway.registry.start_pairing(now);
+        for node in [FAR, NEAR] {
+            let deliveries = air.send(node, BROADCAST, &join_frame(node, 1), now);
+            assert!(matches!(
+                deliveries.as_slice(),
+                [Delivery {
+                    forwarded: Forwarded::Joined(JoinOutcome::Added),
+                    ..
+                }]
+            ));
+            assert!(air.acked(node, 1));
+        }
+        air.gateway.registry.stop_pairing();
+
+        for (seq, now_ms) in [(2, 2_000), (3, 3_000)] {
+            for (node, value) in [(FAR, 10.0), (NEAR, 20.0)] {
+                let parent = air.station(node).parent(now_ms).unwrap().mac;
+                let frame = readings_frame(node, seq, &[value + seq as f32]);
+                let deliveries = air.send(node, parent, &frame, now_ms);
+                assert_eq!(deliveries.len(), 1);
+                assert_eq!(deliveries[0].node, node);
+                assert_eq!(forwarded_values(&deliveries[0]), [value + seq as f32]);
+                assert!(air.acked(node, seq));
+            }
+        }
+        let far = air.gateway.sender(&FAR).unwrap();
+        assert_eq!((far.frames, far.relays, far.last_seq), (3, 2, 3));
+        let near = air.gateway.sender(&NEAR).unwrap();
+        assert_eq!((near.frames, near.relays, near.last_rssi), (3, 0, -55));
+        assert_eq!(air.gateway.link_totals().lost, 0);
+    }
+
+    #[test]
+    fn node_fails_over_to_another_relay() {
+        let mut registry = relays_registry();
+        registry.add(FAR, "sensor", 1, LinkSecurity::Aead).unwrap();
+        let mut air = Air::new(MeshForwarder::new(GATEWAY, registry, None), &[R1, R2, FAR]);
+        air.link(GATEWAY, R1, -50);
+        air.link(GATEWAY, R2, -50);
+        air.link(R1, FAR, -60);
+        air.link(R2, FAR, -75);
+        air.beacons(1_000);
+        assert_eq!(air.station(FAR).parent(1_000).unwrap().mac, R1);
+        let frame = readings_frame(FAR, 1, &[1.0]);
+        assert_eq!(air.send(FAR, R1, &frame, 1_000).len(), 1);
+        assert!(air.acked(FAR, 1));
+
+        // R1 goes silent, frames sent to it before its beacons time out are lost
+        air.unlink(R1);
+        let frame = readings_frame(FAR, 2, &[2.0]);
+        assert!(air.send(FAR, R1, &frame, 2_000).is_empty());
+        let mut now = 1_000;
+        while air.station(FAR).parent(now).unwrap().mac == R1 {
+            now += BEACON_INTERVAL_MS;
+            air.beacons(now);
+        }
+        assert_eq!(air.station(FAR).parent(now).unwrap().mac, R2);
+
+        // The retransmit goes through R2, and so does its ack
+        let deliveries = air.send(FAR, R2, &frame, now);
+        assert_eq!(forwarded_values(&deliveries[0]), [2.0]);
+        assert_eq!(air.gateway.router.next_hop(&FAR, now), Some(R2));
+        assert!(air.acked(FAR, 2));
+    }
+}
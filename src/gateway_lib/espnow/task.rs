@@ -0,0 +1,447 @@
+//! ESP-NOW radio side of the gateway mesh
+//!
+//! `espnow_receive_task` owns the ESP-NOW manager, sender and receiver and the
+//! flash: it feeds the received frames to the `MeshForwarder`, sends what it
+//! returns, keeps the peers and the stored registry in sync and applies the
+//! registry, OTA and downlink commands queued by the other tasks.
+
+use core::sync::atomic::Ordering;
+use embassy_time::{with_timeout, Duration};
+use esp_hal::efuse::Efuse;
+use esp_storage::FlashStorage;
+use esp_wifi::esp_now::{EspNowManager, EspNowReceiver, EspNowSender, PeerInfo, BROADCAST_ADDRESS};
+use log::{debug, error, info, warn};
+
+use super::{
+    ForwardError, Forwarded, MeshForwarder, MESH_FRAMES_DUPLICATE, MESH_FRAMES_LOST,
+    MESH_FRAMES_RECEIVED, MESH_FRAMES_RETRIED,
+};
+use crate::common::mesh::{format_mac, MacAddress};
+use crate::common::mesh_proto::{Header, LinkSecurity, MessageType, OtaOutcome, OTA_CHUNK_LEN};
+use crate::common::routing::{Outbound, BEACON_INTERVAL_MS};
+use crate::common::wifi::CURRENT_CHANNEL;
+use crate::gateway_lib::battery::BatteryAlarm;
+use crate::gateway_lib::downlink::DOWNLINK_COMMANDS;
+use crate::gateway_lib::liveness::{NodeHealth, NODE_SUMMARY};
+use crate::gateway_lib::ota::{OtaCommand, OtaStage, OTA_COMMANDS};
+use crate::gateway_lib::publish::{queue_reading, Reading};
+use crate::gateway_lib::registry::{
+    load_or_move_registry, save_registry, NodeRegistry, RegistryCommand, REGISTRY_COMMANDS,
+    REGISTRY_FLASH_OFFSET,
+};
+use crate::gateway_lib::security::KeyManager;
+
+// Registry commands are applied at least this often
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// While an image is being staged, for the chunks queued by MQTT or HTTP
+const OTA_STAGING_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const ACK_SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn persist(storage: &mut FlashStorage, registry: &NodeRegistry) {
+    match save_registry(storage, REGISTRY_FLASH_OFFSET, registry) {
+        Ok(()) => info!("Saved {} mesh nodes to flash", registry.nodes().len()),
+        Err(e) => error!("Could not save the node registry: {:?}", e),
+    }
+}
+
+/// Register the nodes as ESP-NOW peers for the acks, encrypted with their
+/// current LMK for the nodes using the link layer encryption
+fn sync_peers(manager: &EspNowManager<'_>, forwarder: &MeshForwarder) {
+    let keys = forwarder.keys.as_ref().filter(|keys| keys.pmk().is_some());
+    for node in forwarder.registry.nodes() {
+        let lmk = match (keys, node.security) {
+            (Some(keys), LinkSecurity::EspNow) => Some(keys.lmk(node)),
+            _ => None,
+        };
+        let peer = PeerInfo {
+            peer_address: node.mac,
+            lmk,
+            channel: None,
+            encrypt: lmk.is_some(),
+        };
+        let result = if manager.peer_exists(&node.mac) {
+            manager.modify_peer(peer)
+        } else {
+            manager.add_peer(peer)
+        };
+        if let Err(e) = result {
+            warn!("Could not register peer {}: {:?}", format_mac(&node.mac), e);
+        }
+    }
+}
+
+fn publish_link_totals(forwarder: &MeshForwarder) {
+    let totals = forwarder.link_totals();
+    MESH_FRAMES_RECEIVED.store(totals.received, Ordering::Relaxed);
+    MESH_FRAMES_DUPLICATE.store(totals.duplicates, Ordering::Relaxed);
+    MESH_FRAMES_LOST.store(totals.lost, Ordering::Relaxed);
+    MESH_FRAMES_RETRIED.store(totals.retried, Ordering::Relaxed);
+}
+
+fn publish_node_status(mac: &MacAddress, health: &NodeHealth) {
+    match Reading::node_status(&format_mac(mac), health) {
+        Some(reading) => {
+            queue_reading(reading);
+        }
+        None => warn!("Could not build the status of {}", format_mac(mac)),
+    }
+}
+
+fn publish_ota_status(mac: &MacAddress, state: &'static str, offset: u32, size: u32) {
+    match Reading::ota_status(&format_mac(mac), state, offset, size) {
+        Some(reading) => {
+            queue_reading(reading);
+        }
+        None => warn!("Could not build the OTA status of {}", format_mac(mac)),
+    }
+}
+
+fn publish_battery_alarm(mac: &MacAddress, alarm: &BatteryAlarm) {
+    match Reading::battery_alarm(&format_mac(mac), alarm) {
+        Some(reading) => {
+            queue_reading(reading);
+        }
+        None => warn!("Could not build the battery alarm of {}", format_mac(mac)),
+    }
+}
+
+fn publish_config_status(mac: &MacAddress, state: &'static str, id: u16) {
+    match Reading::config_status(&format_mac(mac), state, id) {
+        Some(reading) => {
+            queue_reading(reading);
+        }
+        None => warn!("Could not build the config status of {}", format_mac(mac)),
+    }
+}
+
+/// Apply a staging or update request, the stage is written here only
+fn apply_ota(
+    forwarder: &mut MeshForwarder,
+    stage: &mut OtaStage,
+    storage: &mut FlashStorage,
+    command: OtaCommand,
+) {
+    let size = forwarder.ota.image().map_or(0, |image| image.size);
+    match command {
+        OtaCommand::Begin(image) => match stage.begin(storage, image) {
+            Ok(received) => info!(
+                "Staging node image {:08x} of {} bytes from offset {}",
+                image.image(),
+                image.size,
+                received
+            ),
+            Err(e) => error!("Cannot stage node image {:08x}: {:?}", image.image(), e),
+        },
+        OtaCommand::Chunk { offset, data } => match stage.write(storage, offset, &data) {
+            Ok(_) => {
+                if let Some(image) = stage.ready().filter(|_| forwarder.ota.image().is_none()) {
+                    info!("Node image {:08x} staged and verified", image.image());
+                }
+            }
+            Err(e) => error!("Cannot stage chunk at offset {}: {:?}", offset, e),
+        },
+        OtaCommand::Push(mac) => {
+            if forwarder.ota.push(mac) {
+                publish_ota_status(&mac, "queued", 0, size);
+            } else {
+                warn!(
+                    "Too many nodes queued for an update, ignoring {}",
+                    format_mac(&mac)
+                );
+            }
+        }
+        OtaCommand::Cancel(mac) => {
+            if forwarder.ota.cancel(&mac) {
+                publish_ota_status(&mac, "cancelled", 0, size);
+            }
+        }
+    }
+    forwarder.ota.set_image(stage.ready());
+}
+
+async fn send_to_node(sender: &mut EspNowSender<'_>, outbound: &Outbound, what: &str) {
+    match with_timeout(
+        ACK_SEND_TIMEOUT,
+        sender.send_async(&outbound.to, &outbound.frame),
+    )
+    .await
+    {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("{} to {} failed: {:?}", what, format_mac(&outbound.to), e),
+        Err(_) => debug!("{} to {} timed out", what, format_mac(&outbound.to)),
+    }
+}
+
+#[embassy_executor::task]
+pub async fn espnow_receive_task(
+    manager: EspNowManager<'static>,
+    mut sender: EspNowSender<'static>,
+    mut receiver: EspNowReceiver<'static>,
+    mut storage: FlashStorage,
+) {
+    info!("Starting ESP-NOW receive task");
+    let registry = match load_or_move_registry(&mut storage) {
+        Ok((registry, moved)) => {
+            if moved {
+                info!("Moved the node registry to the mesh_state partition");
+            }
+            registry
+        }
+        Err(e) => {
+            warn!("No valid node registry in flash ({:?}), starting empty", e);
+            NodeRegistry::new()
+        }
+    };
+    info!("Loaded {} registered mesh nodes", registry.nodes().len());
+    let keys = KeyManager::from_config();
+    match keys.as_ref().map(|keys| keys.pmk()) {
+        Some(Some(pmk)) => {
+            if let Err(e) = manager.set_pmk(pmk) {
+                error!("Could not set the ESP-NOW PMK: {:?}", e);
+            }
+            info!("Mesh encryption enabled (sealed payloads and encrypted peers)");
+        }
+        Some(None) => info!("Mesh encryption enabled (sealed payloads only)"),
+        None => warn!("No MESH_MASTER_KEY configured, mesh frames are not authenticated"),
+    }
+    // The STA interface uses the base MAC
+    let mut forwarder = MeshForwarder::new(Efuse::read_base_mac_address(), registry, keys);
+    sync_peers(&manager, &forwarder);
+    let boot_ms = embassy_time::Instant::now().as_millis();
+    for node in forwarder.registry.nodes() {
+        forwarder.liveness.watch(node.mac, boot_ms);
+    }
+    let mut next_beacon_ms = 0;
+    let mut stage = OtaStage::load(&mut storage);
+    if let Some(image) = stage.ready() {
+        info!(
+            "Node image {:08x} of {} bytes staged",
+            image.image(),
+            image.size
+        );
+    }
+    forwarder.ota.set_image(stage.ready());
+    // Unknown sender warned about last, its next frames are only debug logs
+    let mut warned_unknown = None;
+
+    loop {
+        let now_ms = embassy_time::Instant::now().as_millis();
+        for (mac, health) in forwarder.liveness.check(now_ms) {
+            warn!("Mesh node {} is offline", format_mac(&mac));
+            publish_node_status(&mac, &health);
+        }
+        let channel = CURRENT_CHANNEL.load(Ordering::Relaxed);
+        if forwarder
+            .router
+            .set_channel((channel != 0).then_some(channel))
+            && channel != 0
+        {
+            info!("Mesh now on channel {}, rebroadcasting the beacon", channel);
+            next_beacon_ms = now_ms;
+        }
+        if now_ms >= next_beacon_ms {
+            next_beacon_ms = now_ms + BEACON_INTERVAL_MS;
+            if let Some(beacon) = forwarder.router.beacon(now_ms) {
+                if let Err(e) = sender.send_async(&BROADCAST_ADDRESS, &beacon).await {
+                    warn!("Could not broadcast the mesh beacon: {:?}", e);
+                }
+            }
+        }
+        while let Ok(command) = REGISTRY_COMMANDS.try_receive() {
+            info!("Mesh registry command {:?}", command);
+            if let RegistryCommand::Remove(mac) = command {
+                if manager.peer_exists(&mac) {
+                    let _ = manager.remove_peer(&mac);
+                }
+            }
+            if forwarder.apply(command, now_ms) {
+                persist(&mut storage, &forwarder.registry);
+                sync_peers(&manager, &forwarder);
+            }
+        }
+        while let Ok(command) = OTA_COMMANDS.try_receive() {
+            apply_ota(&mut forwarder, &mut stage, &mut storage, command);
+        }
+        while let Ok(command) = DOWNLINK_COMMANDS.try_receive() {
+            if !forwarder.registry.is_allowed(&command.node) {
+                warn!(
+                    "Ignoring config change for unknown node {}",
+                    format_mac(&command.node)
+                );
+                continue;
+            }
+            match forwarder.downlink.queue(command.node, &command.change) {
+                Ok(id) => {
+                    info!(
+                        "Config change {} queued for {}: {:?}",
+                        id,
+                        format_mac(&command.node),
+                        command.change
+                    );
+                    publish_config_status(&command.node, "queued", id);
+                }
+                Err(e) => warn!(
+                    "Cannot queue config change for {}: {:?}",
+                    format_mac(&command.node),
+                    e
+                ),
+            }
+        }
+        forwarder.registry.publish_status(now_ms);
+        NODE_SUMMARY.signal(forwarder.node_summaries());
+
+        let poll_interval = if stage.is_receiving() {
+            OTA_STAGING_POLL_INTERVAL
+        } else {
+            COMMAND_POLL_INTERVAL
+        };
+        let Ok(received) = with_timeout(poll_interval, receiver.receive_async()).await else {
+            continue;
+        };
+        let src = received.info.src_address;
+        let rssi = received.info.rx_control.rssi as i8;
+        let now_ms = embassy_time::Instant::now().as_millis();
+
+        let delivery = match forwarder.handle_frame(src, rssi, received.data(), now_ms) {
+            Ok(delivery) => delivery,
+            Err(ForwardError::UnknownSender) => {
+                // The node itself, not the relay its frame came through
+                let unknown = forwarder.registry.last_unknown().unwrap_or(src);
+                if warned_unknown != Some(unknown) {
+                    warn!(
+                        "Dropping frames from unknown node {} via {}, pair it to accept them",
+                        format_mac(&unknown),
+                        format_mac(&src)
+                    );
+                    warned_unknown = Some(unknown);
+                } else {
+                    debug!(
+                        "Dropping frame from unknown node {} ({} dropped)",
+                        format_mac(&unknown),
+                        forwarder.registry.unknown_frames()
+                    );
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("Dropping ESP-NOW frame from {}: {:?}", format_mac(&src), e);
+                continue;
+            }
+        };
+
+        let node = delivery.node;
+        if let Some(health) = delivery.came_online {
+            info!("Mesh node {} is online", format_mac(&node));
+            publish_node_status(&node, &health);
+        }
+        if let Some(ack) = delivery.ack {
+            send_to_node(&mut sender, &ack, "Ack").await;
+        }
+        if let Some(config) = delivery.config {
+            send_to_node(&mut sender, &config, "Config").await;
+        }
+        if let Some(id) = delivery.config_applied {
+            info!(
+                "Mesh node {} applied config change {}",
+                format_mac(&node),
+                id
+            );
+            publish_config_status(&node, "applied", id);
+        }
+        if let Some(offer) = delivery.offer {
+            debug!("Offering the staged image to {}", format_mac(&node));
+            send_to_node(&mut sender, &offer, "OTA offer").await;
+        }
+        if let Some(power) = delivery.power {
+            if let Some(reading) = Reading::node_power(&format_mac(&node), &power) {
+                queue_reading(reading);
+            }
+        }
+        if let Some(alarm) = delivery.battery_alarm {
+            if alarm.low {
+                warn!(
+                    "Mesh node {} battery low: {:.2} V, {:?} h left",
+                    format_mac(&node),
+                    alarm.battery_v,
+                    alarm.remaining_hours
+                );
+            } else {
+                info!("Mesh node {} battery recovered", format_mac(&node));
+            }
+            publish_battery_alarm(&node, &alarm);
+        }
+        if let Some(image) = delivery.updated {
+            info!("Mesh node {} runs the staged image", format_mac(&node));
+            publish_ota_status(&node, "updated", image.size, image.size);
+        }
+        match delivery.forwarded {
+            Forwarded::Readings(readings) => {
+                for reading in readings {
+                    debug!(
+                        "ESP-NOW reading from {} rssi={} dBm: {} {}",
+                        reading.source, rssi, reading.value, reading.unit
+                    );
+                    queue_reading(reading);
+                }
+                if let Some(counters) = forwarder.take_link_report(&node, now_ms) {
+                    if let Some(reading) = Reading::mesh_link(&format_mac(&node), counters) {
+                        queue_reading(reading);
+                    }
+                }
+                publish_link_totals(&forwarder);
+            }
+            Forwarded::Duplicate => {
+                debug!("Duplicate frame from {}, acked again", format_mac(&node));
+                publish_link_totals(&forwarder);
+            }
+            Forwarded::Beacon => {}
+            Forwarded::OtaRequest {
+                request,
+                seq,
+                progress,
+            } => {
+                let mut buffer = [0u8; OTA_CHUNK_LEN];
+                let chunk = match stage.chunk(&mut storage, &request, &mut buffer) {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        debug!("No chunk for {}: {:?}", format_mac(&node), e);
+                        continue;
+                    }
+                };
+                let header = Header::new(MessageType::OtaChunk, node, seq);
+                match chunk
+                    .encode(&header)
+                    .map(|frame| forwarder.router.route_down(&frame, now_ms))
+                {
+                    Ok(Ok(outbound)) => send_to_node(&mut sender, &outbound, "OTA chunk").await,
+                    Ok(Err(e)) => debug!("Cannot route chunk to {}: {:?}", format_mac(&node), e),
+                    Err(e) => error!("Cannot encode chunk: {:?}", e),
+                }
+                if let Some(offset) = progress {
+                    let size = forwarder.ota.image().map_or(0, |image| image.size);
+                    publish_ota_status(&node, "downloading", offset, size);
+                }
+            }
+            Forwarded::OtaReport { report, image } => {
+                let Some(image) = image else {
+                    continue;
+                };
+                info!(
+                    "Mesh node {} update: {}",
+                    format_mac(&node),
+                    report.outcome.name()
+                );
+                let offset = match report.outcome {
+                    OtaOutcome::Verified | OtaOutcome::Confirmed => image.size,
+                    _ => 0,
+                };
+                publish_ota_status(&node, report.outcome.name(), offset, image.size);
+            }
+            Forwarded::Joined(outcome) => {
+                info!("Mesh node {} joined: {:?}", format_mac(&node), outcome);
+                persist(&mut storage, &forwarder.registry);
+                sync_peers(&manager, &forwarder);
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! I2C bus shared by the devices of the Norvi iIOT AE04
+//!
+//! The SSD1306 display and the two ADS1115 converters sit on I2C0, each driver
+//! gets a `SharedI2c` which locks the bus for the length of a transaction.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_hal_async::i2c::{ErrorType, I2c, Operation};
+
+pub type I2cBus<BUS> = Mutex<CriticalSectionRawMutex, BUS>;
+
+pub struct SharedI2c<BUS: 'static> {
+    bus: &'static I2cBus<BUS>,
+}
+
+impl<BUS> SharedI2c<BUS> {
+    pub fn new(bus: &'static I2cBus<BUS>) -> Self {
+        SharedI2c { bus }
+    }
+}
+
+impl<BUS: ErrorType> ErrorType for SharedI2c<BUS> {
+    type Error = BUS::Error;
+}
+
+impl<BUS: I2c> I2c for SharedI2c<BUS> {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.bus.lock().await.transaction(address, operations).await
+    }
+}
@@ -0,0 +1,113 @@
+//! Digital inputs and transistor outputs of the Norvi iIOT AE04
+//!
+//! Hardware configuration:
+//! - 6 digital inputs (opto-isolated, active low): I.1 GPIO39, I.2 GPIO34,
+//!   I.3 GPIO35, I.5 GPIO21, I.6 GPIO22, I.7 GPIO15
+//! - 2 transistor outputs (open collector): T0.0 GPIO26, T0.1 GPIO27
+//!
+//! States are kept as bitmasks in atomics so the Modbus server, the display and
+//! the control loops can share them. Outputs driven by a local control loop
+//! (e.g. the PID on T0.0) are locked and reject remote writes.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(feature = "esp32")]
+use embassy_time::{Duration, Ticker};
+#[cfg(feature = "esp32")]
+use esp_hal::gpio::{Input, Output};
+#[cfg(feature = "esp32")]
+use log::info;
+
+pub const DIGITAL_INPUT_COUNT: usize = 6;
+pub const TRANSISTOR_OUTPUT_COUNT: usize = 2;
+
+#[cfg(feature = "esp32")]
+const IO_POLL_PERIOD: Duration = Duration::from_millis(50);
+
+static DIGITAL_INPUTS: AtomicU8 = AtomicU8::new(0);
+static OUTPUTS: AtomicU8 = AtomicU8::new(0);
+static LOCKED_OUTPUTS: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputError {
+    InvalidIndex,
+    Locked,
+}
+
+pub fn digital_input(index: usize) -> Option<bool> {
+    if index >= DIGITAL_INPUT_COUNT {
+        return None;
+    }
+    Some(DIGITAL_INPUTS.load(Ordering::Relaxed) & (1 << index) != 0)
+}
+
+pub fn output(index: usize) -> Option<bool> {
+    if index >= TRANSISTOR_OUTPUT_COUNT {
+        return None;
+    }
+    Some(OUTPUTS.load(Ordering::Relaxed) & (1 << index) != 0)
+}
+
+fn store_bit(mask: &AtomicU8, index: usize, on: bool) {
+    if on {
+        mask.fetch_or(1 << index, Ordering::Relaxed);
+    } else {
+        mask.fetch_and(!(1 << index), Ordering::Relaxed);
+    }
+}
+
+/// Reserve an output for a local control loop
+pub fn lock_output(index: usize) {
+    if index < TRANSISTOR_OUTPUT_COUNT {
+        store_bit(&LOCKED_OUTPUTS, index, true);
+    }
+}
+
+/// Remote request to switch an output, applied by `digital_io_task`
+pub fn request_output(index: usize, on: bool) -> Result<(), OutputError> {
+    if index >= TRANSISTOR_OUTPUT_COUNT {
+        return Err(OutputError::InvalidIndex);
+    }
+    if LOCKED_OUTPUTS.load(Ordering::Relaxed) & (1 << index) != 0 {
+        return Err(OutputError::Locked);
+    }
+    store_bit(&OUTPUTS, index, on);
+    Ok(())
+}
+
+/// State report from the loop owning a locked output
+pub fn report_output(index: usize, on: bool) {
+    if index < TRANSISTOR_OUTPUT_COUNT {
+        store_bit(&OUTPUTS, index, on);
+    }
+}
+
+/// Sample the digital inputs and apply the requested state of unlocked outputs
+#[cfg(feature = "esp32")]
+#[embassy_executor::task]
+pub async fn digital_io_task(
+    inputs: [Input<'static>; DIGITAL_INPUT_COUNT],
+    mut outputs: [Option<Output<'static>>; TRANSISTOR_OUTPUT_COUNT],
+) {
+    info!("Starting digital IO task");
+    let mut ticker = Ticker::every(IO_POLL_PERIOD);
+    loop {
+        // Inputs are pulled up, an active input pulls the pin low
+        let mask = inputs
+            .iter()
+            .enumerate()
+            .fold(0u8, |mask, (i, pin)| mask | (u8::from(pin.is_low()) << i));
+        DIGITAL_INPUTS.store(mask, Ordering::Relaxed);
+
+        for (i, pin) in outputs.iter_mut().enumerate() {
+            if let (Some(pin), Some(on)) = (pin.as_mut(), output(i)) {
+                if on {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+        }
+        ticker.next().await;
+    }
+}
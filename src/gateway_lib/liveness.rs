@@ -0,0 +1,326 @@
+//! Liveness of the mesh nodes, tracked by the gateway
+//!
+//! Each processed frame refreshes the entry of its node: last-seen time, RSSI
+//! (nodes heard directly), sequence gaps, and the battery voltage and firmware
+//! version it reported. A node is declared offline once it missed
+//! `OFFLINE_AFTER_MISSED` transmit intervals, the interval being the one
+//! reported by the node or `NODE_TRANSMIT_INTERVAL_S` from the shared config.
+//! State changes are published retained on `/status/node/{mac}`.
+//!
+//! A summary of the watched nodes is handed to the display in `NODE_SUMMARY`.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use heapless::{FnvIndexMap, Vec};
+
+use crate::common::config::env_u32_or;
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{
+    FirmwareVersion, MeshReading, NodeStatus, Quality, ReadingKind, TlvIter,
+};
+use crate::gateway_lib::registry::MAX_NODES;
+
+pub const DEFAULT_TRANSMIT_INTERVAL_S: u32 =
+    env_u32_or(option_env!("NODE_TRANSMIT_INTERVAL_S"), 300);
+pub const OFFLINE_AFTER_MISSED: u32 = env_u32_or(option_env!("NODE_OFFLINE_MISSED_INTERVALS"), 3);
+
+/// Latest summary of the watched nodes, taken by the display task
+pub static NODE_SUMMARY: Signal<CriticalSectionRawMutex, NodeSummaries> = Signal::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Liveness {
+    // Registered but not heard since the gateway booted
+    Unknown,
+    Online,
+    Offline,
+}
+
+impl Liveness {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Liveness::Unknown => "unknown",
+            Liveness::Online => "online",
+            Liveness::Offline => "offline",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeHealth {
+    pub state: Liveness,
+    pub last_seen_ms: u64,
+    pub last_seq: Option<u16>,
+    pub seq_gaps: u32,
+    pub rssi: Option<i8>,
+    pub battery_v: Option<f32>,
+    pub firmware: Option<FirmwareVersion>,
+    pub transmit_interval_ms: u64,
+}
+
+impl NodeHealth {
+    fn new(now_ms: u64) -> Self {
+        NodeHealth {
+            state: Liveness::Unknown,
+            last_seen_ms: now_ms,
+            last_seq: None,
+            seq_gaps: 0,
+            rssi: None,
+            battery_v: None,
+            firmware: None,
+            transmit_interval_ms: DEFAULT_TRANSMIT_INTERVAL_S as u64 * 1000,
+        }
+    }
+
+    /// Time after which the node is declared offline
+    pub fn deadline_ms(&self) -> u64 {
+        self.last_seen_ms + self.transmit_interval_ms * OFFLINE_AFTER_MISSED as u64
+    }
+}
+
+/// Node line of the display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeSummary {
+    pub mac: MacAddress,
+    pub state: Liveness,
+    pub rssi: Option<i8>,
+    pub battery_v: Option<f32>,
+    pub battery_low: bool,
+}
+
+pub type NodeSummaries = Vec<NodeSummary, MAX_NODES>;
+
+/// What the gateway learned from one frame of a node
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Heard {
+    // None for frames outside the node sequence, as the relay beacons
+    pub seq: Option<u16>,
+    // None for relayed frames
+    pub rssi: Option<i8>,
+    pub battery_v: Option<f32>,
+    pub status: NodeStatus,
+}
+
+/// Supply voltage reading of a payload, if any
+pub fn battery_voltage(payload: &[u8]) -> Option<f32> {
+    TlvIter::new(payload)
+        .map_while(Result::ok)
+        .filter_map(|tlv| MeshReading::from_tlv(&tlv).ok().flatten())
+        .find(|reading| {
+            reading.kind == ReadingKind::SupplyVoltage && reading.quality != Quality::Bad
+        })
+        .map(|reading| reading.value)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LivenessTable {
+    nodes: FnvIndexMap<MacAddress, NodeHealth, MAX_NODES>,
+}
+
+impl LivenessTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, mac: &MacAddress) -> Option<&NodeHealth> {
+        self.nodes.get(mac)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MacAddress, &NodeHealth)> {
+        self.nodes.iter()
+    }
+
+    /// Start watching a registered node, it goes offline if never heard
+    pub fn watch(&mut self, mac: MacAddress, now_ms: u64) {
+        if !self.nodes.contains_key(&mac) {
+            // At most one entry per registered node, cannot be full
+            let _ = self.nodes.insert(mac, NodeHealth::new(now_ms));
+        }
+    }
+
+    pub fn remove(&mut self, mac: &MacAddress) {
+        self.nodes.remove(mac);
+    }
+
+    /// Refresh a node on a processed frame, returns its health when it came
+    /// online
+    pub fn on_frame(&mut self, mac: MacAddress, heard: &Heard, now_ms: u64) -> Option<NodeHealth> {
+        self.watch(mac, now_ms);
+        let health = self.nodes.get_mut(&mac)?;
+        if let (Some(seq), Some(last_seq)) = (heard.seq, health.last_seq) {
+            let diff = seq.wrapping_sub(last_seq) as i16;
+            // Older or equal means the node restarted its sequence
+            if diff > 1 {
+                health.seq_gaps = health.seq_gaps.wrapping_add(diff as u32 - 1);
+            }
+        }
+        health.last_seq = heard.seq.or(health.last_seq);
+        health.last_seen_ms = now_ms;
+        health.rssi = heard.rssi;
+        health.battery_v = heard.battery_v.or(health.battery_v);
+        health.firmware = heard.status.firmware.or(health.firmware);
+        if let Some(interval_s) = heard.status.transmit_interval_s.filter(|s| *s > 0) {
+            health.transmit_interval_ms = interval_s as u64 * 1000;
+        }
+
+        let came_online = health.state != Liveness::Online;
+        health.state = Liveness::Online;
+        came_online.then_some(*health)
+    }
+
+    /// Mark the nodes past their deadline offline, each change is returned once
+    pub fn check(&mut self, now_ms: u64) -> Vec<(MacAddress, NodeHealth), MAX_NODES> {
+        let mut changes = Vec::new();
+        for (mac, health) in self.nodes.iter_mut() {
+            if health.state != Liveness::Offline && now_ms >= health.deadline_ms() {
+                health.state = Liveness::Offline;
+                // As many changes as nodes, cannot be full
+                let _ = changes.push((*mac, *health));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    use crate::common::mesh_proto::{Frame, FrameBuilder, Header, MessageType, Unit};
+
+    const NODE: MacAddress = [1, 2, 3, 4, 5, 6];
+    const DEFAULT_INTERVAL_MS: u64 = DEFAULT_TRANSMIT_INTERVAL_S as u64 * 1000;
+
+    fn heard(seq: u16) -> Heard {
+        Heard {
+            seq: Some(seq),
+            ..Heard::default()
+        }
+    }
+
+    #[test]
+    fn coming_online_is_reported_once() {
+        let mut table = LivenessTable::new();
+        table.watch(NODE, 0);
+        assert_eq!(table.get(&NODE).unwrap().state, Liveness::Unknown);
+
+        let health = table.on_frame(NODE, &heard(1), 1_000).unwrap();
+        assert_eq!(
+            (health.state, health.last_seen_ms),
+            (Liveness::Online, 1_000)
+        );
+        assert_eq!(table.on_frame(NODE, &heard(2), 2_000), None);
+
+        // Back online after being declared offline
+        let offline_at = table.get(&NODE).unwrap().deadline_ms();
+        assert_eq!(table.check(offline_at).len(), 1);
+        assert!(table.on_frame(NODE, &heard(3), offline_at + 1).is_some());
+        assert_eq!(table.on_frame(NODE, &heard(4), offline_at + 2), None);
+    }
+
+    #[test]
+    fn offline_after_the_missed_intervals() {
+        let mut table = LivenessTable::new();
+        table.on_frame(NODE, &heard(1), 1_000);
+        let deadline = 1_000 + DEFAULT_INTERVAL_MS * OFFLINE_AFTER_MISSED as u64;
+        assert_eq!(table.get(&NODE).unwrap().deadline_ms(), deadline);
+        assert!(table.check(deadline - 1).is_empty());
+
+        let changes = table.check(deadline);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, NODE);
+        assert_eq!(changes[0].1.state, Liveness::Offline);
+        // Reported once
+        assert!(table.check(deadline + DEFAULT_INTERVAL_MS).is_empty());
+    }
+
+    #[test]
+    fn nodes_never_heard_go_offline_from_the_watch() {
+        let mut table = LivenessTable::new();
+        table.watch(NODE, 5_000);
+        let deadline = 5_000 + DEFAULT_INTERVAL_MS * OFFLINE_AFTER_MISSED as u64;
+        assert!(table.check(deadline - 1).is_empty());
+        assert_eq!(table.check(deadline).len(), 1);
+    }
+
+    #[test]
+    fn sequence_gaps_are_counted_across_the_wraparound() {
+        let mut table = LivenessTable::new();
+        let mut gaps = StdVec::new();
+        for seq in [u16::MAX - 1, u16::MAX, 1, 5, 5, 4] {
+            table.on_frame(NODE, &heard(seq), 1_000);
+            gaps.push(table.get(&NODE).unwrap().seq_gaps);
+        }
+        // 0, then 2 to 4 missed, repeats and older frames add nothing
+        assert_eq!(gaps, [0, 0, 1, 4, 4, 4]);
+
+        // Frames outside the sequence keep the last seq
+        table.on_frame(NODE, &Heard::default(), 2_000);
+        table.on_frame(NODE, &heard(5), 3_000);
+        let health = table.get(&NODE).unwrap();
+        assert_eq!((health.last_seq, health.seq_gaps), (Some(5), 4));
+    }
+
+    #[test]
+    fn reported_transmit_interval_overrides_the_default() {
+        let mut table = LivenessTable::new();
+        let with_interval = |interval_s| Heard {
+            seq: Some(1),
+            status: NodeStatus {
+                transmit_interval_s: Some(interval_s),
+                ..NodeStatus::default()
+            },
+            ..Heard::default()
+        };
+        table.on_frame(NODE, &with_interval(60), 1_000);
+        let deadline = 1_000 + 60_000 * OFFLINE_AFTER_MISSED as u64;
+        assert_eq!(table.get(&NODE).unwrap().deadline_ms(), deadline);
+        assert!(table.check(deadline - 1).is_empty());
+        assert_eq!(table.check(deadline).len(), 1);
+
+        // Zero is ignored, the last reported interval stays
+        table.on_frame(NODE, &with_interval(0), 2_000);
+        assert_eq!(table.get(&NODE).unwrap().transmit_interval_ms, 60_000);
+    }
+
+    #[test]
+    fn frames_refresh_the_reported_fields() {
+        let mut table = LivenessTable::new();
+        let firmware = FirmwareVersion::parse("1.2.3");
+        let first = Heard {
+            seq: Some(1),
+            rssi: Some(-60),
+            battery_v: Some(3.7),
+            status: NodeStatus {
+                firmware,
+                ..NodeStatus::default()
+            },
+        };
+        table.on_frame(NODE, &first, 1_000);
+        // Relayed frame without the power or status fields
+        table.on_frame(NODE, &heard(2), 2_000);
+        let health = table.get(&NODE).unwrap();
+        assert_eq!(health.rssi, None);
+        assert_eq!(health.battery_v, Some(3.7));
+        assert_eq!(health.firmware, firmware);
+    }
+
+    #[test]
+    fn battery_voltage_is_read_from_the_payload() {
+        let mut builder = FrameBuilder::new(&Header::new(MessageType::Readings, NODE, 1));
+        for (kind, unit, value) in [
+            (ReadingKind::Temperature, Unit::Celsius, 21.5),
+            (ReadingKind::SupplyVoltage, Unit::Volt, 3.6),
+        ] {
+            builder
+                .push_reading(&MeshReading::new(kind, unit, value))
+                .unwrap();
+        }
+        let frame = builder.finish();
+        let payload = Frame::decode(&frame).unwrap().payload;
+        assert_eq!(battery_voltage(payload), Some(3.6));
+        // Cut in the first reading
+        assert_eq!(battery_voltage(&payload[..6]), None);
+    }
+}
@@ -1,2 +1,18 @@
+pub mod ads1115;
+pub mod battery;
+pub mod buttons;
+pub mod commands;
 pub mod display;
+pub mod downlink;
+pub mod espnow;
+pub mod i2c_bus;
+pub mod io;
+pub mod liveness;
+pub mod modbus_rtu;
+pub mod modbus_tcp;
+pub mod ota;
+pub mod pid;
+pub mod publish;
+pub mod registry;
 pub mod requests;
+pub mod security;
@@ -0,0 +1,734 @@
+//! Modbus RTU master over the AE04 RS-485 port
+//!
+//! Hardware configuration (MAX485, half-duplex):
+//! - TX: GPIO2
+//! - RX: GPIO13
+//! - Direction control (DE/RE): GPIO4
+//!
+//! The frame codec, the master and the poll scheduler only depend on the
+//! `RtuTransport` trait, the UART is plugged in by `Rs485Uart`.
+
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+#[cfg(feature = "esp32")]
+use embedded_io_async::{Read, Write};
+#[cfg(feature = "esp32")]
+use esp_hal::gpio::Output;
+#[cfg(feature = "esp32")]
+use esp_hal::uart::Uart;
+#[cfg(feature = "esp32")]
+use esp_hal::Async;
+use heapless::Vec;
+#[cfg(feature = "esp32")]
+use log::info;
+use log::{debug, warn};
+
+use crate::common::crc::crc16_modbus;
+use crate::gateway_lib::publish::{queue_reading, Reading};
+
+// Max RTU ADU is 256 bytes
+pub const MAX_FRAME_SIZE: usize = 256;
+pub const MAX_POLL_ENTRIES: usize = 16;
+// Max registers per read request (spec limit is 125)
+pub const MAX_READ_REGISTERS: u16 = 125;
+pub const MAX_READ_BITS: u16 = 2000;
+
+const EXCEPTION_FRAME_LEN: usize = 5;
+// Reads of stale bytes before a request, a babbling bus does not stall the master
+const MAX_STALE_READS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FunctionCode {
+    ReadCoils = 0x01,
+    ReadDiscreteInputs = 0x02,
+    ReadHoldingRegisters = 0x03,
+    ReadInputRegisters = 0x04,
+}
+
+impl FunctionCode {
+    fn reads_bits(&self) -> bool {
+        matches!(
+            self,
+            FunctionCode::ReadCoils | FunctionCode::ReadDiscreteInputs
+        )
+    }
+
+    /// Bytes of data in the response for `count` registers or bits
+    pub fn data_len(&self, count: u16) -> usize {
+        if self.reads_bits() {
+            (count as usize).div_ceil(8)
+        } else {
+            count as usize * 2
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExceptionCode {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    SlaveDeviceFailure,
+    Acknowledge,
+    SlaveDeviceBusy,
+    GatewayPathUnavailable,
+    GatewayTargetFailed,
+    Other(u8),
+}
+
+impl ExceptionCode {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x01 => ExceptionCode::IllegalFunction,
+            0x02 => ExceptionCode::IllegalDataAddress,
+            0x03 => ExceptionCode::IllegalDataValue,
+            0x04 => ExceptionCode::SlaveDeviceFailure,
+            0x05 => ExceptionCode::Acknowledge,
+            0x06 => ExceptionCode::SlaveDeviceBusy,
+            0x0A => ExceptionCode::GatewayPathUnavailable,
+            0x0B => ExceptionCode::GatewayTargetFailed,
+            other => ExceptionCode::Other(other),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            ExceptionCode::IllegalFunction => 0x01,
+            ExceptionCode::IllegalDataAddress => 0x02,
+            ExceptionCode::IllegalDataValue => 0x03,
+            ExceptionCode::SlaveDeviceFailure => 0x04,
+            ExceptionCode::Acknowledge => 0x05,
+            ExceptionCode::SlaveDeviceBusy => 0x06,
+            ExceptionCode::GatewayPathUnavailable => 0x0A,
+            ExceptionCode::GatewayTargetFailed => 0x0B,
+            ExceptionCode::Other(code) => *code,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModbusError {
+    Timeout,
+    Crc,
+    InvalidFrame,
+    InvalidRequest,
+    UnexpectedResponse,
+    Exception(ExceptionCode),
+    Io,
+}
+
+impl ModbusError {
+    /// Transient errors are retried, exceptions from the slave are not
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ModbusError::Timeout
+                | ModbusError::Crc
+                | ModbusError::InvalidFrame
+                | ModbusError::Exception(ExceptionCode::SlaveDeviceBusy)
+        )
+    }
+}
+
+// *** Frame codec *** //
+
+/// Encode a read request ADU, returns the 8 bytes frame
+pub fn encode_read_request(
+    slave_id: u8,
+    function: FunctionCode,
+    address: u16,
+    count: u16,
+) -> Result<[u8; 8], ModbusError> {
+    let max = if function.reads_bits() {
+        MAX_READ_BITS
+    } else {
+        MAX_READ_REGISTERS
+    };
+    if count == 0 || count > max || slave_id == 0 || slave_id > 247 {
+        return Err(ModbusError::InvalidRequest);
+    }
+
+    let mut frame = [0u8; 8];
+    frame[0] = slave_id;
+    frame[1] = function as u8;
+    frame[2..4].copy_from_slice(&address.to_be_bytes());
+    frame[4..6].copy_from_slice(&count.to_be_bytes());
+    let crc = crc16_modbus(&frame[..6]);
+    frame[6..8].copy_from_slice(&crc.to_le_bytes());
+    Ok(frame)
+}
+
+/// Expected length of a normal read response ADU
+pub fn expected_response_len(function: FunctionCode, count: u16) -> usize {
+    // slave + function + byte count + data + crc
+    3 + function.data_len(count) + 2
+}
+
+fn check_crc(frame: &[u8]) -> Result<(), ModbusError> {
+    if frame.len() < 4 {
+        return Err(ModbusError::InvalidFrame);
+    }
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    if crc16_modbus(body).to_le_bytes() != [crc[0], crc[1]] {
+        return Err(ModbusError::Crc);
+    }
+    Ok(())
+}
+
+/// Validate a read response ADU, returns the data bytes
+pub fn decode_read_response(
+    frame: &[u8],
+    slave_id: u8,
+    function: FunctionCode,
+    count: u16,
+) -> Result<&[u8], ModbusError> {
+    check_crc(frame)?;
+    if frame[0] != slave_id {
+        return Err(ModbusError::UnexpectedResponse);
+    }
+    if frame[1] == (function as u8 | 0x80) {
+        if frame.len() != EXCEPTION_FRAME_LEN {
+            return Err(ModbusError::InvalidFrame);
+        }
+        return Err(ModbusError::Exception(ExceptionCode::from_u8(frame[2])));
+    }
+    if frame[1] != function as u8 {
+        return Err(ModbusError::UnexpectedResponse);
+    }
+
+    let data_len = function.data_len(count);
+    if frame.len() != expected_response_len(function, count) || frame[2] as usize != data_len {
+        return Err(ModbusError::InvalidFrame);
+    }
+    Ok(&frame[3..3 + data_len])
+}
+
+// *** Data types and scaling *** //
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataType {
+    Bool,
+    U16,
+    I16,
+    // 32 bits values are big-endian words (high word first)
+    U32,
+    I32,
+    F32,
+    // Low word first, common on energy meters
+    F32Swapped,
+}
+
+impl DataType {
+    /// Registers needed to hold the value (bits for Bool)
+    pub fn register_count(&self) -> u16 {
+        match self {
+            DataType::Bool | DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 | DataType::F32 | DataType::F32Swapped => 2,
+        }
+    }
+
+    /// Decode the value from the response data bytes
+    pub fn decode(&self, data: &[u8]) -> Option<f32> {
+        let word =
+            |i: usize| -> Option<[u8; 2]> { Some([*data.get(2 * i)?, *data.get(2 * i + 1)?]) };
+        let dword = |hi: [u8; 2], lo: [u8; 2]| [hi[0], hi[1], lo[0], lo[1]];
+
+        let value = match self {
+            DataType::Bool => f32::from(data.first()? & 0x01),
+            DataType::U16 => f32::from(u16::from_be_bytes(word(0)?)),
+            DataType::I16 => f32::from(i16::from_be_bytes(word(0)?)),
+            DataType::U32 => u32::from_be_bytes(dword(word(0)?, word(1)?)) as f32,
+            DataType::I32 => i32::from_be_bytes(dword(word(0)?, word(1)?)) as f32,
+            DataType::F32 => f32::from_be_bytes(dword(word(0)?, word(1)?)),
+            DataType::F32Swapped => f32::from_be_bytes(dword(word(1)?, word(0)?)),
+        };
+        if value.is_finite() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// One line of the poll table, the result is `raw * scale + offset`
+#[derive(Clone, Copy, Debug)]
+pub struct PollEntry {
+    pub name: &'static str,
+    pub slave_id: u8,
+    pub function: FunctionCode,
+    pub address: u16,
+    pub count: u16,
+    pub data_type: DataType,
+    pub scale: f32,
+    pub offset: f32,
+    pub unit: &'static str,
+    pub topic: &'static str,
+    pub interval: Duration,
+}
+
+impl PollEntry {
+    /// None when the raw value or its scaling is not finite
+    pub fn scaled_value(&self, data: &[u8]) -> Option<f32> {
+        self.data_type
+            .decode(data)
+            .map(|raw| raw * self.scale + self.offset)
+            .filter(|value| value.is_finite())
+    }
+}
+
+// *** Poll scheduler *** //
+
+/// Keeps the next due time of every poll table entry
+pub struct PollScheduler {
+    next_due_ms: Vec<u64, MAX_POLL_ENTRIES>,
+}
+
+impl PollScheduler {
+    /// All entries are due right away, extra entries past the max are ignored
+    pub fn new(table: &[PollEntry], now_ms: u64) -> PollScheduler {
+        let mut next_due_ms = Vec::new();
+        for _ in table.iter().take(MAX_POLL_ENTRIES) {
+            let _ = next_due_ms.push(now_ms);
+        }
+        PollScheduler { next_due_ms }
+    }
+
+    /// Index of the most overdue entry, if any
+    pub fn next_due(&self, now_ms: u64) -> Option<usize> {
+        self.next_due_ms
+            .iter()
+            .enumerate()
+            .filter(|(_, due)| **due <= now_ms)
+            .min_by_key(|(_, due)| **due)
+            .map(|(i, _)| i)
+    }
+
+    /// Schedule the entry one interval after its previous due time
+    pub fn mark_polled(&mut self, index: usize, interval: Duration, now_ms: u64) {
+        if let Some(due) = self.next_due_ms.get_mut(index) {
+            let interval_ms = interval.as_millis().max(1);
+            *due += interval_ms;
+            // Do not try to catch up on missed polls after a long stall
+            if *due <= now_ms {
+                *due = now_ms + interval_ms;
+            }
+        }
+    }
+
+    /// Time until the next entry is due
+    pub fn time_until_next(&self, now_ms: u64) -> Option<u64> {
+        self.next_due_ms
+            .iter()
+            .min()
+            .map(|due| due.saturating_sub(now_ms))
+    }
+}
+
+// *** Transport and master *** //
+
+/// Half-duplex serial link used by the master
+#[allow(async_fn_in_trait)]
+pub trait RtuTransport {
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ModbusError>;
+    /// Read whatever bytes are available, waits for at least one
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ModbusError>;
+}
+
+pub struct RtuMasterConfig {
+    pub response_timeout: Duration,
+    // Silence ending a frame, must be >= 3.5 chars at the bus baudrate
+    pub frame_gap: Duration,
+    pub retries: u8,
+    // Delay between two requests on the bus
+    pub turnaround_delay: Duration,
+}
+
+impl Default for RtuMasterConfig {
+    fn default() -> Self {
+        RtuMasterConfig {
+            response_timeout: Duration::from_millis(500),
+            frame_gap: Duration::from_millis(5),
+            retries: 2,
+            turnaround_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+pub struct RtuMaster<T: RtuTransport> {
+    transport: T,
+    config: RtuMasterConfig,
+}
+
+impl<T: RtuTransport> RtuMaster<T> {
+    pub fn new(transport: T, config: RtuMasterConfig) -> Self {
+        RtuMaster { transport, config }
+    }
+
+    /// Read registers or bits, data bytes are copied to `out`
+    pub async fn read(
+        &mut self,
+        slave_id: u8,
+        function: FunctionCode,
+        address: u16,
+        count: u16,
+        out: &mut [u8],
+    ) -> Result<usize, ModbusError> {
+        let request = encode_read_request(slave_id, function, address, count)?;
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .transaction(&request, slave_id, function, count, out)
+                .await;
+            match result {
+                Err(e) if e.is_retryable() && attempt < self.config.retries => {
+                    attempt += 1;
+                    debug!(
+                        "Modbus slave={} error {:?}, retry {}/{}",
+                        slave_id, e, attempt, self.config.retries
+                    );
+                    Timer::after(self.config.turnaround_delay * u32::from(attempt)).await;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    async fn transaction(
+        &mut self,
+        request: &[u8],
+        slave_id: u8,
+        function: FunctionCode,
+        count: u16,
+        out: &mut [u8],
+    ) -> Result<usize, ModbusError> {
+        self.discard_stale_input().await;
+        self.transport.write_frame(request).await?;
+
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let len = self
+            .receive_frame(&mut frame, expected_response_len(function, count))
+            .await?;
+        Timer::after(self.config.turnaround_delay).await;
+
+        let data = decode_read_response(&frame[..len], slave_id, function, count)?;
+        let out = out
+            .get_mut(..data.len())
+            .ok_or(ModbusError::InvalidRequest)?;
+        out.copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    /// Drop the bytes already received, e.g. the end of a late response, so
+    /// they are not taken for the start of the next one
+    async fn discard_stale_input(&mut self) {
+        let mut stale = [0u8; MAX_FRAME_SIZE];
+        for _ in 0..MAX_STALE_READS {
+            match with_timeout(Duration::from_ticks(0), self.transport.read(&mut stale)).await {
+                Ok(Ok(n)) if n > 0 => debug!("Modbus dropped {} stale bytes", n),
+                _ => return,
+            }
+        }
+    }
+
+    /// Read bytes until the expected length, an exception frame or a silent gap
+    async fn receive_frame(
+        &mut self,
+        frame: &mut [u8; MAX_FRAME_SIZE],
+        expected: usize,
+    ) -> Result<usize, ModbusError> {
+        let mut len = with_timeout(self.config.response_timeout, self.transport.read(frame))
+            .await
+            .map_err(|_| ModbusError::Timeout)??;
+
+        loop {
+            let target = if len >= 2 && frame[1] & 0x80 != 0 {
+                EXCEPTION_FRAME_LEN
+            } else {
+                expected
+            };
+            if len >= target || len >= MAX_FRAME_SIZE {
+                return Ok(len);
+            }
+            match with_timeout(
+                self.config.frame_gap,
+                self.transport.read(&mut frame[len..]),
+            )
+            .await
+            {
+                Ok(Ok(n)) => len += n,
+                Ok(Err(e)) => return Err(e),
+                // Gap ended the frame early, let the codec reject it
+                Err(_) => return Ok(len),
+            }
+        }
+    }
+}
+
+/// RS-485 transport on an async UART with the MAX485 direction pin
+#[cfg(feature = "esp32")]
+pub struct Rs485Uart {
+    uart: Uart<'static, Async>,
+    direction: Output<'static>,
+}
+
+#[cfg(feature = "esp32")]
+impl Rs485Uart {
+    pub fn new(uart: Uart<'static, Async>, mut direction: Output<'static>) -> Self {
+        // Receive mode by default
+        direction.set_low();
+        Rs485Uart { uart, direction }
+    }
+}
+
+#[cfg(feature = "esp32")]
+impl RtuTransport for Rs485Uart {
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ModbusError> {
+        self.direction.set_high();
+        let result = match Write::write_all(&mut self.uart, frame).await {
+            Ok(()) => Write::flush(&mut self.uart)
+                .await
+                .map_err(|_| ModbusError::Io),
+            Err(_) => Err(ModbusError::Io),
+        };
+        self.direction.set_low();
+        result
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ModbusError> {
+        Read::read(&mut self.uart, buf)
+            .await
+            .map_err(|_| ModbusError::Io)
+    }
+}
+
+/// Poll the table and queue the scaled results on the shared publish path
+pub async fn run_poll_table<T: RtuTransport>(master: &mut RtuMaster<T>, table: &[PollEntry]) -> ! {
+    let mut scheduler = PollScheduler::new(table, Instant::now().as_millis());
+    let mut data = [0u8; MAX_FRAME_SIZE];
+
+    loop {
+        let now_ms = Instant::now().as_millis();
+        let Some(index) = scheduler.next_due(now_ms) else {
+            let wait_ms = scheduler.time_until_next(now_ms).unwrap_or(1000);
+            Timer::after_millis(wait_ms).await;
+            continue;
+        };
+        let entry = &table[index];
+        scheduler.mark_polled(index, entry.interval, now_ms);
+
+        let count = entry.count.max(entry.data_type.register_count());
+        match master
+            .read(
+                entry.slave_id,
+                entry.function,
+                entry.address,
+                count,
+                &mut data,
+            )
+            .await
+        {
+            Ok(len) => match entry.scaled_value(&data[..len]) {
+                Some(value) => {
+                    debug!("Modbus '{}' = {} {}", entry.name, value, entry.unit);
+                    if let Some(reading) = Reading::new(entry.topic, entry.name, value, entry.unit)
+                    {
+                        queue_reading(reading);
+                    }
+                }
+                None => warn!("Modbus '{}' returned an invalid value", entry.name),
+            },
+            Err(e) => warn!(
+                "Modbus '{}' (slave={}, addr={}) failed: {:?}",
+                entry.name, entry.slave_id, entry.address, e
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "esp32")]
+#[embassy_executor::task]
+pub async fn modbus_rtu_task(
+    uart: Uart<'static, Async>,
+    direction: Output<'static>,
+    table: &'static [PollEntry],
+) {
+    info!(
+        "Starting Modbus RTU master with {} poll entries",
+        table.len()
+    );
+    let mut master = RtuMaster::new(Rs485Uart::new(uart, direction), RtuMasterConfig::default());
+    run_poll_table(&mut master, table).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::vec::Vec as StdVec;
+
+    use embassy_futures::block_on;
+
+    /// Slave answering each request with the next scripted response, `None`
+    /// staying silent
+    struct FakeBus {
+        responses: VecDeque<Option<StdVec<u8>>>,
+        rx: VecDeque<u8>,
+        requests: StdVec<StdVec<u8>>,
+    }
+
+    impl FakeBus {
+        fn new(responses: &[Option<&[u8]>]) -> Self {
+            FakeBus {
+                responses: responses.iter().map(|r| r.map(|r| r.to_vec())).collect(),
+                rx: VecDeque::new(),
+                requests: StdVec::new(),
+            }
+        }
+    }
+
+    impl RtuTransport for FakeBus {
+        async fn write_frame(&mut self, frame: &[u8]) -> Result<(), ModbusError> {
+            self.requests.push(frame.to_vec());
+            if let Some(Some(response)) = self.responses.pop_front() {
+                self.rx.extend(response);
+            }
+            Ok(())
+        }
+
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ModbusError> {
+            if self.rx.is_empty() {
+                core::future::pending::<()>().await;
+            }
+            let n = buf.len().min(self.rx.len());
+            for byte in buf.iter_mut().take(n) {
+                *byte = self.rx.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    fn with_crc(body: &[u8]) -> StdVec<u8> {
+        let mut frame = body.to_vec();
+        frame.extend(crc16_modbus(body).to_le_bytes());
+        frame
+    }
+
+    fn master(bus: FakeBus) -> RtuMaster<FakeBus> {
+        let config = RtuMasterConfig {
+            response_timeout: Duration::from_millis(20),
+            frame_gap: Duration::from_millis(2),
+            retries: 2,
+            turnaround_delay: Duration::from_millis(1),
+        };
+        RtuMaster::new(bus, config)
+    }
+
+    /// Same response to the request and both retries
+    fn master_with_only(response: &[u8]) -> RtuMaster<FakeBus> {
+        master(FakeBus::new(&[
+            Some(response),
+            Some(response),
+            Some(response),
+        ]))
+    }
+
+    fn read_two_registers(master: &mut RtuMaster<FakeBus>) -> Result<StdVec<u8>, ModbusError> {
+        let mut out = [0u8; 4];
+        let len = block_on(master.read(1, FunctionCode::ReadInputRegisters, 0, 2, &mut out))?;
+        Ok(out[..len].to_vec())
+    }
+
+    #[test]
+    fn reads_the_registers_of_a_valid_response() {
+        let response = with_crc(&[1, 0x04, 4, 0x43, 0x5C, 0x00, 0x00]);
+        let mut master = master(FakeBus::new(&[Some(&response)]));
+        assert_eq!(read_two_registers(&mut master).unwrap(), [0x43, 0x5C, 0, 0]);
+        assert_eq!(
+            master.transport.requests,
+            [
+                encode_read_request(1, FunctionCode::ReadInputRegisters, 0, 2)
+                    .unwrap()
+                    .to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_a_response_with_a_bad_crc() {
+        let response = with_crc(&[1, 0x04, 4, 0, 1, 0, 2]);
+        let mut corrupted = response.clone();
+        corrupted[4] ^= 0x10;
+        let mut master = master(FakeBus::new(&[Some(&corrupted), Some(&response)]));
+        assert_eq!(read_two_registers(&mut master).unwrap(), [0, 1, 0, 2]);
+        assert_eq!(master.transport.requests.len(), 2);
+
+        let mut master = master_with_only(&corrupted);
+        assert_eq!(read_two_registers(&mut master), Err(ModbusError::Crc));
+        assert_eq!(master.transport.requests.len(), 3);
+    }
+
+    #[test]
+    fn exceptions_are_not_retried() {
+        let exception = with_crc(&[1, 0x84, 0x02]);
+        let mut master = master_with_only(&exception);
+        assert_eq!(
+            read_two_registers(&mut master),
+            Err(ModbusError::Exception(ExceptionCode::IllegalDataAddress))
+        );
+        assert_eq!(master.transport.requests.len(), 1);
+    }
+
+    #[test]
+    fn silent_slave_times_out_after_the_retries() {
+        let mut master = master(FakeBus::new(&[None, None, None]));
+        assert_eq!(read_two_registers(&mut master), Err(ModbusError::Timeout));
+        assert_eq!(master.transport.requests.len(), 3);
+    }
+
+    #[test]
+    fn short_frame_ends_at_the_gap() {
+        let short = with_crc(&[1, 0x04, 4, 0, 1]);
+        let mut master = master_with_only(&short);
+        assert_eq!(
+            read_two_registers(&mut master),
+            Err(ModbusError::InvalidFrame)
+        );
+    }
+
+    #[test]
+    fn stale_bytes_are_dropped_before_the_request() {
+        let response = with_crc(&[1, 0x04, 4, 0, 7, 0, 8]);
+        let mut bus = FakeBus::new(&[Some(&response)]);
+        // Tail of a response which came after its timeout
+        bus.rx.extend([0x12, 0x34, 0x56]);
+        let mut master = master(bus);
+        assert_eq!(read_two_registers(&mut master).unwrap(), [0, 7, 0, 8]);
+    }
+
+    #[test]
+    fn scaled_values_must_be_finite() {
+        let entry = PollEntry {
+            name: "meter",
+            slave_id: 1,
+            function: FunctionCode::ReadInputRegisters,
+            address: 0,
+            count: 2,
+            data_type: DataType::F32,
+            scale: 1e37,
+            offset: 0.0,
+            unit: "W",
+            topic: "/readings/modbus/meter",
+            interval: Duration::from_secs(30),
+        };
+        // 220.0 is fine, 1e37 times it overflows
+        let data = 220.0f32.to_be_bytes();
+        assert_eq!(entry.scaled_value(&data), None);
+        let entry = PollEntry {
+            scale: 0.5,
+            offset: 1.0,
+            ..entry
+        };
+        assert_eq!(entry.scaled_value(&data), Some(111.0));
+        assert_eq!(entry.scaled_value(&f32::NAN.to_be_bytes()), None);
+    }
+}
@@ -0,0 +1,592 @@
+//! Modbus TCP server exposing the gateway readings and outputs to SCADA
+//!
+//! Register map (0-based addresses):
+//! - Input registers 0-5: analog channels A0-A5 scaled x10 as i16
+//! - Input registers 100-111: analog channels A0-A5 as IEEE-754 f32 (high word first)
+//! - Discrete inputs 0-5: digital inputs I.1, I.2, I.3, I.5, I.6, I.7 (no I.4)
+//! - Coils 0-1: transistor outputs T0.0-T0.1 (writable unless locked by a loop)
+//! - Holding registers 0-7: diagnostics (read-only), see the `HR_*` addresses
+//!
+//! The request handling only depends on the `DataModel` trait and on
+//! `embedded_io_async` streams, the `embassy-net` socket is plugged in by the task.
+
+use core::sync::atomic::Ordering;
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{Duration, Instant};
+use embedded_io_async::{Read, ReadExactError, Write};
+use log::{debug, info, warn};
+
+use crate::common::analog::{reading, ANALOG_CHANNEL_COUNT};
+use crate::common::connectivity::connectivity;
+use crate::common::wifi::{approx_rssi_to_percent, CURRENT_RSSI};
+use crate::gateway_lib::io::{
+    digital_input, output, request_output, OutputError, DIGITAL_INPUT_COUNT,
+    TRANSISTOR_OUTPUT_COUNT,
+};
+use crate::gateway_lib::modbus_rtu::ExceptionCode;
+use crate::gateway_lib::publish::{pending_statuses, READINGS};
+
+pub const MODBUS_TCP_PORT: u16 = 502;
+const MBAP_HEADER_LEN: usize = 7;
+// MBAP header + max PDU of 253 bytes
+pub const MAX_ADU_SIZE: usize = MBAP_HEADER_LEN + 253;
+const MAX_READ_REGISTERS: u16 = 125;
+const MAX_READ_BITS: u16 = 2000;
+const MAX_WRITE_BITS: u16 = 1968;
+
+const FLOAT_REGISTERS_BASE: u16 = 100;
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Diagnostics exposed as read-only holding registers
+pub const HR_RSSI_DBM: u16 = 0;
+pub const HR_WIFI_PERCENT: u16 = 1;
+pub const HR_UPTIME_HIGH: u16 = 2;
+pub const HR_UPTIME_LOW: u16 = 3;
+// `LinkState` code
+pub const HR_LINK_STATE: u16 = 4;
+pub const HR_READINGS_QUEUED: u16 = 5;
+// `LinkError` code of the last failure, 0 for none
+pub const HR_LINK_ERROR: u16 = 6;
+pub const HR_LINK_FAILURES: u16 = 7;
+
+/// Data seen by the server, one bit/register at a time
+pub trait DataModel {
+    fn coil(&self, address: u16) -> Option<bool>;
+    fn discrete_input(&self, address: u16) -> Option<bool>;
+    fn input_register(&self, address: u16) -> Option<u16>;
+    fn holding_register(&self, address: u16) -> Option<u16>;
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), ExceptionCode>;
+}
+
+// *** Request processing *** //
+
+fn exception(function: u8, code: ExceptionCode, pdu: &mut [u8]) -> usize {
+    pdu[0] = function | 0x80;
+    pdu[1] = code.to_u8();
+    2
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *data.get(offset)?,
+        *data.get(offset + 1)?,
+    ]))
+}
+
+fn read_bits(
+    request: &[u8],
+    response: &mut [u8],
+    read: impl Fn(u16) -> Option<bool>,
+) -> Result<usize, ExceptionCode> {
+    let (Some(address), Some(count)) = (read_u16(request, 1), read_u16(request, 3)) else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    if count == 0 || count > MAX_READ_BITS {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let byte_count = (count as usize).div_ceil(8);
+    response[1] = byte_count as u8;
+    response[2..2 + byte_count].fill(0);
+    for i in 0..count {
+        let bit = read(
+            address
+                .checked_add(i)
+                .ok_or(ExceptionCode::IllegalDataAddress)?,
+        )
+        .ok_or(ExceptionCode::IllegalDataAddress)?;
+        if bit {
+            response[2 + i as usize / 8] |= 1 << (i % 8);
+        }
+    }
+    Ok(2 + byte_count)
+}
+
+fn read_registers(
+    request: &[u8],
+    response: &mut [u8],
+    read: impl Fn(u16) -> Option<u16>,
+) -> Result<usize, ExceptionCode> {
+    let (Some(address), Some(count)) = (read_u16(request, 1), read_u16(request, 3)) else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    if count == 0 || count > MAX_READ_REGISTERS {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    response[1] = (count * 2) as u8;
+    for i in 0..count {
+        let value = read(
+            address
+                .checked_add(i)
+                .ok_or(ExceptionCode::IllegalDataAddress)?,
+        )
+        .ok_or(ExceptionCode::IllegalDataAddress)?;
+        let offset = 2 + 2 * i as usize;
+        response[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    Ok(2 + count as usize * 2)
+}
+
+fn write_single_coil<M: DataModel>(
+    request: &[u8],
+    response: &mut [u8],
+    model: &mut M,
+) -> Result<usize, ExceptionCode> {
+    let (Some(address), Some(value)) = (read_u16(request, 1), read_u16(request, 3)) else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    let on = match value {
+        0xFF00 => true,
+        0x0000 => false,
+        _ => return Err(ExceptionCode::IllegalDataValue),
+    };
+    model.write_coil(address, on)?;
+    // Echo of the request
+    response[..5].copy_from_slice(&request[..5]);
+    Ok(5)
+}
+
+fn write_multiple_coils<M: DataModel>(
+    request: &[u8],
+    response: &mut [u8],
+    model: &mut M,
+) -> Result<usize, ExceptionCode> {
+    let (Some(address), Some(count)) = (read_u16(request, 1), read_u16(request, 3)) else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    let byte_count = (count as usize).div_ceil(8);
+    if count == 0
+        || count > MAX_WRITE_BITS
+        || request.get(5).copied() != Some(byte_count as u8)
+        || request.len() != 6 + byte_count
+    {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    // Validate the whole range before touching any output
+    for i in 0..count {
+        let coil = address
+            .checked_add(i)
+            .ok_or(ExceptionCode::IllegalDataAddress)?;
+        model.coil(coil).ok_or(ExceptionCode::IllegalDataAddress)?;
+    }
+    for i in 0..count {
+        let on = request[6 + i as usize / 8] & (1 << (i % 8)) != 0;
+        model.write_coil(address + i, on)?;
+    }
+    response[..5].copy_from_slice(&request[..5]);
+    Ok(5)
+}
+
+/// Process a request PDU, writes the response PDU and returns its length
+pub fn process_pdu<M: DataModel>(request: &[u8], response: &mut [u8], model: &mut M) -> usize {
+    let Some(&function) = request.first() else {
+        return exception(0, ExceptionCode::IllegalFunction, response);
+    };
+    response[0] = function;
+    let result = match function {
+        0x01 => read_bits(request, response, |a| model.coil(a)),
+        0x02 => read_bits(request, response, |a| model.discrete_input(a)),
+        0x03 => read_registers(request, response, |a| model.holding_register(a)),
+        0x04 => read_registers(request, response, |a| model.input_register(a)),
+        0x05 => write_single_coil(request, response, model),
+        0x0F => write_multiple_coils(request, response, model),
+        _ => Err(ExceptionCode::IllegalFunction),
+    };
+    match result {
+        Ok(len) => len,
+        Err(code) => exception(function, code, response),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MbapHeader {
+    pub transaction_id: u16,
+    pub protocol_id: u16,
+    pub length: u16,
+    pub unit_id: u8,
+}
+
+impl MbapHeader {
+    pub fn decode(header: &[u8; MBAP_HEADER_LEN]) -> Option<MbapHeader> {
+        let mbap = MbapHeader {
+            transaction_id: u16::from_be_bytes([header[0], header[1]]),
+            protocol_id: u16::from_be_bytes([header[2], header[3]]),
+            length: u16::from_be_bytes([header[4], header[5]]),
+            unit_id: header[6],
+        };
+        // Length counts the unit id and the PDU
+        let length = mbap.length as usize;
+        if mbap.protocol_id != 0 || !(2..=MAX_ADU_SIZE - MBAP_HEADER_LEN + 1).contains(&length) {
+            return None;
+        }
+        Some(mbap)
+    }
+
+    fn encode(&self, pdu_len: usize, out: &mut [u8]) {
+        out[0..2].copy_from_slice(&self.transaction_id.to_be_bytes());
+        out[2..4].copy_from_slice(&self.protocol_id.to_be_bytes());
+        out[4..6].copy_from_slice(&(pdu_len as u16 + 1).to_be_bytes());
+        out[6] = self.unit_id;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServeError {
+    Closed,
+    InvalidHeader,
+    Io,
+}
+
+/// Serve requests on a connected stream until it is closed or invalid
+pub async fn serve_connection<S: Read + Write, M: DataModel>(
+    stream: &mut S,
+    model: &mut M,
+) -> ServeError {
+    let mut request = [0u8; MAX_ADU_SIZE];
+    let mut response = [0u8; MAX_ADU_SIZE];
+    loop {
+        let mut header = [0u8; MBAP_HEADER_LEN];
+        match stream.read_exact(&mut header).await {
+            Ok(()) => {}
+            Err(ReadExactError::UnexpectedEof) => return ServeError::Closed,
+            Err(ReadExactError::Other(_)) => return ServeError::Io,
+        }
+        let Some(mbap) = MbapHeader::decode(&header) else {
+            return ServeError::InvalidHeader;
+        };
+
+        let pdu_len = mbap.length as usize - 1;
+        if stream.read_exact(&mut request[..pdu_len]).await.is_err() {
+            return ServeError::Io;
+        }
+
+        let (mbap_out, pdu_out) = response.split_at_mut(MBAP_HEADER_LEN);
+        let response_len = process_pdu(&request[..pdu_len], pdu_out, model);
+        mbap.encode(response_len, mbap_out);
+        debug!(
+            "Modbus TCP tx={} fc=0x{:02X} -> {} bytes",
+            mbap.transaction_id, request[0], response_len
+        );
+
+        if stream
+            .write_all(&response[..MBAP_HEADER_LEN + response_len])
+            .await
+            .is_err()
+            || stream.flush().await.is_err()
+        {
+            return ServeError::Io;
+        }
+    }
+}
+
+// *** Gateway data model *** //
+
+/// Live view on the gateway atomics
+pub struct GatewayDataModel;
+
+impl DataModel for GatewayDataModel {
+    fn coil(&self, address: u16) -> Option<bool> {
+        output(address as usize)
+    }
+
+    fn discrete_input(&self, address: u16) -> Option<bool> {
+        if address as usize >= DIGITAL_INPUT_COUNT {
+            return None;
+        }
+        digital_input(address as usize)
+    }
+
+    fn input_register(&self, address: u16) -> Option<u16> {
+        let address = address as usize;
+        let float_base = FLOAT_REGISTERS_BASE as usize;
+        if address < ANALOG_CHANNEL_COUNT {
+            let scaled = (reading(address) * 10.0).clamp(i16::MIN as f32, i16::MAX as f32);
+            // NaN saturates to 0
+            return Some(scaled as i16 as u16);
+        }
+        if (float_base..float_base + 2 * ANALOG_CHANNEL_COUNT).contains(&address) {
+            let offset = address - float_base;
+            let bits = reading(offset / 2).to_bits();
+            let words = [(bits >> 16) as u16, bits as u16];
+            return Some(words[offset % 2]);
+        }
+        None
+    }
+
+    fn holding_register(&self, address: u16) -> Option<u16> {
+        let uptime_s = Instant::now().as_secs() as u32;
+        let link = connectivity();
+        let value = match address {
+            HR_RSSI_DBM => CURRENT_RSSI.load(Ordering::Relaxed) as i16 as u16,
+            HR_WIFI_PERCENT => u16::from(approx_rssi_to_percent(&CURRENT_RSSI)),
+            HR_UPTIME_HIGH => (uptime_s >> 16) as u16,
+            HR_UPTIME_LOW => uptime_s as u16,
+            HR_LINK_STATE => u16::from(link.state as u8),
+            HR_READINGS_QUEUED => (READINGS.len() + pending_statuses()) as u16,
+            HR_LINK_ERROR => u16::from(link.last_error.map_or(0, |error| error as u8)),
+            HR_LINK_FAILURES => link.failures.min(u16::MAX as u32) as u16,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn write_coil(&mut self, address: u16, value: bool) -> Result<(), ExceptionCode> {
+        if address as usize >= TRANSISTOR_OUTPUT_COUNT {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        match request_output(address as usize, value) {
+            Ok(()) => Ok(()),
+            Err(OutputError::InvalidIndex) => Err(ExceptionCode::IllegalDataAddress),
+            Err(OutputError::Locked) => Err(ExceptionCode::SlaveDeviceFailure),
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn modbus_tcp_task(stack: Stack<'static>) {
+    let mut rx_buffer = [0u8; 512];
+    let mut tx_buffer = [0u8; 512];
+    let mut model = GatewayDataModel;
+
+    info!("Starting Modbus TCP server on port {}", MODBUS_TCP_PORT);
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(CLIENT_IDLE_TIMEOUT));
+
+        if let Err(e) = socket.accept(MODBUS_TCP_PORT).await {
+            warn!("Modbus TCP accept error: {:?}", e);
+            continue;
+        }
+        info!(
+            "Modbus TCP client connected from {:?}",
+            socket.remote_endpoint()
+        );
+
+        let reason = serve_connection(&mut socket, &mut model).await;
+        info!("Modbus TCP client disconnected: {:?}", reason);
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    use embassy_futures::block_on;
+    use embedded_io_async::{ErrorKind, ErrorType};
+
+    /// Client side of a connection: the bytes it sent, read in small
+    /// segments, and the ones the server wrote back
+    struct MemStream {
+        rx: StdVec<u8>,
+        position: usize,
+        tx: StdVec<u8>,
+    }
+
+    impl MemStream {
+        fn new(requests: &[&[u8]]) -> Self {
+            MemStream {
+                rx: requests.concat(),
+                position: 0,
+                tx: StdVec::new(),
+            }
+        }
+    }
+
+    impl ErrorType for MemStream {
+        type Error = ErrorKind;
+    }
+
+    impl Read for MemStream {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+            let len = buf.len().min(5).min(self.rx.len() - self.position);
+            buf[..len].copy_from_slice(&self.rx[self.position..self.position + len]);
+            self.position += len;
+            Ok(len)
+        }
+    }
+
+    impl Write for MemStream {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+            self.tx.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// Two coils, the second one locked, and three registers of each kind
+    struct FakeModel {
+        coils: [bool; 2],
+    }
+
+    impl DataModel for FakeModel {
+        fn coil(&self, address: u16) -> Option<bool> {
+            self.coils.get(address as usize).copied()
+        }
+
+        fn discrete_input(&self, address: u16) -> Option<bool> {
+            (address < 10).then_some(address.is_multiple_of(3))
+        }
+
+        fn input_register(&self, address: u16) -> Option<u16> {
+            (address < 3).then(|| 0x1000 + address)
+        }
+
+        fn holding_register(&self, address: u16) -> Option<u16> {
+            (address < 3).then(|| 0x2000 + address)
+        }
+
+        fn write_coil(&mut self, address: u16, value: bool) -> Result<(), ExceptionCode> {
+            match address {
+                0 => {
+                    self.coils[0] = value;
+                    Ok(())
+                }
+                1 => Err(ExceptionCode::SlaveDeviceFailure),
+                _ => Err(ExceptionCode::IllegalDataAddress),
+            }
+        }
+    }
+
+    fn adu(transaction_id: u16, unit_id: u8, pdu: &[u8]) -> StdVec<u8> {
+        let mut adu = transaction_id.to_be_bytes().to_vec();
+        adu.extend([0, 0]);
+        adu.extend((pdu.len() as u16 + 1).to_be_bytes());
+        adu.push(unit_id);
+        adu.extend_from_slice(pdu);
+        adu
+    }
+
+    fn serve(requests: &[&[u8]], model: &mut FakeModel) -> (ServeError, StdVec<u8>) {
+        let mut stream = MemStream::new(requests);
+        let reason = block_on(serve_connection(&mut stream, model));
+        (reason, stream.tx)
+    }
+
+    fn pdu(request: &[u8]) -> StdVec<u8> {
+        let mut model = FakeModel {
+            coils: [false, true],
+        };
+        let mut response = [0u8; MAX_ADU_SIZE];
+        let len = process_pdu(request, &mut response, &mut model);
+        response[..len].to_vec()
+    }
+
+    #[test]
+    fn reads_registers_and_bits() {
+        assert_eq!(pdu(&[0x04, 0, 1, 0, 2]), [0x04, 4, 0x10, 0x01, 0x10, 0x02]);
+        assert_eq!(pdu(&[0x03, 0, 0, 0, 1]), [0x03, 2, 0x20, 0x00]);
+        // Inputs 0, 3, 6 and 9 set, LSB first
+        assert_eq!(pdu(&[0x02, 0, 0, 0, 10]), [0x02, 2, 0b0100_1001, 0b10]);
+        assert_eq!(pdu(&[0x01, 0, 0, 0, 2]), [0x01, 1, 0b10]);
+    }
+
+    #[test]
+    fn writes_coils() {
+        let mut model = FakeModel {
+            coils: [false, false],
+        };
+        let mut response = [0u8; MAX_ADU_SIZE];
+        let request = [0x05, 0, 0, 0xFF, 0x00];
+        let len = process_pdu(&request, &mut response, &mut model);
+        assert_eq!(response[..len], request);
+        assert!(model.coils[0]);
+
+        let request = [0x0F, 0, 0, 0, 1, 1, 0b0];
+        let len = process_pdu(&request, &mut response, &mut model);
+        assert_eq!(response[..len], request[..5]);
+        assert!(!model.coils[0]);
+    }
+
+    #[test]
+    fn invalid_requests_are_exceptions() {
+        // Unknown function, empty PDU
+        assert_eq!(pdu(&[0x2B, 0x0E]), [0xAB, 0x01]);
+        assert_eq!(pdu(&[]), [0x80, 0x01]);
+        // Out of the map, or running past it
+        assert_eq!(pdu(&[0x04, 0, 3, 0, 1]), [0x84, 0x02]);
+        assert_eq!(pdu(&[0x03, 0, 2, 0, 2]), [0x83, 0x02]);
+        assert_eq!(pdu(&[0x03, 0xFF, 0xFF, 0, 2]), [0x83, 0x02]);
+        // Counts of zero or over the limits, truncated request
+        assert_eq!(pdu(&[0x04, 0, 0, 0, 0]), [0x84, 0x03]);
+        assert_eq!(pdu(&[0x03, 0, 0, 0, 126]), [0x83, 0x03]);
+        assert_eq!(pdu(&[0x01, 0, 0, 0x07, 0xD1]), [0x81, 0x03]);
+        assert_eq!(pdu(&[0x04, 0, 0]), [0x84, 0x03]);
+        // Coil value other than on or off, locked coil
+        assert_eq!(pdu(&[0x05, 0, 0, 0x12, 0x34]), [0x85, 0x03]);
+        assert_eq!(pdu(&[0x05, 0, 1, 0xFF, 0x00]), [0x85, 0x04]);
+        // Byte count not matching the coils, range past the outputs
+        assert_eq!(pdu(&[0x0F, 0, 0, 0, 2, 2, 0b11]), [0x8F, 0x03]);
+        assert_eq!(pdu(&[0x0F, 0, 1, 0, 2, 1, 0b11]), [0x8F, 0x02]);
+    }
+
+    #[test]
+    fn multiple_coils_are_checked_before_writing() {
+        let mut model = FakeModel {
+            coils: [false, false],
+        };
+        let mut response = [0u8; MAX_ADU_SIZE];
+        let len = process_pdu(&[0x0F, 0, 0, 0, 3, 1, 0b111], &mut response, &mut model);
+        assert_eq!(response[..len], [0x8F, 0x02]);
+        assert_eq!(model.coils, [false, false]);
+    }
+
+    #[test]
+    fn responses_echo_the_mbap_header() {
+        let mut model = FakeModel {
+            coils: [false, false],
+        };
+        let first = adu(0x0102, 0x11, &[0x03, 0, 1, 0, 1]);
+        let second = adu(0xBEEF, 0xFF, &[0x04, 0, 9, 0, 1]);
+        let (reason, tx) = serve(&[&first, &second], &mut model);
+        assert_eq!(reason, ServeError::Closed);
+
+        // Pipelined requests answered in order, each with its own
+        // transaction and unit ids, and the length of its own PDU
+        let mut expected = adu(0x0102, 0x11, &[0x03, 2, 0x20, 0x01]);
+        expected.extend(adu(0xBEEF, 0xFF, &[0x84, 0x02]));
+        assert_eq!(tx, expected);
+        let mbap = MbapHeader::decode(tx[..MBAP_HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(
+            mbap,
+            MbapHeader {
+                transaction_id: 0x0102,
+                protocol_id: 0,
+                length: 5,
+                unit_id: 0x11,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_headers_close_the_connection() {
+        let mut model = FakeModel {
+            coils: [false, false],
+        };
+        let valid = adu(1, 1, &[0x03, 0, 0, 0, 1]);
+
+        // Other protocol than Modbus
+        let mut other_protocol = adu(2, 1, &[0x03, 0, 0, 0, 1]);
+        other_protocol[3] = 1;
+        let (reason, tx) = serve(&[&valid, &other_protocol, &valid], &mut model);
+        assert_eq!(reason, ServeError::InvalidHeader);
+        // Only the request before it was answered
+        assert_eq!(tx, adu(1, 1, &[0x03, 2, 0x20, 0x00]));
+
+        // Lengths without a function code, or larger than an ADU
+        let mut empty = adu(3, 1, &[]);
+        assert_eq!(serve(&[&empty], &mut model).0, ServeError::InvalidHeader);
+        empty[4..6].copy_from_slice(&(MAX_ADU_SIZE as u16).to_be_bytes());
+        assert_eq!(serve(&[&empty], &mut model).0, ServeError::InvalidHeader);
+
+        // Stream ending inside the PDU
+        let truncated = &valid[..valid.len() - 2];
+        let (reason, tx) = serve(&[truncated], &mut model);
+        assert_eq!(reason, ServeError::Io);
+        assert!(tx.is_empty());
+    }
+}
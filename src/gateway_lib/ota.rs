@@ -0,0 +1,689 @@
+//! Node firmware image staged on the gateway, and the nodes to update with it
+//!
+//! The image comes as hex chunks on the `ota/begin` and `ota/chunk` MQTT
+//! commands, or is downloaded by `ota_download_task` on `ota/fetch`. Either way
+//! it is written in order to the `ota_stage` partition by the ESP-NOW task.
+//! Once complete its SHA-256 is checked and a header is written to the first
+//! sector, so the staged image survives a reboot. The nodes queued with
+//! `ota/push` are offered the image (`common::ota`) until they report its
+//! version. Progress is published retained on `/status/ota/{mac}`.
+//!
+//! Stage header layout (first sector of the partition):
+//! - 4 bytes: magic `OTAS`
+//! - 4 bytes: image size
+//! - 32 bytes: SHA-256 of the image
+//! - 3 bytes: version, all ones when unknown
+//! - 2 bytes: CRC-16/MODBUS of everything before
+
+use embassy_net::dns::DnsSocket;
+use embassy_net::tcp::client::{TcpClient, TcpClientState};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embedded_io_async::Read;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::{String, Vec};
+use log::{error, info};
+use reqwless::client::{HttpClient, TlsConfig};
+use reqwless::request::Method;
+
+use crate::common::crc::crc16_modbus;
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{
+    image_id, FirmwareVersion, OtaChunk, OtaDigest, OtaOutcome, OtaReport, OtaRequest,
+    OTA_CHUNK_LEN, OTA_DIGEST_LEN,
+};
+use crate::common::ota::{chunk_len, FlashRegion, OtaError};
+use crate::gateway_lib::registry::MAX_NODES;
+
+// `ota_stage` partition of `partitions.csv`
+pub const OTA_STAGE_FLASH_OFFSET: u32 = 0x290000;
+pub const OTA_STAGE_LEN: u32 = 0x140000;
+const STAGE_HEADER_SECTOR_LEN: u32 = 0x1000;
+pub const OTA_STAGE_IMAGE: FlashRegion = FlashRegion {
+    offset: OTA_STAGE_FLASH_OFFSET + STAGE_HEADER_SECTOR_LEN,
+    size: OTA_STAGE_LEN - STAGE_HEADER_SECTOR_LEN,
+};
+// Progress is published each time this many bytes are staged or sent
+pub const OTA_PROGRESS_STEP: u32 = 64 * 1024;
+pub const OTA_URL_LEN: usize = 128;
+
+const STAGE_MAGIC: [u8; 4] = *b"OTAS";
+const STAGE_HEADER_LEN: usize = 4 + 4 + OTA_DIGEST_LEN + 3 + 2;
+const DOWNLOAD_BUFFER_SIZE: usize = 0x1000;
+
+/// Staging and update requests, applied by the ESP-NOW task
+pub static OTA_COMMANDS: Channel<CriticalSectionRawMutex, OtaCommand, 8> = Channel::new();
+/// Images to download over HTTP(S), see `ota_download_task`
+pub static OTA_DOWNLOADS: Channel<CriticalSectionRawMutex, OtaFetch, 1> = Channel::new();
+
+pub type ChunkData = Vec<u8, OTA_CHUNK_LEN>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StagedImage {
+    pub size: u32,
+    pub digest: OtaDigest,
+    pub version: Option<FirmwareVersion>,
+}
+
+impl StagedImage {
+    pub fn image(&self) -> u32 {
+        image_id(&self.digest)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OtaCommand {
+    // Start staging a new image, resumes when it is the one being staged
+    Begin(StagedImage),
+    Chunk { offset: u32, data: ChunkData },
+    Push(MacAddress),
+    Cancel(MacAddress),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OtaFetch {
+    pub image: StagedImage,
+    pub url: String<OTA_URL_LEN>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StageState {
+    Empty,
+    Receiving { image: StagedImage, received: u32 },
+    Ready(StagedImage),
+}
+
+/// Image in the `ota_stage` partition
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaStage {
+    state: StageState,
+}
+
+impl OtaStage {
+    /// Staged image of a previous boot, if it was complete
+    pub fn load<F: ReadNorFlash>(flash: &mut F) -> Self {
+        let mut raw = [0u8; STAGE_HEADER_LEN.next_multiple_of(4)];
+        let state = match flash.read(OTA_STAGE_FLASH_OFFSET, &mut raw) {
+            Ok(()) => {
+                decode_header(&raw[..STAGE_HEADER_LEN]).map_or(StageState::Empty, StageState::Ready)
+            }
+            Err(_) => StageState::Empty,
+        };
+        OtaStage { state }
+    }
+
+    pub fn state(&self) -> StageState {
+        self.state
+    }
+
+    pub fn ready(&self) -> Option<StagedImage> {
+        match self.state {
+            StageState::Ready(image) => Some(image),
+            _ => None,
+        }
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        matches!(self.state, StageState::Receiving { .. })
+    }
+
+    /// Start staging `image`, returns the bytes already staged
+    pub fn begin<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        image: StagedImage,
+    ) -> Result<u32, OtaError> {
+        match self.state {
+            StageState::Receiving {
+                image: current,
+                received,
+            } if current == image => return Ok(received),
+            StageState::Ready(current) if current == image => return Ok(image.size),
+            _ => {}
+        }
+        if image.size == 0 || image.size > OTA_STAGE_IMAGE.size {
+            return Err(OtaError::TooLarge);
+        }
+        // The previous image is no longer valid from here
+        flash
+            .erase(
+                OTA_STAGE_FLASH_OFFSET,
+                OTA_STAGE_FLASH_OFFSET + STAGE_HEADER_SECTOR_LEN,
+            )
+            .map_err(|_| OtaError::Flash)?;
+        self.state = StageState::Receiving { image, received: 0 };
+        Ok(0)
+    }
+
+    /// Write the chunk at `offset`, chunks already staged are ignored. Returns
+    /// the bytes staged, the image is checked and kept once complete.
+    pub fn write<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<u32, OtaError> {
+        let (image, received) = match self.state {
+            StageState::Receiving { image, received } => (image, received),
+            // Late duplicate of the last chunks
+            StageState::Ready(image) if offset < image.size => return Ok(image.size),
+            _ => return Err(OtaError::OutOfOrder),
+        };
+        // An offset near the end of the address space cannot be staged
+        let end = offset
+            .checked_add(data.len() as u32)
+            .ok_or(OtaError::TooLarge)?;
+        if end <= received {
+            return Ok(received);
+        }
+        if offset != received || (!data.len().is_multiple_of(4) && end != image.size) {
+            return Err(OtaError::OutOfOrder);
+        }
+        if end > image.size {
+            return Err(OtaError::TooLarge);
+        }
+        OTA_STAGE_IMAGE.write(flash, offset, data)?;
+        self.state = StageState::Receiving {
+            image,
+            received: end,
+        };
+        if end < image.size {
+            return Ok(end);
+        }
+
+        if OTA_STAGE_IMAGE.digest(flash, image.size)? != image.digest {
+            self.state = StageState::Empty;
+            return Err(OtaError::DigestMismatch);
+        }
+        let header = encode_header(&image);
+        flash
+            .write(OTA_STAGE_FLASH_OFFSET, &header)
+            .map_err(|_| OtaError::Flash)?;
+        self.state = StageState::Ready(image);
+        Ok(end)
+    }
+
+    /// Chunk answering a node request, from the staged image
+    pub fn chunk<'b, F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        request: &OtaRequest,
+        buffer: &'b mut [u8; OTA_CHUNK_LEN],
+    ) -> Result<OtaChunk<'b>, OtaError> {
+        let image = self.ready().ok_or(OtaError::OutOfOrder)?;
+        if request.image != image.image()
+            || request.offset >= image.size
+            || !request.offset.is_multiple_of(4)
+        {
+            return Err(OtaError::OutOfOrder);
+        }
+        let data = &mut buffer[..chunk_len(image.size, request.offset)];
+        OTA_STAGE_IMAGE.read(flash, request.offset, data)?;
+        Ok(OtaChunk::new(image.image(), request.offset, data))
+    }
+}
+
+fn encode_header(image: &StagedImage) -> [u8; STAGE_HEADER_LEN.next_multiple_of(4)] {
+    let mut raw = [0xFFu8; STAGE_HEADER_LEN.next_multiple_of(4)];
+    raw[..4].copy_from_slice(&STAGE_MAGIC);
+    raw[4..8].copy_from_slice(&image.size.to_le_bytes());
+    raw[8..8 + OTA_DIGEST_LEN].copy_from_slice(&image.digest);
+    if let Some(version) = image.version {
+        raw[40..43].copy_from_slice(&[version.major, version.minor, version.patch]);
+    }
+    let crc = crc16_modbus(&raw[..STAGE_HEADER_LEN - 2]);
+    raw[STAGE_HEADER_LEN - 2..STAGE_HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    raw
+}
+
+fn decode_header(raw: &[u8]) -> Option<StagedImage> {
+    let (body, crc) = raw.split_at(STAGE_HEADER_LEN - 2);
+    if body[..4] != STAGE_MAGIC || crc16_modbus(body).to_le_bytes() != [crc[0], crc[1]] {
+        return None;
+    }
+    let mut digest = [0u8; OTA_DIGEST_LEN];
+    digest.copy_from_slice(&body[8..8 + OTA_DIGEST_LEN]);
+    let version = match body[40..43] {
+        [0xFF, 0xFF, 0xFF] => None,
+        [major, minor, patch] => Some(FirmwareVersion {
+            major,
+            minor,
+            patch,
+        }),
+        _ => None,
+    };
+    Some(StagedImage {
+        size: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+        digest,
+        version,
+    })
+}
+
+/// Node queued for the staged image, with the offset it last asked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaTarget {
+    pub mac: MacAddress,
+    pub offset: u32,
+}
+
+/// What the gateway does for a node delivering its readings
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetState {
+    Idle,
+    // Offer the image in the ack
+    Pending(StagedImage),
+    // The node reported the staged version, no longer targeted
+    Updated(StagedImage),
+}
+
+/// Staged image and the nodes to update with it
+#[derive(Clone, Debug, Default)]
+pub struct OtaCampaign {
+    image: Option<StagedImage>,
+    targets: Vec<OtaTarget, MAX_NODES>,
+}
+
+impl OtaCampaign {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn image(&self) -> Option<StagedImage> {
+        self.image
+    }
+
+    pub fn targets(&self) -> &[OtaTarget] {
+        &self.targets
+    }
+
+    /// Image ready to be offered, none while staging
+    pub fn set_image(&mut self, image: Option<StagedImage>) {
+        if self.image != image {
+            for target in self.targets.iter_mut() {
+                target.offset = 0;
+            }
+        }
+        self.image = image;
+    }
+
+    /// Queue a node, returns false when too many are queued
+    pub fn push(&mut self, mac: MacAddress) -> bool {
+        self.targets.iter().any(|target| target.mac == mac)
+            || self.targets.push(OtaTarget { mac, offset: 0 }).is_ok()
+    }
+
+    /// Remove a node, returns whether it was queued
+    pub fn cancel(&mut self, mac: &MacAddress) -> bool {
+        let before = self.targets.len();
+        self.targets.retain(|target| target.mac != *mac);
+        self.targets.len() != before
+    }
+
+    /// State of a node running `firmware`
+    pub fn target_state(
+        &mut self,
+        mac: &MacAddress,
+        firmware: Option<FirmwareVersion>,
+    ) -> TargetState {
+        let Some(image) = self.image else {
+            return TargetState::Idle;
+        };
+        if !self.targets.iter().any(|target| target.mac == *mac) {
+            return TargetState::Idle;
+        }
+        if image.version.is_some() && image.version == firmware {
+            self.cancel(mac);
+            return TargetState::Updated(image);
+        }
+        TargetState::Pending(image)
+    }
+
+    /// Record the offset asked by a node, returns it when a progress step
+    /// was crossed
+    pub fn on_request(&mut self, mac: &MacAddress, request: &OtaRequest) -> Option<u32> {
+        let target = self.targets.iter_mut().find(|target| target.mac == *mac)?;
+        let crossed = request.offset / OTA_PROGRESS_STEP != target.offset / OTA_PROGRESS_STEP;
+        target.offset = request.offset;
+        crossed.then_some(request.offset)
+    }
+
+    /// Record the outcome reported by a node, returns the staged image when
+    /// the report is about it. The node is no longer targeted once its update
+    /// is over, a transfer error is retried on the next offer.
+    pub fn on_report(&mut self, mac: &MacAddress, report: &OtaReport) -> Option<StagedImage> {
+        let image = self.image.filter(|image| image.image() == report.image)?;
+        if matches!(
+            report.outcome,
+            OtaOutcome::Confirmed | OtaOutcome::TooLarge | OtaOutcome::RolledBack
+        ) {
+            self.cancel(mac);
+        }
+        Some(image)
+    }
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Http(reqwless::Error),
+    Status,
+    // Body shorter or longer than the announced size
+    Size(u32),
+}
+
+/// Download the images requested with `ota/fetch`, the chunks are handed to
+/// the ESP-NOW task which owns the staging partition
+#[embassy_executor::task]
+pub async fn ota_download_task(stack: Stack<'static>, tls_seed: u64) {
+    loop {
+        let fetch = OTA_DOWNLOADS.receive().await;
+        info!("Downloading node image from {}", fetch.url);
+        match download(stack, tls_seed, &fetch).await {
+            Ok(()) => info!("Downloaded {} bytes of node image", fetch.image.size),
+            Err(e) => error!("Node image download failed: {:?}", e),
+        }
+    }
+}
+
+async fn download(stack: Stack<'_>, tls_seed: u64, fetch: &OtaFetch) -> Result<(), DownloadError> {
+    let mut rx_buffer = [0; DOWNLOAD_BUFFER_SIZE];
+    let mut tx_buffer = [0; DOWNLOAD_BUFFER_SIZE];
+    let dns = DnsSocket::new(stack);
+    let tcp_state = TcpClientState::<1, DOWNLOAD_BUFFER_SIZE, DOWNLOAD_BUFFER_SIZE>::new();
+    let tcp = TcpClient::new(stack, &tcp_state);
+    let tls = TlsConfig::new(
+        tls_seed,
+        &mut rx_buffer,
+        &mut tx_buffer,
+        reqwless::client::TlsVerify::None,
+    );
+    let mut client = HttpClient::new_with_tls(&tcp, &dns, tls);
+
+    let mut headers = [0u8; DOWNLOAD_BUFFER_SIZE];
+    let mut request = client
+        .request(Method::GET, &fetch.url)
+        .await
+        .map_err(DownloadError::Http)?;
+    let response = request
+        .send(&mut headers)
+        .await
+        .map_err(DownloadError::Http)?;
+    if !response.status.is_successful() {
+        return Err(DownloadError::Status);
+    }
+    let mut body = response.body().reader();
+
+    OTA_COMMANDS.send(OtaCommand::Begin(fetch.image)).await;
+    let mut offset = 0;
+    let mut chunk = ChunkData::new();
+    let mut buffer = [0u8; OTA_CHUNK_LEN];
+    loop {
+        let free = OTA_CHUNK_LEN - chunk.len();
+        let read = body
+            .read(&mut buffer[..free])
+            .await
+            .map_err(DownloadError::Http)?;
+        if read == 0 {
+            break;
+        }
+        // Cannot overflow, at most the free space was read
+        let _ = chunk.extend_from_slice(&buffer[..read]);
+        if chunk.is_full() {
+            let len = chunk.len() as u32;
+            let data = core::mem::take(&mut chunk);
+            OTA_COMMANDS.send(OtaCommand::Chunk { offset, data }).await;
+            offset += len;
+        }
+    }
+    if !chunk.is_empty() {
+        let len = chunk.len() as u32;
+        OTA_COMMANDS
+            .send(OtaCommand::Chunk {
+                offset,
+                data: chunk,
+            })
+            .await;
+        offset += len;
+    }
+    if offset != fetch.image.size {
+        return Err(DownloadError::Size(offset));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::common::mem_flash::MemFlash;
+
+    const NODE: MacAddress = [9, 9, 9, 9, 9, 9];
+
+    fn image(size: u32) -> std::vec::Vec<u8> {
+        (0..size).map(|i| (i * 7 + i / 300) as u8).collect()
+    }
+
+    fn staged(image: &[u8]) -> StagedImage {
+        StagedImage {
+            size: image.len() as u32,
+            digest: Sha256::digest(image).into(),
+            version: Some(FirmwareVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            }),
+        }
+    }
+
+    fn flash() -> MemFlash {
+        MemFlash::new((OTA_STAGE_FLASH_OFFSET + OTA_STAGE_LEN) as usize)
+    }
+
+    fn chunk_at(image: &[u8], offset: u32) -> &[u8] {
+        let start = offset as usize;
+        &image[start..start + chunk_len(image.len() as u32, offset)]
+    }
+
+    #[test]
+    fn stages_repeated_and_reordered_chunks() {
+        let image = image(5_003);
+        let staged = staged(&image);
+        let mut flash = flash();
+        let mut stage = OtaStage::load(&mut flash);
+        assert_eq!(stage.state(), StageState::Empty);
+        assert_eq!(stage.begin(&mut flash, staged), Ok(0));
+        let step = OTA_CHUNK_LEN as u32;
+        let mut offset = 0;
+        while offset < staged.size {
+            // Ahead of the stage, sent again by the publisher later
+            let ahead = offset + step;
+            if ahead < staged.size {
+                assert_eq!(
+                    stage.write(&mut flash, ahead, chunk_at(&image, ahead)),
+                    Err(OtaError::OutOfOrder)
+                );
+            }
+            let end = offset + chunk_len(staged.size, offset) as u32;
+            assert_eq!(
+                stage.write(&mut flash, offset, chunk_at(&image, offset)),
+                Ok(end)
+            );
+            // Duplicate of a staged chunk
+            assert_eq!(
+                stage.write(&mut flash, offset, chunk_at(&image, offset)),
+                Ok(end)
+            );
+            offset = end;
+        }
+        assert_eq!(stage.ready(), Some(staged));
+        // Late duplicate once ready
+        assert_eq!(
+            stage.write(&mut flash, 0, chunk_at(&image, 0)),
+            Ok(staged.size)
+        );
+        // Kept across a reboot
+        assert_eq!(OtaStage::load(&mut flash).ready(), Some(staged));
+    }
+
+    #[test]
+    fn begin_resumes_the_image_being_staged() {
+        let image = image(2_000);
+        let staged = staged(&image);
+        let mut flash = flash();
+        let mut stage = OtaStage::load(&mut flash);
+        stage.begin(&mut flash, staged).unwrap();
+        let end = 2 * OTA_CHUNK_LEN as u32;
+        for offset in [0, OTA_CHUNK_LEN as u32] {
+            stage
+                .write(&mut flash, offset, chunk_at(&image, offset))
+                .unwrap();
+        }
+        assert_eq!(stage.begin(&mut flash, staged), Ok(end));
+        // Another image starts over
+        let other = StagedImage {
+            digest: [0; OTA_DIGEST_LEN],
+            ..staged
+        };
+        assert_eq!(stage.begin(&mut flash, other), Ok(0));
+        assert_eq!(OtaStage::load(&mut flash).state(), StageState::Empty);
+    }
+
+    #[test]
+    fn rejects_chunks_out_of_the_image() {
+        let image = image(1_000);
+        let staged = staged(&image);
+        let mut flash = flash();
+        let mut stage = OtaStage::load(&mut flash);
+        let too_large = StagedImage {
+            size: OTA_STAGE_IMAGE.size + 1,
+            ..staged
+        };
+        assert_eq!(stage.begin(&mut flash, too_large), Err(OtaError::TooLarge));
+        stage.begin(&mut flash, staged).unwrap();
+        let data = [0u8; 8];
+        assert_eq!(
+            stage.write(&mut flash, u32::MAX - 3, &data),
+            Err(OtaError::TooLarge)
+        );
+        assert_eq!(
+            stage.write(&mut flash, 0, &data[..3]),
+            Err(OtaError::OutOfOrder)
+        );
+        assert!(stage.is_receiving());
+    }
+
+    #[test]
+    fn digest_mismatch_drops_the_stage() {
+        let mut image = image(500);
+        let staged = staged(&image);
+        image[10] ^= 1;
+        let mut flash = flash();
+        let mut stage = OtaStage::load(&mut flash);
+        stage.begin(&mut flash, staged).unwrap();
+        let mut result = Ok(0);
+        let mut offset = 0;
+        while offset < staged.size {
+            result = stage.write(&mut flash, offset, chunk_at(&image, offset));
+            offset += OTA_CHUNK_LEN as u32;
+        }
+        assert_eq!(result, Err(OtaError::DigestMismatch));
+        assert_eq!(stage.state(), StageState::Empty);
+        assert_eq!(OtaStage::load(&mut flash).ready(), None);
+    }
+
+    #[test]
+    fn serves_chunks_of_the_staged_image() {
+        let image = image(1_000);
+        let staged = staged(&image);
+        let mut flash = flash();
+        let mut stage = OtaStage::load(&mut flash);
+        let mut buffer = [0u8; OTA_CHUNK_LEN];
+        let request = OtaRequest {
+            image: staged.image(),
+            offset: 0,
+        };
+        assert!(stage.chunk(&mut flash, &request, &mut buffer).is_err());
+        stage.begin(&mut flash, staged).unwrap();
+        let mut offset = 0;
+        while offset < staged.size {
+            offset = stage
+                .write(&mut flash, offset, chunk_at(&image, offset))
+                .unwrap();
+        }
+        let last = (staged.size - 1) / OTA_CHUNK_LEN as u32 * OTA_CHUNK_LEN as u32;
+        for offset in [0, last] {
+            let request = OtaRequest { offset, ..request };
+            let chunk = stage.chunk(&mut flash, &request, &mut buffer).unwrap();
+            assert!(chunk.is_intact());
+            assert_eq!(chunk.data, chunk_at(&image, offset));
+        }
+        for offset in [2, staged.size] {
+            let request = OtaRequest { offset, ..request };
+            assert!(stage.chunk(&mut flash, &request, &mut buffer).is_err());
+        }
+        let other = OtaRequest {
+            image: staged.image() ^ 1,
+            ..request
+        };
+        assert!(stage.chunk(&mut flash, &other, &mut buffer).is_err());
+    }
+
+    #[test]
+    fn campaign_targets_nodes_until_updated() {
+        let staged = staged(&image(100));
+        let mut campaign = OtaCampaign::new();
+        assert!(campaign.push(NODE));
+        assert_eq!(campaign.target_state(&NODE, None), TargetState::Idle);
+        campaign.set_image(Some(staged));
+        assert_eq!(
+            campaign.target_state(&NODE, None),
+            TargetState::Pending(staged)
+        );
+        let request = OtaRequest {
+            image: staged.image(),
+            offset: OTA_PROGRESS_STEP,
+        };
+        assert_eq!(
+            campaign.on_request(&NODE, &request),
+            Some(OTA_PROGRESS_STEP)
+        );
+        assert_eq!(campaign.on_request(&NODE, &request), None);
+        assert_eq!(
+            campaign.target_state(&NODE, staged.version),
+            TargetState::Updated(staged)
+        );
+        assert_eq!(
+            campaign.target_state(&NODE, staged.version),
+            TargetState::Idle
+        );
+        assert!(!campaign.cancel(&NODE));
+    }
+
+    #[test]
+    fn campaign_ends_on_the_final_reports() {
+        let staged = staged(&image(100));
+        let mut campaign = OtaCampaign::new();
+        campaign.set_image(Some(staged));
+        campaign.push(NODE);
+        let report = |outcome| OtaReport {
+            image: staged.image(),
+            outcome,
+        };
+        let other = OtaReport {
+            image: staged.image() ^ 1,
+            outcome: OtaOutcome::Confirmed,
+        };
+        assert_eq!(campaign.on_report(&NODE, &other), None);
+        assert_eq!(
+            campaign.on_report(&NODE, &report(OtaOutcome::Verified)),
+            Some(staged)
+        );
+        assert_eq!(campaign.targets().len(), 1);
+        assert_eq!(
+            campaign.on_report(&NODE, &report(OtaOutcome::Confirmed)),
+            Some(staged)
+        );
+        assert!(campaign.targets().is_empty());
+    }
+}
@@ -0,0 +1,336 @@
+//! Embedded PID control loop for closed-loop heating
+//!
+//! The process value is read from an analog channel of the `common::analog`
+//! store and the output drives a transistor output (T0.0/T0.1) with a
+//! time-proportioning window, so the loop keeps running without the broker.
+//! The controller is plain math, the task driving the output is in `task`.
+//!
+//! - Integral term is stored pre-multiplied by `ki` so tuning changes are bumpless
+//! - Anti-windup by clamping the integral term to the output limits
+//! - Derivative on measurement to avoid kicks on setpoint changes
+//! - Manual -> Auto transfer seeds the integral with the manual output minus
+//!   the proportional term, at the first sample in Auto
+//! - Output starts at its minimum and only moves in Auto once a valid process
+//!   value is sampled, the analog store holds NaN until then
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use log::warn;
+
+use crate::common::analog::AnalogChannel;
+
+#[cfg(feature = "esp32")]
+mod task;
+#[cfg(feature = "esp32")]
+pub use task::pid_control_task;
+
+// Setpoint and tuning changes coming from the MQTT command channel
+pub static PID_COMMANDS: Channel<CriticalSectionRawMutex, PidCommand, 4> = Channel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PidMode {
+    Manual,
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PidCommand {
+    Setpoint(f32),
+    Tunings { kp: f32, ki: f32, kd: f32 },
+    Mode(PidMode),
+    ManualOutput(f32),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PidTunings {
+    pub kp: f32,
+    pub ki: f32, // per second
+    pub kd: f32, // seconds
+}
+
+pub struct Pid {
+    tunings: PidTunings,
+    setpoint: f32,
+    out_min: f32,
+    out_max: f32,
+    mode: PidMode,
+    integral: f32,
+    last_pv: Option<f32>,
+    // Integral to seed at the next sample after a Manual -> Auto transfer
+    transfer_pending: bool,
+    output: f32,
+}
+
+impl Pid {
+    pub fn new(tunings: PidTunings, setpoint: f32, out_min: f32, out_max: f32) -> Pid {
+        Pid {
+            tunings,
+            setpoint,
+            out_min,
+            out_max,
+            mode: PidMode::Auto,
+            integral: 0.0,
+            last_pv: None,
+            transfer_pending: false,
+            output: out_min,
+        }
+    }
+
+    pub fn setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    pub fn set_setpoint(&mut self, setpoint: f32) {
+        self.setpoint = setpoint;
+    }
+
+    pub fn tunings(&self) -> PidTunings {
+        self.tunings
+    }
+
+    /// Negative gains are rejected, the integral term is already scaled by ki
+    pub fn set_tunings(&mut self, tunings: PidTunings) -> bool {
+        if tunings.kp < 0.0 || tunings.ki < 0.0 || tunings.kd < 0.0 {
+            return false;
+        }
+        self.tunings = tunings;
+        true
+    }
+
+    pub fn mode(&self) -> PidMode {
+        self.mode
+    }
+
+    /// Switching to Auto starts from the current output for a bumpless transfer
+    pub fn set_mode(&mut self, mode: PidMode) {
+        if self.mode == PidMode::Manual && mode == PidMode::Auto {
+            self.transfer_pending = true;
+        }
+        self.mode = mode;
+    }
+
+    /// Only used in manual mode, ignored in auto
+    pub fn set_manual_output(&mut self, output: f32) {
+        if self.mode == PidMode::Manual {
+            self.output = output.clamp(self.out_min, self.out_max);
+        }
+    }
+
+    pub fn set_output_limits(&mut self, out_min: f32, out_max: f32) {
+        if out_min >= out_max {
+            return;
+        }
+        self.out_min = out_min;
+        self.out_max = out_max;
+        self.integral = self.integral.clamp(out_min, out_max);
+        self.output = self.output.clamp(out_min, out_max);
+    }
+
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    pub fn apply(&mut self, command: PidCommand) {
+        match command {
+            PidCommand::Setpoint(sp) => self.set_setpoint(sp),
+            PidCommand::Tunings { kp, ki, kd } => {
+                if !self.set_tunings(PidTunings { kp, ki, kd }) {
+                    warn!(
+                        "Rejected negative PID tunings kp={} ki={} kd={}",
+                        kp, ki, kd
+                    );
+                }
+            }
+            PidCommand::Mode(mode) => self.set_mode(mode),
+            PidCommand::ManualOutput(out) => self.set_manual_output(out),
+        }
+    }
+
+    /// Compute a new output from the process value and elapsed time in seconds,
+    /// the output is kept when the process value is not finite
+    pub fn compute(&mut self, pv: f32, dt_s: f32) -> f32 {
+        if self.mode == PidMode::Manual || !pv.is_finite() || dt_s <= 0.0 {
+            self.last_pv = if pv.is_finite() { Some(pv) } else { None };
+            return self.output;
+        }
+
+        let error = self.setpoint - pv;
+        if self.transfer_pending {
+            // The proportional term alone gives back the manual output
+            self.integral = self.output - self.tunings.kp * error;
+            self.transfer_pending = false;
+        }
+        self.integral += self.tunings.ki * error * dt_s;
+        self.integral = self.integral.clamp(self.out_min, self.out_max);
+
+        let d_input = match self.last_pv {
+            Some(last) => (pv - last) / dt_s,
+            None => 0.0,
+        };
+        self.last_pv = Some(pv);
+
+        let output = self.tunings.kp * error + self.integral - self.tunings.kd * d_input;
+        self.output = output.clamp(self.out_min, self.out_max);
+        self.output
+    }
+}
+
+/// Slow PWM for relay/transistor outputs, output given in percent
+pub struct TimeProportioning {
+    window_ms: u64,
+    min_switch_ms: u64,
+    window_start_ms: u64,
+    on_time_ms: u64,
+}
+
+impl TimeProportioning {
+    pub fn new(window_ms: u64, min_switch_ms: u64) -> TimeProportioning {
+        TimeProportioning {
+            window_ms: window_ms.max(1),
+            min_switch_ms,
+            window_start_ms: 0,
+            on_time_ms: 0,
+        }
+    }
+
+    /// Returns the output state at `now_ms`, the duty cycle is latched per window
+    pub fn update(&mut self, now_ms: u64, output_pct: f32) -> bool {
+        if now_ms.saturating_sub(self.window_start_ms) >= self.window_ms {
+            // Skip whole windows if we were not polled for a while
+            let elapsed = now_ms - self.window_start_ms;
+            self.window_start_ms += elapsed - elapsed % self.window_ms;
+            self.on_time_ms = self.on_time_for(output_pct);
+        }
+        now_ms - self.window_start_ms < self.on_time_ms
+    }
+
+    fn on_time_for(&self, output_pct: f32) -> u64 {
+        let pct = if output_pct.is_finite() {
+            output_pct.clamp(0.0, 100.0)
+        } else {
+            0.0
+        };
+        let on_time = (self.window_ms as f32 * pct / 100.0) as u64;
+        // Avoid chattering the output with very short pulses
+        if on_time < self.min_switch_ms {
+            0
+        } else if self.window_ms - on_time < self.min_switch_ms {
+            self.window_ms
+        } else {
+            on_time
+        }
+    }
+}
+
+pub struct PidConfig {
+    pub channel: AnalogChannel,
+    // Transistor output driven by the loop, locked for remote writes
+    pub output_index: usize,
+    pub tunings: PidTunings,
+    pub setpoint: f32,
+    pub sample_time: Duration,
+    pub tpo_window: Duration,
+    pub tpo_min_switch: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TUNINGS: PidTunings = PidTunings {
+        kp: 4.0,
+        ki: 0.05,
+        kd: 0.0,
+    };
+
+    /// First order heater: full power settles 80 C above ambient
+    struct Plant {
+        temperature: f32,
+    }
+
+    impl Plant {
+        const AMBIENT: f32 = 20.0;
+        const TIME_CONSTANT_S: f32 = 600.0;
+        const HEATING_C_PER_S: f32 = 80.0 / Self::TIME_CONSTANT_S;
+
+        fn step(&mut self, heating: bool, dt_s: f32) {
+            let losses = (Self::AMBIENT - self.temperature) / Self::TIME_CONSTANT_S;
+            let power = if heating { Self::HEATING_C_PER_S } else { 0.0 };
+            self.temperature += (losses + power) * dt_s;
+        }
+    }
+
+    #[test]
+    fn output_stays_off_until_a_valid_sample() {
+        let mut pid = Pid::new(TUNINGS, 50.0, 0.0, 100.0);
+        assert_eq!(pid.compute(f32::NAN, 1.0), 0.0);
+        assert_eq!(pid.compute(f32::INFINITY, 1.0), 0.0);
+        assert_eq!(pid.output(), 0.0);
+        assert!(pid.compute(45.0, 1.0) > 0.0);
+    }
+
+    #[test]
+    fn transfer_to_auto_is_bumpless() {
+        let mut pid = Pid::new(TUNINGS, 50.0, 0.0, 100.0);
+        pid.set_mode(PidMode::Manual);
+        pid.set_manual_output(40.0);
+        assert_eq!(pid.compute(45.0, 1.0), 40.0);
+
+        pid.set_mode(PidMode::Auto);
+        // Only the integral of one sample is added to the manual output
+        let output = pid.compute(45.0, 1.0);
+        assert!((output - 40.25).abs() < 1e-3, "{}", output);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up_out_of_the_limits() {
+        let mut pid = Pid::new(TUNINGS, 50.0, 0.0, 100.0);
+        for _ in 0..10_000 {
+            assert_eq!(pid.compute(0.0, 1.0), 100.0);
+        }
+        // Back under the maximum as soon as the error is negative
+        assert!(pid.compute(60.0, 1.0) < 100.0);
+    }
+
+    #[test]
+    fn regulates_a_heater_through_the_time_proportioning() {
+        let setpoint = 50.0;
+        let mut pid = Pid::new(TUNINGS, setpoint, 0.0, 100.0);
+        let mut tpo = TimeProportioning::new(10_000, 500);
+        let mut plant = Plant {
+            temperature: Plant::AMBIENT,
+        };
+        let mut highest = plant.temperature;
+        // Two simulated hours in the 100ms ticks of the task, sampled each second
+        for tick in 0..72_000u64 {
+            let now_ms = tick * 100;
+            if now_ms.is_multiple_of(1000) {
+                pid.compute(plant.temperature, 1.0);
+            }
+            plant.step(tpo.update(now_ms, pid.output()), 0.1);
+            highest = highest.max(plant.temperature);
+        }
+        assert!(
+            (plant.temperature - setpoint).abs() < 1.0,
+            "{}",
+            plant.temperature
+        );
+        // The integral charged during the warm-up at full power overshoots
+        assert!(highest < setpoint + 8.0, "overshoot to {}", highest);
+    }
+
+    #[test]
+    fn time_proportioning_follows_the_duty_cycle() {
+        // The duty cycle is latched at the start of the next window
+        let mut tpo = TimeProportioning::new(10_000, 500);
+        let on_ticks = (0..200u64)
+            .filter(|tick| tpo.update(tick * 100, 25.0))
+            .count();
+        assert_eq!(on_ticks, 25);
+        // Pulses shorter than the minimum switch time are dropped
+        assert!(!(200..300u64).any(|tick| tpo.update(tick * 100, 2.0)));
+        // and nearly full windows are kept on
+        assert!((300..400u64).all(|tick| tpo.update(tick * 100, 98.0)));
+    }
+}
@@ -0,0 +1,60 @@
+//! Task driving a transistor output from the PID loop
+
+use embassy_time::{Duration, Instant, Ticker};
+use esp_hal::gpio::Output;
+use log::{debug, info};
+
+use super::{Pid, PidConfig, TimeProportioning, PID_COMMANDS};
+use crate::gateway_lib::io::{lock_output, report_output};
+
+// Resolution of the time-proportioning output
+const TPO_TICK: Duration = Duration::from_millis(100);
+
+#[embassy_executor::task]
+pub async fn pid_control_task(mut output: Output<'static>, config: PidConfig) {
+    info!(
+        "Starting PID loop on '{}' with setpoint={} {}",
+        config.channel.name, config.setpoint, config.channel.unit
+    );
+    lock_output(config.output_index);
+    let mut pid = Pid::new(config.tunings, config.setpoint, 0.0, 100.0);
+    let mut tpo = TimeProportioning::new(
+        config.tpo_window.as_millis(),
+        config.tpo_min_switch.as_millis(),
+    );
+    let mut ticker = Ticker::every(TPO_TICK);
+    let mut last_compute = Instant::now();
+
+    loop {
+        while let Ok(command) = PID_COMMANDS.try_receive() {
+            info!("Applying PID command: {:?}", command);
+            pid.apply(command);
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(last_compute);
+        if elapsed >= config.sample_time {
+            let pv = config.channel.value();
+            let dt_s = elapsed.as_millis() as f32 / 1000.0;
+            let out = pid.compute(pv, dt_s);
+            debug!(
+                "PID pv={} sp={} out={}% mode={:?}",
+                pv,
+                pid.setpoint(),
+                out,
+                pid.mode()
+            );
+            last_compute = now;
+        }
+
+        let on = tpo.update(now.as_millis(), pid.output());
+        if on {
+            output.set_high();
+        } else {
+            output.set_low();
+        }
+        report_output(config.output_index, on);
+
+        ticker.next().await;
+    }
+}
@@ -0,0 +1,516 @@
+//! Shared publish path for readings produced by gateway tasks
+//!
+//! Analog channels, field bus devices and mesh nodes queue their readings here
+//! and the MQTT loop in `main_gateway` publishes them as they come while it is
+//! connected. New readings are dropped once the queue is full.
+//!
+//! Retained states (node status, alarms, OTA and config progress) are not
+//! dropped with the readings when the broker is away: only the latest state
+//! of each topic is kept until it is published.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use heapless::{String, Vec};
+use log::warn;
+
+use crate::common::analog::AnalogChannel;
+use crate::common::mesh_proto::{MeshReading, ReadingKind};
+use crate::common::reliability::LinkCounters;
+use crate::common::units::{UnitPreferences, PAYLOAD_DISPLAY_UNITS, UNITS};
+use crate::gateway_lib::battery::{BatteryAlarm, NodePower};
+use crate::gateway_lib::liveness::NodeHealth;
+use crate::gateway_lib::registry::MAX_NODES;
+
+pub const READINGS_QUEUE_SIZE: usize = 16;
+// Status, OTA, config and battery alarm topics of every node
+pub const STATUS_TOPICS: usize = 4 * MAX_NODES;
+
+pub static READINGS: Channel<CriticalSectionRawMutex, Reading, READINGS_QUEUE_SIZE> =
+    Channel::new();
+
+static STATUS: Mutex<CriticalSectionRawMutex, RefCell<StatusQueue<STATUS_TOPICS>>> =
+    Mutex::new(RefCell::new(StatusQueue::new()));
+
+/// JSON shape of the payload, mesh temperatures keep the backend format
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PayloadFormat {
+    Value,
+    MeshTemperature,
+    MeshLink(LinkCounters),
+    NodeStatus(NodeHealth),
+    // Firmware update of a node, `state` as in `ota_status`
+    OtaStatus { state: &'static str, size: u32 },
+    // Configuration change of a node, `state` as in `config_status`
+    ConfigStatus { state: &'static str },
+    NodePower(NodePower),
+    BatteryAlarm(BatteryAlarm),
+}
+
+#[derive(Clone, Debug)]
+pub struct Reading {
+    pub topic: String<64>,
+    pub source: String<24>,
+    pub value: f32,
+    pub unit: &'static str,
+    pub timestamp_ms: u64,
+    pub format: PayloadFormat,
+    // Kept by the broker for the late subscribers
+    pub retain: bool,
+}
+
+impl Reading {
+    pub fn new(topic: &str, source: &str, value: f32, unit: &'static str) -> Option<Reading> {
+        let mut reading = Reading {
+            topic: String::new(),
+            source: String::new(),
+            value,
+            unit,
+            timestamp_ms: embassy_time::Instant::now().as_millis(),
+            format: PayloadFormat::Value,
+            retain: false,
+        };
+        reading.topic.push_str(topic).ok()?;
+        reading.source.push_str(source).ok()?;
+        Some(reading)
+    }
+
+    /// Reading of an analog channel on `/readings/analog/{mac}/{channel}`,
+    /// none until the input is sampled or while its loop is broken
+    pub fn from_analog(channel: &AnalogChannel, mac: &str) -> Option<Reading> {
+        let value = channel.value();
+        if !value.is_finite() {
+            return None;
+        }
+        let mut topic = String::<64>::new();
+        write!(topic, "/readings/analog/{}/{}", mac, channel.name).ok()?;
+        Reading::new(&topic, channel.name, value, channel.unit)
+    }
+
+    /// Temperature of a mesh node on `/readings/temperature/{node_mac}`
+    pub fn mesh_temperature(node_mac: &str, temperature_c: f32) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/readings/temperature/{}", node_mac).ok()?;
+        let mut reading = Reading::new(&topic, node_mac, temperature_c, "C")?;
+        reading.format = PayloadFormat::MeshTemperature;
+        Some(reading)
+    }
+
+    /// Reading of a mesh node, temperatures keep their dedicated topic and the
+    /// other kinds go to `/readings/mesh/{node_mac}/{kind}`
+    pub fn from_mesh(node_mac: &str, reading: &MeshReading) -> Option<Reading> {
+        if reading.kind == ReadingKind::Temperature {
+            return Reading::mesh_temperature(node_mac, reading.value);
+        }
+        let mut topic = String::<64>::new();
+        write!(topic, "/readings/mesh/{}/{}", node_mac, reading.kind.name()).ok()?;
+        Reading::new(&topic, node_mac, reading.value, reading.unit.symbol())
+    }
+
+    /// Delivery counters of a mesh node on `/diagnostics/mesh/{node_mac}`
+    pub fn mesh_link(node_mac: &str, counters: LinkCounters) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/diagnostics/mesh/{}", node_mac).ok()?;
+        let mut reading = Reading::new(&topic, node_mac, counters.lost as f32, "")?;
+        reading.format = PayloadFormat::MeshLink(counters);
+        Some(reading)
+    }
+
+    /// Liveness of a mesh node, retained on `/status/node/{node_mac}`
+    pub fn node_status(node_mac: &str, health: &NodeHealth) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/status/node/{}", node_mac).ok()?;
+        let mut reading = Reading::new(&topic, node_mac, health.last_seen_ms as f32, "ms")?;
+        reading.format = PayloadFormat::NodeStatus(*health);
+        reading.retain = true;
+        Some(reading)
+    }
+
+    /// Firmware update progress of a mesh node, retained on
+    /// `/status/ota/{node_mac}`. The state is `queued`, `cancelled`,
+    /// `downloading`, `updated` or an `OtaOutcome` name.
+    pub fn ota_status(
+        node_mac: &str,
+        state: &'static str,
+        offset: u32,
+        size: u32,
+    ) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/status/ota/{}", node_mac).ok()?;
+        let mut reading = Reading::new(&topic, node_mac, offset as f32, "B")?;
+        reading.format = PayloadFormat::OtaStatus { state, size };
+        reading.retain = true;
+        Some(reading)
+    }
+
+    /// Battery, wake count and reset cause of a mesh node on
+    /// `/diagnostics/power/{node_mac}`
+    pub fn node_power(node_mac: &str, power: &NodePower) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/diagnostics/power/{}", node_mac).ok()?;
+        let value = power.battery_v.unwrap_or(0.0);
+        let mut reading = Reading::new(&topic, node_mac, value, "V")?;
+        reading.format = PayloadFormat::NodePower(*power);
+        Some(reading)
+    }
+
+    /// Low-battery alarm of a mesh node raised or cleared, retained on
+    /// `/alarms/battery/{node_mac}`
+    pub fn battery_alarm(node_mac: &str, alarm: &BatteryAlarm) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/alarms/battery/{}", node_mac).ok()?;
+        let mut reading = Reading::new(&topic, node_mac, alarm.battery_v, "V")?;
+        reading.format = PayloadFormat::BatteryAlarm(*alarm);
+        reading.retain = true;
+        Some(reading)
+    }
+
+    /// Configuration change `id` of a mesh node, retained on
+    /// `/status/config/{node_mac}`. The state is `queued` or `applied`.
+    pub fn config_status(node_mac: &str, state: &'static str, id: u16) -> Option<Reading> {
+        let mut topic = String::<64>::new();
+        write!(topic, "/status/config/{}", node_mac).ok()?;
+        let mut reading = Reading::new(&topic, node_mac, id as f32, "")?;
+        reading.format = PayloadFormat::ConfigStatus { state };
+        reading.retain = true;
+        Some(reading)
+    }
+
+    /// JSON payload in the same shape as the other gateway payloads, with
+    /// the values in the deployment units when `PAYLOAD_DISPLAY_UNITS` is set
+    pub fn to_payload(&self) -> String<256> {
+        self.to_payload_in(PAYLOAD_DISPLAY_UNITS.then_some(UNITS))
+    }
+
+    /// JSON payload, the canonical value and unit followed by the value in
+    /// `display_units` when they differ
+    pub fn to_payload_in(&self, display_units: Option<UnitPreferences>) -> String<256> {
+        let mut payload = String::<256>::new();
+        let display = display_units
+            .map(|units| units.convert(self.value, self.unit))
+            .filter(|(_, unit)| *unit != self.unit);
+        match self.format {
+            PayloadFormat::Value => write_value(
+                &mut payload,
+                &self.source,
+                self.timestamp_ms,
+                self.value,
+                self.unit,
+                display,
+            ),
+            PayloadFormat::MeshTemperature => {
+                write_mesh_temperature(&mut payload, &self.source, self.timestamp_ms, self.value, display)
+            }
+            PayloadFormat::MeshLink(c) => write!(
+                payload,
+                "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"received\":{}, \"duplicates\":{}, \"lost\":{}, \"retried\":{}, \"givenUp\":{}}}",
+                self.source, self.timestamp_ms, c.received, c.duplicates, c.lost, c.retried, c.given_up
+            ),
+            PayloadFormat::NodeStatus(health) => {
+                write_node_status(&mut payload, &self.source, self.timestamp_ms, &health)
+            }
+            PayloadFormat::OtaStatus { state, size } => write!(
+                payload,
+                "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"state\":\"{}\", \"offset\":{}, \"size\":{}}}",
+                self.source, self.timestamp_ms, state, self.value as u32, size
+            ),
+            PayloadFormat::NodePower(power) => {
+                write_node_power(&mut payload, &self.source, self.timestamp_ms, &power)
+            }
+            PayloadFormat::BatteryAlarm(alarm) => {
+                write_battery_alarm(&mut payload, &self.source, self.timestamp_ms, &alarm)
+            }
+            PayloadFormat::ConfigStatus { state } => write!(
+                payload,
+                "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"state\":\"{}\", \"id\":{}}}",
+                self.source, self.timestamp_ms, state, self.value as u16
+            ),
+        }
+        .expect("write! failed!");
+        payload
+    }
+}
+
+fn write_value(
+    payload: &mut String<256>,
+    source: &str,
+    timestamp_ms: u64,
+    value: f32,
+    unit: &str,
+    display: Option<(f32, &str)>,
+) -> core::fmt::Result {
+    write!(
+        payload,
+        "{{\"source\":\"{}\", \"timestamp\":{}, \"value\":{:.3}, \"unit\":\"{}\"",
+        source, timestamp_ms, value, unit
+    )?;
+    if let Some((value, unit)) = display {
+        write!(
+            payload,
+            ", \"displayValue\":{:.3}, \"displayUnit\":\"{}\"",
+            value, unit
+        )?;
+    }
+    payload.push('}').map_err(|_| core::fmt::Error)
+}
+
+fn write_mesh_temperature(
+    payload: &mut String<256>,
+    node_mac: &str,
+    timestamp_ms: u64,
+    temperature_c: f32,
+    display: Option<(f32, &str)>,
+) -> core::fmt::Result {
+    write!(
+        payload,
+        "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"temperature\":{:.2}",
+        node_mac, timestamp_ms, temperature_c
+    )?;
+    if let Some((temperature, unit)) = display {
+        write!(
+            payload,
+            ", \"displayTemperature\":{:.2}, \"displayUnit\":\"{}\"",
+            temperature, unit
+        )?;
+    }
+    payload.push('}').map_err(|_| core::fmt::Error)
+}
+
+fn write_node_status(
+    payload: &mut String<256>,
+    node_mac: &str,
+    timestamp_ms: u64,
+    health: &NodeHealth,
+) -> core::fmt::Result {
+    write!(
+        payload,
+        "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"state\":\"{}\", \"lastSeen\":{}, \"seqGaps\":{}",
+        node_mac,
+        timestamp_ms,
+        health.state.name(),
+        health.last_seen_ms,
+        health.seq_gaps
+    )?;
+    // Fields the node did not report are left out
+    if let Some(rssi) = health.rssi {
+        write!(payload, ", \"rssi\":{}", rssi)?;
+    }
+    if let Some(battery_v) = health.battery_v {
+        write!(payload, ", \"batteryV\":{:.2}", battery_v)?;
+    }
+    if let Some(firmware) = health.firmware {
+        write!(payload, ", \"firmware\":\"{}\"", firmware)?;
+    }
+    payload.push('}').map_err(|_| core::fmt::Error)
+}
+
+fn write_node_power(
+    payload: &mut String<256>,
+    node_mac: &str,
+    timestamp_ms: u64,
+    power: &NodePower,
+) -> core::fmt::Result {
+    write!(
+        payload,
+        "{{\"macAddress\":\"{}\", \"timestamp\":{}",
+        node_mac, timestamp_ms
+    )?;
+    // Fields the node did not report are left out
+    if let Some(battery_v) = power.battery_v {
+        write!(payload, ", \"batteryV\":{:.2}", battery_v)?;
+    }
+    if let Some(hours) = power.remaining_hours {
+        write!(payload, ", \"remainingHours\":{:.0}", hours)?;
+    }
+    if let Some(wake_count) = power.wake_count {
+        write!(payload, ", \"wakeCount\":{}", wake_count)?;
+    }
+    if let Some(reason) = power.reset_reason {
+        write!(payload, ", \"resetReason\":\"{}\"", reason.name())?;
+    }
+    payload.push('}').map_err(|_| core::fmt::Error)
+}
+
+fn write_battery_alarm(
+    payload: &mut String<256>,
+    node_mac: &str,
+    timestamp_ms: u64,
+    alarm: &BatteryAlarm,
+) -> core::fmt::Result {
+    write!(
+        payload,
+        "{{\"macAddress\":\"{}\", \"timestamp\":{}, \"state\":\"{}\", \"batteryV\":{:.2}",
+        node_mac,
+        timestamp_ms,
+        if alarm.low { "low" } else { "ok" },
+        alarm.battery_v
+    )?;
+    if let Some(hours) = alarm.remaining_hours {
+        write!(payload, ", \"remainingHours\":{:.0}", hours)?;
+    }
+    payload.push('}').map_err(|_| core::fmt::Error)
+}
+
+/// Latest retained state of each topic, in the order the topics changed
+pub struct StatusQueue<const N: usize> {
+    pending: Vec<Reading, N>,
+}
+
+impl<const N: usize> StatusQueue<N> {
+    pub const fn new() -> Self {
+        StatusQueue {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Replace the pending state of the topic, false when all slots are taken
+    pub fn push(&mut self, reading: Reading) -> bool {
+        match self.pending.iter_mut().find(|r| r.topic == reading.topic) {
+            Some(pending) => {
+                *pending = reading;
+                true
+            }
+            None => self.pending.push(reading).is_ok(),
+        }
+    }
+
+    /// Put back a state which could not be published, unless a newer one came
+    pub fn restore(&mut self, reading: Reading) -> bool {
+        if self.pending.iter().any(|r| r.topic == reading.topic) {
+            return true;
+        }
+        self.pending.insert(0, reading).is_ok()
+    }
+
+    pub fn pop(&mut self) -> Option<Reading> {
+        (!self.pending.is_empty()).then(|| self.pending.remove(0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl<const N: usize> Default for StatusQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Next retained state to publish
+pub fn next_status() -> Option<Reading> {
+    STATUS.lock(|status| status.borrow_mut().pop())
+}
+
+/// Retained states waiting for the broker
+pub fn pending_statuses() -> usize {
+    STATUS.lock(|status| status.borrow().len())
+}
+
+/// Retained state whose publish failed, sent again on the next session
+pub fn restore_status(reading: Reading) {
+    if !STATUS.lock(|status| status.borrow_mut().restore(reading)) {
+        warn!("Status queue full, dropping a state not published");
+    }
+}
+
+/// Queue a reading without blocking, dropped when the queue is full.
+/// Retained states replace the pending state of their topic instead.
+pub fn queue_reading(reading: Reading) -> bool {
+    if reading.retain {
+        let topic = reading.topic.clone();
+        let queued = STATUS.lock(|status| status.borrow_mut().push(reading));
+        if !queued {
+            warn!("Status queue full, dropping state for {}", topic);
+        }
+        return queued;
+    }
+    match READINGS.try_send(reading) {
+        Ok(()) => true,
+        Err(embassy_sync::channel::TrySendError::Full(reading)) => {
+            warn!(
+                "Readings queue full, dropping reading for {}",
+                reading.topic
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(node: &str, state: &'static str, id: u16) -> Reading {
+        Reading::config_status(node, state, id).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_latest_state_of_each_topic() {
+        let mut queue = StatusQueue::<4>::new();
+        assert!(queue.push(status("AA", "queued", 1)));
+        assert!(queue.push(status("BB", "queued", 2)));
+        assert!(queue.push(status("AA", "applied", 1)));
+        assert_eq!(queue.len(), 2);
+
+        let first = queue.pop().unwrap();
+        assert_eq!(first.topic.as_str(), "/status/config/AA");
+        assert_eq!(
+            first.format,
+            PayloadFormat::ConfigStatus { state: "applied" }
+        );
+        assert_eq!(queue.pop().unwrap().topic.as_str(), "/status/config/BB");
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn is_bounded_by_the_topics_not_the_events() {
+        let mut queue = StatusQueue::<2>::new();
+        for id in 0..100 {
+            assert!(queue.push(status("AA", "queued", id)));
+            assert!(queue.push(status("BB", "queued", id)));
+        }
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.push(status("CC", "queued", 0)));
+    }
+
+    #[test]
+    fn restored_state_does_not_replace_a_newer_one() {
+        let mut queue = StatusQueue::<4>::new();
+        queue.push(status("AA", "queued", 1));
+        queue.push(status("BB", "queued", 2));
+        let failed = queue.pop().unwrap();
+        queue.push(status("AA", "applied", 1));
+        assert!(queue.restore(failed));
+        assert_eq!(queue.len(), 2);
+
+        let failed = queue.pop().unwrap();
+        assert_eq!(failed.topic.as_str(), "/status/config/BB");
+        // Published first on the next session
+        assert!(queue.restore(failed));
+        assert_eq!(queue.pop().unwrap().topic.as_str(), "/status/config/BB");
+        let newer = queue.pop().unwrap();
+        assert_eq!(
+            newer.format,
+            PayloadFormat::ConfigStatus { state: "applied" }
+        );
+    }
+
+    #[test]
+    fn retained_readings_skip_the_readings_queue() {
+        while READINGS.try_receive().is_ok() {}
+        for id in 0..READINGS_QUEUE_SIZE as u16 + 4 {
+            assert!(queue_reading(status("DD", "queued", id)));
+        }
+        assert!(READINGS.try_receive().is_err());
+        let latest = next_status().unwrap();
+        assert_eq!(latest.value, (READINGS_QUEUE_SIZE + 3) as f32);
+        assert!(next_status().is_none());
+    }
+}
@@ -0,0 +1,523 @@
+//! Allowlist of the mesh nodes accepted by the gateway, persisted in flash
+//!
+//! Only registered senders get their readings forwarded, frames from unknown
+//! MACs are counted and dropped. New nodes are added by sending a join request
+//! while pairing mode is active (60 s, started by a long-press of the front
+//! panel button or the `mesh/pairing` MQTT command). Each node also keeps its
+//! link security mode and key epoch, see `common::keys`. The registry has the
+//! `mesh_state` partition to itself, one found where earlier firmwares kept it
+//! is moved there.
+//!
+//! Flash blob layout:
+//! - 4 bytes: magic `NREG`
+//! - 1 byte: layout version
+//! - 1 byte: node count
+//! - `MAX_NODES` entries of 26 bytes: MAC, radio channel, link security, key
+//!   epoch, name length, name
+//! - 2 bytes: CRC-16/MODBUS of everything before
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embedded_storage::{ReadStorage, Storage};
+use heapless::{String, Vec};
+
+use crate::common::crc::crc16_modbus;
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::LinkSecurity;
+
+pub const MAX_NODES: usize = 16;
+pub const NODE_NAME_LEN: usize = 16;
+pub const PAIRING_WINDOW_MS: u64 = 60_000;
+// Encrypted peers supported by the ESP-NOW driver (ESP32 default)
+pub const MAX_ESPNOW_ENCRYPTED_PEERS: usize = 6;
+// `mesh_state` partition of `partitions.csv`
+pub const REGISTRY_FLASH_OFFSET: u32 = 0x3D0000;
+// Registries saved by earlier firmwares, at the start of the `nvs` partition
+pub const LEGACY_REGISTRY_FLASH_OFFSET: u32 = 0x9000;
+
+const REGISTRY_MAGIC: [u8; 4] = *b"NREG";
+const REGISTRY_LAYOUT_VERSION: u8 = 2;
+const REGISTRY_HEADER_LEN: usize = 6;
+const ENTRY_LEN: usize = 6 + 1 + 1 + 1 + 1 + NODE_NAME_LEN;
+pub const REGISTRY_BLOB_LEN: usize = REGISTRY_HEADER_LEN + MAX_NODES * ENTRY_LEN + 2;
+
+/// Live registry status for the display and diagnostics
+pub static UNKNOWN_SENDER_FRAMES: AtomicU32 = AtomicU32::new(0);
+pub static REGISTERED_NODES: AtomicU32 = AtomicU32::new(0);
+pub static PAIRING_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Requests from the button and MQTT command paths, applied by the ESP-NOW task
+pub static REGISTRY_COMMANDS: Channel<CriticalSectionRawMutex, RegistryCommand, 4> = Channel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistryCommand {
+    StartPairing,
+    StopPairing,
+    Remove(MacAddress),
+    RotateKey(MacAddress),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistryError {
+    Full,
+    InvalidName,
+    NotPairing,
+    TooManyEncryptedPeers,
+    UnknownNode,
+    BadMagic,
+    BadCrc,
+    Corrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JoinOutcome {
+    Added,
+    Updated,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeEntry {
+    pub mac: MacAddress,
+    pub name: String<NODE_NAME_LEN>,
+    pub channel: u8,
+    pub security: LinkSecurity,
+    pub key_epoch: u8,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NodeRegistry {
+    nodes: Vec<NodeEntry, MAX_NODES>,
+    pairing_until_ms: Option<u64>,
+    unknown_frames: u32,
+    // Sender of the last frame dropped as unknown, a node to register or a
+    // neighbour on the same channel
+    last_unknown: Option<MacAddress>,
+}
+
+impl NodeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn nodes(&self) -> &[NodeEntry] {
+        &self.nodes
+    }
+
+    pub fn get(&self, mac: &MacAddress) -> Option<&NodeEntry> {
+        self.nodes.iter().find(|node| node.mac == *mac)
+    }
+
+    pub fn is_allowed(&self, mac: &MacAddress) -> bool {
+        self.get(mac).is_some()
+    }
+
+    /// Nodes registered as ESP-NOW encrypted peers
+    pub fn espnow_peers(&self) -> impl Iterator<Item = &NodeEntry> {
+        self.nodes
+            .iter()
+            .filter(|node| node.security == LinkSecurity::EspNow)
+    }
+
+    /// Add or update a node, the name is truncated to the stored length and
+    /// the key epoch of a known node is kept
+    pub fn add(
+        &mut self,
+        mac: MacAddress,
+        name: &str,
+        channel: u8,
+        security: LinkSecurity,
+    ) -> Result<JoinOutcome, RegistryError> {
+        if name.is_empty() {
+            return Err(RegistryError::InvalidName);
+        }
+        let known = self.get(&mac);
+        let key_epoch = known.map_or(0, |node| node.key_epoch);
+        let was_espnow = known.is_some_and(|node| node.security == LinkSecurity::EspNow);
+        if security == LinkSecurity::EspNow
+            && !was_espnow
+            && self.espnow_peers().count() >= MAX_ESPNOW_ENCRYPTED_PEERS
+        {
+            return Err(RegistryError::TooManyEncryptedPeers);
+        }
+        let mut stored_name = String::new();
+        for c in name.chars() {
+            if stored_name.push(c).is_err() {
+                break;
+            }
+        }
+        let entry = NodeEntry {
+            mac,
+            name: stored_name,
+            channel,
+            security,
+            key_epoch,
+        };
+        match self.nodes.iter_mut().find(|node| node.mac == mac) {
+            Some(node) => {
+                *node = entry;
+                Ok(JoinOutcome::Updated)
+            }
+            None => {
+                self.nodes.push(entry).map_err(|_| RegistryError::Full)?;
+                Ok(JoinOutcome::Added)
+            }
+        }
+    }
+
+    pub fn remove(&mut self, mac: &MacAddress) -> bool {
+        match self.nodes.iter().position(|node| node.mac == *mac) {
+            Some(index) => {
+                self.nodes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move a node to its next key epoch, returns the previous one
+    pub fn rotate_key(&mut self, mac: &MacAddress) -> Result<u8, RegistryError> {
+        let node = self
+            .nodes
+            .iter_mut()
+            .find(|node| node.mac == *mac)
+            .ok_or(RegistryError::UnknownNode)?;
+        let previous = node.key_epoch;
+        node.key_epoch = previous.wrapping_add(1);
+        Ok(previous)
+    }
+
+    pub fn start_pairing(&mut self, now_ms: u64) {
+        self.pairing_until_ms = Some(now_ms + PAIRING_WINDOW_MS);
+    }
+
+    pub fn stop_pairing(&mut self) {
+        self.pairing_until_ms = None;
+    }
+
+    /// Pairing mode closes by itself once the window has elapsed
+    pub fn is_pairing(&mut self, now_ms: u64) -> bool {
+        match self.pairing_until_ms {
+            Some(until_ms) if now_ms < until_ms => true,
+            Some(_) => {
+                self.pairing_until_ms = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Accept a join request, only while pairing
+    pub fn handle_join(
+        &mut self,
+        mac: MacAddress,
+        name: &str,
+        channel: u8,
+        security: LinkSecurity,
+        now_ms: u64,
+    ) -> Result<JoinOutcome, RegistryError> {
+        if !self.is_pairing(now_ms) {
+            return Err(RegistryError::NotPairing);
+        }
+        self.add(mac, name, channel, security)
+    }
+
+    /// Count a frame dropped from the unknown sender `mac`, returns the total
+    pub fn record_unknown(&mut self, mac: MacAddress) -> u32 {
+        self.unknown_frames = self.unknown_frames.wrapping_add(1);
+        self.last_unknown = Some(mac);
+        self.unknown_frames
+    }
+
+    pub fn unknown_frames(&self) -> u32 {
+        self.unknown_frames
+    }
+
+    pub fn last_unknown(&self) -> Option<MacAddress> {
+        self.last_unknown
+    }
+
+    pub fn encode(&self) -> [u8; REGISTRY_BLOB_LEN] {
+        let mut blob = [0u8; REGISTRY_BLOB_LEN];
+        blob[..4].copy_from_slice(&REGISTRY_MAGIC);
+        blob[4] = REGISTRY_LAYOUT_VERSION;
+        blob[5] = self.nodes.len() as u8;
+        for (node, entry) in self
+            .nodes
+            .iter()
+            .zip(blob[REGISTRY_HEADER_LEN..].chunks_exact_mut(ENTRY_LEN))
+        {
+            entry[..6].copy_from_slice(&node.mac);
+            entry[6] = node.channel;
+            entry[7] = node.security as u8;
+            entry[8] = node.key_epoch;
+            entry[9] = node.name.len() as u8;
+            entry[10..10 + node.name.len()].copy_from_slice(node.name.as_bytes());
+        }
+        let crc_at = REGISTRY_BLOB_LEN - 2;
+        let crc = crc16_modbus(&blob[..crc_at]);
+        blob[crc_at..].copy_from_slice(&crc.to_le_bytes());
+        blob
+    }
+
+    pub fn decode(blob: &[u8]) -> Result<Self, RegistryError> {
+        if blob.len() != REGISTRY_BLOB_LEN {
+            return Err(RegistryError::Corrupt);
+        }
+        if blob[..4] != REGISTRY_MAGIC || blob[4] != REGISTRY_LAYOUT_VERSION {
+            return Err(RegistryError::BadMagic);
+        }
+        let crc_at = REGISTRY_BLOB_LEN - 2;
+        if crc16_modbus(&blob[..crc_at]).to_le_bytes() != [blob[crc_at], blob[crc_at + 1]] {
+            return Err(RegistryError::BadCrc);
+        }
+        let count = blob[5] as usize;
+        if count > MAX_NODES {
+            return Err(RegistryError::Corrupt);
+        }
+
+        let mut registry = NodeRegistry::new();
+        for entry in blob[REGISTRY_HEADER_LEN..]
+            .chunks_exact(ENTRY_LEN)
+            .take(count)
+        {
+            let mut mac = [0u8; 6];
+            mac.copy_from_slice(&entry[..6]);
+            let security = LinkSecurity::from_u8(entry[7]).ok_or(RegistryError::Corrupt)?;
+            let name = entry
+                .get(10..10 + entry[9] as usize)
+                .and_then(|name| core::str::from_utf8(name).ok())
+                .ok_or(RegistryError::Corrupt)?;
+            registry
+                .add(mac, name, entry[6], security)
+                .map_err(|_| RegistryError::Corrupt)?;
+            registry
+                .nodes
+                .last_mut()
+                .ok_or(RegistryError::Corrupt)?
+                .key_epoch = entry[8];
+        }
+        Ok(registry)
+    }
+
+    /// Mirror the state into the shared atomics
+    pub fn publish_status(&mut self, now_ms: u64) {
+        let pairing = self.is_pairing(now_ms);
+        PAIRING_ACTIVE.store(pairing, Ordering::Relaxed);
+        REGISTERED_NODES.store(self.nodes.len() as u32, Ordering::Relaxed);
+        UNKNOWN_SENDER_FRAMES.store(self.unknown_frames, Ordering::Relaxed);
+    }
+}
+
+/// Load the registry stored in flash, fails on a blank or corrupted sector
+pub fn load_registry<S: ReadStorage>(
+    storage: &mut S,
+    offset: u32,
+) -> Result<NodeRegistry, RegistryError> {
+    let mut blob = [0u8; REGISTRY_BLOB_LEN];
+    storage
+        .read(offset, &mut blob)
+        .map_err(|_| RegistryError::Corrupt)?;
+    NodeRegistry::decode(&blob)
+}
+
+pub fn save_registry<S: Storage>(
+    storage: &mut S,
+    offset: u32,
+    registry: &NodeRegistry,
+) -> Result<(), S::Error> {
+    storage.write(offset, &registry.encode())
+}
+
+/// Load the registry from its partition, or move there the one saved by an
+/// earlier firmware. Returns whether it was moved, a registry that could not
+/// be written yet is still used and saved with the next change.
+pub fn load_or_move_registry<S: Storage>(
+    storage: &mut S,
+) -> Result<(NodeRegistry, bool), RegistryError> {
+    let error = match load_registry(storage, REGISTRY_FLASH_OFFSET) {
+        Ok(registry) => return Ok((registry, false)),
+        Err(e) => e,
+    };
+    let registry = load_registry(storage, LEGACY_REGISTRY_FLASH_OFFSET).map_err(|_| error)?;
+    let moved = save_registry(storage, REGISTRY_FLASH_OFFSET, &registry).is_ok();
+    Ok((registry, moved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::mem_flash::{MemFlash, SECTOR_LEN};
+    use embedded_storage::nor_flash::RmwNorFlashStorage;
+
+    // Covers the `mesh_state` partition
+    const FLASH_LEN: usize = REGISTRY_FLASH_OFFSET as usize + SECTOR_LEN;
+
+    fn mac(last: u8) -> MacAddress {
+        [0x24, 0x6F, 0x28, 0, 0, last]
+    }
+
+    fn registry() -> NodeRegistry {
+        let mut registry = NodeRegistry::new();
+        registry.add(mac(1), "tank", 1, LinkSecurity::Aead).unwrap();
+        registry
+            .add(mac(2), "pump-house-north-side", 6, LinkSecurity::EspNow)
+            .unwrap();
+        registry.rotate_key(&mac(2)).unwrap();
+        registry.rotate_key(&mac(2)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn registry_survives_a_save_and_load() {
+        let mut flash = MemFlash::new(FLASH_LEN);
+        let mut buffer = [0u8; SECTOR_LEN];
+        let mut storage = RmwNorFlashStorage::new(&mut flash, &mut buffer);
+        let saved = registry();
+        save_registry(&mut storage, REGISTRY_FLASH_OFFSET, &saved).unwrap();
+
+        let loaded = load_registry(&mut storage, REGISTRY_FLASH_OFFSET).unwrap();
+        assert_eq!(loaded.nodes(), saved.nodes());
+        let node = loaded.get(&mac(2)).unwrap();
+        assert_eq!(node.name.as_str(), "pump-house-north");
+        assert_eq!(node.channel, 6);
+        assert_eq!(node.security, LinkSecurity::EspNow);
+        assert_eq!(node.key_epoch, 2);
+    }
+
+    #[test]
+    fn blank_or_damaged_flash_is_rejected() {
+        let mut flash = MemFlash::new(FLASH_LEN);
+        let mut buffer = [0u8; SECTOR_LEN];
+        let mut storage = RmwNorFlashStorage::new(&mut flash, &mut buffer);
+        assert_eq!(
+            load_registry(&mut storage, REGISTRY_FLASH_OFFSET).unwrap_err(),
+            RegistryError::BadMagic
+        );
+
+        let mut blob = registry().encode();
+        blob[REGISTRY_HEADER_LEN + 6] ^= 0x01;
+        assert_eq!(
+            NodeRegistry::decode(&blob).unwrap_err(),
+            RegistryError::BadCrc
+        );
+        let mut blob = registry().encode();
+        blob[4] = REGISTRY_LAYOUT_VERSION - 1;
+        assert_eq!(
+            NodeRegistry::decode(&blob).unwrap_err(),
+            RegistryError::BadMagic
+        );
+        assert_eq!(
+            NodeRegistry::decode(&blob[1..]).unwrap_err(),
+            RegistryError::Corrupt
+        );
+    }
+
+    #[test]
+    fn registry_of_an_earlier_firmware_is_moved() {
+        let mut flash = MemFlash::new(FLASH_LEN);
+        let mut buffer = [0u8; SECTOR_LEN];
+        let mut storage = RmwNorFlashStorage::new(&mut flash, &mut buffer);
+        assert_eq!(
+            load_or_move_registry(&mut storage).unwrap_err(),
+            RegistryError::BadMagic
+        );
+
+        let saved = registry();
+        save_registry(&mut storage, LEGACY_REGISTRY_FLASH_OFFSET, &saved).unwrap();
+        let (loaded, moved) = load_or_move_registry(&mut storage).unwrap();
+        assert!(moved);
+        assert_eq!(loaded.nodes(), saved.nodes());
+
+        let (loaded, moved) = load_or_move_registry(&mut storage).unwrap();
+        assert!(!moved);
+        assert_eq!(loaded.nodes(), saved.nodes());
+    }
+
+    #[test]
+    fn joins_are_only_accepted_while_pairing() {
+        let mut registry = NodeRegistry::new();
+        assert_eq!(
+            registry.handle_join(mac(1), "tank", 1, LinkSecurity::Aead, 0),
+            Err(RegistryError::NotPairing)
+        );
+
+        registry.start_pairing(1_000);
+        assert!(registry.is_pairing(1_000));
+        assert_eq!(
+            registry.handle_join(mac(1), "tank", 1, LinkSecurity::Aead, 2_000),
+            Ok(JoinOutcome::Added)
+        );
+        registry.rotate_key(&mac(1)).unwrap();
+        // A node joining again is updated and keeps its key epoch
+        assert_eq!(
+            registry.handle_join(mac(1), "tank-2", 11, LinkSecurity::Aead, 3_000),
+            Ok(JoinOutcome::Updated)
+        );
+        let node = registry.get(&mac(1)).unwrap();
+        assert_eq!(
+            (node.name.as_str(), node.channel, node.key_epoch),
+            ("tank-2", 11, 1)
+        );
+
+        let closes_at = 1_000 + PAIRING_WINDOW_MS;
+        assert!(registry.is_pairing(closes_at - 1));
+        assert_eq!(
+            registry.handle_join(mac(2), "well", 1, LinkSecurity::Aead, closes_at),
+            Err(RegistryError::NotPairing)
+        );
+        assert!(!registry.is_pairing(closes_at - 1));
+
+        registry.start_pairing(closes_at);
+        registry.stop_pairing();
+        assert!(!registry.is_pairing(closes_at));
+        assert_eq!(registry.nodes().len(), 1);
+    }
+
+    #[test]
+    fn registry_limits_are_enforced() {
+        let mut registry = NodeRegistry::new();
+        assert_eq!(
+            registry.add(mac(0), "", 1, LinkSecurity::Aead),
+            Err(RegistryError::InvalidName)
+        );
+        for last in 0..MAX_ESPNOW_ENCRYPTED_PEERS as u8 {
+            registry
+                .add(mac(last), "node", 1, LinkSecurity::EspNow)
+                .unwrap();
+        }
+        let extra = mac(0xFE);
+        assert_eq!(
+            registry.add(extra, "node", 1, LinkSecurity::EspNow),
+            Err(RegistryError::TooManyEncryptedPeers)
+        );
+        // Encrypted peers can still be updated, and sealed nodes added
+        assert_eq!(
+            registry.add(mac(0), "renamed", 1, LinkSecurity::EspNow),
+            Ok(JoinOutcome::Updated)
+        );
+        for last in MAX_ESPNOW_ENCRYPTED_PEERS as u8..MAX_NODES as u8 {
+            registry
+                .add(mac(last), "node", 1, LinkSecurity::Aead)
+                .unwrap();
+        }
+        assert_eq!(
+            registry.add(mac(0xFF), "node", 1, LinkSecurity::Aead),
+            Err(RegistryError::Full)
+        );
+
+        assert!(registry.remove(&mac(0)));
+        assert!(!registry.remove(&mac(0)));
+        assert_eq!(
+            registry.rotate_key(&mac(0)),
+            Err(RegistryError::UnknownNode)
+        );
+        // Removing an encrypted peer frees both its slots
+        assert_eq!(
+            registry.add(extra, "node", 1, LinkSecurity::EspNow),
+            Ok(JoinOutcome::Added)
+        );
+        assert_eq!(registry.nodes().len(), MAX_NODES);
+    }
+}
@@ -1,70 +1,450 @@
-use log::{error, info};
+//! HTTP(S) client of the gateway
+//!
+//! An `HttpRequest` (method, URL, headers and an optional body) is sent with
+//! `fetch` over the network stack, HTTPS without certificate checks as for the
+//! node image downloads, or with `send` over any `reqwless` client. The
+//! `HttpResponse` holds the status, the first `MAX_RESPONSE_HEADERS` headers
+//! (headers with a name over `HEADER_NAME_LEN` bytes or a value over
+//! `HEADER_VALUE_LEN` bytes are skipped) and the body, read into the buffer of the caller which must hold the
+//! response headers and the whole body. Any status is a response, callers
+//! wanting a success use `error_for_status`. JSON bodies are deserialized into
+//! the types of the caller with `json`, without allocation.
 
 use embassy_net::dns::DnsSocket;
 use embassy_net::tcp::client::{TcpClient, TcpClientState};
 use embassy_net::Stack;
+use embedded_io_async::{Read, Write};
+use embedded_nal_async::{Dns, TcpConnect};
+use heapless::{String, Vec};
+use log::debug;
 use reqwless::client::{HttpClient, TlsConfig};
+use reqwless::headers::ContentType;
 use reqwless::request::{Method, RequestBuilder};
+use reqwless::response::Response;
+use reqwless::TryBufRead;
+use serde::Deserialize;
 
 const BUFFER_SIZE: usize = 0x1000;
+// Headers kept from a response, the others are dropped
+pub const MAX_RESPONSE_HEADERS: usize = 8;
+pub const HEADER_NAME_LEN: usize = 32;
+pub const HEADER_VALUE_LEN: usize = 96;
 
-pub async fn make_get_request(stack: Stack<'_>, tls_seed: u64, url: &str) {
+#[derive(Debug)]
+pub enum HttpError {
+    // DNS, connection, TLS or a malformed response
+    Http(reqwless::Error),
+    // Status of a response expected to succeed
+    Status(u16),
+    Json(serde_json_core::de::Error),
+}
+
+impl From<reqwless::Error> for HttpError {
+    fn from(error: reqwless::Error) -> Self {
+        HttpError::Http(error)
+    }
+}
+
+pub struct HttpRequest<'a> {
+    pub method: Method,
+    pub url: &'a str,
+    // Sent after `Host`, `Content-Type` and `Content-Length`
+    pub headers: &'a [(&'a str, &'a str)],
+    pub body: Option<(ContentType, &'a [u8])>,
+}
+
+impl<'a> HttpRequest<'a> {
+    pub fn new(method: Method, url: &'a str) -> Self {
+        HttpRequest {
+            method,
+            url,
+            headers: &[],
+            body: None,
+        }
+    }
+
+    pub fn get(url: &'a str) -> Self {
+        HttpRequest::new(Method::GET, url)
+    }
+
+    pub fn post(url: &'a str, content_type: ContentType, body: &'a [u8]) -> Self {
+        HttpRequest {
+            body: Some((content_type, body)),
+            ..HttpRequest::new(Method::POST, url)
+        }
+    }
+
+    pub fn with_headers(self, headers: &'a [(&'a str, &'a str)]) -> Self {
+        HttpRequest { headers, ..self }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub name: String<HEADER_NAME_LEN>,
+    pub value: String<HEADER_VALUE_LEN>,
+}
+
+#[derive(Debug)]
+pub struct HttpResponse<'b> {
+    pub status: u16,
+    pub content_type: Option<ContentType>,
+    // First headers of the response, in order, without the ones too long
+    pub headers: Vec<Header, MAX_RESPONSE_HEADERS>,
+    pub body: &'b [u8],
+}
+
+impl<'b> HttpResponse<'b> {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The response if its status is a success, else the status as an error
+    pub fn error_for_status(self) -> Result<Self, HttpError> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(HttpError::Status(self.status))
+        }
+    }
+
+    /// Value of the header `name`, whatever its case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+
+    /// Body as text, none if not UTF-8
+    pub fn text(&self) -> Option<&'b str> {
+        core::str::from_utf8(self.body).ok()
+    }
+
+    /// Body deserialized from JSON, its strings borrowed from the body
+    pub fn json<T: Deserialize<'b>>(&self) -> Result<T, HttpError> {
+        serde_json_core::from_slice(self.body)
+            .map(|(value, _)| value)
+            .map_err(HttpError::Json)
+    }
+}
+
+/// Send `request` over the network stack, the response read into `buffer`
+pub async fn fetch<'b>(
+    stack: Stack<'_>,
+    tls_seed: u64,
+    request: HttpRequest<'_>,
+    buffer: &'b mut [u8],
+) -> Result<HttpResponse<'b>, HttpError> {
     let mut rx_buffer = [0; BUFFER_SIZE];
     let mut tx_buffer = [0; BUFFER_SIZE];
     let dns = DnsSocket::new(stack);
     let tcp_state = TcpClientState::<1, BUFFER_SIZE, BUFFER_SIZE>::new();
     let tcp = TcpClient::new(stack, &tcp_state);
-
     let tls = TlsConfig::new(
         tls_seed,
         &mut rx_buffer,
         &mut tx_buffer,
         reqwless::client::TlsVerify::None,
     );
-
     let mut client = HttpClient::new_with_tls(&tcp, &dns, tls);
-    let mut buffer = [0u8; BUFFER_SIZE];
+    send(&mut client, request, buffer).await
+}
 
-    // TODO: PASS IN PARAMS TO CONSTRUCT URL ?
+/// Send `request` with `client`, the response read into `buffer`
+pub async fn send<'b, T: TcpConnect, D: Dns>(
+    client: &mut HttpClient<'_, T, D>,
+    request: HttpRequest<'_>,
+    buffer: &'b mut [u8],
+) -> Result<HttpResponse<'b>, HttpError> {
+    debug!("HTTP {:?} {}", request.method, request.url);
+    let handle = client
+        .request(request.method, request.url)
+        .await?
+        .headers(request.headers);
+    // Without a body reqwless would announce a chunked one
+    match request.body {
+        Some((content_type, body)) => {
+            let mut handle = handle.content_type(content_type).body(body);
+            read_response(handle.send(buffer).await?).await
+        }
+        None => {
+            let mut handle = handle;
+            read_response(handle.send(buffer).await?).await
+        }
+    }
+}
 
-    // Create the request
-    let http_req = match client.request(Method::GET, url).await {
-        Ok(req) => req,
-        Err(e) => {
-            error!("Could not create request from {}. Error: {:?}", url, e);
-            return;
+async fn read_response<'b, C: Read + Write + TryBufRead>(
+    mut response: Response<'_, 'b, C>,
+) -> Result<HttpResponse<'b>, HttpError> {
+    let status = response.status.0;
+    let content_type = response.content_type.take();
+    // Copied, the body overwrites them in the buffer
+    let mut headers = Vec::new();
+    for (name, value) in response.headers() {
+        if name.is_empty() {
+            break;
         }
-    };
-
-    // TODO: PASS IN  HEADERS FROM FUNC SIG
-    let mut http_req_with_headers = http_req
-        .content_type(reqwless::headers::ContentType::TextPlain)
-        .headers(&[("x-api-key", "MYKEY")]);
-
-    info!("GET Request to '{}' created, attempting to send...", url);
-    let http_res = match http_req_with_headers.send(&mut buffer).await {
-        Ok(res) => {
-            info!("Response statusCode: {:?}", res.status);
-            res
+        let (Ok(name), Some(value)) = (
+            String::try_from(name),
+            core::str::from_utf8(value)
+                .ok()
+                .and_then(|value| String::try_from(value).ok()),
+        ) else {
+            debug!("HTTP header {} skipped, too long or not UTF-8", name);
+            continue;
+        };
+        if headers.push(Header { name, value }).is_err() {
+            break;
         }
-        Err(e) => {
-            error!("Could not perform http request. Error: {:?}", e);
-            return;
+    }
+    let body = response.body().read_to_end().await?;
+    debug!("HTTP response {}, {} bytes", status, body.len());
+    Ok(HttpResponse {
+        status,
+        content_type,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core::cell::RefCell;
+    use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::vec::Vec as StdVec;
+
+    use embassy_futures::block_on;
+    use embedded_io_async::ErrorType;
+    use embedded_nal_async::AddrType;
+
+    /// HTTP server stand-in answering a canned response and recording the
+    /// request sent to it
+    struct Server {
+        response: &'static [u8],
+        request: RefCell<StdVec<u8>>,
+        remote: RefCell<Option<SocketAddr>>,
+    }
+
+    impl Server {
+        fn new(response: &'static [u8]) -> Self {
+            Server {
+                response,
+                request: RefCell::new(StdVec::new()),
+                remote: RefCell::new(None),
+            }
+        }
+
+        fn request(&self) -> std::string::String {
+            std::string::String::from_utf8(self.request.borrow().clone()).unwrap()
+        }
+    }
+
+    struct Connection<'a> {
+        server: &'a Server,
+        position: usize,
+    }
+
+    #[derive(Debug)]
+    struct NetError;
+
+    impl embedded_io_async::Error for NetError {
+        fn kind(&self) -> embedded_io_async::ErrorKind {
+            embedded_io_async::ErrorKind::Other
+        }
+    }
+
+    impl ErrorType for Connection<'_> {
+        type Error = NetError;
+    }
+
+    impl Read for Connection<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+            // Small reads, as TCP segments would come
+            let remaining = &self.server.response[self.position..];
+            let len = buf.len().min(7).min(remaining.len());
+            buf[..len].copy_from_slice(&remaining[..len]);
+            self.position += len;
+            Ok(len)
+        }
+    }
+
+    impl Write for Connection<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, NetError> {
+            self.server.request.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    impl TcpConnect for Server {
+        type Error = NetError;
+        type Connection<'a>
+            = Connection<'a>
+        where
+            Self: 'a;
+
+        async fn connect<'a>(&'a self, remote: SocketAddr) -> Result<Connection<'a>, NetError> {
+            *self.remote.borrow_mut() = Some(remote);
+            Ok(Connection {
+                server: self,
+                position: 0,
+            })
         }
-    };
-
-    info!("Got response, attempting to print content...");
-    let res = match http_res.body().read_to_end().await {
-        Ok(data) => data,
-        Err(e) => {
-            error!("Failed to read response body: {:?}", e);
-            return;
+    }
+
+    /// Resolves `api.local` only
+    struct Resolver;
+
+    impl Dns for Resolver {
+        type Error = NetError;
+
+        async fn get_host_by_name(&self, host: &str, _: AddrType) -> Result<IpAddr, NetError> {
+            match host {
+                "api.local" => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                _ => Err(NetError),
+            }
         }
-    };
 
-    match core::str::from_utf8(res) {
-        Ok(content) => info!("{}", content),
-        Err(e) => error!("Response wasn't valid UTF-8: {:?}", e),
+        async fn get_host_by_address(&self, _: IpAddr, _: &mut [u8]) -> Result<usize, NetError> {
+            Err(NetError)
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Post<'a> {
+        id: u32,
+        title: &'a str,
+        done: bool,
+    }
+
+    #[test]
+    fn get_returns_headers_and_json() {
+        let server = Server::new(
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Request-Id: abc\r\n\
+              Content-Length: 37\r\n\r\n{\"id\":1,\"title\":\"hello\",\"done\":false}",
+        );
+        let mut client = HttpClient::new(&server, &Resolver);
+        let mut buffer = [0; 512];
+        let headers = [("x-api-key", "KEY")];
+        let request = HttpRequest::get("http://api.local:8080/posts/1?x=2").with_headers(&headers);
+        let response = block_on(send(&mut client, request, &mut buffer)).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert!(matches!(
+            response.content_type,
+            Some(ContentType::ApplicationJson)
+        ));
+        assert_eq!(response.header("x-request-id"), Some("abc"));
+        assert_eq!(response.header("X-REQUEST-ID"), Some("abc"));
+        assert_eq!(response.header("x-missing"), None);
+        assert_eq!(
+            response.json::<Post>().unwrap(),
+            Post {
+                id: 1,
+                title: "hello",
+                done: false
+            }
+        );
+        assert!(response.error_for_status().is_ok());
+
+        let sent = server.request();
+        assert!(sent.starts_with("GET /posts/1?x=2 HTTP/1.1\r\n"));
+        assert!(sent.contains("Host: api.local\r\n"));
+        assert!(sent.contains("x-api-key: KEY\r\n"));
+        // No body announced without one
+        assert!(!sent.contains("Content-Length") && !sent.contains("chunked"));
+        assert_eq!(
+            *server.remote.borrow(),
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080))
+        );
+    }
+
+    #[test]
+    fn error_status_is_a_response() {
+        let server = Server::new(
+            b"HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nnope!\r\n0\r\n\r\n",
+        );
+        let mut client = HttpClient::new(&server, &Resolver);
+        let mut buffer = [0; 512];
+        let request = HttpRequest::post(
+            "http://api.local/items",
+            ContentType::ApplicationJson,
+            b"{\"a\":1}",
+        );
+        let response = block_on(send(&mut client, request, &mut buffer)).unwrap();
+
+        assert_eq!(response.status, 404);
+        assert!(!response.is_success());
+        assert_eq!(response.text(), Some("nope!"));
+        assert!(matches!(response.json::<Post>(), Err(HttpError::Json(_))));
+        assert!(matches!(
+            response.error_for_status(),
+            Err(HttpError::Status(404))
+        ));
+
+        let sent = server.request();
+        assert!(sent.starts_with("POST /items HTTP/1.1\r\n"));
+        assert!(sent.contains("Content-Type: application/json\r\n"));
+        assert!(sent.contains("Content-Length: 7\r\n"));
+        assert!(sent.ends_with("\r\n\r\n{\"a\":1}"));
+    }
+
+    #[test]
+    fn long_and_extra_headers_are_dropped() {
+        let server = Server::new(
+            b"HTTP/1.1 204 No Content\r\n\
+              X-A-Header-Name-Longer-Than-32-Bytes: 1\r\n\
+              X-Long-Value: 0123456789012345678901234567890123456789012345678901234567890123456789012345678901234567890123456789\r\n\
+              A: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\nF: 6\r\nG: 7\r\nH: 8\r\nI: 9\r\n\
+              Content-Length: 0\r\n\r\n",
+        );
+        let mut client = HttpClient::new(&server, &Resolver);
+        let mut buffer = [0; 1024];
+        let request = HttpRequest::get("http://api.local/");
+        let response = block_on(send(&mut client, request, &mut buffer)).unwrap();
+
+        assert_eq!(
+            response.header("x-a-header-name-longer-than-32-bytes"),
+            None
+        );
+        assert_eq!(response.header("x-long-value"), None);
+        assert_eq!(response.headers.len(), MAX_RESPONSE_HEADERS);
+        assert_eq!(response.headers[0].name, "A");
+        assert_eq!(response.header("h"), Some("8"));
+        assert_eq!(response.header("i"), None);
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn connection_errors_are_http_errors() {
+        let server = Server::new(b"HTTP/1.1 200 OK\r\nContent-Length: 600\r\n\r\n");
+        let mut client = HttpClient::new(&server, &Resolver);
+        let mut buffer = [0; 64];
+        assert!(matches!(
+            block_on(send(
+                &mut client,
+                HttpRequest::get("http://nowhere/"),
+                &mut buffer
+            )),
+            Err(HttpError::Http(reqwless::Error::Dns))
+        ));
+        assert!(matches!(
+            block_on(send(
+                &mut client,
+                HttpRequest::get("not a url"),
+                &mut buffer
+            )),
+            Err(HttpError::Http(reqwless::Error::InvalidUrl(_)))
+        ));
+        // Body larger than the buffer, and cut short by the server
+        assert!(block_on(send(
+            &mut client,
+            HttpRequest::get("http://api.local/"),
+            &mut buffer
+        ))
+        .is_err());
     }
-    // TODO: PARSE THE RESPONSE AND RETURN AS JSON
 }
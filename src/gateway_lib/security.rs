@@ -0,0 +1,370 @@
+//! Link security of the mesh on the gateway side
+//!
+//! Security is enabled when `MESH_MASTER_KEY` is configured, the readings of a
+//! registered node are then only forwarded when:
+//! - sealed (`FLAG_ENCRYPTED`) with the node key of its current or previous
+//!   epoch, and fresh according to the node replay window
+//! - or sent in clear by a node registered as an ESP-NOW encrypted peer, the
+//!   driver drops unencrypted frames from such peers
+//!
+//! The previous epoch stays valid after a rotation until the node sends its
+//! first frame under the new one. Replay windows live in RAM only.
+
+use heapless::FnvIndexMap;
+
+use crate::common::keys::{
+    ack_auth, config_auth, derive_aead_key, derive_lmk, ota_auth, parse_key, CryptoError, MeshKey,
+    ReplayWindow, SealedPayload,
+};
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{
+    Ack, ConfigUpdate, Frame, LinkSecurity, OtaOffer, CONFIG_AUTH_LEN, FLAG_ENCRYPTED,
+    MAX_PAYLOAD_LEN, OTA_AUTH_LEN,
+};
+use crate::gateway_lib::registry::{NodeEntry, MAX_NODES};
+
+pub const MESH_MASTER_KEY: Option<&str> = option_env!("MESH_MASTER_KEY");
+pub const MESH_PMK: Option<&str> = option_env!("MESH_PMK");
+
+#[derive(Clone, Copy, Debug, Default)]
+struct NodeLink {
+    replay: ReplayWindow,
+    previous_epoch: Option<u8>,
+}
+
+pub struct KeyManager {
+    master: MeshKey,
+    pmk: Option<MeshKey>,
+    links: FnvIndexMap<MacAddress, NodeLink, MAX_NODES>,
+}
+
+impl KeyManager {
+    pub fn new(master: MeshKey, pmk: Option<MeshKey>) -> Self {
+        KeyManager {
+            master,
+            pmk,
+            links: FnvIndexMap::new(),
+        }
+    }
+
+    /// Keys from the build config, `None` leaves the mesh unencrypted
+    pub fn from_config() -> Option<Self> {
+        let master = MESH_MASTER_KEY.and_then(parse_key)?;
+        Some(KeyManager::new(master, MESH_PMK.and_then(parse_key)))
+    }
+
+    pub fn pmk(&self) -> Option<&MeshKey> {
+        self.pmk.as_ref()
+    }
+
+    pub fn lmk(&self, node: &NodeEntry) -> MeshKey {
+        derive_lmk(&self.master, &node.mac, node.key_epoch)
+    }
+
+    /// Keep accepting the previous epoch until the node switched over
+    pub fn on_rotate(&mut self, mac: MacAddress, previous_epoch: u8) {
+        if let Some(link) = self.links.get_mut(&mac) {
+            link.previous_epoch = Some(previous_epoch);
+        } else {
+            let link = NodeLink {
+                previous_epoch: Some(previous_epoch),
+                ..NodeLink::default()
+            };
+            // At most one link per registered node, cannot be full
+            let _ = self.links.insert(mac, link);
+        }
+    }
+
+    pub fn forget(&mut self, mac: &MacAddress) {
+        self.links.remove(mac);
+    }
+
+    /// Authenticate the payload of a registered node, returns its TLV records
+    pub fn open<'a>(
+        &mut self,
+        node: &NodeEntry,
+        frame: &Frame<'a>,
+        out: &'a mut [u8; MAX_PAYLOAD_LEN],
+    ) -> Result<&'a [u8], CryptoError> {
+        if !frame.header.has_flag(FLAG_ENCRYPTED) {
+            return match node.security {
+                LinkSecurity::EspNow if self.pmk.is_some() => Ok(frame.payload),
+                _ => Err(CryptoError::NotEncrypted),
+            };
+        }
+
+        if !self.links.contains_key(&node.mac) {
+            self.links
+                .insert(node.mac, NodeLink::default())
+                .map_err(|_| CryptoError::UnknownEpoch(node.key_epoch))?;
+        }
+        let link = self
+            .links
+            .get_mut(&node.mac)
+            .ok_or(CryptoError::UnknownEpoch(node.key_epoch))?;
+
+        let sealed = SealedPayload::parse(frame)?;
+        if sealed.epoch != node.key_epoch && Some(sealed.epoch) != link.previous_epoch {
+            return Err(CryptoError::UnknownEpoch(sealed.epoch));
+        }
+        // Authenticated first, a replayed frame is then a genuine retransmit
+        let key = derive_aead_key(&self.master, &node.mac, sealed.epoch);
+        let plaintext = sealed.open(frame, &key, out)?;
+        if !link.replay.is_fresh(sealed.session, frame.header.seq) {
+            return Err(CryptoError::Replayed);
+        }
+        link.replay.accept(sealed.session, frame.header.seq)?;
+        if sealed.epoch == node.key_epoch {
+            link.previous_epoch = None;
+        }
+        Ok(plaintext)
+    }
+
+    /// Ack of a frame, bound to its session when it was sealed
+    pub fn ack_for(&self, frame: &Frame<'_>) -> Ack {
+        match SealedPayload::parse(frame) {
+            Ok(sealed) => {
+                let key = derive_aead_key(&self.master, &frame.header.node_id, sealed.epoch);
+                Ack {
+                    session: Some(sealed.session),
+                    auth: Some(ack_auth(
+                        &key,
+                        &frame.header.node_id,
+                        frame.header.seq,
+                        sealed.session,
+                    )),
+                }
+            }
+            Err(_) => Ack::default(),
+        }
+    }
+
+    /// Tag of a configuration update answering a sealed frame, none in clear
+    pub fn config_auth(
+        &self,
+        frame: &Frame<'_>,
+        update: &ConfigUpdate,
+    ) -> Option<[u8; CONFIG_AUTH_LEN]> {
+        let sealed = SealedPayload::parse(frame).ok()?;
+        let key = derive_aead_key(&self.master, &frame.header.node_id, sealed.epoch);
+        Some(config_auth(
+            &key,
+            &frame.header.node_id,
+            frame.header.seq,
+            sealed.session,
+            update,
+        ))
+    }
+
+    /// Tag of a firmware offer answering a sealed frame, none in clear
+    pub fn offer_auth(&self, frame: &Frame<'_>, offer: &OtaOffer) -> Option<[u8; OTA_AUTH_LEN]> {
+        let sealed = SealedPayload::parse(frame).ok()?;
+        let key = derive_aead_key(&self.master, &frame.header.node_id, sealed.epoch);
+        Some(ota_auth(&key, &frame.header.node_id, sealed.session, offer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    use crate::common::crc::crc16_modbus;
+    use crate::common::keys::{seal, KEY_LEN, REPLAY_WINDOW};
+    use crate::common::mesh_proto::{
+        FrameBuilder, Header, MeshReading, MessageType, ReadingKind, TlvIter, Unit, HEADER_LEN,
+    };
+    use heapless::String;
+
+    const MASTER: MeshKey = [0x11; KEY_LEN];
+    const NODE: MacAddress = [1, 2, 3, 4, 5, 6];
+
+    fn node(security: LinkSecurity, key_epoch: u8) -> NodeEntry {
+        NodeEntry {
+            mac: NODE,
+            name: String::try_from("boiler").unwrap(),
+            channel: 1,
+            security,
+            key_epoch,
+        }
+    }
+
+    fn readings(seq: u16, value: f32) -> FrameBuilder {
+        let mut builder = FrameBuilder::new(&Header::new(MessageType::Readings, NODE, seq));
+        builder
+            .push_reading(&MeshReading::new(
+                ReadingKind::Temperature,
+                Unit::Celsius,
+                value,
+            ))
+            .unwrap();
+        builder
+    }
+
+    fn sealed(master: &MeshKey, epoch: u8, session: u32, seq: u16, value: f32) -> StdVec<u8> {
+        let key = derive_aead_key(master, &NODE, epoch);
+        seal(readings(seq, value), &key, epoch, session)
+            .unwrap()
+            .to_vec()
+    }
+
+    /// Flip one bit of the frame and fix its CRC, as a forger would
+    fn tampered(frame: &[u8], index: usize) -> StdVec<u8> {
+        let mut frame = frame.to_vec();
+        frame[index] ^= 0x01;
+        let crc_at = frame.len() - 2;
+        let crc = crc16_modbus(&frame[..crc_at]);
+        frame[crc_at..].copy_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    fn open(keys: &mut KeyManager, node: &NodeEntry, data: &[u8]) -> Result<f32, CryptoError> {
+        let frame = Frame::decode(data).unwrap();
+        let mut out = [0u8; MAX_PAYLOAD_LEN];
+        let plaintext = keys.open(node, &frame, &mut out)?;
+        let tlv = TlvIter::new(plaintext).next().unwrap().unwrap();
+        Ok(MeshReading::from_tlv(&tlv).unwrap().unwrap().value)
+    }
+
+    #[test]
+    fn sealed_readings_are_opened() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let frame = sealed(&MASTER, 0, 1, 1, 21.5);
+        assert_eq!(
+            open(&mut keys, &node(LinkSecurity::Aead, 0), &frame),
+            Ok(21.5)
+        );
+    }
+
+    #[test]
+    fn tampered_frames_are_rejected() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let node = node(LinkSecurity::Aead, 0);
+        let frame = sealed(&MASTER, 0, 1, 1, 21.5);
+        let tag_at = frame.len() - 2 - 16;
+        // Header, session, ciphertext and tag are all covered
+        for index in [9, HEADER_LEN + 1, HEADER_LEN + 5, tag_at] {
+            assert_eq!(
+                open(&mut keys, &node, &tampered(&frame, index)),
+                Err(CryptoError::AuthFailed),
+                "byte {}",
+                index
+            );
+        }
+        // Failed frames do not move the replay window
+        assert_eq!(open(&mut keys, &node, &frame), Ok(21.5));
+    }
+
+    #[test]
+    fn frames_sealed_with_another_key_are_rejected() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let node = node(LinkSecurity::Aead, 0);
+        let frame = sealed(&[0x22; KEY_LEN], 0, 1, 1, 21.5);
+        assert_eq!(open(&mut keys, &node, &frame), Err(CryptoError::AuthFailed));
+        let frame = sealed(&MASTER, 1, 1, 1, 21.5);
+        assert_eq!(
+            open(&mut keys, &node, &frame),
+            Err(CryptoError::UnknownEpoch(1))
+        );
+    }
+
+    #[test]
+    fn replayed_and_stale_frames_are_rejected() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let node = node(LinkSecurity::Aead, 0);
+        let first = sealed(&MASTER, 0, 5, 100, 1.0);
+        assert_eq!(open(&mut keys, &node, &first), Ok(1.0));
+        assert_eq!(open(&mut keys, &node, &first), Err(CryptoError::Replayed));
+
+        // Late frames inside the window are accepted once
+        let late = sealed(&MASTER, 0, 5, 99, 2.0);
+        let newer = sealed(&MASTER, 0, 5, 101, 3.0);
+        assert_eq!(open(&mut keys, &node, &newer), Ok(3.0));
+        assert_eq!(open(&mut keys, &node, &late), Ok(2.0));
+        assert_eq!(open(&mut keys, &node, &late), Err(CryptoError::Replayed));
+
+        let too_old = sealed(&MASTER, 0, 5, 101 - REPLAY_WINDOW, 4.0);
+        assert_eq!(open(&mut keys, &node, &too_old), Err(CryptoError::Replayed));
+        let previous_session = sealed(&MASTER, 0, 4, 200, 5.0);
+        assert_eq!(
+            open(&mut keys, &node, &previous_session),
+            Err(CryptoError::Replayed)
+        );
+        // A new session starts over at any sequence number
+        let next_session = sealed(&MASTER, 0, 6, 1, 6.0);
+        assert_eq!(open(&mut keys, &node, &next_session), Ok(6.0));
+    }
+
+    #[test]
+    fn forged_copies_of_accepted_frames_fail_authentication() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let node = node(LinkSecurity::Aead, 0);
+        let frame = sealed(&MASTER, 0, 5, 100, 1.0);
+        assert_eq!(open(&mut keys, &node, &frame), Ok(1.0));
+        // Not reported as a replay, it would be acked as a retransmit
+        let tag_at = frame.len() - 2 - 16;
+        assert_eq!(
+            open(&mut keys, &node, &tampered(&frame, tag_at)),
+            Err(CryptoError::AuthFailed)
+        );
+        assert_eq!(open(&mut keys, &node, &frame), Err(CryptoError::Replayed));
+    }
+
+    #[test]
+    fn a_reused_nonce_is_never_opened() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let node = node(LinkSecurity::Aead, 0);
+        // A node reusing its session after a reboot seals new readings under
+        // the nonce of frames already accepted
+        let before = sealed(&MASTER, 0, 3, 10, 1.0);
+        let reused = sealed(&MASTER, 0, 3, 10, 2.0);
+        assert_ne!(before, reused);
+        assert_eq!(open(&mut keys, &node, &before), Ok(1.0));
+        assert_eq!(open(&mut keys, &node, &reused), Err(CryptoError::Replayed));
+    }
+
+    #[test]
+    fn previous_epoch_is_accepted_until_the_node_switches() {
+        let mut keys = KeyManager::new(MASTER, None);
+        let old = node(LinkSecurity::Aead, 0);
+        assert_eq!(
+            open(&mut keys, &old, &sealed(&MASTER, 0, 1, 1, 1.0)),
+            Ok(1.0)
+        );
+
+        keys.on_rotate(NODE, 0);
+        let rotated = node(LinkSecurity::Aead, 1);
+        assert_eq!(
+            open(&mut keys, &rotated, &sealed(&MASTER, 0, 1, 2, 2.0)),
+            Ok(2.0)
+        );
+        assert_eq!(
+            open(&mut keys, &rotated, &sealed(&MASTER, 1, 1, 3, 3.0)),
+            Ok(3.0)
+        );
+        assert_eq!(
+            open(&mut keys, &rotated, &sealed(&MASTER, 0, 1, 4, 4.0)),
+            Err(CryptoError::UnknownEpoch(0))
+        );
+    }
+
+    #[test]
+    fn clear_frames_need_an_espnow_peer() {
+        let clear = readings(1, 21.5).finish().to_vec();
+        let mut keys = KeyManager::new(MASTER, None);
+        assert_eq!(
+            open(&mut keys, &node(LinkSecurity::Aead, 0), &clear),
+            Err(CryptoError::NotEncrypted)
+        );
+        assert_eq!(
+            open(&mut keys, &node(LinkSecurity::EspNow, 0), &clear),
+            Err(CryptoError::NotEncrypted)
+        );
+        let mut keys = KeyManager::new(MASTER, Some([0x33; KEY_LEN]));
+        assert_eq!(
+            open(&mut keys, &node(LinkSecurity::EspNow, 0), &clear),
+            Ok(21.5)
+        );
+    }
+}
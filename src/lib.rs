@@ -1,3 +1,4 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 pub mod common;
 pub mod gateway_lib;
+pub mod node_lib;
@@ -0,0 +1,287 @@
+//! Search of the mesh channel by a node
+//!
+//! ESP-NOW frames are only heard on the channel of the AP the gateway joined.
+//! The node listens for beacons during one beacon period on its cached channel
+//! first, then on each other channel in turn until one is heard. The channel
+//! found is kept in RTC memory (`NodeState`) and searched again once the
+//! gateway stops answering, e.g. after it roamed to an AP on another channel.
+//!
+//! Relays sweep the channels the same way while they have no parent
+//! (`RelayScan`). The timers stay with the callers, which pass their clock in.
+
+use crate::common::routing::{Neighbor, BEACON_INTERVAL_MS};
+
+pub const MIN_CHANNEL: u8 = 1;
+pub const MAX_CHANNEL: u8 = 13;
+const CHANNEL_COUNT: u8 = MAX_CHANNEL - MIN_CHANNEL + 1;
+// A full beacon period on each channel, with some margin
+pub const CHANNEL_DWELL_MS: u64 = BEACON_INTERVAL_MS + 1_000;
+
+pub fn is_valid_channel(channel: u8) -> bool {
+    (MIN_CHANNEL..=MAX_CHANNEL).contains(&channel)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanStep {
+    // Listen for beacons on this channel for `CHANNEL_DWELL_MS`
+    Listen(u8),
+    // A beacon was heard, the mesh uses this channel
+    Found(u8),
+    // No beacon on any channel
+    Exhausted,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelSearch {
+    // Channel the sweep starts from, then wraps around
+    first: u8,
+    // Channels of the sweep listened to so far
+    swept: u8,
+    // A beacon already sent us to the channel it advertised
+    followed: bool,
+    step: ScanStep,
+}
+
+impl ChannelSearch {
+    /// Start on the cached channel, or on `fallback` (`MESH_CHANNEL`)
+    pub fn new(cached: Option<u8>, fallback: u8) -> Self {
+        let first = cached
+            .filter(|c| is_valid_channel(*c))
+            .or(Some(fallback).filter(|c| is_valid_channel(*c)))
+            .unwrap_or(MIN_CHANNEL);
+        ChannelSearch {
+            first,
+            swept: 1,
+            followed: false,
+            step: ScanStep::Listen(first),
+        }
+    }
+
+    pub fn step(&self) -> ScanStep {
+        self.step
+    }
+
+    /// A beacon was heard on `heard_on`, `advertised` being the gateway
+    /// channel it carries
+    pub fn on_beacon(&mut self, heard_on: u8, advertised: Option<u8>) -> ScanStep {
+        if !matches!(self.step, ScanStep::Listen(_)) {
+            return self.step;
+        }
+        self.step = match advertised.filter(|c| is_valid_channel(*c)) {
+            // Relay not yet aware the gateway moved, follow the gateway once
+            Some(channel) if channel != heard_on && !self.followed => {
+                self.followed = true;
+                ScanStep::Listen(channel)
+            }
+            _ => ScanStep::Found(heard_on),
+        };
+        self.step
+    }
+
+    /// End of the dwell time, with the parent elected from the beacons heard
+    pub fn on_dwell_end(&mut self, parent: Option<&Neighbor>) -> ScanStep {
+        match (self.step, parent) {
+            (ScanStep::Listen(channel), Some(parent)) => self.on_beacon(channel, parent.channel),
+            _ => self.on_timeout(),
+        }
+    }
+
+    /// Nothing heard during the dwell time, move to the next channel
+    pub fn on_timeout(&mut self) -> ScanStep {
+        if !matches!(self.step, ScanStep::Listen(_)) {
+            return self.step;
+        }
+        self.step = if self.swept >= CHANNEL_COUNT {
+            ScanStep::Exhausted
+        } else {
+            let offset = (self.first - MIN_CHANNEL + self.swept) % CHANNEL_COUNT;
+            self.swept += 1;
+            ScanStep::Listen(MIN_CHANNEL + offset)
+        };
+        self.step
+    }
+}
+
+/// Channel of a relay, kept while it has a parent and swept one dwell time
+/// per channel otherwise
+#[derive(Clone, Copy, Debug)]
+pub struct RelayScan {
+    channel: u8,
+    // Sweep in progress, none while a parent is heard
+    sweep: Option<ChannelSearch>,
+    listening_since_ms: u64,
+}
+
+impl RelayScan {
+    pub fn new(channel: u8, now_ms: u64) -> Self {
+        RelayScan {
+            channel,
+            sweep: None,
+            listening_since_ms: now_ms,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Called once per beacon period, returns the channel to move to
+    pub fn poll(&mut self, has_parent: bool, now_ms: u64) -> Option<u8> {
+        if has_parent {
+            self.sweep = None;
+            return None;
+        }
+        if now_ms.saturating_sub(self.listening_since_ms) < CHANNEL_DWELL_MS {
+            return None;
+        }
+        self.listening_since_ms = now_ms;
+        let channel = self.channel;
+        let sweep = self
+            .sweep
+            .get_or_insert_with(|| ChannelSearch::new(Some(channel), channel));
+        match sweep.on_timeout() {
+            ScanStep::Listen(next) => {
+                self.channel = next;
+                Some(next)
+            }
+            // Start over from the current channel
+            _ => {
+                self.sweep = None;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    const GATEWAY_CHANNEL: u8 = 6;
+
+    fn parent(channel: Option<u8>) -> Neighbor {
+        Neighbor {
+            mac: [1; 6],
+            hops: 0,
+            rssi: -50,
+            last_heard_ms: 0,
+            channel,
+            gateway: Some([1; 6]),
+        }
+    }
+
+    fn sweep(search: &mut ChannelSearch) -> StdVec<u8> {
+        let mut channels = StdVec::new();
+        while let ScanStep::Listen(channel) = search.step() {
+            channels.push(channel);
+            search.on_timeout();
+        }
+        channels
+    }
+
+    #[test]
+    fn sweep_starts_on_the_cached_channel_and_wraps_around() {
+        let mut search = ChannelSearch::new(Some(11), 1);
+        assert_eq!(
+            sweep(&mut search),
+            [11, 12, 13, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]
+        );
+        assert_eq!(search.step(), ScanStep::Exhausted);
+        assert_eq!(search.on_beacon(3, None), ScanStep::Exhausted);
+
+        // Invalid channels fall back to `MESH_CHANNEL`, then to the first one
+        assert_eq!(ChannelSearch::new(Some(14), 4).step(), ScanStep::Listen(4));
+        assert_eq!(
+            ChannelSearch::new(None, 0).step(),
+            ScanStep::Listen(MIN_CHANNEL)
+        );
+    }
+
+    #[test]
+    fn advertised_channel_is_followed_once() {
+        let mut search = ChannelSearch::new(Some(1), 1);
+        // A relay still on the old channel points to the gateway one
+        assert_eq!(
+            search.on_dwell_end(Some(&parent(Some(GATEWAY_CHANNEL)))),
+            ScanStep::Listen(GATEWAY_CHANNEL)
+        );
+        assert_eq!(
+            search.on_dwell_end(Some(&parent(Some(9)))),
+            ScanStep::Found(GATEWAY_CHANNEL)
+        );
+        assert_eq!(search.on_timeout(), ScanStep::Found(GATEWAY_CHANNEL));
+
+        let mut search = ChannelSearch::new(Some(1), 1);
+        assert_eq!(search.on_dwell_end(None), ScanStep::Listen(2));
+        assert_eq!(search.on_dwell_end(Some(&parent(None))), ScanStep::Found(2));
+    }
+
+    #[test]
+    fn node_finds_the_gateway_whatever_the_beacon_phase() {
+        for phase_ms in (0..BEACON_INTERVAL_MS).step_by(700) {
+            let beacon_in = |from_ms: u64, to_ms: u64| {
+                let next = phase_ms
+                    + from_ms
+                        .saturating_sub(phase_ms)
+                        .div_ceil(BEACON_INTERVAL_MS)
+                        * BEACON_INTERVAL_MS;
+                next < to_ms
+            };
+            let mut search = ChannelSearch::new(Some(1), 1);
+            let mut now_ms = 0;
+            while let ScanStep::Listen(channel) = search.step() {
+                let heard =
+                    channel == GATEWAY_CHANNEL && beacon_in(now_ms, now_ms + CHANNEL_DWELL_MS);
+                now_ms += CHANNEL_DWELL_MS;
+                search.on_dwell_end(heard.then(|| parent(Some(GATEWAY_CHANNEL))).as_ref());
+            }
+            assert_eq!(
+                search.step(),
+                ScanStep::Found(GATEWAY_CHANNEL),
+                "phase {}",
+                phase_ms
+            );
+            assert_eq!(now_ms, GATEWAY_CHANNEL as u64 * CHANNEL_DWELL_MS);
+        }
+    }
+
+    #[test]
+    fn relay_keeps_its_channel_while_it_has_a_parent() {
+        let mut scan = RelayScan::new(GATEWAY_CHANNEL, 0);
+        let mut now_ms = 0;
+        for _ in 0..10 {
+            now_ms += BEACON_INTERVAL_MS;
+            assert_eq!(scan.poll(true, now_ms), None);
+        }
+        assert_eq!(scan.channel(), GATEWAY_CHANNEL);
+    }
+
+    #[test]
+    fn relay_without_parent_sweeps_one_dwell_per_channel() {
+        let mut scan = RelayScan::new(12, 0);
+        let mut moves = StdVec::new();
+        let mut now_ms = 0;
+        while moves.len() < 13 {
+            now_ms += BEACON_INTERVAL_MS;
+            if let Some(channel) = scan.poll(false, now_ms) {
+                moves.push((channel, now_ms));
+            }
+        }
+        let channels: StdVec<u8> = moves.iter().map(|(channel, _)| *channel).collect();
+        // Back on the first channel after a full sweep, then again
+        assert_eq!(channels[..13], [13, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        for pair in moves.windows(2) {
+            assert!(pair[1].1 - pair[0].1 >= CHANNEL_DWELL_MS);
+        }
+
+        // A parent heard stops the sweep, losing it starts over from there
+        assert_eq!(scan.poll(true, now_ms + CHANNEL_DWELL_MS), None);
+        let channel = scan.channel();
+        assert_eq!(
+            scan.poll(false, now_ms + 2 * CHANNEL_DWELL_MS),
+            Some(channel % MAX_CHANNEL + 1)
+        );
+    }
+}
@@ -0,0 +1,5 @@
+pub mod channel_scan;
+pub mod ota;
+pub mod power;
+pub mod schedule;
+pub mod session;
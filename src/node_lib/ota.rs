@@ -0,0 +1,201 @@
+//! Boot partition selection of the node, in the format of the ESP-IDF
+//! bootloader (`partitions.csv`)
+//!
+//! `otadata` holds two sectors with one select entry each, the bootloader runs
+//! the slot `(seq - 1) % 2` of the valid entry with the highest `seq`. A new
+//! image is written to the slot not running, then selected in the `New` state
+//! (`PendingVerify` once booted by a bootloader with rollback). The new image
+//! confirms itself (`Valid`) once its readings reached the gateway, or selects
+//! the previous slot back when it could not after `OTA_CONFIRM_ATTEMPTS`
+//! transmits.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::common::crc::crc32_le;
+use crate::common::ota::FlashRegion;
+
+pub const OTADATA_FLASH_OFFSET: u32 = 0xD000;
+const OTADATA_SECTOR_LEN: u32 = 0x1000;
+const SELECT_ENTRY_LEN: usize = 32;
+// App slots of `partitions.csv`
+pub const OTA_SLOTS: [FlashRegion; 2] = [
+    FlashRegion {
+        offset: 0x10000,
+        size: 0x140000,
+    },
+    FlashRegion {
+        offset: 0x150000,
+        size: 0x140000,
+    },
+];
+// Failed transmits of a new image before going back to the previous one
+pub const OTA_CONFIRM_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageState {
+    New,
+    PendingVerify,
+    Valid,
+    Invalid,
+    Aborted,
+    Undefined,
+}
+
+impl ImageState {
+    fn from_u32(value: u32) -> Self {
+        match value {
+            0 => ImageState::New,
+            1 => ImageState::PendingVerify,
+            2 => ImageState::Valid,
+            3 => ImageState::Invalid,
+            4 => ImageState::Aborted,
+            _ => ImageState::Undefined,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            ImageState::New => 0,
+            ImageState::PendingVerify => 1,
+            ImageState::Valid => 2,
+            ImageState::Invalid => 3,
+            ImageState::Aborted => 4,
+            ImageState::Undefined => u32::MAX,
+        }
+    }
+}
+
+/// `esp_ota_select_entry_t`: seq, 20 bytes label, state, CRC of seq
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelectEntry {
+    pub seq: u32,
+    pub state: ImageState,
+}
+
+impl SelectEntry {
+    pub fn slot(&self) -> usize {
+        (self.seq.wrapping_sub(1) % OTA_SLOTS.len() as u32) as usize
+    }
+
+    fn decode(raw: &[u8; SELECT_ENTRY_LEN]) -> Option<Self> {
+        let word = |at: usize| u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]]);
+        let seq = word(0);
+        if seq == u32::MAX || word(28) != crc32_le(u32::MAX, &raw[..4]) {
+            return None;
+        }
+        Some(SelectEntry {
+            seq,
+            state: ImageState::from_u32(word(24)),
+        })
+    }
+
+    fn encode(&self) -> [u8; SELECT_ENTRY_LEN] {
+        let mut raw = [0xFFu8; SELECT_ENTRY_LEN];
+        raw[..4].copy_from_slice(&self.seq.to_le_bytes());
+        raw[24..28].copy_from_slice(&self.state.to_u32().to_le_bytes());
+        let crc = crc32_le(u32::MAX, &raw[..4]);
+        raw[28..].copy_from_slice(&crc.to_le_bytes());
+        raw
+    }
+}
+
+/// Both select entries, none for an erased or corrupt sector
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OtaData {
+    entries: [Option<SelectEntry>; 2],
+}
+
+impl OtaData {
+    pub fn read<F: ReadNorFlash>(flash: &mut F) -> Result<Self, F::Error> {
+        let mut entries = [None; 2];
+        for (sector, entry) in entries.iter_mut().enumerate() {
+            let mut raw = [0u8; SELECT_ENTRY_LEN];
+            flash.read(
+                OTADATA_FLASH_OFFSET + sector as u32 * OTADATA_SECTOR_LEN,
+                &mut raw,
+            )?;
+            *entry = SelectEntry::decode(&raw);
+        }
+        Ok(OtaData { entries })
+    }
+
+    /// Sector and entry the bootloader follows, none after a serial flash
+    fn active(&self) -> Option<(usize, SelectEntry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(sector, entry)| entry.map(|entry| (sector, entry)))
+            .filter(|(_, entry)| !matches!(entry.state, ImageState::Invalid | ImageState::Aborted))
+            .max_by_key(|(_, entry)| entry.seq)
+    }
+
+    /// Slot running now, the first one without a valid entry
+    pub fn running_slot(&self) -> usize {
+        self.active().map_or(0, |(_, entry)| entry.slot())
+    }
+
+    /// Slot a new image is written to
+    pub fn update_slot(&self) -> usize {
+        (self.running_slot() + 1) % OTA_SLOTS.len()
+    }
+
+    pub fn running_state(&self) -> Option<ImageState> {
+        self.active().map(|(_, entry)| entry.state)
+    }
+
+    /// The running image was installed by an update and not confirmed yet
+    pub fn is_unconfirmed(&self) -> bool {
+        matches!(
+            self.running_state(),
+            Some(ImageState::New | ImageState::PendingVerify)
+        )
+    }
+
+    /// Boot `slot` from now on, in the sector not holding the active entry
+    pub fn select<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        slot: usize,
+        state: ImageState,
+    ) -> Result<(), F::Error> {
+        let (sector, seq) = match self.active() {
+            Some((sector, entry)) => {
+                let mut seq = entry.seq + 1;
+                while (seq - 1) as usize % OTA_SLOTS.len() != slot {
+                    seq += 1;
+                }
+                ((sector + 1) % 2, seq)
+            }
+            None => (0, slot as u32 + 1),
+        };
+        self.write(flash, sector, SelectEntry { seq, state })
+    }
+
+    /// Mark the running image as valid, it is kept from now on
+    pub fn confirm<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        match self.active() {
+            Some((sector, entry)) if entry.state != ImageState::Valid => self.write(
+                flash,
+                sector,
+                SelectEntry {
+                    state: ImageState::Valid,
+                    ..entry
+                },
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn write<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        sector: usize,
+        entry: SelectEntry,
+    ) -> Result<(), F::Error> {
+        let start = OTADATA_FLASH_OFFSET + sector as u32 * OTADATA_SECTOR_LEN;
+        flash.erase(start, start + OTADATA_SECTOR_LEN)?;
+        flash.write(start, &entry.encode())?;
+        self.entries[sector] = Some(entry);
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+//! Supply voltage and reset cause of a battery powered node
+//!
+//! The battery is read through a resistor divider on an ADC1 input (11 dB
+//! attenuation), averaged over a few conversions. The ESP32 ADC is not
+//! calibrated here, the voltage is good to about 5%, enough for the discharge
+//! trend the gateway follows (`gateway_lib::battery`).
+
+use crate::common::mesh_proto::ResetReason;
+
+// Conversions averaged per reading
+pub const BATTERY_SAMPLES: u32 = 16;
+// Input voltage of a full scale 12-bit conversion at 11 dB attenuation
+const ADC_FULL_SCALE_MV: f32 = 3300.0;
+const ADC_MAX_RAW: f32 = 4095.0;
+
+/// Battery voltage from the sum of `samples` raw conversions, `divider` being
+/// the ratio of the resistor divider (2 for two equal resistors)
+pub fn supply_voltage(raw_sum: u32, samples: u32, divider: u32) -> Option<f32> {
+    if samples == 0 {
+        return None;
+    }
+    let raw = raw_sum as f32 / samples as f32;
+    Some(raw / ADC_MAX_RAW * ADC_FULL_SCALE_MV * divider as f32 / 1000.0)
+}
+
+/// Reset cause of the ESP32 from its `SocResetReason` code
+pub fn reset_reason(soc_code: u8) -> ResetReason {
+    match soc_code {
+        0x01 => ResetReason::PowerOn,
+        // Core and CPU0 software resets, as after a firmware update
+        0x03 | 0x0C => ResetReason::Software,
+        0x05 => ResetReason::DeepSleep,
+        // Main system, RTC and CPU watchdogs
+        0x07 | 0x08 | 0x09 | 0x0B | 0x0D | 0x10 => ResetReason::Watchdog,
+        0x0F => ResetReason::Brownout,
+        _ => ResetReason::Other,
+    }
+}
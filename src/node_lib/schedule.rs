@@ -0,0 +1,417 @@
+//! Wake/sample/transmit scheduling of a deep-sleeping sensor node
+//!
+//! The node wakes every sample interval, accumulates the reading in RTC memory
+//! and only powers the radio once the transmit interval is reached. A cold boot
+//! always transmits right away so a new node shows up on the gateway quickly.
+//!
+//! The intervals set by the gateway (`mesh_proto::ConfigUpdate`) override the
+//! built-in ones, and a sample outside its alarm thresholds is sent right
+//! away. The gateway clock received with each update is kept across the deep
+//! sleeps.
+
+use crate::common::mesh::MacAddress;
+use crate::common::mesh_proto::{ConfigUpdate, NodeConfig, ResetReason};
+use crate::common::ota::OtaProgress;
+use crate::node_lib::channel_scan::is_valid_channel;
+
+// Detects garbage in RTC memory after a power loss ("NODE")
+const NODE_STATE_MAGIC: u32 = 0x4E4F_4445;
+// Never sleep less than this, even when the awake time overran the interval
+pub const MIN_SLEEP_MS: u64 = 1_000;
+// Frames sent between two session checks: the ones of a wake-up, or the
+// attempts of a firmware chunk request then the update report
+pub const SESSION_SEQ_MARGIN: u16 = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct NodeSchedule {
+    pub sample_interval_ms: u64,
+    pub transmit_interval_ms: u64,
+}
+
+/// State kept in RTC memory across deep sleeps
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct NodeState {
+    magic: u32,
+    pub wake_count: u32,
+    pub samples: u32,
+    pub sum: f32,
+    pub since_transmit_ms: u64,
+    pub failed_transmits: u32,
+    pub seq: u16,
+    // 0 until a session was started, see `node_lib::session`
+    pub session: u32,
+    // Cumulative delivery counters, sent in the link report of each frame
+    pub retries: u16,
+    pub given_up: u16,
+    // Parent found from the beacons, cleared to search again after a failure
+    pub parent: Option<MacAddress>,
+    // Mesh channel found with the parent, where the next search starts
+    pub channel: Option<u8>,
+    // Image being downloaded, resumed on the next wake
+    pub ota: Option<OtaProgress>,
+    // Failed transmits since a new image booted, see `node_lib::ota`
+    pub unconfirmed_transmits: u32,
+    // Configuration set by the gateway and the id it was sent with, 0 if none
+    pub config: NodeConfig,
+    pub config_id: u16,
+    // Gateway clock at the start of this wake-up, once synced
+    pub clock_ms: Option<u64>,
+    // Last reset other than a deep sleep wake-up, reported to the gateway
+    pub reset_reason: Option<ResetReason>,
+}
+
+impl Default for NodeState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeState {
+    pub const fn new() -> Self {
+        NodeState {
+            magic: NODE_STATE_MAGIC,
+            wake_count: 0,
+            samples: 0,
+            sum: 0.0,
+            since_transmit_ms: 0,
+            failed_transmits: 0,
+            seq: 0,
+            session: 0,
+            retries: 0,
+            given_up: 0,
+            parent: None,
+            channel: None,
+            ota: None,
+            unconfirmed_transmits: 0,
+            config: NodeConfig {
+                sample_interval_s: None,
+                transmit_interval_s: None,
+                alarm_low: None,
+                alarm_high: None,
+                channel: None,
+            },
+            config_id: 0,
+            clock_ms: None,
+            reset_reason: None,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == NODE_STATE_MAGIC && self.sum.is_finite()
+    }
+
+    /// Sequence number for the next frame, wraps around
+    pub fn next_seq(&mut self) -> u16 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    /// A new session is needed after a cold boot and before `seq` wraps, with
+    /// room for the frames sent until the next check
+    pub fn needs_session(&self) -> bool {
+        self.session == 0 || self.seq > u16::MAX - SESSION_SEQ_MARGIN
+    }
+
+    pub fn start_session(&mut self, session: u32) {
+        self.session = session;
+        self.seq = 0;
+    }
+
+    /// Record the reset cause of this boot, after `NodeSchedule::on_wake`
+    pub fn on_reset(&mut self, reason: ResetReason) {
+        if reason != ResetReason::DeepSleep {
+            self.reset_reason = Some(reason);
+        }
+    }
+
+    /// Take the configuration of an update when it is a new one, returns
+    /// whether it changed. A new channel has the mesh searched again from it.
+    pub fn apply_config(&mut self, update: &ConfigUpdate) -> bool {
+        match update.id {
+            Some(id) if id != self.config_id => {
+                if update.config.channel.is_some_and(|channel| {
+                    is_valid_channel(channel) && Some(channel) != self.channel
+                }) {
+                    self.channel = update.config.channel;
+                    self.parent = None;
+                }
+                self.config = update.config;
+                self.config_id = id;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Sync the clock on the gateway time received `awake_ms` after the
+    /// wake-up, returns the drift of the previous estimate
+    pub fn sync_clock(&mut self, gateway_ms: u64, awake_ms: u64) -> Option<i64> {
+        let clock_ms = gateway_ms.saturating_sub(awake_ms);
+        let drift_ms = self
+            .clock_ms
+            .map(|previous| previous as i64 - clock_ms as i64);
+        self.clock_ms = Some(clock_ms);
+        drift_ms
+    }
+
+    /// Move the clock to the next wake-up, `elapsed_ms` from this one
+    pub fn advance_clock(&mut self, elapsed_ms: u64) {
+        self.clock_ms = self.clock_ms.map(|clock_ms| clock_ms + elapsed_ms);
+    }
+
+    pub fn average(&self) -> Option<f32> {
+        if self.samples == 0 {
+            None
+        } else {
+            Some(self.sum / self.samples as f32)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WakePlan {
+    Sleep,
+    Transmit { average: f32, samples: u32 },
+}
+
+impl NodeSchedule {
+    pub fn new(sample_interval_ms: u64, transmit_interval_ms: u64) -> Self {
+        let sample_interval_ms = sample_interval_ms.max(MIN_SLEEP_MS);
+        NodeSchedule {
+            sample_interval_ms,
+            // Cannot transmit more often than we sample
+            transmit_interval_ms: transmit_interval_ms.max(sample_interval_ms),
+        }
+    }
+
+    /// Built-in intervals overridden by the ones set in `config`
+    pub fn with_config(&self, config: &NodeConfig) -> Self {
+        let interval_ms = |interval_s: Option<u32>, default_ms| {
+            interval_s.map_or(default_ms, |interval_s| interval_s as u64 * 1000)
+        };
+        NodeSchedule::new(
+            interval_ms(config.sample_interval_s, self.sample_interval_ms),
+            interval_ms(config.transmit_interval_s, self.transmit_interval_ms),
+        )
+    }
+
+    /// Record the sample of this wake-up, returns whether the radio is needed
+    pub fn on_wake(&self, state: &mut NodeState, sample: f32) -> WakePlan {
+        let cold_boot = !state.is_valid();
+        if cold_boot {
+            *state = NodeState::new();
+        }
+        state.wake_count = state.wake_count.wrapping_add(1);
+        if !cold_boot {
+            state.since_transmit_ms += self.sample_interval_ms;
+        }
+        if sample.is_finite() {
+            state.samples += 1;
+            state.sum += sample;
+        }
+
+        let alarm = sample.is_finite() && state.config.is_alarm(sample);
+        let due = cold_boot || alarm || state.since_transmit_ms >= self.transmit_interval_ms;
+        match (due, state.average()) {
+            (true, Some(average)) => WakePlan::Transmit {
+                average,
+                samples: state.samples,
+            },
+            _ => WakePlan::Sleep,
+        }
+    }
+
+    /// Reset the accumulator once sent, failed samples are kept for the next try
+    pub fn on_transmit_done(&self, state: &mut NodeState, success: bool) {
+        if success {
+            state.samples = 0;
+            state.sum = 0.0;
+            state.since_transmit_ms = 0;
+            state.failed_transmits = 0;
+        } else {
+            state.failed_transmits = state.failed_transmits.saturating_add(1);
+        }
+    }
+
+    /// Sleep time to keep the sampling period, minus the time spent awake
+    pub fn sleep_duration_ms(&self, awake_ms: u64) -> u64 {
+        self.sample_interval_ms
+            .saturating_sub(awake_ms)
+            .max(MIN_SLEEP_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::vec::Vec as StdVec;
+
+    const SAMPLE_MS: u64 = 60_000;
+    const TRANSMIT_MS: u64 = 5 * SAMPLE_MS;
+    // Time spent awake on each wake-up, without and with the radio
+    const SAMPLE_AWAKE_MS: u64 = 80;
+    const RADIO_AWAKE_MS: u64 = 1_500;
+
+    /// RTC memory as found after a power loss
+    fn power_loss() -> NodeState {
+        NodeState {
+            sum: f32::NAN,
+            ..NodeState::new()
+        }
+    }
+
+    /// Wake-ups of a node over `wakes` sample intervals, returns the wakes it
+    /// transmitted on and its time at each wake-up. `delivered` tells whether
+    /// the transmit of a wake went through.
+    fn run(
+        schedule: &NodeSchedule,
+        state: &mut NodeState,
+        wakes: u32,
+        delivered: impl Fn(u32) -> bool,
+    ) -> (StdVec<u32>, StdVec<u64>) {
+        let mut transmits = StdVec::new();
+        let mut wake_times = StdVec::new();
+        let mut now_ms = 0;
+        for wake in 1..=wakes {
+            wake_times.push(now_ms);
+            let awake_ms = match schedule.on_wake(state, 20.0 + wake as f32) {
+                WakePlan::Transmit { .. } => {
+                    transmits.push(wake);
+                    schedule.on_transmit_done(state, delivered(wake));
+                    RADIO_AWAKE_MS
+                }
+                WakePlan::Sleep => SAMPLE_AWAKE_MS,
+            };
+            now_ms += awake_ms + schedule.sleep_duration_ms(awake_ms);
+        }
+        (transmits, wake_times)
+    }
+
+    #[test]
+    fn node_transmits_on_cold_boot_then_every_transmit_interval() {
+        let schedule = NodeSchedule::new(SAMPLE_MS, TRANSMIT_MS);
+        let mut state = power_loss();
+        let (transmits, wake_times) = run(&schedule, &mut state, 16, |_| true);
+        assert_eq!(transmits, [1, 6, 11, 16]);
+        // The time spent awake is taken off the sleep
+        for (index, wake_ms) in wake_times.iter().enumerate() {
+            assert_eq!(*wake_ms, index as u64 * SAMPLE_MS);
+        }
+        assert_eq!(state.wake_count, 16);
+        assert_eq!(state.samples, 0);
+    }
+
+    #[test]
+    fn failed_transmits_keep_the_samples_and_retry_on_the_next_wake() {
+        let schedule = NodeSchedule::new(SAMPLE_MS, TRANSMIT_MS);
+        let mut state = power_loss();
+        let (transmits, _) = run(&schedule, &mut state, 8, |wake| wake != 6);
+        assert_eq!(transmits, [1, 6, 7]);
+        assert_eq!(state.failed_transmits, 0);
+
+        let mut state = power_loss();
+        run(&schedule, &mut state, 6, |wake| wake == 1);
+        assert_eq!(state.failed_transmits, 1);
+        // Samples of wakes 2 to 6 wait for the next transmit
+        assert_eq!(state.samples, 5);
+        assert_eq!(state.average(), Some(24.0));
+    }
+
+    #[test]
+    fn alarms_and_invalid_samples() {
+        let schedule = NodeSchedule::new(SAMPLE_MS, TRANSMIT_MS);
+        let mut state = NodeState::new();
+        state.config.alarm_high = Some(30.0);
+        schedule.on_wake(&mut state, 20.0);
+        schedule.on_transmit_done(&mut state, true);
+
+        assert_eq!(schedule.on_wake(&mut state, 25.0), WakePlan::Sleep);
+        // Not counted, and no average to send while none was valid
+        assert_eq!(schedule.on_wake(&mut state, f32::NAN), WakePlan::Sleep);
+        assert_eq!(
+            schedule.on_wake(&mut state, 35.0),
+            WakePlan::Transmit {
+                average: 30.0,
+                samples: 2
+            }
+        );
+
+        let mut state = power_loss();
+        assert_eq!(schedule.on_wake(&mut state, f32::NAN), WakePlan::Sleep);
+    }
+
+    #[test]
+    fn intervals_are_bounded_and_overridden_by_the_gateway() {
+        let schedule = NodeSchedule::new(10, 5);
+        assert_eq!(schedule.sample_interval_ms, MIN_SLEEP_MS);
+        assert_eq!(schedule.transmit_interval_ms, MIN_SLEEP_MS);
+        // An awake time overrunning the interval still sleeps a little
+        assert_eq!(schedule.sleep_duration_ms(5_000), MIN_SLEEP_MS);
+
+        let defaults = NodeSchedule::new(SAMPLE_MS, TRANSMIT_MS);
+        let config = NodeConfig {
+            transmit_interval_s: Some(30),
+            ..NodeConfig::default()
+        };
+        let schedule = defaults.with_config(&config);
+        assert_eq!(schedule.sample_interval_ms, SAMPLE_MS);
+        // Never transmits more often than it samples
+        assert_eq!(schedule.transmit_interval_ms, SAMPLE_MS);
+    }
+
+    #[test]
+    fn gateway_clock_is_carried_across_sleeps() {
+        let schedule = NodeSchedule::new(SAMPLE_MS, TRANSMIT_MS);
+        let mut state = NodeState::new();
+        // Gateway time received 900 ms after the wake-up
+        assert_eq!(state.sync_clock(1_000_900, 900), None);
+        assert_eq!(state.clock_ms, Some(1_000_000));
+
+        let awake_ms = RADIO_AWAKE_MS;
+        state.advance_clock(awake_ms + schedule.sleep_duration_ms(awake_ms));
+        assert_eq!(state.clock_ms, Some(1_000_000 + SAMPLE_MS));
+        // The RTC ran 40 ms fast over the sleep
+        assert_eq!(
+            state.sync_clock(1_000_000 + SAMPLE_MS - 40 + 700, 700),
+            Some(40)
+        );
+    }
+
+    #[test]
+    fn config_updates_are_applied_once() {
+        let mut state = NodeState::new();
+        state.channel = Some(1);
+        state.parent = Some([1; 6]);
+        let update = ConfigUpdate {
+            id: Some(7),
+            time_ms: 0,
+            config: NodeConfig {
+                channel: Some(6),
+                ..NodeConfig::default()
+            },
+            auth: None,
+        };
+        assert!(state.apply_config(&update));
+        // A new channel has the mesh searched again
+        assert_eq!((state.channel, state.parent), (Some(6), None));
+        assert!(!state.apply_config(&update));
+        assert!(!state.apply_config(&ConfigUpdate { id: None, ..update }));
+        assert_eq!(state.config_id, 7);
+    }
+
+    #[test]
+    fn session_is_renewed_before_the_sequence_wraps() {
+        let mut state = NodeState::new();
+        assert!(state.needs_session());
+        state.start_session(3);
+        assert!(!state.needs_session());
+        state.seq = u16::MAX - SESSION_SEQ_MARGIN;
+        assert!(!state.needs_session());
+        state.next_seq();
+        assert!(state.needs_session());
+        state.start_session(4);
+        assert_eq!(state.next_seq(), 1);
+    }
+}
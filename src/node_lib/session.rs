@@ -0,0 +1,69 @@
+//! Session counter of the node, persisted in flash
+//!
+//! The session is part of the AEAD nonce (see `common::keys`) and must never
+//! repeat under the same key. It moves forward on each cold boot, when the RTC
+//! memory is lost, and before the frame sequence number wraps around. The
+//! counter lives in the `mesh_state` partition, a blank partition carries on
+//! from the counter earlier firmwares kept in `nvs`.
+
+use embedded_storage::{ReadStorage, Storage};
+
+// `mesh_state` partition of `partitions.csv`
+pub const SESSION_FLASH_OFFSET: u32 = 0x3D0000;
+// Counter of earlier firmwares, at the start of the `nvs` partition
+pub const LEGACY_SESSION_FLASH_OFFSET: u32 = 0x9000;
+
+// Erased flash reads as all ones
+const BLANK: u32 = u32::MAX;
+
+fn read_counter<S: ReadStorage>(storage: &mut S, offset: u32) -> Result<u32, S::Error> {
+    let mut raw = [0u8; 4];
+    storage.read(offset, &mut raw)?;
+    Ok(u32::from_le_bytes(raw))
+}
+
+/// Read, bump and store the session counter, returns the new session
+pub fn next_session<S: Storage>(storage: &mut S) -> Result<u32, S::Error> {
+    let previous = match read_counter(storage, SESSION_FLASH_OFFSET)? {
+        BLANK => read_counter(storage, LEGACY_SESSION_FLASH_OFFSET)?,
+        previous => previous,
+    };
+    // A stored all ones would read back as blank and restart at 1
+    let session = match previous.wrapping_add(1) {
+        0 | BLANK => 1,
+        session => session,
+    };
+    storage.write(SESSION_FLASH_OFFSET, &session.to_le_bytes())?;
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::mem_flash::{MemFlash, SECTOR_LEN};
+    use embedded_storage::nor_flash::RmwNorFlashStorage;
+
+    const FLASH_LEN: usize = SESSION_FLASH_OFFSET as usize + SECTOR_LEN;
+
+    #[test]
+    fn sessions_move_forward_across_boots() {
+        let mut flash = MemFlash::new(FLASH_LEN);
+        let mut buffer = [0u8; SECTOR_LEN];
+        let mut storage = RmwNorFlashStorage::new(&mut flash, &mut buffer);
+        assert_eq!(next_session(&mut storage).unwrap(), 1);
+        assert_eq!(next_session(&mut storage).unwrap(), 2);
+        assert_eq!(next_session(&mut storage).unwrap(), 3);
+    }
+
+    #[test]
+    fn counter_of_an_earlier_firmware_is_carried_on() {
+        let mut flash = MemFlash::new(FLASH_LEN);
+        let mut buffer = [0u8; SECTOR_LEN];
+        let mut storage = RmwNorFlashStorage::new(&mut flash, &mut buffer);
+        storage
+            .write(LEGACY_SESSION_FLASH_OFFSET, &41u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(next_session(&mut storage).unwrap(), 42);
+        assert_eq!(next_session(&mut storage).unwrap(), 43);
+    }
+}